@@ -1,5 +1,26 @@
 MEMORY
 {
-    FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+    /* The first 50K hold the bootloader and its scratch page, and the
+       bootloader starts the firmware from here. See bootloader/memory.x. */
+    FLASH : ORIGIN = 0x0800C800, LENGTH = 100K
+    /* Where the bootloader stages updates, read for the installed image */
+    STAGING : ORIGIN = 0x08025800, LENGTH = 102K
+    /* Two 2K pages for the config store, kept out of the firmware image so
+       flashing new firmware doesn't wipe the settings. See src/flash.rs. */
+    CONFIG : ORIGIN = 0x0803F000, LENGTH = 4K
     RAM : ORIGIN = 0x20000000, LENGTH = 40K
+    /* Core-coupled RAM, which neither image uses and a reset leaves alone */
+    CCMRAM : ORIGIN = 0x10000000, LENGTH = 8K
 }
+
+_app_start = ORIGIN(FLASH);
+_config_start = ORIGIN(CONFIG);
+_config_end = ORIGIN(CONFIG) + LENGTH(CONFIG);
+_staging_end = ORIGIN(STAGING) + LENGTH(STAGING);
+/* CCMRAM holds only these, at fixed places both images agree on, so they
+   outlive a reset and whichever image runs after it. Neither image may put
+   anything else there. */
+_boot_request = ORIGIN(CCMRAM);
+_boot_confirm = ORIGIN(CCMRAM) + 4;
+/* 256 bytes, for the firmware's crash record (see board/src/crash.rs) */
+_crash_record = ORIGIN(CCMRAM) + 8;
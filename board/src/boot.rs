@@ -0,0 +1,57 @@
+//! The firmware's side of updates: it runs from the application slot of a
+//! bootloader (see `bootloader/src/main.rs`), which it resets into when the
+//! host asks, it reports the image the bootloader last installed, and it
+//! confirms that image boots.
+
+use common::dispatch::Target;
+use common::update::{Header, BOOT_CONFIRM, BOOT_REQUEST, HEADER_SIZE};
+use core::ptr::{self, addr_of, addr_of_mut};
+use cortex_m::peripheral::SCB;
+
+// Bytes in a flash page, the last of the staging slot holding the header
+const PAGE_SIZE: usize = 2048;
+
+extern "C" {
+    // Defined in memory.x
+    static _app_start: u8;
+    static _staging_end: u8;
+    static mut _boot_request: u32;
+    static mut _boot_confirm: u32;
+}
+
+/// Take interrupts through the firmware's own vector table, rather than
+/// the one the bootloader left in place, or none at all when started by a
+/// debugger.
+///
+/// # Safety
+/// Call before enabling any interrupt.
+pub unsafe fn use_vector_table() {
+    (*SCB::ptr()).vtor.write(addr_of!(_app_start) as u32);
+}
+
+pub fn reset(target: Target) -> ! {
+    let request = match target {
+        Target::Bootloader => BOOT_REQUEST,
+        Target::Application => 0,
+    };
+    // Safety: only the bootloader reads it, after the reset
+    unsafe { addr_of_mut!(_boot_request).write_volatile(request) };
+    SCB::sys_reset()
+}
+
+/// Keep the image the bootloader installed last, rather than have it put
+/// the old firmware back at the next reset.
+pub fn confirm() {
+    // Safety: only the bootloader reads it, after a reset
+    unsafe { addr_of_mut!(_boot_confirm).write_volatile(BOOT_CONFIRM) };
+}
+
+/// The header of the last image the bootloader staged, if there is one.
+pub fn staged_header() -> Option<Header> {
+    let start = unsafe { addr_of!(_staging_end) as usize } - PAGE_SIZE;
+    let mut bytes = [0u8; HEADER_SIZE];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = unsafe { ptr::read_volatile((start + i) as *const u8) };
+    }
+    Header::parse(&bytes)
+}
@@ -0,0 +1,36 @@
+//! Monotonic microsecond clock built on the DWT cycle counter.
+//!
+//! CYCCNT is only 32 bits wide and wraps every ~89 s at 48 MHz, so wraps are
+//! counted in software. `now_us` must be called at least once per wrap
+//! period; the TIM7 handler takes care of that.
+
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::{DCB, DWT};
+
+// (last CYCCNT seen, number of wraps)
+static STATE: Mutex<Cell<(u32, u32)>> = Mutex::new(Cell::new((0, 0)));
+static CYCLES_PER_US: Mutex<Cell<u32>> = Mutex::new(Cell::new(1));
+
+pub fn setup(dcb: &mut DCB, dwt: &mut DWT, sysclk_hz: u32) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    cortex_m::interrupt::free(|cs| {
+        CYCLES_PER_US.borrow(cs).set(sysclk_hz / 1_000_000);
+        STATE.borrow(cs).set((DWT::get_cycle_count(), 0));
+    });
+}
+
+/// Microseconds since boot.
+pub fn now_us() -> u64 {
+    cortex_m::interrupt::free(|cs| {
+        let cyccnt = DWT::get_cycle_count();
+        let (last, mut wraps) = STATE.borrow(cs).get();
+        if cyccnt < last {
+            wraps += 1;
+        }
+        STATE.borrow(cs).set((cyccnt, wraps));
+        let cycles = ((wraps as u64) << 32) | cyccnt as u64;
+        cycles / CYCLES_PER_US.borrow(cs).get() as u64
+    })
+}
@@ -0,0 +1,116 @@
+//! Crash capture, in place of semihosting.
+//!
+//! A panic or a HardFault is written to CCMRAM, which neither the firmware
+//! nor the bootloader clears at boot, and the board resets. The next boot picks it up with `take` and reports it to
+//! the host as a `CrashReport`.
+
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+
+use common::crash::{CrashReport, ExceptionFrame, MAX_CRASH_FILE_SIZE};
+use common::message::{BoundedText, LogText, MAX_LOG_SIZE};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::exception;
+
+// Marks `_crash_record` as written by a handler rather than left over from power-up
+const MAGIC: u32 = 0x4352_5348;
+const PANIC: u32 = 0;
+const HARD_FAULT: u32 = 1;
+
+// One byte more than a `CrashReport` carries, so `LogText::new` can tell
+// the message was cut short
+type PanicText = BoundedText<{ MAX_LOG_SIZE + 1 }>;
+
+// Room for a `Crash` at `_crash_record` in memory.x
+const CRASH_RECORD_SIZE: usize = 256;
+
+// Only plain integers, so whatever was in RAM at power-up is a valid value
+#[repr(C)]
+struct Crash {
+    magic: u32,
+    cause: u32,
+    // PC, LR and xPSR, for a fault
+    frame: [u32; 3],
+    line: u32,
+    file_len: u32,
+    file: [u8; MAX_CRASH_FILE_SIZE],
+    message_len: u32,
+    message: [u8; MAX_LOG_SIZE + 1],
+}
+
+impl Crash {
+    const EMPTY: Crash = Crash {
+        magic: MAGIC,
+        cause: PANIC,
+        frame: [0; 3],
+        line: 0,
+        file_len: 0,
+        file: [0; MAX_CRASH_FILE_SIZE],
+        message_len: 0,
+        message: [0; MAX_LOG_SIZE + 1],
+    };
+}
+
+const _: () = assert!(core::mem::size_of::<Crash>() <= CRASH_RECORD_SIZE);
+
+extern "C" {
+    // Defined in memory.x, outside the RAM the bootloader uses, as it runs
+    // between the crash and `take`
+    static mut _crash_record: MaybeUninit<Crash>;
+}
+
+/// The crash that ended the last boot, if it ended in one. Only the first
+/// call after a crash gets it.
+pub fn take() -> Option<CrashReport> {
+    // Safety: called before interrupts are enabled, and `Crash` has no
+    // invalid values
+    let crash = unsafe { addr_of!(_crash_record).read_volatile().assume_init() };
+    if crash.magic != MAGIC {
+        return None;
+    }
+    unsafe { addr_of_mut!(_crash_record).cast::<u32>().write_volatile(0) };
+    let [pc, lr, xpsr] = crash.frame;
+    match crash.cause {
+        PANIC => {
+            let file = &crash.file[..(crash.file_len as usize).min(MAX_CRASH_FILE_SIZE)];
+            let message = &crash.message[..(crash.message_len as usize).min(crash.message.len())];
+            let file = core::str::from_utf8(file).unwrap_or("");
+            Some(CrashReport::panic(LogText::new(message), file, crash.line))
+        }
+        _ => Some(CrashReport::hard_fault(ExceptionFrame { pc, lr, xpsr })),
+    }
+}
+
+fn store(crash: Crash) -> ! {
+    // Safety: interrupts are off and nothing else writes `_crash_record`
+    unsafe { addr_of_mut!(_crash_record).write_volatile(MaybeUninit::new(crash)) };
+    SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let mut crash = Crash::EMPTY;
+    if let Some(location) = info.location() {
+        // The end of the path names the file
+        let file = location.file().as_bytes();
+        let file = &file[file.len().saturating_sub(MAX_CRASH_FILE_SIZE)..];
+        crash.file[..file.len()].copy_from_slice(file);
+        crash.file_len = file.len() as u32;
+        crash.line = location.line();
+    }
+    let mut message = PanicText::EMPTY;
+    let _ = write!(message, "{}", info);
+    let message = message.as_bytes();
+    crash.message[..message.len()].copy_from_slice(message);
+    crash.message_len = message.len() as u32;
+    store(crash)
+}
+
+#[exception]
+fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    cortex_m::interrupt::disable();
+    store(Crash { cause: HARD_FAULT, frame: [frame.pc, frame.lr, frame.xpsr], ..Crash::EMPTY })
+}
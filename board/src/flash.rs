@@ -0,0 +1,147 @@
+//! The `CONFIG` region of the STM32F303's internal flash, as a
+//! `common::config::Flash`.
+//!
+//! The HAL only exposes the flash latency settings, so erasing and
+//! programming go straight to the FLASH registers (RM0316 section 4).
+//! The CPU stalls on flash reads while a page erase is in progress, which
+//! can hold up USB for a few tens of milliseconds during a config commit.
+
+use common::config::Flash;
+use core::ptr;
+use stm32f3xx_hal::pac;
+
+const PAGE_SIZE: usize = 2048;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+extern "C" {
+    // Defined in memory.x
+    static _config_start: u8;
+    static _config_end: u8;
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    OutOfRange,
+    Unaligned,
+    /// Tried to program a half-word that wasn't erased.
+    Program,
+    WriteProtected,
+}
+
+/// The two pages reserved for the config store. Only one may exist, as it
+/// owns the flash controller's erase and program operations.
+pub struct ConfigFlash {
+    start: usize,
+}
+
+impl ConfigFlash {
+    /// # Safety
+    /// Nothing else may erase or program flash while this is alive.
+    pub unsafe fn new() -> ConfigFlash {
+        let start = &_config_start as *const u8 as usize;
+        let end = &_config_end as *const u8 as usize;
+        debug_assert_eq!(end - start, 2 * PAGE_SIZE);
+        ConfigFlash { start }
+    }
+
+    fn regs() -> &'static pac::flash::RegisterBlock {
+        unsafe { &*pac::FLASH::ptr() }
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), FlashError> {
+        if offset + len > 2 * PAGE_SIZE {
+            Err(FlashError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Run `f` with the flash controller unlocked and wait for it to finish.
+    fn unlocked<T>(f: impl FnOnce(&pac::flash::RegisterBlock) -> Result<T, FlashError>) -> Result<T, FlashError> {
+        let regs = Self::regs();
+        if regs.cr.read().bits() & CR_LOCK != 0 {
+            regs.keyr.write(|w| unsafe { w.bits(KEY1) });
+            regs.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+        let result = f(regs);
+        regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_LOCK) });
+        result
+    }
+
+    fn wait(regs: &pac::flash::RegisterBlock) -> Result<(), FlashError> {
+        while regs.sr.read().bits() & SR_BSY != 0 {}
+        let sr = regs.sr.read().bits();
+        // Flags are cleared by writing 1
+        regs.sr.write(|w| unsafe { w.bits(SR_EOP | SR_PGERR | SR_WRPRTERR) });
+        if sr & SR_WRPRTERR != 0 {
+            Err(FlashError::WriteProtected)
+        } else if sr & SR_PGERR != 0 {
+            Err(FlashError::Program)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Flash for ConfigFlash {
+    type Error = FlashError;
+
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.check(offset, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { ptr::read_volatile((self.start + offset + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.check(offset, data.len())?;
+        if !offset.is_multiple_of(2) || !data.len().is_multiple_of(2) {
+            return Err(FlashError::Unaligned);
+        }
+        let start = self.start + offset;
+        Self::unlocked(|regs| {
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PG) });
+            let mut result = Ok(());
+            for (i, half_word) in data.chunks(2).enumerate() {
+                let value = u16::from_le_bytes([half_word[0], half_word[1]]);
+                unsafe { ptr::write_volatile((start + 2 * i) as *mut u16, value) };
+                result = Self::wait(regs);
+                if result.is_err() {
+                    break;
+                }
+            }
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_PG) });
+            result
+        })
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        if page > 1 {
+            return Err(FlashError::OutOfRange);
+        }
+        let address = (self.start + page * PAGE_SIZE) as u32;
+        Self::unlocked(|regs| {
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PER) });
+            regs.ar.write(|w| unsafe { w.bits(address) });
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+            let result = Self::wait(regs);
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_PER) });
+            result
+        })
+    }
+}
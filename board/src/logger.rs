@@ -0,0 +1,122 @@
+//! Log records for the host, in place of semihosting.
+//!
+//! Firmware code logs with the `log` crate's macros. Records at `MAX_LEVEL`
+//! or above are kept in a ring of `RING_SIZE` until the host has said
+//! `Hello`, then sent to it as unsolicited `Message::Log`s. When the ring is
+//! full the oldest records make way, and the host is told how many it lost.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use common::message::{LogLevel, LogTarget, LogText};
+use common::Message;
+use cortex_m::interrupt::Mutex;
+use log::{LevelFilter, Log, Metadata, Record};
+
+const RING_SIZE: usize = 8;
+#[cfg(debug_assertions)]
+const MAX_LEVEL: LevelFilter = LevelFilter::Debug;
+#[cfg(not(debug_assertions))]
+const MAX_LEVEL: LevelFilter = LevelFilter::Info;
+
+static LOGGER: Logger = Logger;
+static RING: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring::new()));
+
+#[derive(Clone, Copy)]
+struct Entry {
+    level: LogLevel,
+    target: LogTarget,
+    text: LogText,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry { level: LogLevel::Info, target: LogTarget::EMPTY, text: LogText::EMPTY };
+}
+
+struct Ring {
+    entries: [Entry; RING_SIZE],
+    first: usize,
+    len: usize,
+    // Records lost to a full ring since the host was last told
+    dropped: u32,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring { entries: [Entry::EMPTY; RING_SIZE], first: 0, len: 0, dropped: 0 }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if self.len == RING_SIZE {
+            self.first = (self.first + 1) % RING_SIZE;
+            self.len -= 1;
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        self.entries[(self.first + self.len) % RING_SIZE] = entry;
+        self.len += 1;
+    }
+
+    /// What to send next: how many records were dropped, if any were, then
+    /// the oldest record.
+    fn peek(&self) -> Option<Message> {
+        if self.dropped > 0 {
+            let mut text = LogText::EMPTY;
+            let _ = write!(text, "{} log records dropped", self.dropped);
+            return Some(Message::Log { level: LogLevel::Warn, target: LogTarget::new(module_path!().as_bytes()), text });
+        }
+        if self.len == 0 {
+            return None;
+        }
+        let Entry { level, target, text } = self.entries[self.first];
+        Some(Message::Log { level, target, text })
+    }
+
+    /// Forget what `peek` returned.
+    fn pop(&mut self) {
+        if self.dropped > 0 {
+            self.dropped = 0;
+        } else if self.len > 0 {
+            self.first = (self.first + 1) % RING_SIZE;
+            self.len -= 1;
+        }
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= MAX_LEVEL
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Anything past `MAX_LOG_SIZE` is cut off and flagged
+        let mut text = LogText::EMPTY;
+        let _ = write!(text, "{}", record.args());
+        let entry = Entry { level: record.level().into(), target: LogTarget::new(record.target().as_bytes()), text };
+        cortex_m::interrupt::free(|cs| RING.borrow(cs).borrow_mut().push(entry));
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn setup() {
+    // Only fails if a logger is already set
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(MAX_LEVEL);
+}
+
+/// Hand the next record to `send`, and forget it if `send` took it.
+pub fn send_next(send: impl FnOnce(Message) -> bool) {
+    cortex_m::interrupt::free(|cs| {
+        let mut ring = RING.borrow(cs).borrow_mut();
+        if let Some(msg) = ring.peek() {
+            if send(msg) {
+                ring.pop();
+            }
+        }
+    });
+}
@@ -1,33 +1,35 @@
 #![no_std]
 #![no_main]
 
-//use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
-use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
-
 use stm32f3xx_hal as hal;
 
 use common::{
-    link::Link,
+    config::ConfigStore,
+    device::{DeviceInfo, FirmwareVersion, Uid},
+    dispatch::{AppHardware, AppState, Hardware, Reading, Target},
+    link::{Link, LinkError, MAX_FRAME_SIZE},
+    message_queue::QueueStats,
+    update::ImageInfo,
     usb::{VENDOR_ID, PROD_ID},
-    Message,
+    Envelope, Sensors, Stamp,
 };
 
 use core::cell::RefCell;
 
 use cortex_m::{asm::delay, interrupt::Mutex};
 use cortex_m_rt::entry;
-use cortex_m_semihosting::hprintln;
+use log::{error, info, warn};
 
-use accelerometer::{
-    Accelerometer,
-    vector::{F32x3, I16x3},
-};
+use accelerometer::Accelerometer;
 use stm32f3_discovery::compass::Compass;
+use embedded_hal::{blocking::spi, digital::v2::OutputPin};
+use l3gd20::L3gd20;
 
 use hal::{
     gpio::{gpioe, Output, PushPull},
     interrupt,
     pac,
+    spi::Spi,
     timer::{Timer, Event},
     usb::{Peripheral, UsbBus as UsbBusType},
 };
@@ -36,9 +38,15 @@ use hal::prelude::*;
 use usb_device::prelude::*;
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
+mod boot;
+mod clock;
+mod crash;
+mod flash;
+mod logger;
 mod message_manager;
 
-use message_manager::{message_pop, message_push};
+use flash::ConfigFlash;
+use message_manager::{message_pop, message_push, message_stats};
 
 type Timer7 = Timer<pac::TIM7>;
 static TIMER7: Mutex<RefCell<Option<Timer7>>> = Mutex::new(RefCell::new(None));
@@ -48,24 +56,80 @@ static GREENLED: Mutex<RefCell<Option<GreenLed>>> = Mutex::new(RefCell::new(None
 
 static TRIGGER_READ: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
 
-struct App {
-    mag_data: I16x3,
-    accel_data: F32x3,
+// Where the factory programs the 96-bit unique device ID (RM0316 34.1)
+const UID_ADDRESS: usize = 0x1FFF_F7AC;
+// L3GD20 sensitivity at its default ±250 dps full scale
+const GYRO_DPS_PER_DIGIT: f32 = 0.00875;
+
+/// The firmware's side of `Hardware`, apart from the message queue, the
+/// clock and TIM7, which are globals.
+struct Board {
+    config: Option<ConfigStore<ConfigFlash>>,
+    // Reset once every queued reply is sent
+    reset: Option<Target>,
 }
 
-impl App {
-    fn new() -> App {
-        App {
-            mag_data: I16x3::new(0, 0, 0),
-            accel_data: F32x3::new(0., 0., 0.),
-        }
+impl Hardware for Board {
+    fn push(&mut self, env: Envelope) -> bool {
+        message_push(env)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let version = FirmwareVersion::parse(env!("CARGO_PKG_VERSION")).unwrap_or_default();
+        DeviceInfo::new(version, uid(), Sensors::NONE)
+    }
+
+    fn stats(&self) -> QueueStats {
+        message_stats()
+    }
+
+    fn reset(&mut self, target: Target) {
+        self.reset = Some(target);
+    }
+}
+
+impl AppHardware for Board {
+    type Flash = ConfigFlash;
+
+    fn now_us(&self) -> u64 {
+        clock::now_us()
+    }
+
+    fn set_sample_rate(&mut self, rate_hz: u16) {
+        cortex_m::interrupt::free(|cs| {
+            TIMER7.borrow(cs).borrow_mut().as_mut().unwrap().start((rate_hz as u32).Hz());
+        });
+    }
+
+    fn config(&mut self) -> Option<&mut ConfigStore<ConfigFlash>> {
+        self.config.as_mut()
+    }
+
+    fn installed(&mut self) -> Option<ImageInfo> {
+        boot::staged_header().and_then(|header| header.application())
+    }
+
+    fn confirm_boot(&mut self) {
+        boot::confirm();
     }
 }
 
 #[entry]
 fn main() -> ! {
-    let mut app = App::new();
+    // Safety: interrupts are still off
+    unsafe { boot::use_vector_table() };
+    logger::setup();
+    let crash = crash::take();
+    // Safety: nothing else touches flash
+    let config = ConfigStore::new(unsafe { ConfigFlash::new() });
+    if let Err(e) = &config {
+        error!("Config store unavailable: {:?}", e);
+    }
+    let mut board = Board { config: config.ok(), reset: None };
+    let mut app = AppState::new(Sensors::ACCEL | Sensors::MAG, crash);
+    app.apply_config(board.config());
 
+    let mut core_peris = cortex_m::Peripherals::take().unwrap();
     let peris = pac::Peripherals::take().unwrap();
     let mut acr = peris.FLASH.constrain().acr;
     let mut rcc = peris.RCC.constrain();
@@ -76,6 +140,7 @@ fn main() -> ! {
         .pclk1(24.MHz())
         .pclk2(24.MHz())
         .freeze(&mut acr);
+    clock::setup(&mut core_peris.DCB, &mut core_peris.DWT, clocks.sysclk().0);
 
     let mut gpioa = peris.GPIOA.split(&mut rcc.ahb);
     let mut gpiob = peris.GPIOB.split(&mut rcc.ahb);
@@ -109,6 +174,26 @@ fn main() -> ! {
     )
     .unwrap();
 
+    let sck = gpioa.pa5.into_af5_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrl);
+    let miso = gpioa.pa6.into_af5_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrl);
+    let mosi = gpioa.pa7.into_af5_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrl);
+    let mut gyro_cs = gpioe.pe3.into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+    gyro_cs.set_high().ok();
+    let spi = Spi::spi1(
+        peris.SPI1,
+        (sck, miso, mosi),
+        l3gd20::MODE,
+        1.MHz(),
+        clocks,
+        &mut rcc.apb2,
+    );
+    // Keep running without the gyro rather than refusing to enumerate
+    let mut gyro = L3gd20::new(spi, gyro_cs).ok();
+    match gyro {
+        Some(_) => app.sensors = app.sensors | Sensors::GYRO,
+        None => warn!("Gyro not responding"),
+    }
+
     let usb = Peripheral {
         usb: peris.USB,
         pin_dm: usb_dm,
@@ -119,16 +204,20 @@ fn main() -> ! {
     let mut serial = SerialPort::new(&usb_bus);
 
     let mut link = Link::new();
+    // The chip's unique ID tells boards on the same host apart
+    let serial_number = cortex_m::singleton!(: [u8; Uid::HEX_LEN] = [0; Uid::HEX_LEN]).unwrap();
+    let serial_number = uid().to_hex(serial_number);
     // Thanks interbiometrics!
     let vid_pid = UsbVidPid(VENDOR_ID, PROD_ID);
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, vid_pid)
         .manufacturer("Fake Company")
         .product("Serial Port")
-        .serial_number("TEST")
+        .serial_number(serial_number)
         .device_class(USB_CLASS_CDC)
         .build();
 
-    let mut tim7 = Timer::tim7(peris.TIM7, 1.Hz(), clocks, &mut rcc.apb1);
+    let mut sample_count: u32 = 0;
+    let mut tim7 = Timer::tim7(peris.TIM7, (app.idle_rate_hz as u32).Hz(), clocks, &mut rcc.apb1);
     tim7.listen(Event::Update);
 
     cortex_m::interrupt::free(|cs| {
@@ -139,25 +228,35 @@ fn main() -> ! {
 
     unsafe { pac::NVIC::unmask(pac::Interrupt::TIM7) };
     message_manager::setup();
-    let _ = hprintln!("Starting loop!");
+    info!("Starting loop");
 
     loop {
-        if !usb_dev.poll(&mut [&mut serial]) {
-            continue;
+        if usb_dev.state() != UsbDeviceState::Configured {
+            app.host_connected = false;
         }
-
-        let mut buf = [0u8; 256];
-        match serial.read(&mut buf) {
-            Ok(count) if count > 0 => {
-                red_led.set_high().ok();
-                decode_messages(&mut buf[..count], &mut link, &mut app);
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        // Nothing to read without a USB event, but samples and queued
+        // replies still go out
+        if usb_dev.poll(&mut [&mut serial]) {
+            match serial.read(&mut buf) {
+                Ok(count) if count > 0 => {
+                    red_led.set_high().ok();
+                    decode_messages(&mut buf[..count], &mut link, &mut app, &mut board);
+                }
+                _ => {}
             }
-            _ => {}
+            red_led.set_low().ok();
         }
-        red_led.set_low().ok();
 
-        if let Some(msg) = message_pop() {
-            encode_and_send(msg, &mut buf, &mut link, &mut serial);
+        if app.host_connected {
+            logger::send_next(|msg| message_push(msg.into()));
+        }
+        if let Some(env) = message_pop() {
+            encode_and_send(env, &mut buf, &mut link, &mut serial);
+        } else if let Some(target) = board.reset {
+            // Give the host a moment to read the last reply
+            delay(clocks.sysclk().0 / 100);
+            boot::reset(target);
         }
         let mut read = false;
         cortex_m::interrupt::free(|cs| {
@@ -165,32 +264,59 @@ fn main() -> ! {
         });
         if read {
             orange_led.set_high().ok();
-            read_accel(&mut compass, &mut app);
-            read_mag(&mut compass, &mut app);
+            sample_count = sample_count.wrapping_add(1);
+            read_accel(&mut compass, &mut app, sample_count);
+            read_mag(&mut compass, &mut app, sample_count);
+            if let Some(gyro) = gyro.as_mut() {
+                read_gyro(gyro, &mut app, sample_count);
+            }
+            app.push_samples(&mut board);
             orange_led.set_low().ok();
         }
     }
 }
 
-fn read_accel(compass: &mut Compass, app: &mut App) {
+fn uid() -> Uid {
+    let read = |i: usize| unsafe { core::ptr::read_volatile((UID_ADDRESS + 4 * i) as *const u32) };
+    Uid([read(0), read(1), read(2)])
+}
+
+fn read_accel(compass: &mut Compass, app: &mut AppState, count: u32) {
     if let Ok(accel) = compass.accel_norm() {
-        app.accel_data = accel;
+        let value = app.accel_calibration.apply([accel.x, accel.y, accel.z]);
+        app.accel = Reading { stamp: Stamp::new(clock::now_us(), count), value };
     }
 }
 
-fn read_mag(compass: &mut Compass, app: &mut App) {
+fn read_mag(compass: &mut Compass, app: &mut AppState, count: u32) {
     if let Ok(mag) = compass.mag_raw() {
-        app.mag_data = mag;
+        let (x, y, z) = app.mag_calibration.apply_raw(mag.x, mag.y, mag.z);
+        app.mag = Reading { stamp: Stamp::new(clock::now_us(), count), value: [x, y, z] };
+    }
+}
+
+fn read_gyro<SPI, CS, E>(gyro: &mut L3gd20<SPI, CS>, app: &mut AppState, count: u32)
+where
+    SPI: spi::Transfer<u8, Error = E> + spi::Write<u8, Error = E>,
+    CS: OutputPin,
+{
+    if let Ok(raw) = gyro.gyro() {
+        let value = [
+            raw.x as f32 * GYRO_DPS_PER_DIGIT,
+            raw.y as f32 * GYRO_DPS_PER_DIGIT,
+            raw.z as f32 * GYRO_DPS_PER_DIGIT,
+        ];
+        app.gyro = Reading { stamp: Stamp::new(clock::now_us(), count), value };
     }
 }
 
 fn encode_and_send<T: usb_device::bus::UsbBus>(
-    msg: Message,
+    env: Envelope,
     buf: &mut [u8],
     link: &mut Link,
     serial: &mut SerialPort<T>
 ) {
-    match link.encode(&msg, buf) {
+    match link.encode(&env, buf) {
         Ok(size) => {
             let mut write_offset = 0;
             while write_offset < size {
@@ -202,63 +328,42 @@ fn encode_and_send<T: usb_device::bus::UsbBus>(
                         break;
                     }
                     Err(e) => {
-                        let _ = hprintln!("Partial write! {:?}", e);
+                        warn!("Partial write: {:?}", e);
                         break;
                     }
                 }
             }
         }
         Err(e) => {
-            let _ = hprintln!("Failed to encode! {:?}", e);
+            error!("Failed to encode: {:?}", e);
         }
     }
 
 }
 
-fn decode_messages(buf: &mut [u8], link: &mut Link, app: &mut App) {
+fn decode_messages(buf: &mut [u8], link: &mut Link, app: &mut AppState, board: &mut Board) {
     let length = buf.len();
     let mut offset = 0;
-    loop {
+    while offset < length {
         let read = match link.decode(&buf[offset..]) {
-            Ok((read, Some(msg))) => {
-                process_message(msg, app);
+            Ok((read, Some(env))) => {
+                app.process_message(env, board);
                 read
             }
             Ok((read, None)) => {
                 read
             }
+            Err(LinkError::Checksum { read }) => {
+                warn!("Bad checksum");
+                read
+            }
             Err(e) => {
-                let _ = hprintln!("Error decoding! {:?}", e);
-                break;
+                warn!("Error decoding: {:?}", e);
+                // Decoding errors skip the bad frame, so carry on after it
+                e.read().unwrap_or(length - offset)
             }
         };
         offset += read;
-        if offset >= length {
-            break;
-        }
-    }
-}
-
-fn process_message(msg: Message, app: &mut App) {
-    use common::Message::*;
-    match msg {
-        Nop => (),
-        Hello => {
-            message_push(Message::HelloAck);
-            ()
-        }
-        HelloAck => (),
-        AccelReq => {
-            let accel = &app.accel_data;
-            let msg = Message::Accel(accel.x, accel.y, accel.z);
-            message_push(msg);
-        },
-        MagReq => {
-            let mag = &app.mag_data;
-            let msg = Message::Mag(mag.x, mag.y, mag.z);
-            message_push(msg);
-        },
-        _ => (),
     }
 }
 
@@ -269,5 +374,7 @@ fn TIM7() {
         GREENLED.borrow(cs).borrow_mut().as_mut().unwrap().toggle().unwrap();
         TRIGGER_READ.borrow(cs).replace(true);
     });
+    // Guarantees the clock sees every CYCCNT wrap
+    clock::now_us();
 }
 
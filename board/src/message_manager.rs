@@ -1,24 +1,31 @@
 use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
-use common::{Message, MessageQueue};
+use common::message_queue::{OverflowPolicy, QueueStats};
+use common::{Envelope, MessageQueue};
 
 static QUEUE: Mutex<RefCell<Option<MessageQueue>>> = Mutex::new(RefCell::new(None));
 
 pub fn setup() {
     cortex_m::interrupt::free(|cs| {
-        *QUEUE.borrow(cs).borrow_mut() = Some(MessageQueue::new());
+        // A host falling behind a subscription still gets its replies, and
+        // the latest samples
+        *QUEUE.borrow(cs).borrow_mut() = Some(MessageQueue::with_policy(OverflowPolicy::Coalesce));
     });
 }
 
-pub fn message_push(msg: Message) -> bool {
+pub fn message_push(env: Envelope) -> bool {
     let mut res = false;
     cortex_m::interrupt::free(|cs| {
-        res = QUEUE.borrow(cs).borrow_mut().as_mut().unwrap().enqueue(&msg).is_ok();
+        res = QUEUE.borrow(cs).borrow_mut().as_mut().unwrap().enqueue(&env).is_ok();
     });
     res
 }
 
-pub fn message_pop() -> Option<Message> {
+pub fn message_stats() -> QueueStats {
+    cortex_m::interrupt::free(|cs| QUEUE.borrow(cs).borrow().as_ref().unwrap().stats())
+}
+
+pub fn message_pop() -> Option<Envelope> {
     let mut res = None;
     cortex_m::interrupt::free(|cs| {
         res = QUEUE.borrow(cs).borrow_mut().as_mut().unwrap().dequeue();
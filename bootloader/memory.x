@@ -0,0 +1,29 @@
+MEMORY
+{
+    /* The bootloader, first in flash so the chip starts it at reset */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 48K
+    /* A page to swap the application and staging slots through */
+    SCRATCH : ORIGIN = 0x0800C000, LENGTH = 2K
+    /* The rest of flash, managed by the bootloader. Keep in step with
+       board/memory.x. */
+    APP : ORIGIN = 0x0800C800, LENGTH = 100K
+    /* An image's worth of pages, then one for its header */
+    STAGING : ORIGIN = 0x08025800, LENGTH = 102K
+    CONFIG : ORIGIN = 0x0803F000, LENGTH = 4K
+    RAM : ORIGIN = 0x20000000, LENGTH = 40K
+    /* Core-coupled RAM, which neither image uses and a reset leaves alone */
+    CCMRAM : ORIGIN = 0x10000000, LENGTH = 8K
+}
+
+_app_start = ORIGIN(APP);
+_app_end = ORIGIN(APP) + LENGTH(APP);
+_staging_start = ORIGIN(STAGING);
+_staging_end = ORIGIN(STAGING) + LENGTH(STAGING);
+_scratch_start = ORIGIN(SCRATCH);
+/* CCMRAM holds only these, at fixed places both images agree on, so they
+   outlive a reset and whichever image runs after it. Neither image may put
+   anything else there. */
+_boot_request = ORIGIN(CCMRAM);
+_boot_confirm = ORIGIN(CCMRAM) + 4;
+/* 256 bytes, for the firmware's crash record (see board/src/crash.rs) */
+_crash_record = ORIGIN(CCMRAM) + 8;
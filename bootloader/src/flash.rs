@@ -0,0 +1,160 @@
+//! The `APP`, `STAGING` and `SCRATCH` regions of the STM32F303's internal flash, as
+//! `common::update::Slots`.
+//!
+//! Erasing and programming go straight to the FLASH registers (RM0316
+//! section 4), as in `board/src/flash.rs`.
+
+use common::update::{Slot, Slots};
+use core::ptr;
+use stm32f3xx_hal::pac;
+
+const PAGE_SIZE: usize = 2048;
+const IMAGE_SIZE: usize = 100 * 1024;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+extern "C" {
+    // Defined in memory.x
+    static _app_start: u8;
+    static _app_end: u8;
+    static _staging_start: u8;
+    static _staging_end: u8;
+    static _scratch_start: u8;
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    OutOfRange,
+    Unaligned,
+    /// Tried to program a half-word that wasn't erased.
+    Program,
+    WriteProtected,
+}
+
+/// The application, staging and scratch slots. Only one may exist, as it
+/// owns the flash controller's erase and program operations.
+pub struct SlotFlash {
+    staging: usize,
+    scratch: usize,
+}
+
+impl SlotFlash {
+    /// # Safety
+    /// Nothing else may erase or program flash while this is alive.
+    pub unsafe fn new() -> SlotFlash {
+        let staging = &_staging_start as *const u8 as usize;
+        debug_assert_eq!(&_app_end as *const u8 as usize - Self::app_start(), IMAGE_SIZE);
+        debug_assert_eq!(&_staging_end as *const u8 as usize - staging, IMAGE_SIZE + PAGE_SIZE);
+        let scratch = &_scratch_start as *const u8 as usize;
+        SlotFlash { staging, scratch }
+    }
+
+    /// Where the application, and so its vector table, starts.
+    pub fn app_start() -> usize {
+        unsafe { &_app_start as *const u8 as usize }
+    }
+
+    // The address of `offset` in `slot`, checking `len` bytes from there
+    // fit
+    fn address(&self, slot: Slot, offset: usize, len: usize) -> Result<usize, FlashError> {
+        let (start, size) = match slot {
+            Slot::Application => (Self::app_start(), IMAGE_SIZE),
+            Slot::Staging => (self.staging, IMAGE_SIZE + PAGE_SIZE),
+            Slot::Scratch => (self.scratch, PAGE_SIZE),
+        };
+        if offset + len > size {
+            Err(FlashError::OutOfRange)
+        } else {
+            Ok(start + offset)
+        }
+    }
+
+    fn regs() -> &'static pac::flash::RegisterBlock {
+        unsafe { &*pac::FLASH::ptr() }
+    }
+
+    // Run `f` with the flash controller unlocked and wait for it to finish.
+    fn unlocked<T>(f: impl FnOnce(&pac::flash::RegisterBlock) -> Result<T, FlashError>) -> Result<T, FlashError> {
+        let regs = Self::regs();
+        if regs.cr.read().bits() & CR_LOCK != 0 {
+            regs.keyr.write(|w| unsafe { w.bits(KEY1) });
+            regs.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+        let result = f(regs);
+        regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_LOCK) });
+        result
+    }
+
+    fn wait(regs: &pac::flash::RegisterBlock) -> Result<(), FlashError> {
+        while regs.sr.read().bits() & SR_BSY != 0 {}
+        let sr = regs.sr.read().bits();
+        // Flags are cleared by writing 1
+        regs.sr.write(|w| unsafe { w.bits(SR_EOP | SR_PGERR | SR_WRPRTERR) });
+        if sr & SR_WRPRTERR != 0 {
+            Err(FlashError::WriteProtected)
+        } else if sr & SR_PGERR != 0 {
+            Err(FlashError::Program)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Slots for SlotFlash {
+    type Error = FlashError;
+
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const IMAGE_SIZE: usize = IMAGE_SIZE;
+
+    fn read(&mut self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        let start = self.address(slot, offset, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { ptr::read_volatile((start + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let start = self.address(slot, offset, data.len())?;
+        if !offset.is_multiple_of(2) || !data.len().is_multiple_of(2) {
+            return Err(FlashError::Unaligned);
+        }
+        Self::unlocked(|regs| {
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PG) });
+            let mut result = Ok(());
+            for (i, half_word) in data.chunks(2).enumerate() {
+                let value = u16::from_le_bytes([half_word[0], half_word[1]]);
+                unsafe { ptr::write_volatile((start + 2 * i) as *mut u16, value) };
+                result = Self::wait(regs);
+                if result.is_err() {
+                    break;
+                }
+            }
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_PG) });
+            result
+        })
+    }
+
+    fn erase_page(&mut self, slot: Slot, page: usize) -> Result<(), FlashError> {
+        let address = self.address(slot, page * PAGE_SIZE, PAGE_SIZE)? as u32;
+        Self::unlocked(|regs| {
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_PER) });
+            regs.ar.write(|w| unsafe { w.bits(address) });
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+            let result = Self::wait(regs);
+            regs.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_PER) });
+            result
+        })
+    }
+}
@@ -0,0 +1,269 @@
+//! Bootloader for the compass board: starts the firmware in the application
+//! slot, or stays in charge to take a new image from the host over the same
+//! USB serial link and protocol.
+//!
+//! At reset it installs whatever `common::update::Updater` has staged, or
+//! puts the old firmware back if the image it installed at the last reset
+//! didn't leave `BOOT_CONFIRM` in `_boot_confirm`. Then it starts the
+//! application unless the application asked for the bootloader by leaving
+//! `BOOT_REQUEST` in `_boot_request`, there's no application or it doesn't
+//! match its CRC, or installing failed. Left idle for `IDLE_TIMEOUT_MS`
+//! with a good application in place, it resets into it.
+
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+use stm32f3xx_hal as hal;
+
+use common::{
+    device::{DeviceInfo, FirmwareVersion, Uid},
+    dispatch::{self, BootHardware, Hardware, Target},
+    link::{Link, MAX_FRAME_SIZE},
+    message_queue::QueueStats,
+    update::{Updater, BOOT_CONFIRM, BOOT_REQUEST},
+    usb::{VENDOR_ID, PROD_ID},
+    Envelope, MessageQueue, Sensors,
+};
+
+use core::ptr::{self, addr_of_mut};
+
+use cortex_m::{
+    asm::delay,
+    peripheral::{syst::SystClkSource, SCB},
+};
+use cortex_m_rt::entry;
+
+use hal::{
+    pac,
+    usb::{Peripheral, UsbBus as UsbBusType},
+};
+use hal::prelude::*;
+
+use usb_device::prelude::*;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+mod flash;
+
+use flash::SlotFlash;
+
+// How long to wait for the host to say anything before starting the
+// application anyway
+const IDLE_TIMEOUT_MS: u32 = 60_000;
+// Where the factory programs the 96-bit unique device ID (RM0316 34.1)
+const UID_ADDRESS: usize = 0x1FFF_F7AC;
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2000_A000;
+
+extern "C" {
+    // Defined in memory.x
+    static mut _boot_request: u32;
+    static mut _boot_confirm: u32;
+}
+
+struct Bootloader {
+    updater: Updater<SlotFlash>,
+    queue: MessageQueue,
+    // Reset once every queued reply is sent
+    reset: bool,
+}
+
+impl Hardware for Bootloader {
+    fn push(&mut self, env: Envelope) -> bool {
+        self.queue.enqueue(&env).is_ok()
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let version = FirmwareVersion::parse(env!("CARGO_PKG_VERSION")).unwrap_or_default();
+        DeviceInfo::new(version, uid(), Sensors::NONE)
+    }
+
+    fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    // Only ever into the application: the bootloader is already running
+    fn reset(&mut self, _target: Target) {
+        self.reset = true;
+    }
+}
+
+impl BootHardware for Bootloader {
+    type Slots = SlotFlash;
+
+    fn updater(&mut self) -> &mut Updater<SlotFlash> {
+        &mut self.updater
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    // Safety: only the application writes them, before a reset
+    let (requested, confirmed) = unsafe {
+        (take(addr_of_mut!(_boot_request)) == BOOT_REQUEST, take(addr_of_mut!(_boot_confirm)) == BOOT_CONFIRM)
+    };
+    // Safety: nothing else touches flash
+    let mut updater = Updater::new(unsafe { SlotFlash::new() });
+    let installed = updater.install(confirmed).is_ok();
+    // With no good application to go to, stay until the host sends one
+    let can_leave = app_present() && updater.application_intact() == Ok(true);
+    // Stay to report why installing failed, if it did
+    if can_leave && installed && !requested {
+        // Safety: the application slot holds a vector table, and nothing
+        // has been set up yet
+        unsafe { start_app() }
+    }
+    let mut boot = Bootloader { updater, queue: MessageQueue::new(), reset: false };
+
+    let core_peris = cortex_m::Peripherals::take().unwrap();
+    let peris = pac::Peripherals::take().unwrap();
+    let mut acr = peris.FLASH.constrain().acr;
+    let mut rcc = peris.RCC.constrain();
+
+    let clocks = rcc.cfgr
+        .use_hse(8.MHz())
+        .sysclk(48.MHz())
+        .pclk1(24.MHz())
+        .pclk2(24.MHz())
+        .freeze(&mut acr);
+
+    // Counts milliseconds for the idle timeout
+    let mut syst = core_peris.SYST;
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(clocks.sysclk().0 / 1000 - 1);
+    syst.clear_current();
+    syst.enable_counter();
+
+    let mut gpioa = peris.GPIOA.split(&mut rcc.ahb);
+
+    let mut usb_dp = gpioa
+        .pa12
+        .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+    usb_dp.set_low().ok();
+    delay(clocks.sysclk().0 / 100);
+
+    let usb_dm = gpioa
+        .pa11
+        .into_af14_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+    let usb_dp = usb_dp
+        .into_af14_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+
+    let usb = Peripheral {
+        usb: peris.USB,
+        pin_dm: usb_dm,
+        pin_dp: usb_dp,
+    };
+    let usb_bus = UsbBusType::new(usb);
+
+    let mut serial = SerialPort::new(&usb_bus);
+
+    let mut link = Link::new();
+    // The same serial number as the application, so the host finds the
+    // board again after the reset
+    let serial_number = cortex_m::singleton!(: [u8; Uid::HEX_LEN] = [0; Uid::HEX_LEN]).unwrap();
+    let serial_number = uid().to_hex(serial_number);
+    let vid_pid = UsbVidPid(VENDOR_ID, PROD_ID);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, vid_pid)
+        .manufacturer("Fake Company")
+        .product("Bootloader")
+        .serial_number(serial_number)
+        .device_class(USB_CLASS_CDC)
+        .build();
+
+    let mut idle_ms = 0;
+    loop {
+        if syst.has_wrapped() {
+            idle_ms += 1;
+            if can_leave && idle_ms >= IDLE_TIMEOUT_MS {
+                SCB::sys_reset();
+            }
+        }
+
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        if usb_dev.poll(&mut [&mut serial]) {
+            match serial.read(&mut buf) {
+                Ok(count) if count > 0 => {
+                    idle_ms = 0;
+                    decode_messages(&mut buf[..count], &mut link, &mut boot);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(env) = boot.queue.dequeue() {
+            encode_and_send(env, &mut buf, &mut link, &mut serial);
+        } else if boot.reset {
+            // Give the host a moment to read the last reply
+            delay(clocks.sysclk().0 / 100);
+            SCB::sys_reset();
+        }
+    }
+}
+
+// Read a word left in CCMRAM, and clear it for the next reset
+unsafe fn take(word: *mut u32) -> u32 {
+    let value = word.read_volatile();
+    word.write_volatile(0);
+    value
+}
+
+// An erased slot reads all ones, where an application's vector table
+// starts with its initial stack pointer
+fn app_present() -> bool {
+    let sp = unsafe { ptr::read_volatile(SlotFlash::app_start() as *const u32) };
+    (RAM_START..=RAM_END).contains(&sp)
+}
+
+/// Jump to the application's reset handler, with its vector table and
+/// stack, as if the chip had started it.
+///
+/// # Safety
+/// The application slot must hold a vector table, and no peripheral or
+/// interrupt may have been set up.
+unsafe fn start_app() -> ! {
+    let vectors = SlotFlash::app_start();
+    let sp = ptr::read_volatile(vectors as *const u32);
+    let reset = ptr::read_volatile((vectors + 4) as *const u32);
+    (*SCB::ptr()).vtor.write(vectors as u32);
+    cortex_m::register::msp::write(sp);
+    let reset: extern "C" fn() -> ! = core::mem::transmute(reset as usize);
+    reset()
+}
+
+fn uid() -> Uid {
+    let read = |i: usize| unsafe { core::ptr::read_volatile((UID_ADDRESS + 4 * i) as *const u32) };
+    Uid([read(0), read(1), read(2)])
+}
+
+fn encode_and_send<T: usb_device::bus::UsbBus>(
+    env: Envelope,
+    buf: &mut [u8],
+    link: &mut Link,
+    serial: &mut SerialPort<T>
+) {
+    if let Ok(size) = link.encode(&env, buf) {
+        let mut write_offset = 0;
+        while write_offset < size {
+            match serial.write(&buf[write_offset..size]) {
+                Ok(len) if len > 0 => write_offset += len,
+                _ => break,
+            }
+        }
+    }
+}
+
+fn decode_messages(buf: &mut [u8], link: &mut Link, boot: &mut Bootloader) {
+    let length = buf.len();
+    let mut offset = 0;
+    while offset < length {
+        let read = match link.decode(&buf[offset..]) {
+            Ok((read, Some(env))) => {
+                dispatch::process_boot_message(env, boot);
+                read
+            }
+            Ok((read, None)) => read,
+            Err(e) => e.read().unwrap_or(length - offset),
+        };
+        offset += read;
+    }
+}
@@ -0,0 +1,621 @@
+use common::crash::CrashReport;
+use common::device::{protocol_supported, DeviceInfo};
+use common::message_queue::QueueStats;
+use common::link::Link;
+use common::{Envelope, Message, Sensors};
+use crate::clock::ClockSync;
+use crate::link::{hello, shuttle};
+use crate::record::{Recorder, RecordingTransport};
+use crate::tracker::{Event, RequestTracker};
+use crate::transport::{Arrivals, Endpoint, Transport};
+use crate::{CompError, Result};
+use log::{debug, error, info, log, trace, warn};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RECV_TIMEOUT: Duration = Duration::from_millis(10);
+// How often to look for a board that isn't there
+const RECONNECT_PERIOD: Duration = Duration::from_secs(1);
+// Back off further when opening fails for other reasons, e.g. permissions
+const OPEN_FAILED_PERIOD: Duration = Duration::from_secs(10);
+
+type ReplySender = Sender<Result<Envelope>>;
+
+enum Command {
+    Request(Message, ReplySender),
+    Subscribe(Sensors, u16, ReplySender, Sender<Message>),
+    Close(u16),
+}
+
+/// Whether the link thread behind a `Board` can currently reach it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkState {
+    /// The board is open and messages are flowing.
+    Connected,
+    /// The board went away, or hasn't been found yet.
+    #[default]
+    Disconnected,
+    /// Trying to open the board again.
+    Reconnecting,
+}
+
+// Shared between the link thread, which sets the state, and every handle
+pub(crate) struct LinkStatus {
+    state: Mutex<LinkState>,
+    watchers: Mutex<Vec<Sender<LinkState>>>,
+}
+
+impl LinkStatus {
+    fn new(state: LinkState) -> LinkStatus {
+        LinkStatus {
+            state: Mutex::new(state),
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn set(&self, state: LinkState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != state {
+            *current = state;
+            self.watchers.lock().unwrap().retain(|watcher| watcher.send(state).is_ok());
+        }
+    }
+
+    /// Every change of state from now on, for as long as the receiver is
+    /// kept.
+    pub(crate) fn watch(&self) -> Receiver<LinkState> {
+        let (tx, rx) = channel();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// A handle for talking to a board over the channels of a running
+/// `usb_link`. Requests are matched to replies and retried by a background
+/// dispatcher thread; the handle can be cloned and shared between threads.
+#[derive(Clone)]
+pub struct Board {
+    commands: Sender<Command>,
+    link: Arc<LinkStatus>,
+}
+
+impl Board {
+    /// Talk to a board over the channels of a `usb_link` the caller runs,
+    /// which is taken to be connected for as long as the `Board` lives.
+    pub fn spawn(to_board: Sender<Envelope>, from_board: Receiver<Envelope>) -> Board {
+        Board::with_tracker(to_board, from_board, RequestTracker::default())
+    }
+
+    /// Talk to the board at `endpoint`, through a link thread that reopens
+    /// and reconfigures it whenever it goes away, until every handle is
+    /// dropped. The link only counts as connected once the board has
+    /// answered `Hello` with a protocol this client speaks. Requests made
+    /// while it's away are retried as usual; streams end with
+    /// `Disconnected` when it goes, and have to be subscribed to again once
+    /// it's back, which `link_events` tells of.
+    pub fn connect(endpoint: Endpoint) -> Board {
+        Board::connect_with(endpoint, None)
+    }
+
+    /// `connect`, recording everything the board sends to `recorder`.
+    pub fn connect_recorded(endpoint: Endpoint, recorder: Recorder) -> Board {
+        Board::connect_with(endpoint, Some(recorder))
+    }
+
+    fn connect_with(endpoint: Endpoint, recorder: Option<Recorder>) -> Board {
+        let (to_board, from_board, link) = spawn_link(endpoint, recorder);
+        Board::with_link(to_board, from_board, RequestTracker::default(), link)
+    }
+
+    pub fn with_tracker(to_board: Sender<Envelope>, from_board: Receiver<Envelope>, tracker: RequestTracker) -> Board {
+        let link = Arc::new(LinkStatus::new(LinkState::Connected));
+        Board::with_link(to_board, from_board, tracker, link)
+    }
+
+    fn with_link(
+        to_board: Sender<Envelope>,
+        from_board: Receiver<Envelope>,
+        tracker: RequestTracker,
+        link: Arc<LinkStatus>,
+    ) -> Board {
+        let (commands, commands_rx) = channel();
+        let link_events = link.watch();
+        thread::spawn(move || {
+            let mut dispatcher = Dispatcher {
+                tracker,
+                to_board,
+                link_events,
+                replies: HashMap::new(),
+                streams: HashMap::new(),
+            };
+            if let Err(e) = dispatcher.run(commands_rx, from_board) {
+                debug!("Dispatcher stopped: {}", e);
+            }
+        });
+        Board { commands, link }
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        *self.link.state.lock().unwrap()
+    }
+
+    /// Wait up to `timeout` for the link to come up, failing with
+    /// `NotFound` if it doesn't.
+    pub fn wait_connected(&self, timeout: Duration) -> Result<()> {
+        let events = self.link_events();
+        let deadline = Instant::now() + timeout;
+        let mut state = self.link_state();
+        while state != LinkState::Connected {
+            let left = deadline.checked_duration_since(Instant::now()).ok_or(CompError::NotFound)?;
+            state = events.recv_timeout(left).map_err(|_| CompError::NotFound)?;
+        }
+        Ok(())
+    }
+
+    /// Every change of `link_state` from now on, for as long as the
+    /// receiver is kept.
+    pub fn link_events(&self) -> Receiver<LinkState> {
+        self.link.watch()
+    }
+
+    /// Send `msg` and wait for the board's reply.
+    pub fn request(&self, msg: Message) -> Result<Message> {
+        let (tx, rx) = channel();
+        self.commands.send(Command::Request(msg, tx)).map_err(|_| CompError::Disconnected)?;
+        match rx.recv().map_err(|_| CompError::Disconnected)??.msg {
+            Message::Unknown(tag) => Err(CompError::UnsupportedMessage(tag)),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Open a session and find out what the board is. Fails with
+    /// `UnsupportedProtocol` if the firmware is too old or too new to talk
+    /// to; firmware from before the handshake carried a `DeviceInfo` never
+    /// gets a reply through, and times out.
+    pub fn hello(&self) -> Result<DeviceInfo> {
+        match self.request(Message::Hello)? {
+            Message::HelloAck(info) => check_protocol(info),
+            other => Err(CompError::Unexpected(Box::new(other))),
+        }
+    }
+
+    /// How the board's last boot ended, if it ended in a crash.
+    pub fn crash_report(&self) -> Result<Option<CrashReport>> {
+        match self.request(Message::CrashReportReq)? {
+            Message::CrashReport(report) => Ok(report),
+            other => Err(CompError::Unexpected(Box::new(other))),
+        }
+    }
+
+    /// How many messages the board has queued for the host, and dropped
+    /// when the queue was full, since it booted.
+    pub fn stats(&self) -> Result<QueueStats> {
+        match self.request(Message::StatsReq)? {
+            Message::Stats(stats) => Ok(stats),
+            other => Err(CompError::Unexpected(Box::new(other))),
+        }
+    }
+
+    /// Run `rounds` `TimeReq` round trips and feed them into `clock`.
+    pub fn sync_clock(&self, clock: &mut ClockSync, rounds: usize) -> Result<()> {
+        for _ in 0..rounds {
+            let sent = Instant::now();
+            match self.request(Message::TimeReq)? {
+                Message::Time(board_us) => clock.add(sent, board_us, Instant::now()),
+                other => return Err(CompError::Unexpected(Box::new(other))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the board to push samples of `sensors` at `rate_hz`. Samples keep
+    /// arriving until the returned stream is dropped. The board only streams
+    /// to one subscription, so this fails with `AlreadySubscribed` while
+    /// another stream from this `Board` is open.
+    pub fn subscribe(&self, sensors: Sensors, rate_hz: u16) -> Result<Samples> {
+        let (reply_tx, reply_rx) = channel();
+        let (tx, rx) = channel();
+        self.commands.send(Command::Subscribe(sensors, rate_hz, reply_tx, tx)).map_err(|_| CompError::Disconnected)?;
+        let reply = reply_rx.recv().map_err(|_| CompError::Disconnected)??;
+        let rate_hz = match reply.msg {
+            Message::SubscribeAck(rate_hz) => rate_hz,
+            other => return Err(CompError::Unexpected(Box::new(other))),
+        };
+        Ok(Samples {
+            seq: reply.seq,
+            rate_hz,
+            rx,
+            commands: self.commands.clone(),
+        })
+    }
+}
+
+/// Run a link to `endpoint` on its own thread, reopening it whenever it goes
+/// away, until the returned channels hang up or the status is dropped.
+/// Everything the board sends goes to `recorder` as well, if there is one.
+pub(crate) fn spawn_link(
+    endpoint: Endpoint,
+    recorder: Option<Recorder>,
+) -> (Sender<Envelope>, Receiver<Envelope>, Arc<LinkStatus>) {
+    let (to_board_tx, mut to_board_rx) = channel();
+    let (from_board_tx, from_board_rx) = channel();
+    let status = Arc::new(LinkStatus::new(LinkState::Disconnected));
+    let link = status.clone();
+    thread::spawn(move || {
+        let arrivals = Arrivals::new(&endpoint);
+        loop {
+            link.set(LinkState::Reconnecting);
+            let opened = endpoint.open().map(|transport| match &recorder {
+                Some(recorder) => Box::new(RecordingTransport::new(transport, recorder.clone())) as Box<dyn Transport>,
+                None => transport,
+            });
+            let period = match opened {
+                Ok(mut transport) => {
+                    let mut framing = Link::new();
+                    match hello(&mut transport, &mut framing, &from_board_tx).and_then(check_protocol) {
+                        Ok(info) => {
+                            info!("Connected to {} ({})", endpoint, info.uid);
+                            link.set(LinkState::Connected);
+                            let result = shuttle(&mut to_board_rx, &from_board_tx, &mut transport, &mut framing);
+                            link.set(LinkState::Disconnected);
+                            match result {
+                                // Whoever the link is for hung up, so nobody is listening
+                                Err(CompError::SendError { .. }) | Err(CompError::TryRecvError { .. }) => return,
+                                Err(e) => info!("Lost {}: {}", endpoint, e),
+                                Ok(()) => (),
+                            }
+                            RECONNECT_PERIOD
+                        }
+                        Err(CompError::SendError { .. }) => return,
+                        Err(e) => {
+                            error!("No greeting from {}! {}", endpoint, e);
+                            OPEN_FAILED_PERIOD
+                        }
+                    }
+                }
+                Err(CompError::NotFound) => RECONNECT_PERIOD,
+                Err(e) => {
+                    error!("Failed to open {}! {}", endpoint, e);
+                    OPEN_FAILED_PERIOD
+                }
+            };
+            // Only this thread is left holding on
+            if Arc::strong_count(&link) == 1 {
+                return;
+            }
+            arrivals.wait(period);
+        }
+    });
+    (to_board_tx, from_board_rx, status)
+}
+
+fn check_protocol(info: DeviceInfo) -> Result<DeviceInfo> {
+    if protocol_supported(info.protocol_version) {
+        Ok(info)
+    } else {
+        Err(CompError::UnsupportedProtocol(info.protocol_version))
+    }
+}
+
+/// Samples pushed by the board for one subscription.
+pub struct Samples {
+    seq: u16,
+    rate_hz: u16,
+    rx: Receiver<Message>,
+    commands: Sender<Command>,
+}
+
+impl Samples {
+    /// The rate the board agreed to push at.
+    pub fn rate_hz(&self) -> u16 {
+        self.rate_hz
+    }
+
+    /// Wait up to `timeout` for the next sample.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(CompError::Disconnected),
+        }
+    }
+}
+
+impl Iterator for Samples {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for Samples {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Close(self.seq));
+    }
+}
+
+struct Dispatcher {
+    tracker: RequestTracker,
+    to_board: Sender<Envelope>,
+    link_events: Receiver<LinkState>,
+    replies: HashMap<u16, ReplySender>,
+    streams: HashMap<u16, Sender<Message>>,
+}
+
+impl Dispatcher {
+    fn run(&mut self, commands: Receiver<Command>, from_board: Receiver<Envelope>) -> Result<()> {
+        loop {
+            loop {
+                match commands.try_recv() {
+                    Ok(cmd) => self.command(cmd)?,
+                    Err(TryRecvError::Empty) => break,
+                    // Every handle and stream is gone
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            if self.link_events.try_iter().any(|state| state != LinkState::Connected) {
+                self.link_lost();
+            }
+
+            let expired = self.tracker.expire(Instant::now());
+            for env in expired.resend {
+                trace!("Retrying {:?}", env);
+                self.to_board.send(env)?;
+            }
+            for env in expired.lost {
+                warn!("No reply to {:?}", env.msg);
+                self.streams.remove(&env.seq);
+                if let Some(tx) = self.replies.remove(&env.seq) {
+                    let _ = tx.send(Err(CompError::Timeout));
+                }
+            }
+
+            match from_board.recv_timeout(RECV_TIMEOUT) {
+                Ok(env) => self.receive(env)?,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Err(CompError::Disconnected),
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: Command) -> Result<()> {
+        let now = Instant::now();
+        match cmd {
+            Command::Request(msg, tx) => {
+                let env = self.tracker.request(msg, now);
+                self.replies.insert(env.seq, tx);
+                self.to_board.send(env)?;
+            }
+            Command::Subscribe(sensors, rate_hz, reply_tx, tx) => {
+                // Another `Subscribe` would take the board's stream away
+                // from the open one
+                if !self.streams.is_empty() {
+                    let _ = reply_tx.send(Err(CompError::AlreadySubscribed));
+                    return Ok(());
+                }
+                let env = self.tracker.subscribe(Message::Subscribe { sensors, rate_hz }, now);
+                self.replies.insert(env.seq, reply_tx);
+                self.streams.insert(env.seq, tx);
+                self.to_board.send(env)?;
+            }
+            Command::Close(seq) => self.close(seq)?,
+        }
+        Ok(())
+    }
+
+    // The board forgets what it was streaming when the link drops, so end
+    // every stream: their `Samples` fail with `Disconnected`
+    fn link_lost(&mut self) {
+        for (seq, _) in self.streams.drain() {
+            self.tracker.close(seq);
+            if let Some(tx) = self.replies.remove(&seq) {
+                let _ = tx.send(Err(CompError::Disconnected));
+            }
+        }
+    }
+
+    fn close(&mut self, seq: u16) -> Result<()> {
+        self.tracker.close(seq);
+        if self.streams.remove(&seq).is_some() && self.streams.is_empty() {
+            self.to_board.send(Envelope::from(Message::Unsubscribe))?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, env: Envelope) -> Result<()> {
+        match self.tracker.receive(env, Instant::now()) {
+            Event::Reply { seq, reply, rtt, .. } => {
+                trace!("Reply {:?} after {:?}", reply, rtt);
+                if let Some(tx) = self.replies.remove(&seq) {
+                    let _ = tx.send(Ok(Envelope::new(seq, reply)));
+                }
+            }
+            Event::Stream(env) => {
+                let closed = match self.streams.get(&env.seq) {
+                    Some(tx) => tx.send(env.msg).is_err(),
+                    None => false,
+                };
+                if closed {
+                    self.close(env.seq)?;
+                }
+            }
+            Event::Unsolicited(Message::Log { level, target, text }) => {
+                // Under the firmware's own module path, so RUST_LOG=board=debug
+                // and the like pick them out
+                let target = target.to_string_lossy();
+                let target = if target.is_empty() { "board" } else { &*target };
+                let more = if text.is_truncated() { "..." } else { "" };
+                log!(target: target, log::Level::from(level), "{}{}", text.to_string_lossy(), more);
+            }
+            Event::Unsolicited(Message::CrashReport(Some(report))) => {
+                error!("Board reset after a crash: {}", report);
+            }
+            Event::Unsolicited(msg) => debug!("Board said: {:?}", msg),
+            Event::Unmatched(env) => debug!("Unmatched reply {:?}", env),
+        }
+        Ok(())
+    }
+}
+
+/// A `Board` talking to a `mock::MockBoard` over a loopback transport.
+#[cfg(test)]
+pub(crate) fn mock_board() -> Board {
+    mock_board_with(mock::MockBoard::new())
+}
+
+/// `mock_board` for a mock set up by the caller.
+#[cfg(test)]
+pub(crate) fn mock_board_with(mock: mock::MockBoard) -> Board {
+    use crate::link::usb_link;
+    use crate::transport::Loopback;
+
+    let (to_mock, from_mock) = mock::spawn_board(mock);
+    let mut transport = Loopback::new(to_mock, from_mock);
+    let (to_board_tx, mut to_board_rx) = channel();
+    let (from_board_tx, from_board_rx) = channel();
+    thread::spawn(move || {
+        let _ = usb_link(&mut to_board_rx, &from_board_tx, &mut transport);
+    });
+    Board::spawn(to_board_tx, from_board_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request() {
+        let board = mock_board();
+        assert!(matches!(board.request(Message::Hello).unwrap(), Message::HelloAck(_)));
+    }
+
+    #[test]
+    fn unsupported_message() {
+        let board = mock_board();
+        assert!(matches!(board.request(Message::Unknown(1000)), Err(CompError::UnsupportedMessage(1000))));
+    }
+
+    #[test]
+    fn hello() {
+        let board = mock_board();
+        let info = board.hello().unwrap();
+        assert_eq!(info.uid.0[0], mock::MOCK_UID_TAG);
+        assert_eq!(info.sensors, Sensors::ALL);
+        assert_eq!(info.max_message_size as usize, Message::MAX_SIZE);
+
+        let newer = DeviceInfo { protocol_version: info.protocol_version + 1, ..info };
+        assert!(matches!(check_protocol(newer), Err(CompError::UnsupportedProtocol(v)) if v == newer.protocol_version));
+        assert_eq!(check_protocol(info).unwrap(), info);
+    }
+
+    #[test]
+    fn sync_clock() {
+        let board = mock_board();
+        let mut clock = ClockSync::default();
+        board.sync_clock(&mut clock, 4).unwrap();
+        // The mock board booted moments ago, so its clock should read close
+        // to zero now.
+        let board_now = clock.to_board(Instant::now()).unwrap();
+        assert!(board_now < 1_000_000, "board clock {}", board_now);
+    }
+
+    #[test]
+    fn crash_report() {
+        let board = mock_board();
+        assert_eq!(board.crash_report().unwrap(), None);
+    }
+
+    #[test]
+    fn stats() {
+        let board = mock_board();
+        board.hello().unwrap();
+        let stats = board.stats().unwrap();
+        assert_eq!(stats.dropped, 0);
+        assert!(stats.enqueued >= 1);
+    }
+
+    #[test]
+    fn subscribe() {
+        let board = mock_board();
+        let samples = board.subscribe(Sensors::MAG, 50).unwrap();
+        assert_eq!(samples.rate_hz(), 50);
+        for msg in samples.take(3) {
+            assert!(matches!(msg, Message::Mag(..)));
+        }
+    }
+
+    #[test]
+    fn subscribe_gyro() {
+        let board = mock_board();
+        let samples = board.subscribe(Sensors::ACCEL | Sensors::GYRO, 50).unwrap();
+        let msgs: Vec<Message> = samples.take(4).collect();
+        assert!(msgs.iter().any(|msg| matches!(msg, Message::Accel(..))));
+        assert!(msgs.iter().any(|msg| matches!(msg, Message::Gyro(..))));
+        assert!(!msgs.iter().any(|msg| matches!(msg, Message::Mag(..))));
+    }
+
+    #[test]
+    fn one_subscription_at_a_time() {
+        let board = mock_board();
+        let mags = board.subscribe(Sensors::MAG, 50).unwrap();
+        assert!(matches!(board.subscribe(Sensors::ACCEL, 50), Err(CompError::AlreadySubscribed)));
+        // The open stream carries on regardless
+        assert!(mags.take(2).all(|msg| matches!(msg, Message::Mag(..))));
+        let accels = board.subscribe(Sensors::ACCEL, 50).unwrap();
+        assert!(accels.take(2).all(|msg| matches!(msg, Message::Accel(..))));
+    }
+
+    #[test]
+    fn nothing_to_connect_to() {
+        // Nothing listens on port 9 of localhost
+        let board = Board::connect(Endpoint::Tcp("127.0.0.1:9".to_string()));
+        assert!(matches!(board.wait_connected(Duration::from_millis(100)), Err(CompError::NotFound)));
+    }
+
+    #[test]
+    fn reconnect() {
+        use std::net::{Shutdown, TcpListener};
+
+        // A fresh mock board for every connection, like `mock --tcp`
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (streams_tx, streams) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                streams_tx.send(stream.try_clone().unwrap()).unwrap();
+                let reader = stream.try_clone().unwrap();
+                thread::spawn(move || mock::serve(reader, stream));
+            }
+        });
+
+        let board = Board::connect(Endpoint::Tcp(addr.to_string()));
+        board.wait_connected(Duration::from_secs(5)).unwrap();
+        // Only the changes from here on, not those of the first connect
+        let events = board.link_events();
+        let first = board.hello().unwrap();
+        let samples = board.subscribe(Sensors::MAG, 50).unwrap();
+
+        // Pull the plug
+        streams.recv().unwrap().shutdown(Shutdown::Both).unwrap();
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), LinkState::Disconnected);
+        // What arrived before the plug was pulled, then the end of the stream
+        while let Some(msg) = samples.recv_timeout(Duration::from_secs(5)).transpose() {
+            match msg {
+                Ok(msg) => assert!(matches!(msg, Message::Mag(..))),
+                Err(e) => {
+                    assert!(matches!(e, CompError::Disconnected));
+                    break;
+                }
+            }
+        }
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), LinkState::Reconnecting);
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), LinkState::Connected);
+        let second = board.hello().unwrap();
+        assert_ne!(first.uid, second.uid);
+    }
+}
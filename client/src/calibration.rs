@@ -0,0 +1,544 @@
+use common::{
+    calibration::{AccelCalibration, MagCalibration},
+    Message, Sensors,
+};
+use crate::board::Board;
+use crate::{CompError, Result};
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Fewest readings an ellipsoid fit is attempted with.
+pub const MIN_MAG_SAMPLES: usize = 50;
+const COLLECT_RATE_HZ: u16 = 50;
+/// Readings averaged for each accelerometer pose.
+pub const POSE_SAMPLES: usize = 50;
+// Per-axis standard deviation, in g, above which the board wasn't held still
+const MAX_POSE_NOISE: f64 = 0.02;
+// How closely a pose's reading must line up with the expected axis
+const MIN_POSE_ALIGNMENT: f64 = 0.9;
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Stream raw magnetometer readings for `duration` while the user turns the
+/// board every which way. Any calibration on the board is cleared first.
+pub fn collect_mag(board: &Board, duration: Duration) -> Result<Vec<[f64; 3]>> {
+    send_mag(board, MagCalibration::IDENTITY)?;
+    let samples = board.subscribe(Sensors::MAG, COLLECT_RATE_HZ)?;
+    let start = Instant::now();
+    let mut points = Vec::new();
+    while let Some(left) = duration.checked_sub(start.elapsed()) {
+        if let Some(Message::Mag(_, x, y, z)) = samples.recv_timeout(left)? {
+            points.push([x as f64, y as f64, z as f64]);
+        }
+    }
+    Ok(points)
+}
+
+/// Collect readings, fit them and send the result to the board.
+pub fn calibrate_mag(board: &Board, duration: Duration) -> Result<MagCalibration> {
+    let points = collect_mag(board, duration)?;
+    info!("Collected {} magnetometer readings", points.len());
+    let cal = fit_ellipsoid(&points).ok_or_else(|| {
+        CompError::Calibration("readings don't cover enough orientations".to_string())
+    })?;
+    info!("Field strength varies by {:.1}% after correction", 100. * spread(&cal, &points));
+    send_mag(board, cal)?;
+    Ok(cal)
+}
+
+/// Have the board correct its `Mag` readings with `cal`.
+pub fn send_mag(board: &Board, cal: MagCalibration) -> Result<()> {
+    match board.request(Message::SetMagCalibration(cal))? {
+        Message::Ack => Ok(()),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, value)?;
+    Ok(())
+}
+
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// The six orientations the accelerometer is calibrated in. Each one points
+/// one of the board's axes straight up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pose {
+    ZUp,
+    ZDown,
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+}
+
+impl Pose {
+    pub const ALL: [Pose; 6] = [Pose::ZUp, Pose::ZDown, Pose::XUp, Pose::XDown, Pose::YUp, Pose::YDown];
+
+    // The axis pointing up or down, and which
+    fn axis(&self) -> (usize, f64) {
+        match self {
+            Pose::XUp => (0, 1.),
+            Pose::XDown => (0, -1.),
+            Pose::YUp => (1, 1.),
+            Pose::YDown => (1, -1.),
+            Pose::ZUp => (2, 1.),
+            Pose::ZDown => (2, -1.),
+        }
+    }
+
+    /// What a perfect accelerometer reads in this pose, in g.
+    pub fn expected(&self) -> [f64; 3] {
+        let (axis, sign) = self.axis();
+        let mut g = [0.; 3];
+        g[axis] = sign;
+        g
+    }
+
+    pub fn instructions(&self) -> &'static str {
+        match self {
+            Pose::ZUp => "lay the board flat, components facing up",
+            Pose::ZDown => "lay the board flat, components facing down",
+            Pose::XUp => "stand the board on its edge with the X axis pointing up",
+            Pose::XDown => "stand the board on its edge with the X axis pointing down",
+            Pose::YUp => "stand the board on its edge with the Y axis pointing up",
+            Pose::YDown => "stand the board on its edge with the Y axis pointing down",
+        }
+    }
+}
+
+/// How far a calibrated reading in one pose is from the ideal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoseResidual {
+    pub pose: Pose,
+    /// Length of the difference from `Pose::expected`, in g.
+    pub error: f64,
+}
+
+/// Average the accelerometer over `POSE_SAMPLES` readings while the board is
+/// held in `pose`. Fails if the board moved or doesn't seem to be in `pose`.
+pub fn measure_pose(board: &Board, pose: Pose) -> Result<[f64; 3]> {
+    let samples = board.subscribe(Sensors::ACCEL, COLLECT_RATE_HZ)?;
+    let mut readings = Vec::with_capacity(POSE_SAMPLES);
+    while readings.len() < POSE_SAMPLES {
+        match samples.recv_timeout(SAMPLE_TIMEOUT)? {
+            Some(Message::Accel(_, x, y, z)) => readings.push([x as f64, y as f64, z as f64]),
+            Some(_) => (),
+            None => return Err(CompError::Timeout),
+        }
+    }
+
+    let n = readings.len() as f64;
+    let mut mean = [0.; 3];
+    for reading in &readings {
+        for (m, v) in mean.iter_mut().zip(reading) {
+            *m += v / n;
+        }
+    }
+    for axis in 0..3 {
+        let var = readings.iter().map(|r| (r[axis] - mean[axis]).powi(2)).sum::<f64>() / n;
+        if var.sqrt() > MAX_POSE_NOISE {
+            return Err(CompError::Calibration(format!("board moved while measuring {:?}", pose)));
+        }
+    }
+    let norm = dot3(mean, mean).sqrt();
+    if norm == 0. || dot3(mean, pose.expected()) / norm < MIN_POSE_ALIGNMENT {
+        return Err(CompError::Calibration(format!("board doesn't look like {:?}, reads {:?}", pose, mean)));
+    }
+    Ok(mean)
+}
+
+/// Work out each axis' offset and scale from its readings pointing up and
+/// down. Needs all six poses.
+pub fn fit_six_position(readings: &[(Pose, [f64; 3])]) -> Option<AccelCalibration> {
+    let find = |pose: Pose| readings.iter().find(|(p, _)| *p == pose).map(|(_, r)| *r);
+    let mut cal = AccelCalibration::IDENTITY;
+    let pairs = [(Pose::XUp, Pose::XDown), (Pose::YUp, Pose::YDown), (Pose::ZUp, Pose::ZDown)];
+    for (axis, &(up, down)) in pairs.iter().enumerate() {
+        let up = find(up)?[axis];
+        let down = find(down)?[axis];
+        if up <= down {
+            return None;
+        }
+        cal.offset[axis] = ((up + down) / 2.) as f32;
+        cal.scale[axis] = (2. / (up - down)) as f32;
+    }
+    Some(cal)
+}
+
+/// Apply `cal` to each pose's reading and see how far off it still is.
+pub fn accel_residuals(cal: &AccelCalibration, readings: &[(Pose, [f64; 3])]) -> Vec<PoseResidual> {
+    readings.iter().map(|&(pose, r)| {
+        let c = cal.apply([r[0] as f32, r[1] as f32, r[2] as f32]);
+        let e = pose.expected();
+        let d = [c[0] as f64 - e[0], c[1] as f64 - e[1], c[2] as f64 - e[2]];
+        PoseResidual { pose, error: dot3(d, d).sqrt() }
+    }).collect()
+}
+
+/// Run the six-position calibration and send the result to the board.
+/// `prompt` is called before each pose and should return once the user is
+/// holding the board still in it.
+pub fn calibrate_accel<F>(board: &Board, mut prompt: F) -> Result<(AccelCalibration, Vec<PoseResidual>)>
+where
+    F: FnMut(Pose),
+{
+    send_accel(board, AccelCalibration::IDENTITY)?;
+    let mut readings = Vec::with_capacity(Pose::ALL.len());
+    for &pose in &Pose::ALL {
+        prompt(pose);
+        let reading = measure_pose(board, pose)?;
+        info!("{:?}: {:?}", pose, reading);
+        readings.push((pose, reading));
+    }
+    let cal = fit_six_position(&readings)
+        .ok_or_else(|| CompError::Calibration("readings don't bracket zero g".to_string()))?;
+    let residuals = accel_residuals(&cal, &readings);
+    send_accel(board, cal)?;
+    Ok((cal, residuals))
+}
+
+/// Have the board correct its `Accel` readings with `cal`.
+pub fn send_accel(board: &Board, cal: AccelCalibration) -> Result<()> {
+    match board.request(Message::SetAccelCalibration(cal))? {
+        Message::Ack => Ok(()),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+/// Fit an ellipsoid to magnetometer readings and return the correction that
+/// maps it onto a sphere centred on the origin.
+///
+/// The sphere's radius is the geometric mean of the ellipsoid's semi-axes,
+/// so corrected readings stay in roughly the same units. Returns `None` if
+/// the readings don't pin down an ellipsoid, e.g. if the board was only
+/// turned about one axis.
+pub fn fit_ellipsoid(points: &[[f64; 3]]) -> Option<MagCalibration> {
+    if points.len() < MIN_MAG_SAMPLES {
+        return None;
+    }
+
+    // Centre and scale the points first to keep the normal equations well
+    // conditioned.
+    let n = points.len() as f64;
+    let mut mean = [0.; 3];
+    for p in points {
+        for (m, v) in mean.iter_mut().zip(p) {
+            *m += v / n;
+        }
+    }
+    let scale = points.iter()
+        .flat_map(|p| (0..3).map(move |i| (p[i] - mean[i]).abs()))
+        .fold(0., f64::max);
+    if scale == 0. {
+        return None;
+    }
+
+    // Least squares for
+    // a x² + b y² + c z² + 2d xy + 2e xz + 2f yz + 2g x + 2h y + 2i z = 1
+    let mut ata = vec![vec![0.; 9]; 9];
+    let mut atb = vec![0.; 9];
+    for p in points {
+        let [x, y, z] = [(p[0] - mean[0]) / scale, (p[1] - mean[1]) / scale, (p[2] - mean[2]) / scale];
+        let row = [x * x, y * y, z * z, 2. * x * y, 2. * x * z, 2. * y * z, 2. * x, 2. * y, 2. * z];
+        for ((ata_row, b), ri) in ata.iter_mut().zip(atb.iter_mut()).zip(&row) {
+            for (a, rj) in ata_row.iter_mut().zip(&row) {
+                *a += ri * rj;
+            }
+            *b += ri;
+        }
+    }
+    let v = solve(ata, atb)?;
+
+    let a = [[v[0], v[3], v[4]], [v[3], v[1], v[5]], [v[4], v[5], v[2]]];
+    let linear = [v[6], v[7], v[8]];
+    let a_inv = invert3(&a)?;
+    let c = mul3(&a_inv, linear);
+    let centre = [-c[0], -c[1], -c[2]];
+    // Moving the origin to the centre leaves (p - c)ᵀ A (p - c) = k
+    let k = 1. + dot3(centre, mul3(&a, centre));
+    let (values, vectors) = symmetric_eigen(&a);
+    if k <= 0. || values.iter().any(|&l| l <= 0.) {
+        return None;
+    }
+
+    // Semi-axes are sqrt(k / λ); undo the scaling while we're at it
+    let mut radii = [0.; 3];
+    for (r, l) in radii.iter_mut().zip(&values) {
+        *r = scale * (k / l).sqrt();
+    }
+    let radius = (radii[0] * radii[1] * radii[2]).cbrt();
+    // V diag(radius / radii) Vᵀ
+    let mut matrix = [[0f32; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, m) in row.iter_mut().enumerate() {
+            let sum: f64 = (0..3).map(|e| vectors[i][e] * vectors[j][e] * radius / radii[e]).sum();
+            *m = sum as f32;
+        }
+    }
+    let mut offset = [0f32; 3];
+    for ((o, m), c) in offset.iter_mut().zip(&mean).zip(&centre) {
+        *o = (m + scale * c) as f32;
+    }
+    Some(MagCalibration { offset, matrix })
+}
+
+/// Relative standard deviation of the corrected field strength: zero for a
+/// perfect calibration.
+pub fn spread(cal: &MagCalibration, points: &[[f64; 3]]) -> f64 {
+    let norms: Vec<f64> = points.iter()
+        .map(|p| {
+            let c = cal.apply([p[0] as f32, p[1] as f32, p[2] as f32]);
+            let c = [c[0] as f64, c[1] as f64, c[2] as f64];
+            dot3(c, c).sqrt()
+        })
+        .collect();
+    let n = norms.len() as f64;
+    let mean = norms.iter().sum::<f64>() / n;
+    let var = norms.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / n;
+    var.sqrt() / mean
+}
+
+fn dot3(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn mul3(m: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    [dot3(m[0], v), dot3(m[1], v), dot3(m[2], v)]
+}
+
+fn invert3(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cof = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let det = m[0][0] * cof(1, 2, 1, 2) - m[0][1] * cof(1, 2, 0, 2) + m[0][2] * cof(1, 2, 0, 1);
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    Some([
+        [cof(1, 2, 1, 2) / det, -cof(0, 2, 1, 2) / det, cof(0, 1, 1, 2) / det],
+        [-cof(1, 2, 0, 2) / det, cof(0, 2, 0, 2) / det, -cof(0, 1, 0, 2) / det],
+        [cof(1, 2, 0, 1) / det, -cof(0, 2, 0, 1) / det, cof(0, 1, 0, 1) / det],
+    ])
+}
+
+// Gaussian elimination with partial pivoting
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let f = a[row][col] / pivot_row[col];
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= f * p;
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = vec![0.; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+// Jacobi eigenvalue algorithm. Returns the eigenvalues and a matrix whose
+// columns are the matching eigenvectors.
+fn symmetric_eigen(m: &[[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut a = *m;
+    let mut v = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+    for _ in 0..50 {
+        let off = a[0][1].powi(2) + a[0][2].powi(2) + a[1][2].powi(2);
+        if off < 1e-30 {
+            break;
+        }
+        for &(p, q) in &[(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-300 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+            let t = if theta == 0. { 1. } else { t };
+            let c = 1. / (t * t + 1.).sqrt();
+            let s = t * c;
+            for row in a.iter_mut().chain(v.iter_mut()) {
+                let (xp, xq) = (row[p], row[q]);
+                row[p] = c * xp - s * xq;
+                row[q] = s * xp + c * xq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            a[p] = [0, 1, 2].map(|k| c * row_p[k] - s * row_q[k]);
+            a[q] = [0, 1, 2].map(|k| s * row_p[k] + c * row_q[k]);
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Evenly spread unit vectors
+    fn sphere(n: usize) -> Vec<[f64; 3]> {
+        let golden = std::f64::consts::PI * (3. - 5f64.sqrt());
+        (0..n).map(|i| {
+            let z = 1. - 2. * (i as f64 + 0.5) / n as f64;
+            let r = (1. - z * z).sqrt();
+            let phi = golden * i as f64;
+            [r * phi.cos(), r * phi.sin(), z]
+        }).collect()
+    }
+
+    // Deterministic noise in [-1, 1]
+    fn noise(i: usize) -> f64 {
+        ((i as f64 * 12.9898).sin() * 43758.5453).fract()
+    }
+
+    fn distort(points: &[[f64; 3]], soft: [[f64; 3]; 3], hard: [f64; 3], noise_counts: f64) -> Vec<[f64; 3]> {
+        points.iter().enumerate().map(|(i, p)| {
+            let d = mul3(&soft, [p[0] * 450., p[1] * 450., p[2] * 450.]);
+            [
+                d[0] + hard[0] + noise_counts * noise(3 * i),
+                d[1] + hard[1] + noise_counts * noise(3 * i + 1),
+                d[2] + hard[2] + noise_counts * noise(3 * i + 2),
+            ]
+        }).collect()
+    }
+
+    #[test]
+    fn recovers_distortion() {
+        let soft = [[1.2, 0.1, 0.0], [0.1, 0.8, 0.05], [0.0, 0.05, 1.0]];
+        let hard = [-120., 310., 45.];
+        let raw = distort(&sphere(500), soft, hard, 0.);
+        let cal = fit_ellipsoid(&raw).unwrap();
+        for (offset, hard) in cal.offset.iter().zip(&hard) {
+            assert!((*offset as f64 - hard).abs() < 0.1, "offset {:?}", cal.offset);
+        }
+        assert!(spread(&cal, &raw) < 1e-4);
+
+        // The correction undoes the soft-iron matrix up to overall scale
+        let m = cal.matrix;
+        let mut product = [[0.; 3]; 3];
+        for (i, row) in product.iter_mut().enumerate() {
+            for (j, p) in row.iter_mut().enumerate() {
+                *p = (0..3).map(|k| m[i][k] as f64 * soft[k][j]).sum();
+            }
+        }
+        let s = product[0][0];
+        for i in 0..3 {
+            for j in 0..3 {
+                let expected = if i == j { s } else { 0. };
+                assert!((product[i][j] - expected).abs() < 1e-3 * s, "{:?}", product);
+            }
+        }
+    }
+
+    #[test]
+    fn noisy_readings() {
+        let soft = [[1.0, 0.0, 0.0], [0.0, 1.3, 0.0], [0.0, 0.0, 0.9]];
+        let hard = [200., -80., -300.];
+        let raw = distort(&sphere(300), soft, hard, 5.);
+        let cal = fit_ellipsoid(&raw).unwrap();
+        for (offset, hard) in cal.offset.iter().zip(&hard) {
+            assert!((*offset as f64 - hard).abs() < 2., "offset {:?}", cal.offset);
+        }
+        assert!(spread(&cal, &raw) < 0.01);
+    }
+
+    #[test]
+    fn needs_coverage() {
+        // Only turned about z: a circle doesn't determine an ellipsoid
+        let circle: Vec<[f64; 3]> = (0..100)
+            .map(|i| (i as f64).to_radians() * 3.6)
+            .map(|a| [400. * a.cos(), 400. * a.sin(), -300.])
+            .collect();
+        assert!(fit_ellipsoid(&circle).is_none());
+        assert!(fit_ellipsoid(&circle[..10]).is_none());
+    }
+
+    #[test]
+    fn mock_round_trip() {
+        let board = crate::board::mock_board();
+        let points = collect_mag(&board, Duration::from_millis(200)).unwrap();
+        assert!(!points.is_empty());
+        // The mock only ever turns about its vertical axis
+        assert!(matches!(calibrate_mag(&board, Duration::from_millis(200)), Err(CompError::Calibration(_))));
+
+        let cal = MagCalibration { offset: [0., 0., 50.], ..MagCalibration::IDENTITY };
+        send_mag(&board, cal).unwrap();
+        let mut samples = board.subscribe(Sensors::MAG, 50).unwrap();
+        match samples.next() {
+            Some(Message::Mag(_, _, _, z)) => assert_eq!(z, -350),
+            other => panic!("Unexpected sample {:?}", other),
+        }
+    }
+
+    // Readings from an accelerometer with known bias and gain errors, held
+    // slightly off each pose so the other two axes don't read exactly zero
+    fn six_positions() -> Vec<(Pose, [f64; 3])> {
+        let offset = [0.03, -0.05, 0.08];
+        let gain = [1.02, 0.97, 1.05];
+        Pose::ALL.iter().map(|&pose| {
+            let mut tilted = [0.01; 3];
+            let (axis, sign) = pose.axis();
+            tilted[axis] = sign;
+            (pose, [
+                tilted[0] * gain[0] + offset[0],
+                tilted[1] * gain[1] + offset[1],
+                tilted[2] * gain[2] + offset[2],
+            ])
+        }).collect()
+    }
+
+    #[test]
+    fn six_position_fit() {
+        let readings = six_positions();
+        let cal = fit_six_position(&readings).unwrap();
+        for (got, want) in cal.offset.iter().zip(&[0.03, -0.05, 0.08]) {
+            assert!((*got as f64 - want).abs() < 1e-5, "offset {:?}", cal.offset);
+        }
+        for (got, want) in cal.scale.iter().zip(&[1.02, 0.97, 1.05]) {
+            assert!((*got as f64 * want - 1.).abs() < 1e-5, "scale {:?}", cal.scale);
+        }
+        let residuals = accel_residuals(&cal, &readings);
+        assert_eq!(residuals.len(), 6);
+        // Only the tilt remains
+        for r in &residuals {
+            assert!(r.error > 0.005 && r.error < 0.02, "{:?}", r);
+        }
+        let before = accel_residuals(&AccelCalibration::IDENTITY, &readings);
+        assert!(before.iter().map(|r| r.error).sum::<f64>() > residuals.iter().map(|r| r.error).sum::<f64>());
+
+        assert!(fit_six_position(&readings[..5]).is_none());
+    }
+
+    #[test]
+    fn mock_poses() {
+        let board = crate::board::mock_board();
+        send_accel(&board, AccelCalibration::IDENTITY).unwrap();
+        let flat = measure_pose(&board, Pose::ZUp).unwrap();
+        assert!(flat[0].abs() < 1e-9 && flat[1].abs() < 1e-9 && (flat[2] - 1.).abs() < 1e-9);
+        assert!(matches!(measure_pose(&board, Pose::XUp), Err(CompError::Calibration(_))));
+    }
+
+    #[test]
+    fn eigen() {
+        let m = [[2., 1., 0.], [1., 2., 0.], [0., 0., 5.]];
+        let (mut values, _) = symmetric_eigen(&m);
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (got, want) in values.iter().zip(&[1., 3., 5.]) {
+            assert!((got - want).abs() < 1e-9);
+        }
+    }
+}
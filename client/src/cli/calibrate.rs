@@ -0,0 +1,123 @@
+use client::{
+    board::Board,
+    calibration::{self, calibrate_accel, calibrate_mag, send_accel, send_mag, Pose},
+    config, Result,
+};
+use common::{
+    calibration::{AccelCalibration, MagCalibration},
+    config::keys,
+    device::Uid,
+};
+use log::{info, warn};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+
+const MAG_CALIBRATION_FILE: &str = "mag_calibration";
+const MAG_CALIBRATION_TIME: Duration = Duration::from_secs(20);
+const ACCEL_CALIBRATION_FILE: &str = "accel_calibration";
+// Time given to settle the board into each accelerometer pose
+const POSE_SETTLE_TIME: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Calibrate {
+    Mag,
+    Accel,
+}
+
+impl FromStr for Calibrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Calibrate, String> {
+        match s {
+            "mag" => Ok(Calibrate::Mag),
+            "accel" => Ok(Calibrate::Accel),
+            _ => Err(format!("no {} calibration, only mag or accel", s)),
+        }
+    }
+}
+
+// Each board has its own calibration, kept in a file named after its UID
+fn calibration_file(kind: &str, uid: Uid) -> String {
+    format!("{}_{}.json", kind, uid)
+}
+
+/// Calibrations to send the board whenever it (re)connects.
+pub struct Calibrations {
+    pub uid: Uid,
+    mag: Option<MagCalibration>,
+    accel: Option<AccelCalibration>,
+}
+
+impl Calibrations {
+    pub fn load(uid: Uid) -> Calibrations {
+        Calibrations {
+            uid,
+            mag: calibration::load(Path::new(&calibration_file(MAG_CALIBRATION_FILE, uid))).ok(),
+            accel: calibration::load(Path::new(&calibration_file(ACCEL_CALIBRATION_FILE, uid))).ok(),
+        }
+    }
+
+    pub fn send(&self, board: &Board) -> Result<()> {
+        if let Some(cal) = self.mag {
+            send_mag(board, cal)?;
+        }
+        if let Some(cal) = self.accel {
+            send_accel(board, cal)?;
+        }
+        Ok(())
+    }
+
+    /// Calibrate `which` sensor, telling the user what to do through
+    /// `prompt`, which is passed `None` once they can stop. The result is
+    /// saved next to the other calibrations and on the board.
+    pub fn run(&mut self, which: Calibrate, board: &Board, prompt: &dyn Fn(Option<&str>)) -> Result<()> {
+        let result = match which {
+            Calibrate::Mag => {
+                info!("Calibrating: turn the board every which way for {:?}", MAG_CALIBRATION_TIME);
+                prompt(Some("turn the board every which way"));
+                calibrate_mag(board, MAG_CALIBRATION_TIME).map(|cal| {
+                    info!("Magnetometer calibration: {:?}", cal);
+                    save(&calibration_file(MAG_CALIBRATION_FILE, self.uid), &cal);
+                    persist(board, keys::MAG_CALIBRATION, &cal);
+                    self.mag = Some(cal);
+                })
+            }
+            Calibrate::Accel => {
+                let pose_prompt = |pose: Pose| {
+                    info!("Calibrating: {}", pose.instructions());
+                    prompt(Some(pose.instructions()));
+                    sleep(POSE_SETTLE_TIME);
+                    prompt(Some("hold still"));
+                };
+                calibrate_accel(board, pose_prompt).map(|(cal, residuals)| {
+                    info!("Accelerometer calibration: {:?}", cal);
+                    for r in &residuals {
+                        info!("  {:?} is off by {:.4} g", r.pose, r.error);
+                    }
+                    let worst = residuals.iter().map(|r| r.error).fold(0., f64::max);
+                    info!("Worst residual {:.4} g", worst);
+                    save(&calibration_file(ACCEL_CALIBRATION_FILE, self.uid), &cal);
+                    persist(board, keys::ACCEL_CALIBRATION, &cal);
+                    self.accel = Some(cal);
+                })
+            }
+        };
+        prompt(None);
+        result
+    }
+}
+
+fn save<T: serde::Serialize>(file: &str, cal: &T) {
+    if let Err(e) = calibration::save(Path::new(file), cal) {
+        warn!("Failed to save {}: {}", file, e);
+    }
+}
+
+// Keep `cal` in the board's flash so it survives without the host
+fn persist<T: serde::Serialize>(board: &Board, key: u16, cal: &T) {
+    if let Err(e) = config::store(board, key, cal) {
+        warn!("Failed to store calibration on the board: {}", e);
+    }
+}
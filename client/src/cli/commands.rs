@@ -0,0 +1,238 @@
+use super::calibrate::{Calibrate, Calibrations};
+use super::output::{BoardRecord, CrashRecord, InfoRecord, Output, SampleRecord, StatsRecord, TraceRecord};
+use client::{
+    board::Board,
+    daemon,
+    export::Exporter,
+    fleet::{stream_all, Sample},
+    link::usb_link,
+    record::{self, Recorder},
+    symbols::Symbolizer,
+    transport::{list_usb, Endpoint},
+    update::{self, Progress},
+    CompError, Result,
+};
+use common::{Envelope, Message, Sensors};
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a one-shot command waits for its board to turn up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest a read waits between samples before giving up
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(2);
+const MONITOR_POLL: Duration = Duration::from_millis(100);
+
+fn connect(endpoint: &Endpoint) -> Result<Board> {
+    let board = Board::connect(endpoint.clone());
+    board.wait_connected(CONNECT_TIMEOUT)?;
+    Ok(board)
+}
+
+/// Connect to `endpoint`, recording the session if there's a `recorder`.
+pub fn connect_recorded(endpoint: Endpoint, recorder: &Option<Recorder>) -> Board {
+    match recorder {
+        Some(recorder) => Board::connect_recorded(endpoint, recorder.clone()),
+        None => Board::connect(endpoint),
+    }
+}
+
+pub fn list<W: Write>(out: &mut Output<W>) -> Result<()> {
+    for serial in list_usb()? {
+        let endpoint = Endpoint::Usb(Some(serial.clone())).to_string();
+        out.write(&BoardRecord { endpoint, serial })?;
+    }
+    Ok(())
+}
+
+pub fn info<W: Write>(endpoints: &[Endpoint], out: &mut Output<W>) -> Result<()> {
+    for endpoint in endpoints {
+        let info = connect(endpoint)?.hello()?;
+        out.write(&InfoRecord::new(endpoint.to_string(), &info))?;
+    }
+    Ok(())
+}
+
+/// Print how each board's last boot ended, with the fault's location in the
+/// firmware if there's a `symbolizer` for it.
+pub fn crash<W: Write>(endpoints: &[Endpoint], symbolizer: Option<&Symbolizer>, out: &mut Output<W>) -> Result<()> {
+    for endpoint in endpoints {
+        let report = connect(endpoint)?.crash_report()?;
+        let located = match (report.and_then(|report| report.frame), symbolizer) {
+            (Some(frame), Some(symbolizer)) => Some(symbolizer.locate_frame(&frame)?),
+            _ => None,
+        };
+        out.write(&CrashRecord::new(endpoint.to_string(), report.as_ref(), located))?;
+    }
+    Ok(())
+}
+
+/// How many messages each board has queued, and dropped, since it booted.
+pub fn stats<W: Write>(endpoints: &[Endpoint], out: &mut Output<W>) -> Result<()> {
+    for endpoint in endpoints {
+        let stats = connect(endpoint)?.stats()?;
+        out.write(&StatsRecord::new(endpoint.to_string(), stats))?;
+    }
+    Ok(())
+}
+
+/// Print `count` readings of each of `sensors` from every board, one board
+/// after another.
+pub fn read<W: Write>(endpoints: &[Endpoint], sensors: Sensors, count: usize, rate_hz: u16, out: &mut Output<W>) -> Result<()> {
+    for endpoint in endpoints {
+        let board = connect(endpoint)?;
+        let info = board.hello()?;
+        let sensors = sensors & info.sensors;
+        let samples = board.subscribe(sensors, info.clamp_rate(rate_hz))?;
+        let mut left: Vec<(Sensors, usize)> = [Sensors::ACCEL, Sensors::MAG, Sensors::GYRO]
+            .iter()
+            .filter(|sensor| sensors.contains(**sensor))
+            .map(|sensor| (*sensor, count))
+            .collect();
+        while left.iter().any(|(_, n)| *n > 0) {
+            let msg = samples.recv_timeout(SAMPLE_TIMEOUT)?.ok_or(CompError::Timeout)?;
+            let sensor = match msg {
+                Message::Accel(..) => Sensors::ACCEL,
+                Message::Mag(..) => Sensors::MAG,
+                Message::Gyro(..) => Sensors::GYRO,
+                _ => continue,
+            };
+            if let Some((_, n)) = left.iter_mut().find(|(s, n)| *s == sensor && *n > 0) {
+                *n -= 1;
+                if let Some(record) = SampleRecord::new(&Sample { device: info.uid, msg }) {
+                    out.write(&record)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print readings from every board as they arrive, until killed.
+pub fn stream<W: Write>(
+    endpoints: &[Endpoint],
+    sensors: Sensors,
+    rate_hz: u16,
+    recorder: Option<Recorder>,
+    out: &mut Output<W>,
+) -> Result<()> {
+    let boards = endpoints.iter().cloned().map(|endpoint| connect_recorded(endpoint, &recorder)).collect();
+    for sample in stream_all(boards, sensors, rate_hz) {
+        if let Some(record) = SampleRecord::new(&sample) {
+            out.write(&record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Export the samples in a recording, up to `limit` of them.
+pub fn export_recording<W: Write>(path: &Path, mut exporter: Exporter<W>, limit: Option<usize>) -> Result<()> {
+    for frame in record::load(path)? {
+        if matches!(limit, Some(limit) if exporter.exported() >= limit) {
+            break;
+        }
+        exporter.push_frame(&frame)?;
+    }
+    exporter.finish()?;
+    Ok(())
+}
+
+/// Export readings from every board as they arrive, until `limit` of them
+/// have been exported or forever.
+pub fn export_live<W: Write>(
+    endpoints: &[Endpoint],
+    sensors: Sensors,
+    rate_hz: u16,
+    mut exporter: Exporter<W>,
+    limit: Option<usize>,
+) -> Result<()> {
+    let boards = endpoints.iter().map(connect).collect::<Result<_>>()?;
+    let start = Instant::now();
+    for sample in stream_all(boards, sensors, rate_hz) {
+        if exporter.push(start.elapsed().as_micros() as u64, &sample)? {
+            exporter.flush()?;
+        }
+        if matches!(limit, Some(limit) if exporter.exported() >= limit) {
+            break;
+        }
+    }
+    exporter.finish()?;
+    Ok(())
+}
+
+/// Calibrate each board in turn, prompting on stderr.
+pub fn calibrate(endpoints: &[Endpoint], which: Calibrate) -> Result<()> {
+    for endpoint in endpoints {
+        let board = connect(endpoint)?;
+        let info = board.hello()?;
+        eprintln!("Calibrating {} on {}", info.uid, endpoint);
+        let prompt = |prompt: Option<&str>| match prompt {
+            Some(prompt) => eprintln!("Now {}", prompt),
+            None => eprintln!("Done"),
+        };
+        Calibrations::load(info.uid).run(which, &board, &prompt)?;
+    }
+    Ok(())
+}
+
+/// Install the firmware in `image` on each board, reporting progress on
+/// stderr.
+pub fn flash(endpoints: &[Endpoint], image: &Path) -> Result<()> {
+    let image = std::fs::read(image)?;
+    for endpoint in endpoints {
+        let board = connect(endpoint)?;
+        eprintln!("Flashing {} bytes to {}", image.len(), endpoint);
+        let info = update::flash(&board, &image, |progress| match progress {
+            Progress::Rebooting => eprintln!("Rebooting into the bootloader"),
+            Progress::Sent { sent, size } => eprint!("\rSent {}%", sent as u64 * 100 / size as u64),
+            Progress::Verified(info) => eprintln!("\nStaged {}", info),
+            Progress::Installing => eprintln!("Installing"),
+            Progress::Confirming => eprintln!("Confirming"),
+        })?;
+        eprintln!("Running {}", info);
+    }
+    Ok(())
+}
+
+/// Share one board with other processes until killed, serving them over
+/// TCP at `tcp` and over WebSocket at `websocket`.
+pub fn daemon(endpoint: Endpoint, tcp: &str, websocket: &str) -> Result<()> {
+    let tcp = TcpListener::bind(tcp)?;
+    let websocket = TcpListener::bind(websocket)?;
+    eprintln!("Sharing {} on tcp:{} and ws://{}", endpoint, tcp.local_addr()?, websocket.local_addr()?);
+    daemon::run(endpoint, Some(tcp), Some(websocket))
+}
+
+/// Print every message to and from the board, bypassing the request
+/// tracking `Board` does. Sends `Hello`, and a `Subscribe` to `subscribe`
+/// if given, so there is something to see.
+pub fn monitor<W: Write>(endpoint: &Endpoint, subscribe: Option<(Sensors, u16)>, out: &mut Output<W>) -> Result<()> {
+    let mut transport = endpoint.open()?;
+    let (to_board_tx, mut to_board_rx) = channel();
+    let (from_board_tx, from_board_rx) = channel();
+    let link = thread::spawn(move || usb_link(&mut to_board_rx, &from_board_tx, &mut transport));
+
+    let start = Instant::now();
+    let mut requests = vec![Message::Hello];
+    if let Some((sensors, rate_hz)) = subscribe {
+        requests.push(Message::Subscribe { sensors, rate_hz });
+    }
+    for (seq, msg) in requests.into_iter().enumerate() {
+        let env = Envelope::new(seq as u16 + 1, msg);
+        out.write(&TraceRecord::new(start.elapsed().as_secs_f64(), "tx", &env))?;
+        to_board_tx.send(env)?;
+    }
+    loop {
+        match from_board_rx.recv_timeout(MONITOR_POLL) {
+            Ok(env) => out.write(&TraceRecord::new(start.elapsed().as_secs_f64(), "rx", &env))?,
+            Err(RecvTimeoutError::Timeout) => (),
+            // The link stopped, so find out why
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    drop(to_board_tx);
+    link.join().expect("link thread panicked")
+}
@@ -0,0 +1,6 @@
+//! The `client` binary's subcommands, and the output and UI they share.
+
+pub mod calibrate;
+pub mod commands;
+pub mod output;
+pub mod view;
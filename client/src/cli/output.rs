@@ -0,0 +1,436 @@
+//! What the subcommands print: one record per line, as plain text for
+//! people, JSON lines or CSV for scripts.
+
+use client::{fleet::Sample, symbols::Location, Result};
+use common::{
+    crash::{CrashCause, CrashReport},
+    device::DeviceInfo,
+    message_queue::QueueStats,
+    sensors::mag_gauss,
+    Envelope, Message, Sensors,
+};
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// A header line naming the columns, then comma-separated values.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}, try text, json or csv", s)),
+        }
+    }
+}
+
+/// Something printed as one line of output.
+pub trait Record: Serialize {
+    /// Names of the CSV columns, in the order `fields` gives them.
+    const COLUMNS: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+
+    fn text(&self) -> String;
+}
+
+pub struct Output<W: Write> {
+    format: Format,
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(format: Format, out: W) -> Output<W> {
+        Output { format, out, header_written: false }
+    }
+
+    /// Print `record`, flushing straight away so output can be piped into
+    /// another program as it arrives.
+    pub fn write<R: Record>(&mut self, record: &R) -> Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", record.text())?,
+            Format::Json => {
+                serde_json::to_writer(&mut self.out, record)?;
+                writeln!(self.out)?;
+            }
+            Format::Csv => {
+                if !self.header_written {
+                    writeln!(self.out, "{}", R::COLUMNS.join(","))?;
+                    self.header_written = true;
+                }
+                let fields: Vec<_> = record.fields().into_iter().map(csv_field).collect();
+                writeln!(self.out, "{}", fields.join(","))?;
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// Quote fields that would otherwise split or end the row
+fn csv_field(field: String) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Names of `sensors`, joined with `+`.
+pub fn sensor_names(sensors: Sensors) -> String {
+    let names: Vec<_> = [(Sensors::ACCEL, "accel"), (Sensors::MAG, "mag"), (Sensors::GYRO, "gyro")]
+        .iter()
+        .filter(|(sensor, _)| sensors.contains(*sensor))
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join("+")
+    }
+}
+
+/// A board found by `list`.
+#[derive(Serialize)]
+pub struct BoardRecord {
+    pub endpoint: String,
+    pub serial: String,
+}
+
+impl Record for BoardRecord {
+    const COLUMNS: &'static [&'static str] = &["endpoint", "serial"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![self.endpoint.clone(), self.serial.clone()]
+    }
+
+    fn text(&self) -> String {
+        self.endpoint.clone()
+    }
+}
+
+/// What a board said about itself in its `HelloAck`.
+#[derive(Serialize)]
+pub struct InfoRecord {
+    pub endpoint: String,
+    pub uid: String,
+    pub firmware: String,
+    pub protocol: u16,
+    pub sensors: String,
+    pub max_message_size: u16,
+    pub min_rate_hz: u16,
+    pub max_rate_hz: u16,
+}
+
+impl InfoRecord {
+    pub fn new(endpoint: String, info: &DeviceInfo) -> InfoRecord {
+        InfoRecord {
+            endpoint,
+            uid: info.uid.to_string(),
+            firmware: info.firmware_version.to_string(),
+            protocol: info.protocol_version,
+            sensors: sensor_names(info.sensors),
+            max_message_size: info.max_message_size,
+            min_rate_hz: info.min_rate_hz,
+            max_rate_hz: info.max_rate_hz,
+        }
+    }
+}
+
+impl Record for InfoRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "endpoint", "uid", "firmware", "protocol", "sensors", "max_message_size", "min_rate_hz", "max_rate_hz",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.endpoint.clone(),
+            self.uid.clone(),
+            self.firmware.clone(),
+            self.protocol.to_string(),
+            self.sensors.clone(),
+            self.max_message_size.to_string(),
+            self.min_rate_hz.to_string(),
+            self.max_rate_hz.to_string(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "{} {} firmware {} protocol {} sensors {} rates {}-{} Hz",
+            self.endpoint, self.uid, self.firmware, self.protocol, self.sensors, self.min_rate_hz, self.max_rate_hz
+        )
+    }
+}
+
+/// One sensor reading, in g, gauss or degrees per second.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SampleRecord {
+    pub device: String,
+    pub sensor: &'static str,
+    /// Board time the sample was taken, in microseconds since it booted.
+    pub time_us: u64,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl SampleRecord {
+    /// `None` for anything that isn't a sensor reading.
+    pub fn new(sample: &Sample) -> Option<SampleRecord> {
+        let (sensor, stamp, [x, y, z]) = match sample.msg {
+            Message::Accel(stamp, x, y, z) => ("accel", stamp, [x, y, z]),
+            Message::Mag(stamp, x, y, z) => ("mag", stamp, mag_gauss(x, y, z)),
+            Message::Gyro(stamp, x, y, z) => ("gyro", stamp, [x, y, z]),
+            _ => return None,
+        };
+        Some(SampleRecord { device: sample.device.to_string(), sensor, time_us: stamp.time_us, x, y, z })
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.sensor {
+            "accel" => "g",
+            "mag" => "gauss",
+            _ => "dps",
+        }
+    }
+}
+
+impl Record for SampleRecord {
+    const COLUMNS: &'static [&'static str] = &["device", "sensor", "time_us", "x", "y", "z"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.device.clone(),
+            self.sensor.to_string(),
+            self.time_us.to_string(),
+            self.x.to_string(),
+            self.y.to_string(),
+            self.z.to_string(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "{} {:<5} {:>12} {:>9.4} {:>9.4} {:>9.4} {}",
+            self.device, self.sensor, self.time_us, self.x, self.y, self.z, self.unit()
+        )
+    }
+}
+
+/// How a board's last boot ended, from `crash`.
+#[derive(Serialize)]
+pub struct CrashRecord {
+    pub endpoint: String,
+    /// `panic`, `hard_fault`, or `none` if it didn't crash.
+    pub cause: &'static str,
+    pub report: String,
+    /// Where the fault was taken, if the firmware's ELF was given.
+    pub pc: Option<Location>,
+    /// The call the faulting function was called from, likewise.
+    pub caller: Option<Location>,
+}
+
+impl CrashRecord {
+    pub fn new(endpoint: String, report: Option<&CrashReport>, located: Option<(Location, Location)>) -> CrashRecord {
+        let cause = match report.map(|report| report.cause) {
+            Some(CrashCause::Panic) => "panic",
+            Some(CrashCause::HardFault) => "hard_fault",
+            Some(CrashCause::Unknown(_)) => "unknown",
+            None => "none",
+        };
+        let report = report.map(|report| report.to_string()).unwrap_or_default();
+        let (pc, caller) = match located {
+            Some((pc, caller)) => (Some(pc), Some(caller)),
+            None => (None, None),
+        };
+        CrashRecord { endpoint, cause, report, pc, caller }
+    }
+}
+
+impl Record for CrashRecord {
+    const COLUMNS: &'static [&'static str] = &["endpoint", "cause", "report", "pc", "caller"];
+
+    fn fields(&self) -> Vec<String> {
+        let location = |location: &Option<Location>| location.as_ref().map(|l| l.to_string()).unwrap_or_default();
+        vec![
+            self.endpoint.clone(),
+            self.cause.to_string(),
+            self.report.clone(),
+            location(&self.pc),
+            location(&self.caller),
+        ]
+    }
+
+    fn text(&self) -> String {
+        if self.cause == "none" {
+            return format!("{} didn't crash", self.endpoint);
+        }
+        let mut text = format!("{} {}", self.endpoint, self.report);
+        if let Some(pc) = &self.pc {
+            text += &format!("\n  at {}", pc);
+        }
+        if let Some(caller) = &self.caller {
+            text += &format!("\n  called from {}", caller);
+        }
+        text
+    }
+}
+
+/// What a board has queued for the host since it booted, from `stats`.
+#[derive(Serialize)]
+pub struct StatsRecord {
+    pub endpoint: String,
+    pub enqueued: u32,
+    /// Messages lost to a full queue, or to a newer sample replacing them.
+    pub dropped: u32,
+    pub high_watermark: u16,
+}
+
+impl StatsRecord {
+    pub fn new(endpoint: String, stats: QueueStats) -> StatsRecord {
+        StatsRecord { endpoint, enqueued: stats.enqueued, dropped: stats.dropped, high_watermark: stats.high_watermark }
+    }
+}
+
+impl Record for StatsRecord {
+    const COLUMNS: &'static [&'static str] = &["endpoint", "enqueued", "dropped", "high_watermark"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.endpoint.clone(),
+            self.enqueued.to_string(),
+            self.dropped.to_string(),
+            self.high_watermark.to_string(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "{} queued {} dropped {} most queued at once {}",
+            self.endpoint, self.enqueued, self.dropped, self.high_watermark
+        )
+    }
+}
+
+/// A message seen by `monitor`, going either way.
+#[derive(Serialize)]
+pub struct TraceRecord {
+    /// Seconds since the monitor started.
+    pub time: f64,
+    /// `tx` to the board, `rx` from it.
+    pub direction: &'static str,
+    pub seq: u16,
+    pub tag: u16,
+    pub msg: String,
+}
+
+impl TraceRecord {
+    pub fn new(time: f64, direction: &'static str, env: &Envelope) -> TraceRecord {
+        TraceRecord { time, direction, seq: env.seq, tag: env.msg.tag(), msg: format!("{:?}", env.msg) }
+    }
+}
+
+impl Record for TraceRecord {
+    const COLUMNS: &'static [&'static str] = &["time", "direction", "seq", "tag", "msg"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            format!("{:.6}", self.time),
+            self.direction.to_string(),
+            self.seq.to_string(),
+            self.tag.to_string(),
+            self.msg.clone(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        format!("{:10.4} {} #{:<5} {}", self.time, self.direction, self.seq, self.msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::crash::ExceptionFrame;
+    use common::device::Uid;
+    use common::sensors::Stamp;
+
+    fn sample() -> SampleRecord {
+        let msg = Message::Accel(Stamp::new(1500, 3), 0., 0.5, 1.);
+        SampleRecord::new(&Sample { device: Uid([1, 2, 3]), msg }).unwrap()
+    }
+
+    fn written<R: Record>(format: Format, records: &[R]) -> String {
+        let mut out = Output::new(format, Vec::new());
+        for record in records {
+            out.write(record).unwrap();
+        }
+        String::from_utf8(out.out).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let records = [sample(), sample()];
+        let device = Uid([1, 2, 3]).to_string();
+        assert_eq!(
+            written(Format::Csv, &records),
+            format!("device,sensor,time_us,x,y,z\n{0},accel,1500,0,0.5,1\n{0},accel,1500,0,0.5,1\n", device)
+        );
+        assert_eq!(
+            written(Format::Json, &records[..1]),
+            format!("{{\"device\":\"{}\",\"sensor\":\"accel\",\"time_us\":1500,\"x\":0.0,\"y\":0.5,\"z\":1.0}}\n", device)
+        );
+        assert!(written(Format::Text, &records[..1]).ends_with("1.0000 g\n"));
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let env = Envelope::new(4, Message::Subscribe { sensors: Sensors::MAG, rate_hz: 10 });
+        let csv = written(Format::Csv, &[TraceRecord::new(0.5, "tx", &env)]);
+        assert_eq!(csv.lines().nth(1).unwrap(), format!("0.500000,tx,4,8,\"{:?}\"", env.msg));
+        assert_eq!(csv_field("say \"hi\"".to_string()), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn crash() {
+        let report = CrashReport::hard_fault(ExceptionFrame { pc: 0x0800_1234, lr: 0x0800_0101, xpsr: 0x6100_0000 });
+        let pc = Location { address: 0x0800_1234, function: Some("board::main".to_string()), file: None, line: None };
+        let caller = Location { address: 0x0800_00ff, function: None, file: None, line: None };
+        let record = CrashRecord::new("usb".to_string(), Some(&report), Some((pc, caller)));
+        assert_eq!(
+            record.text(),
+            "usb HardFault with PC 0x08001234, LR 0x08000101, xPSR 0x61000000\n  at 0x08001234 in board::main\n  called from 0x080000ff in ??"
+        );
+        let csv = written(Format::Csv, &[record]);
+        assert_eq!(csv.lines().nth(1).unwrap().split(',').nth(1), Some("hard_fault"));
+        assert_eq!(CrashRecord::new("usb".to_string(), None, None).text(), "usb didn't crash");
+    }
+
+    #[test]
+    fn stats() {
+        let stats = QueueStats { enqueued: 1000, dropped: 3, high_watermark: 10 };
+        let record = StatsRecord::new("usb".to_string(), stats);
+        assert_eq!(record.text(), "usb queued 1000 dropped 3 most queued at once 10");
+        assert_eq!(written(Format::Csv, &[record]), "endpoint,enqueued,dropped,high_watermark\nusb,1000,3,10\n");
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(sensor_names(Sensors::ALL), "accel+mag+gyro");
+        assert_eq!(sensor_names(Sensors::MAG), "mag");
+        assert_eq!(sensor_names(Sensors::NONE), "none");
+    }
+}
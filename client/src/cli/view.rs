@@ -0,0 +1,293 @@
+//! The 3D view of each board's attitude, with its heading in the title.
+
+use super::calibrate::{Calibrate, Calibrations};
+use super::commands::connect_recorded;
+use client::{
+    board::{Board, LinkState},
+    orientation::Orientation,
+    record::Recorder,
+    transport::Endpoint,
+    CompError,
+};
+use common::{
+    device::Uid,
+    fusion::{Madgwick, Quaternion},
+    heading::{heading, true_heading},
+    sensors::mag_gauss,
+    Message, Sensors,
+};
+use log::trace;
+use std::time::{Duration, Instant};
+use std::thread::sleep;
+use std::sync::{Arc, mpsc::Receiver, Mutex};
+use bevy::{pbr::AmbientLight, prelude::*};
+
+const STREAM_RATE_HZ: u16 = 20;
+// Resubscribe if the stream goes quiet, e.g. after the board was replugged
+const STALE_TIMEOUT: Duration = Duration::from_secs(2);
+// How often to look for link events between samples
+const EVENT_POLL: Duration = Duration::from_millis(100);
+// Firmware with the wrong protocol won't change until someone reflashes it
+const UNSUPPORTED_RETRY: Duration = Duration::from_secs(10);
+
+/// What the scene shows for one board, updated from its chatter thread.
+#[derive(Default)]
+struct Reading {
+    /// The board's UID, once it has said hello.
+    device: Option<Uid>,
+    link: LinkState,
+    /// Rotates board coordinates into the earth frame (x north, z up).
+    attitude: Quaternion,
+    /// True heading in degrees, if the board's readings give one.
+    heading: Option<f32>,
+    /// Set from the UI to ask for a calibration.
+    calibrate: Option<Calibrate>,
+    /// What the user should be doing while a calibration runs.
+    prompt: Option<String>,
+}
+
+fn chatter(board: Board, reading: Arc<Mutex<Reading>>, declination: f32) {
+    trace!("Starting chatter loop");
+    let events = board.link_events();
+    let mut calibrations: Option<Calibrations> = None;
+    let mut known = None;
+    loop {
+        let info = match board.hello() {
+            Ok(info) => info,
+            Err(e @ CompError::UnsupportedProtocol(_)) => {
+                error!("{}, try updating both", e);
+                sleep(UNSUPPORTED_RETRY);
+                continue;
+            }
+            Err(e) => {
+                warn!("No answer to hello: {}", e);
+                sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        if known != Some(info) {
+            info!(
+                "Board {} running firmware {} (protocol {}) with {:?}",
+                info.uid, info.firmware_version, info.protocol_version, info.sensors
+            );
+            known = Some(info);
+        }
+        if calibrations.as_ref().map(|c| c.uid) != Some(info.uid) {
+            // The first hello, or another board plugged in at this endpoint
+            calibrations = Some(Calibrations::load(info.uid));
+            reading.lock().unwrap().device = Some(info.uid);
+        }
+        let calibrations = calibrations.as_mut().unwrap();
+        // Stream whatever this board has; without a gyro or magnetometer
+        // the attitude and heading are just less accurate, or missing
+        let sensors = Sensors::ALL & info.sensors;
+
+        let which = reading.lock().unwrap().calibrate.take();
+        if let Some(which) = which {
+            let prompt = |prompt: Option<&str>| reading.lock().unwrap().prompt = prompt.map(str::to_string);
+            if let Err(e) = calibrations.run(which, &board, &prompt) {
+                warn!("{:?} calibration failed: {}", which, e);
+            }
+        }
+        // A different board, or one whose flash was wiped, won't have the
+        // calibration stored, so send it again on every (re)subscribe
+        if let Err(e) = calibrations.send(&board) {
+            warn!("Failed to send calibration: {}", e);
+            sleep(Duration::from_secs(1));
+            continue;
+        }
+        let samples = match board.subscribe(sensors, info.clamp_rate(STREAM_RATE_HZ)) {
+            Ok(samples) => samples,
+            Err(e) => {
+                warn!("Failed to subscribe: {}", e);
+                sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        info!("Streaming from {} at {} Hz", info.uid, samples.rate_hz());
+        let mut orientation = Orientation::new(sensors, Madgwick::default());
+        let mut accel = None;
+        let mut last = Instant::now();
+        // A reconnected board has to be greeted and subscribed to again
+        while !events.try_iter().any(|state| state == LinkState::Connected) {
+            let msg = match samples.recv_timeout(EVENT_POLL) {
+                Ok(Some(msg)) => msg,
+                Ok(None) if last.elapsed() < STALE_TIMEOUT => continue,
+                _ => break,
+            };
+            last = Instant::now();
+            trace!("Board said: {:?}", msg);
+            if reading.lock().unwrap().calibrate.is_some() {
+                break;
+            }
+            if let Some(q) = orientation.feed(&msg) {
+                reading.lock().unwrap().attitude = q;
+            }
+            match msg {
+                Message::Accel(_, x, y, z) => accel = Some([x, y, z]),
+                Message::Mag(_, x, y, z) => if let Some(accel) = accel {
+                    reading.lock().unwrap().heading = heading(accel, mag_gauss(x, y, z))
+                        .map(|h| true_heading(h, declination));
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn show_link_state(initial: LinkState, events: Receiver<LinkState>, reading: Arc<Mutex<Reading>>) {
+    for state in std::iter::once(initial).chain(events) {
+        let mut reading = reading.lock().unwrap();
+        reading.link = state;
+        if state != LinkState::Connected {
+            // Don't leave the last heading up as if it were current
+            reading.heading = None;
+        }
+    }
+}
+
+/// Show every board at `endpoints` until the window is closed, recording
+/// them to `recorder` if given.
+/// `declination` is in degrees east of true north; leave it at zero to show
+/// magnetic heading.
+pub fn run(endpoints: Vec<Endpoint>, declination: f32, recorder: Option<Recorder>) {
+    let readings = endpoints.into_iter().map(|endpoint| {
+        let reading = Arc::new(Mutex::new(Reading::default()));
+        let reading_clone = reading.clone();
+        let board = connect_recorded(endpoint, &recorder);
+        let link_events = board.link_events();
+        let link_state = board.link_state();
+        let link_reading = reading.clone();
+        std::thread::spawn( move || {
+            show_link_state(link_state, link_events, link_reading);
+        });
+        std::thread::spawn( move || {
+            chatter(board, reading_clone, declination);
+        });
+        reading
+    }).collect();
+    App::build()
+        .insert_resource(Latest(readings))
+        .add_plugins(DefaultPlugins)
+        .add_plugin(HelloPlugin)
+        .add_system(attitude_system.system())
+        .add_system(heading_system.system())
+        .add_system(calibrate_key_system.system())
+        .run();
+}
+
+struct HelloPlugin;
+
+impl Plugin for HelloPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(AmbientLight {
+                color: Color::WHITE,
+                brightness: 1.0 / 5.0f32,
+            })
+            .add_startup_system(setup_scene.system())
+            .add_system(rotator_system.system());
+    }
+}
+
+fn setup_scene(
+    latest: Res<Latest>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // commands.spawn_bundle( PbrBundle {
+    //     mesh: meshes.add(Mesh::from(shape::Box::new(4.0, 0.5, 2.0))),
+    //     material: materials.add(Color::rgb(0.3, 0.5, 0.5).into()),
+    //     transform: Transform::from_xyz(0.0, 0.5, 0.0),
+    //     ..Default::default()
+    // }).insert(Rotates);
+    // Roughly the Discovery board's proportions, long side along its x axis.
+    // With several boards they sit side by side in the order given.
+    let mesh = meshes.add(Mesh::from(shape::Box::new(1.0, 0.05, 0.6)));
+    let material = materials.add(Color::rgb(0.3, 0.0, 0.0).into());
+    let count = latest.0.len();
+    for i in 0..count {
+        let x = (i as f32 - (count - 1) as f32 / 2.) * BOARD_SPACING;
+        commands.spawn_bundle( PbrBundle {
+            mesh: mesh.clone(),
+            material: material.clone(),
+            transform: Transform::from_xyz(x, 0.5, 0.0),
+            ..Default::default()
+        }).insert(BoardModel(i));
+    }
+    commands.spawn_bundle( LightBundle {
+        transform: Transform::from_xyz(1.0, 1.0, 1.0),
+        ..Default::default()
+    });
+    commands.spawn_bundle( PerspectiveCameraBundle {
+        transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+        ..Default::default()
+    });
+
+}
+
+// Distance between neighbouring board models along x
+const BOARD_SPACING: f32 = 1.5;
+
+/// One reading per board, in the order the endpoints were given.
+struct Latest(Vec<Arc<Mutex<Reading>>>);
+
+/// The model showing the reading at this index of `Latest`.
+struct BoardModel(usize);
+
+fn attitude_system(latest: Res<Latest>, mut query: Query<(&mut Transform, &BoardModel)>) {
+    // Bevy is y-up, so map the z-up frames onto it: x stays, z becomes y
+    // and y becomes -z. The same mapping applies to the board model.
+    let to_bevy = Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2);
+    for (mut transform, model) in query.iter_mut() {
+        let q = latest.0[model.0].lock().unwrap().attitude;
+        transform.rotation = to_bevy * Quat::from_xyzw(q.x, q.y, q.z, q.w) * to_bevy.inverse();
+    }
+}
+
+fn heading_system(latest: Res<Latest>, mut windows: ResMut<Windows>) {
+    let several = latest.0.len() > 1;
+    let parts: Vec<String> = latest.0.iter().map(|reading| {
+        let reading = reading.lock().unwrap();
+        let part = match (reading.link, &reading.prompt, reading.heading) {
+            (LinkState::Disconnected, _, _) => "disconnected".to_string(),
+            (LinkState::Reconnecting, _, _) => "reconnecting".to_string(),
+            (_, Some(prompt), _) => format!("calibrating, {}", prompt),
+            (_, None, Some(heading)) => format!("{:05.1}°", heading),
+            (_, None, None) => "no heading".to_string(),
+        };
+        match reading.device {
+            Some(uid) if several => format!("{} {}", uid, part),
+            _ => part,
+        }
+    }).collect();
+    let title = format!("usb-compass: {}", parts.join(" | "));
+    if let Some(window) = windows.get_primary_mut() {
+        if window.title() != title {
+            window.set_title(title);
+        }
+    }
+}
+
+// Press C to calibrate the magnetometers, A for the accelerometers. Every
+// board calibrates at once, so move them together.
+fn calibrate_key_system(keys: Res<Input<KeyCode>>, latest: Res<Latest>) {
+    let which = if keys.just_pressed(KeyCode::C) {
+        Calibrate::Mag
+    } else if keys.just_pressed(KeyCode::A) {
+        Calibrate::Accel
+    } else {
+        return;
+    };
+    for reading in &latest.0 {
+        reading.lock().unwrap().calibrate = Some(which);
+    }
+}
+
+struct Rotates;
+
+fn rotator_system(time: Res<Time>, mut query: Query<&mut Transform, With<Rotates>>) {
+    for mut transform in query.iter_mut() {
+        *transform = Transform::from_rotation(Quat::from_rotation_y((4.0 * std::f32::consts::PI / 20.0) * time.delta_seconds(),)) * *transform;
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many round trips the estimate is based on.
+pub const DEFAULT_PROBES: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+struct Probe {
+    // Host time halfway through the round trip, µs since `ClockSync::origin`
+    host_us: f64,
+    board_us: f64,
+    rtt_us: f64,
+}
+
+/// Linear model of the board clock: `board_us = offset_us + rate * host_us`,
+/// with host time measured from `ClockSync::origin`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockFit {
+    pub offset_us: f64,
+    pub rate: f64,
+}
+
+impl ClockFit {
+    /// How much faster the board clock runs than the host's, in parts per
+    /// million.
+    pub fn drift_ppm(&self) -> f64 {
+        (self.rate - 1.0) * 1e6
+    }
+}
+
+/// Estimates the offset and drift between the board's microsecond clock and
+/// the host's from `TimeReq`/`Time` round trips.
+///
+/// Each round trip assumes the board read its clock halfway between sending
+/// and receiving. Only the faster half of the round trips are used, since
+/// slow ones are the most likely to be lopsided.
+pub struct ClockSync {
+    origin: Instant,
+    capacity: usize,
+    probes: VecDeque<Probe>,
+    fit: Option<ClockFit>,
+}
+
+impl ClockSync {
+    pub fn new(capacity: usize) -> ClockSync {
+        ClockSync {
+            origin: Instant::now(),
+            capacity: capacity.max(1),
+            probes: VecDeque::new(),
+            fit: None,
+        }
+    }
+
+    /// Record a round trip: the request went out at `sent`, the board said
+    /// its clock read `board_us`, and the reply arrived at `received`.
+    pub fn add(&mut self, sent: Instant, board_us: u64, received: Instant) {
+        let sent_us = self.host_us(sent);
+        let received_us = self.host_us(received);
+        if self.probes.len() == self.capacity {
+            self.probes.pop_front();
+        }
+        self.probes.push_back(Probe {
+            host_us: (sent_us + received_us) / 2.0,
+            board_us: board_us as f64,
+            rtt_us: received_us - sent_us,
+        });
+        self.fit = self.refit();
+    }
+
+    pub fn fit(&self) -> Option<ClockFit> {
+        self.fit
+    }
+
+    /// Map a board timestamp onto the host clock.
+    pub fn to_host(&self, board_us: u64) -> Option<Instant> {
+        let fit = self.fit?;
+        let host_us = (board_us as f64 - fit.offset_us) / fit.rate;
+        if host_us >= 0.0 {
+            self.origin.checked_add(Duration::from_micros(host_us as u64))
+        } else {
+            self.origin.checked_sub(Duration::from_micros(-host_us as u64))
+        }
+    }
+
+    /// Predict what the board clock reads at host time `host`.
+    pub fn to_board(&self, host: Instant) -> Option<u64> {
+        let fit = self.fit?;
+        let board_us = fit.offset_us + fit.rate * self.host_us(host);
+        if board_us >= 0.0 {
+            Some(board_us as u64)
+        } else {
+            None
+        }
+    }
+
+    fn host_us(&self, t: Instant) -> f64 {
+        if t >= self.origin {
+            (t - self.origin).as_secs_f64() * 1e6
+        } else {
+            -(self.origin - t).as_secs_f64() * 1e6
+        }
+    }
+
+    fn refit(&self) -> Option<ClockFit> {
+        let mut best: Vec<Probe> = self.probes.iter().copied().collect();
+        best.sort_by(|a, b| a.rtt_us.partial_cmp(&b.rtt_us).unwrap());
+        best.truncate(best.len().div_ceil(2));
+
+        let n = best.len() as f64;
+        let mean_host = best.iter().map(|p| p.host_us).sum::<f64>() / n;
+        let mean_board = best.iter().map(|p| p.board_us).sum::<f64>() / n;
+        let var_host = best.iter().map(|p| (p.host_us - mean_host).powi(2)).sum::<f64>();
+        let cov = best.iter().map(|p| (p.host_us - mean_host) * (p.board_us - mean_board)).sum::<f64>();
+
+        // A single probe, or several at the same instant, can't show drift
+        let rate = if var_host > 0.0 { cov / var_host } else { 1.0 };
+        if !rate.is_finite() {
+            return None;
+        }
+        Some(ClockFit {
+            offset_us: mean_board - rate * mean_host,
+            rate,
+        })
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> ClockSync {
+        ClockSync::new(DEFAULT_PROBES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Board booted 5 s before the host origin and runs 100 ppm fast
+    const OFFSET_US: f64 = 5e6;
+    const RATE: f64 = 1.0001;
+
+    fn board_at(host_us: f64) -> u64 {
+        (OFFSET_US + RATE * host_us) as u64
+    }
+
+    #[test]
+    fn no_probes() {
+        let sync = ClockSync::default();
+        assert!(sync.fit().is_none());
+        assert!(sync.to_board(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn single_probe() {
+        let mut sync = ClockSync::default();
+        let sent = sync.origin + Duration::from_millis(100);
+        let received = sent + Duration::from_millis(2);
+        sync.add(sent, board_at(101_000.0), received);
+        let fit = sync.fit().unwrap();
+        assert_eq!(fit.rate, 1.0);
+        assert!((sync.to_board(sent + Duration::from_millis(1)).unwrap() as f64 - board_at(101_000.0) as f64).abs() < 2.0);
+    }
+
+    #[test]
+    fn offset_and_drift() {
+        let mut sync = ClockSync::default();
+        let origin = sync.origin;
+        for i in 0..DEFAULT_PROBES {
+            let sent_us = i as f64 * 250_000.0;
+            // Every other round trip is slow and lopsided: the reply sat in
+            // a queue on the host side.
+            let (rtt_us, board_delay_us) = if i % 2 == 0 { (1_000.0, 500.0) } else { (20_000.0, 1_000.0) };
+            let sent = origin + Duration::from_micros(sent_us as u64);
+            let received = origin + Duration::from_micros((sent_us + rtt_us) as u64);
+            sync.add(sent, board_at(sent_us + board_delay_us), received);
+        }
+        let fit = sync.fit().unwrap();
+        assert!((fit.drift_ppm() - 100.0).abs() < 1.0, "drift {}", fit.drift_ppm());
+        assert!((fit.offset_us - OFFSET_US).abs() < 10.0, "offset {}", fit.offset_us);
+
+        let host = origin + Duration::from_secs(3);
+        let board_us = sync.to_board(host).unwrap();
+        assert!((board_us as f64 - board_at(3e6) as f64).abs() < 10.0);
+        let back = sync.to_host(board_us).unwrap();
+        let error = if back > host { back - host } else { host - back };
+        assert!(error < Duration::from_micros(10));
+    }
+}
@@ -0,0 +1,92 @@
+//! Reading and changing the settings the board keeps in flash. Changes are
+//! staged on the board until `commit`.
+
+use common::config::{ConfigData, ConfigError};
+use common::Message;
+use crate::board::Board;
+use crate::{CompError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The raw bytes stored under `key`, if any.
+pub fn get_raw(board: &Board, key: u16) -> Result<Option<ConfigData>> {
+    match board.request(Message::ConfigGet(key))? {
+        Message::Config(k, data) if k == key => Ok(data),
+        Message::ConfigFailed(e) => Err(CompError::Config(e)),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+/// The value stored under `key`, if any.
+pub fn get<T: DeserializeOwned>(board: &Board, key: u16) -> Result<Option<T>> {
+    match get_raw(board, key)? {
+        Some(data) => match data.to_value() {
+            Some(value) => Ok(Some(value)),
+            None => Err(CompError::Unexpected(Box::new(Message::Config(key, Some(data))))),
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn set_raw(board: &Board, key: u16, data: ConfigData) -> Result<()> {
+    acknowledged(board.request(Message::ConfigSet(key, data))?)
+}
+
+/// Stage `value` under `key`.
+pub fn set<T: Serialize>(board: &Board, key: u16, value: &T) -> Result<()> {
+    let data = ConfigData::from_value(value).ok_or(CompError::Config(ConfigError::TooLarge))?;
+    set_raw(board, key, data)
+}
+
+/// Stage removing `key`.
+pub fn erase(board: &Board, key: u16) -> Result<()> {
+    acknowledged(board.request(Message::ConfigErase(key))?)
+}
+
+/// Write the staged changes to flash. The board applies them straight away.
+pub fn commit(board: &Board) -> Result<()> {
+    acknowledged(board.request(Message::ConfigCommit)?)
+}
+
+/// Store `value` under `key` and commit it.
+pub fn store<T: Serialize>(board: &Board, key: u16, value: &T) -> Result<()> {
+    set(board, key, value)?;
+    commit(board)
+}
+
+fn acknowledged(reply: Message) -> Result<()> {
+    match reply {
+        Message::Ack => Ok(()),
+        Message::ConfigFailed(e) => Err(CompError::Config(e)),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::mock_board;
+    use common::calibration::MagCalibration;
+    use common::config::keys;
+
+    #[test]
+    fn round_trip() {
+        let board = mock_board();
+        assert_eq!(get::<MagCalibration>(&board, keys::MAG_CALIBRATION).unwrap(), None);
+        let cal = MagCalibration { offset: [3., 2., 1.], ..MagCalibration::IDENTITY };
+        store(&board, keys::MAG_CALIBRATION, &cal).unwrap();
+        assert_eq!(get(&board, keys::MAG_CALIBRATION).unwrap(), Some(cal));
+        erase(&board, keys::MAG_CALIBRATION).unwrap();
+        commit(&board).unwrap();
+        assert_eq!(get::<MagCalibration>(&board, keys::MAG_CALIBRATION).unwrap(), None);
+    }
+
+    #[test]
+    fn errors() {
+        let board = mock_board();
+        assert!(matches!(set(&board, 0xFFFF, &1u16), Err(CompError::Config(ConfigError::BadKey))));
+        assert!(matches!(set(&board, 1, &[0u8; 100].to_vec()), Err(CompError::Config(ConfigError::TooLarge))));
+        set_raw(&board, keys::IDLE_RATE_HZ, ConfigData::new(b"junk").unwrap()).unwrap();
+        assert!(matches!(get::<u16>(&board, keys::IDLE_RATE_HZ), Err(CompError::Unexpected(_))));
+    }
+}
@@ -0,0 +1,479 @@
+//! Sharing one board between several local processes.
+//!
+//! Only one process can claim the board's USB interface, so the daemon
+//! holds the link and serves everyone else. Subscribers talk to it as if it
+//! were the board. Over TCP they use the same SLIP+CBOR framing as
+//! `common::link`, so `-b tcp:<addr>` works against it. Over WebSocket each
+//! text message is an `Envelope` as JSON, with the message in the same
+//! tagged array form it has on the wire.
+//!
+//! Requests go to the board under a sequence number the daemon picks, and
+//! the reply goes back to whoever asked under the number they used.
+//! Subscriptions are arbitrated. The board streams every sensor anyone
+//! subscribed to, at the fastest rate anyone asked for, and each subscriber
+//! gets the samples of the sensors they asked for. `Unsubscribe` only ends
+//! the sender's own subscription.
+
+use crate::board::{spawn_link, LinkState};
+use crate::link::usb_link;
+use crate::transport::{Endpoint, TcpTransport};
+use crate::{CompError, Result};
+use common::{Envelope, Message, Sensors};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::{HandshakeError, Message as WsMessage};
+
+// A reply that hasn't come by then isn't coming, and the subscriber will
+// have asked again under a new number
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a WebSocket subscriber's thread waits for a message before
+// sending whatever is queued for it
+const WEBSOCKET_POLL: Duration = Duration::from_millis(10);
+
+type ClientId = usize;
+
+enum Event {
+    Joined(ClientId, Sender<Envelope>),
+    FromClient(ClientId, Envelope),
+    Left(ClientId),
+    FromBoard(Envelope),
+    Link(LinkState),
+}
+
+/// Where an envelope is going.
+#[derive(Debug, PartialEq)]
+enum Out {
+    Board(Envelope),
+    Client(ClientId, Envelope),
+}
+
+// A request waiting on the board's reply
+struct Route {
+    client: ClientId,
+    seq: u16,
+    sent: Instant,
+}
+
+struct Subscription {
+    seq: u16,
+    sensors: Sensors,
+    rate_hz: u16,
+}
+
+/// Decides where each envelope goes. Kept apart from the sockets so the
+/// arbitration can be tested on its own.
+#[derive(Default)]
+struct Router {
+    next_seq: u16,
+    clients: Vec<ClientId>,
+    routes: HashMap<u16, Route>,
+    subscriptions: HashMap<ClientId, Subscription>,
+    /// The board's subscription: its seq, sensors and the rate asked for.
+    stream: Option<(u16, Sensors, u16)>,
+    /// The rate the board agreed to for `stream`, once it has.
+    rate_hz: Option<u16>,
+    /// Subscribers still to be told the rate, and the seq they asked under.
+    waiting: Vec<(ClientId, u16)>,
+}
+
+impl Router {
+    fn joined(&mut self, client: ClientId) {
+        self.clients.push(client);
+    }
+
+    fn left(&mut self, client: ClientId) -> Vec<Out> {
+        self.clients.retain(|c| *c != client);
+        self.routes.retain(|_, route| route.client != client);
+        self.waiting.retain(|(c, _)| *c != client);
+        self.subscriptions.remove(&client);
+        self.arbitrate()
+    }
+
+    fn client_sent(&mut self, client: ClientId, env: Envelope, now: Instant) -> Vec<Out> {
+        // Stragglers from a subscriber that already left
+        if !self.clients.contains(&client) {
+            return Vec::new();
+        }
+        match env.msg {
+            Message::Subscribe { sensors, rate_hz } => {
+                self.subscriptions.insert(client, Subscription { seq: env.seq, sensors, rate_hz });
+                self.waiting.push((client, env.seq));
+                self.arbitrate()
+            }
+            Message::Unsubscribe => {
+                self.subscriptions.remove(&client);
+                self.arbitrate()
+            }
+            Message::Nop => Vec::new(),
+            msg => {
+                let seq = self.allocate();
+                self.routes.insert(seq, Route { client, seq: env.seq, sent: now });
+                vec![Out::Board(Envelope::new(seq, msg))]
+            }
+        }
+    }
+
+    fn board_sent(&mut self, env: Envelope) -> Vec<Out> {
+        if env.seq == Envelope::UNSOLICITED {
+            return self.clients.iter().map(|client| Out::Client(*client, env.clone())).collect();
+        }
+        if matches!(self.stream, Some((seq, ..)) if seq == env.seq) {
+            return match env.msg {
+                Message::SubscribeAck(rate_hz) => {
+                    self.rate_hz = Some(rate_hz);
+                    self.answer_waiting()
+                }
+                msg => self.fan_out(msg),
+            };
+        }
+        match self.routes.remove(&env.seq) {
+            Some(route) => vec![Out::Client(route.client, Envelope::new(route.seq, env.msg))],
+            None => {
+                debug!("Nobody is waiting on {:?}", env);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The board came back, and has forgotten what it was streaming.
+    fn reconnected(&mut self) -> Vec<Out> {
+        match self.stream {
+            Some((_, sensors, rate_hz)) => vec![self.subscribe_board(sensors, rate_hz)],
+            None => Vec::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.routes.retain(|_, route| now.saturating_duration_since(route.sent) < ROUTE_TIMEOUT);
+    }
+
+    // Bring what the board streams in line with what's subscribed to
+    fn arbitrate(&mut self) -> Vec<Out> {
+        let wanted = self.subscriptions.values().fold(None, |wanted, sub| {
+            Some(match wanted {
+                None => (sub.sensors, sub.rate_hz),
+                Some((sensors, rate_hz)) => (sensors | sub.sensors, u16::max(rate_hz, sub.rate_hz)),
+            })
+        });
+        let streaming = self.stream.map(|(_, sensors, rate_hz)| (sensors, rate_hz));
+        let mut out = Vec::new();
+        match wanted {
+            // Ask again if the board never answered, since the subscriber
+            // asking now is likely retrying
+            Some((sensors, rate_hz)) if wanted != streaming || self.rate_hz.is_none() => {
+                out.push(self.subscribe_board(sensors, rate_hz));
+            }
+            None if streaming.is_some() => {
+                self.stream = None;
+                self.rate_hz = None;
+                out.push(Out::Board(Envelope::new(self.allocate(), Message::Unsubscribe)));
+            }
+            _ => (),
+        }
+        out.extend(self.answer_waiting());
+        out
+    }
+
+    fn subscribe_board(&mut self, sensors: Sensors, rate_hz: u16) -> Out {
+        let seq = self.allocate();
+        self.stream = Some((seq, sensors, rate_hz));
+        self.rate_hz = None;
+        Out::Board(Envelope::new(seq, Message::Subscribe { sensors, rate_hz }))
+    }
+
+    // Tell subscribers the rate, once the board has agreed to one
+    fn answer_waiting(&mut self) -> Vec<Out> {
+        match self.rate_hz {
+            Some(rate_hz) => self
+                .waiting
+                .drain(..)
+                .map(|(client, seq)| Out::Client(client, Envelope::new(seq, Message::SubscribeAck(rate_hz))))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn fan_out(&self, msg: Message) -> Vec<Out> {
+        let sensor = match msg {
+            Message::Accel(..) => Some(Sensors::ACCEL),
+            Message::Mag(..) => Some(Sensors::MAG),
+            Message::Gyro(..) => Some(Sensors::GYRO),
+            _ => None,
+        };
+        self.subscriptions
+            .iter()
+            .filter(|(_, sub)| match sensor {
+                Some(sensor) => sub.sensors.contains(sensor),
+                None => true,
+            })
+            .map(|(client, sub)| Out::Client(*client, Envelope::new(sub.seq, msg.clone())))
+            .collect()
+    }
+
+    fn allocate(&mut self) -> u16 {
+        loop {
+            self.next_seq = self.next_seq.wrapping_add(1);
+            let seq = self.next_seq;
+            let streaming = matches!(self.stream, Some((stream, ..)) if stream == seq);
+            if seq != Envelope::UNSOLICITED && !streaming && !self.routes.contains_key(&seq) {
+                return seq;
+            }
+        }
+    }
+}
+
+/// Share the board at `endpoint` with subscribers connecting to `tcp` and
+/// `websocket`, reopening it whenever it goes away. Only returns if the
+/// link thread stops.
+pub fn run(endpoint: Endpoint, tcp: Option<TcpListener>, websocket: Option<TcpListener>) -> Result<()> {
+    let (events_tx, events) = channel();
+    let (to_board, from_board, link) = spawn_link(endpoint, None);
+    forward(from_board, events_tx.clone(), Event::FromBoard);
+    forward(link.watch(), events_tx.clone(), Event::Link);
+    let ids = Arc::new(AtomicUsize::new(0));
+    for (listener, serve) in [(tcp, serve_tcp as Serve), (websocket, serve_websocket as Serve)] {
+        if let Some(listener) = listener {
+            info!("Serving subscribers on {}", listener.local_addr()?);
+            let events = events_tx.clone();
+            let ids = ids.clone();
+            thread::spawn(move || accept(listener, serve, events, ids));
+        }
+    }
+    drop(events_tx);
+
+    let mut router = Router::default();
+    let mut clients: HashMap<ClientId, Sender<Envelope>> = HashMap::new();
+    for event in events {
+        let now = Instant::now();
+        router.expire(now);
+        let out = match event {
+            Event::Joined(client, tx) => {
+                clients.insert(client, tx);
+                router.joined(client);
+                Vec::new()
+            }
+            Event::FromClient(client, env) => router.client_sent(client, env, now),
+            Event::Left(client) => {
+                clients.remove(&client);
+                router.left(client)
+            }
+            Event::FromBoard(env) => router.board_sent(env),
+            Event::Link(LinkState::Connected) => router.reconnected(),
+            Event::Link(_) => Vec::new(),
+        };
+        for out in out {
+            match out {
+                Out::Board(env) => to_board.send(env)?,
+                Out::Client(client, env) => {
+                    if let Some(tx) = clients.get(&client) {
+                        // If they've gone, `Left` is on its way
+                        let _ = tx.send(env);
+                    }
+                }
+            }
+        }
+    }
+    Err(CompError::Disconnected)
+}
+
+type Serve = fn(TcpStream, ClientId, &Sender<Event>) -> Result<()>;
+
+// Feed everything from `rx` into the event loop
+fn forward<T, F>(rx: Receiver<T>, events: Sender<Event>, wrap: F)
+where
+    T: Send + 'static,
+    F: Fn(T) -> Event + Send + 'static,
+{
+    thread::spawn(move || {
+        for item in rx {
+            if events.send(wrap(item)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn accept(listener: TcpListener, serve: Serve, events: Sender<Event>, ids: Arc<AtomicUsize>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept a subscriber: {}", e);
+                continue;
+            }
+        };
+        let client = ids.fetch_add(1, Ordering::Relaxed);
+        let events = events.clone();
+        thread::spawn(move || {
+            info!("Subscriber {} connected from {:?}", client, stream.peer_addr());
+            if let Err(e) = serve(stream, client, &events) {
+                debug!("Subscriber {} stopped: {}", client, e);
+            }
+            info!("Subscriber {} left", client);
+            let _ = events.send(Event::Left(client));
+        });
+    }
+}
+
+fn join(client: ClientId, events: &Sender<Event>) -> Result<Receiver<Envelope>> {
+    let (tx, rx) = channel();
+    events.send(Event::Joined(client, tx)).map_err(|_| CompError::Disconnected)?;
+    Ok(rx)
+}
+
+// The same link a board has, with the daemon on the board's end
+fn serve_tcp(stream: TcpStream, client: ClientId, events: &Sender<Event>) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut to_client = join(client, events)?;
+    let (from_client, from_client_rx) = channel();
+    forward(from_client_rx, events.clone(), move |env| Event::FromClient(client, env));
+    usb_link(&mut to_client, &from_client, &mut TcpTransport::from(stream))
+}
+
+fn serve_websocket(stream: TcpStream, client: ClientId, events: &Sender<Event>) -> Result<()> {
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(HandshakeError::Failure(e)) => return Err(e.into()),
+        // Only non-blocking sockets get interrupted
+        Err(HandshakeError::Interrupted(_)) => return Err(CompError::Disconnected),
+    };
+    socket.get_mut().set_read_timeout(Some(WEBSOCKET_POLL))?;
+    let to_client = join(client, events)?;
+    loop {
+        match socket.read_message() {
+            Ok(WsMessage::Text(text)) => match serde_json::from_str(&text) {
+                Ok(env) => events.send(Event::FromClient(client, env)).map_err(|_| CompError::Disconnected)?,
+                Err(e) => warn!("Subscriber {} sent something that isn't an envelope: {}", client, e),
+            },
+            Ok(WsMessage::Close(_)) => return Ok(()),
+            // tungstenite answers pings itself
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            Err(e) => return Err(e.into()),
+        }
+        loop {
+            match to_client.try_recv() {
+                Ok(env) => socket.write_message(WsMessage::Text(serde_json::to_string(&env)?))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use common::sensors::Stamp;
+
+    fn sample(sensor: Sensors) -> Message {
+        match sensor {
+            Sensors::ACCEL => Message::Accel(Stamp::default(), 0., 0., 1.),
+            _ => Message::Mag(Stamp::default(), 1, 2, 3),
+        }
+    }
+
+    fn board_envelope(out: &[Out]) -> Envelope {
+        match out {
+            [.., Out::Board(env)] => env.clone(),
+            other => panic!("Nothing for the board in {:?}", other),
+        }
+    }
+
+    #[test]
+    fn routes_replies_back() {
+        let mut router = Router::default();
+        let now = Instant::now();
+        router.joined(1);
+        router.joined(2);
+        let first = board_envelope(&router.client_sent(1, Envelope::new(7, Message::Hello), now));
+        let second = board_envelope(&router.client_sent(2, Envelope::new(7, Message::TimeReq), now));
+        assert_ne!(first.seq, second.seq);
+
+        let out = router.board_sent(second.reply(Message::Time(42)));
+        assert_eq!(out, vec![Out::Client(2, Envelope::new(7, Message::Time(42)))]);
+        assert!(router.board_sent(second.reply(Message::Time(42))).is_empty());
+
+        router.expire(now + ROUTE_TIMEOUT);
+        assert!(router.board_sent(first.reply(Message::Ack)).is_empty());
+    }
+
+    #[test]
+    fn arbitrates_subscriptions() {
+        let mut router = Router::default();
+        let now = Instant::now();
+        router.joined(1);
+        router.joined(2);
+
+        let sub = Message::Subscribe { sensors: Sensors::MAG, rate_hz: 10 };
+        let stream = board_envelope(&router.client_sent(1, Envelope::new(3, sub), now));
+        assert_eq!(stream.msg, Message::Subscribe { sensors: Sensors::MAG, rate_hz: 10 });
+        let out = router.board_sent(stream.reply(Message::SubscribeAck(10)));
+        assert_eq!(out, vec![Out::Client(1, Envelope::new(3, Message::SubscribeAck(10)))]);
+
+        // The board streams both sensors at the faster rate
+        let sub = Message::Subscribe { sensors: Sensors::ACCEL, rate_hz: 50 };
+        let stream = board_envelope(&router.client_sent(2, Envelope::new(9, sub), now));
+        assert_eq!(stream.msg, Message::Subscribe { sensors: Sensors::ACCEL | Sensors::MAG, rate_hz: 50 });
+        let out = router.board_sent(stream.reply(Message::SubscribeAck(50)));
+        assert_eq!(out, vec![Out::Client(2, Envelope::new(9, Message::SubscribeAck(50)))]);
+
+        // Each subscriber gets their own sensors, under their own seq
+        let out = router.board_sent(stream.reply(sample(Sensors::MAG)));
+        assert_eq!(out, vec![Out::Client(1, Envelope::new(3, sample(Sensors::MAG)))]);
+        let out = router.board_sent(stream.reply(sample(Sensors::ACCEL)));
+        assert_eq!(out, vec![Out::Client(2, Envelope::new(9, sample(Sensors::ACCEL)))]);
+
+        // Back to what's left once one goes, and off once both have
+        let out = router.client_sent(2, Envelope::new(10, Message::Unsubscribe), now);
+        assert_eq!(board_envelope(&out).msg, Message::Subscribe { sensors: Sensors::MAG, rate_hz: 10 });
+        let stream = board_envelope(&router.reconnected());
+        assert_eq!(stream.msg, Message::Subscribe { sensors: Sensors::MAG, rate_hz: 10 });
+        assert_eq!(board_envelope(&router.left(1)).msg, Message::Unsubscribe);
+        assert!(router.board_sent(stream.reply(sample(Sensors::MAG))).is_empty());
+    }
+
+    #[test]
+    fn unsolicited_goes_to_everyone() {
+        let mut router = Router::default();
+        router.joined(1);
+        router.joined(2);
+        let out = router.board_sent(Envelope::from(Message::Ack));
+        assert_eq!(out.len(), 2);
+        router.left(2);
+        assert!(router.client_sent(2, Envelope::new(1, Message::Hello), Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn shares_a_board() {
+        // A mock board served over TCP stands in for one on USB
+        let board_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(board_listener.local_addr().unwrap().to_string());
+        thread::spawn(move || {
+            let (stream, _) = board_listener.accept().unwrap();
+            mock::serve(stream.try_clone().unwrap(), stream);
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let daemon = Endpoint::Tcp(listener.local_addr().unwrap().to_string());
+        thread::spawn(move || run(endpoint, Some(listener), None));
+
+        let timeout = Duration::from_secs(5);
+        let (mag, accel) = (Board::connect(daemon.clone()), Board::connect(daemon));
+        mag.wait_connected(timeout).unwrap();
+        accel.wait_connected(timeout).unwrap();
+        assert_eq!(mag.hello().unwrap().uid, accel.hello().unwrap().uid);
+
+        let mags = mag.subscribe(Sensors::MAG, 20).unwrap();
+        let accels = accel.subscribe(Sensors::ACCEL, 50).unwrap();
+        assert!(mags.take(5).all(|msg| matches!(msg, Message::Mag(..))));
+        assert!(accels.take(5).all(|msg| matches!(msg, Message::Accel(..))));
+    }
+}
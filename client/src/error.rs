@@ -1,6 +1,9 @@
 use thiserror::Error;
+use common::config::ConfigError;
 use common::link::LinkError;
-use common::Message;
+use common::update::UpdateError;
+use common::ProtocolVersion;
+use common::{Envelope, Message};
 
 pub type Result<T> = std::result::Result<T, CompError>;
 
@@ -13,10 +16,54 @@ pub enum CompError {
     },
     #[error("Link Error: {0}")]
     LinkError(String),
+    #[error("Io Error: {error}")]
+    IoError {
+        #[from]
+        error: std::io::Error,
+    },
+    #[error("Serial Error: {error}")]
+    SerialError {
+        #[from]
+        error: serialport::Error,
+    },
+    #[error("No compass board found")]
+    NotFound,
+    #[error("Transport disconnected")]
+    Disconnected,
+    #[error("Bad endpoint: {0}")]
+    BadEndpoint(String),
+    #[error("No reply from board")]
+    Timeout,
+    #[error("Board speaks protocol version {0}, which this client doesn't support")]
+    UnsupportedProtocol(ProtocolVersion),
+    #[error("Already subscribed: the board streams to one subscription at a time")]
+    AlreadySubscribed,
+    #[error("Board doesn't understand message {0}")]
+    UnsupportedMessage(u16),
+    #[error("Unexpected reply {0:?}")]
+    Unexpected(Box<Message>),
+    #[error("Config store error: {0:?}")]
+    Config(ConfigError),
+    #[error("Bad export option: {0}")]
+    BadExport(String),
+    #[error("Bad recording: {0}")]
+    Recording(String),
+    #[error("Calibration failed: {0}")]
+    Calibration(String),
+    #[error("Symbol lookup failed: {0}")]
+    Symbols(String),
+    #[error("Firmware update failed: {0:?}")]
+    Update(UpdateError),
+    #[error("WebSocket Error: {0}")]
+    WebSocketError(String),
+    #[error("Json Error: {error}")]
+    JsonError {
+        #[from]
+        error: serde_json::Error,
+    },
     #[error("Sync Send Error {error}")]
     SendError {
-        #[from]
-        error: std::sync::mpsc::SendError<Message>,
+        error: Box<std::sync::mpsc::SendError<Envelope>>,
     },
     #[error("Sync Recv Error {error}")]
     RecvError {
@@ -35,3 +82,15 @@ impl From<LinkError> for CompError {
         CompError::LinkError(format!("{:?}", error))
     }
 }
+
+impl From<std::sync::mpsc::SendError<Envelope>> for CompError {
+    fn from(error: std::sync::mpsc::SendError<Envelope>) -> CompError {
+        CompError::SendError { error: Box::new(error) }
+    }
+}
+
+impl From<tungstenite::Error> for CompError {
+    fn from(error: tungstenite::Error) -> CompError {
+        CompError::WebSocketError(error.to_string())
+    }
+}
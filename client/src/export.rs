@@ -0,0 +1,462 @@
+//! Exporting samples for analysis in other tools, from a live stream or a
+//! recording.
+//!
+//! There are three formats:
+//!
+//! - CSV: a header naming the columns, then one row per sample.
+//! - NDJSON: one JSON object per sample.
+//! - Columnar: laid out for numpy to memory-map. The file starts with
+//!   `COLUMNAR_MAGIC` and then the length of a JSON header as a
+//!   little-endian `u32`. Next comes the header itself, padded with spaces
+//!   so that the columns that follow start on an 8-byte boundary. The
+//!   header gives `rows` and, for each column, its `name`, numpy `dtype`
+//!   and `offset` from the start of the file. The `device` and `sensor`
+//!   columns hold indices into the header's `devices` and `sensors` lists.
+//!   Each entry in `sensors` also gives that sensor's unit.
+//!
+//! Readings are in g, gauss or degrees per second.
+
+use crate::fleet::Sample;
+use crate::record::Frame;
+use crate::{CompError, Result};
+use common::device::Uid;
+use common::sensors::mag_gauss;
+use common::{Message, Sensors};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+pub const COLUMNAR_MAGIC: [u8; 8] = *b"COMPCOL1";
+const COLUMNAR_ALIGN: usize = 8;
+// Magic and header length
+const COLUMNAR_PREFIX_SIZE: usize = 8 + 4;
+
+// Each sensor's name and unit, in the order the columnar `sensor` column
+// indexes them
+const SENSORS: [(Sensors, &str, &str); 3] =
+    [(Sensors::ACCEL, "accel", "g"), (Sensors::MAG, "mag", "gauss"), (Sensors::GYRO, "gyro", "dps")];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Columnar,
+}
+
+impl FromStr for ExportFormat {
+    type Err = CompError;
+
+    fn from_str(s: &str) -> Result<ExportFormat> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "columnar" => Ok(ExportFormat::Columnar),
+            _ => Err(CompError::BadExport(format!("unknown format {}, try csv, ndjson or columnar", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// UID of the board that took the sample.
+    Device,
+    /// accel, mag or gyro.
+    Sensor,
+    /// Microseconds, by the clock `TimeBase` picks.
+    Time,
+    /// The board's count of samples of this sensor, which skips if any were
+    /// dropped on the way.
+    Count,
+    X,
+    Y,
+    Z,
+    /// g, gauss or dps. Left out of columnar exports, whose header gives
+    /// each sensor's unit.
+    Unit,
+}
+
+impl Column {
+    pub const DEFAULT: &'static [Column] =
+        &[Column::Device, Column::Sensor, Column::Time, Column::X, Column::Y, Column::Z, Column::Unit];
+
+    /// Comma separated column names, in the order to export them.
+    pub fn parse_list(s: &str) -> Result<Vec<Column>> {
+        s.split(',').map(str::parse).collect()
+    }
+
+    fn name(self, time_base: TimeBase) -> &'static str {
+        match (self, time_base) {
+            (Column::Device, _) => "device",
+            (Column::Sensor, _) => "sensor",
+            (Column::Time, TimeBase::Board) => "board_time_us",
+            (Column::Time, TimeBase::Host) => "host_time_us",
+            (Column::Count, _) => "count",
+            (Column::X, _) => "x",
+            (Column::Y, _) => "y",
+            (Column::Z, _) => "z",
+            (Column::Unit, _) => "unit",
+        }
+    }
+
+    // numpy dtype and size of the column in columnar exports
+    fn dtype(self) -> Option<(&'static str, usize)> {
+        match self {
+            Column::Device => Some(("<u2", 2)),
+            Column::Sensor => Some(("|u1", 1)),
+            Column::Time => Some(("<u8", 8)),
+            Column::Count => Some(("<u4", 4)),
+            Column::X | Column::Y | Column::Z => Some(("<f4", 4)),
+            Column::Unit => None,
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = CompError;
+
+    fn from_str(s: &str) -> Result<Column> {
+        match s {
+            "device" => Ok(Column::Device),
+            "sensor" => Ok(Column::Sensor),
+            "time" => Ok(Column::Time),
+            "count" => Ok(Column::Count),
+            "x" => Ok(Column::X),
+            "y" => Ok(Column::Y),
+            "z" => Ok(Column::Z),
+            "unit" => Ok(Column::Unit),
+            _ => Err(CompError::BadExport(format!("unknown column {}", s))),
+        }
+    }
+}
+
+/// Which clock the time column is by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBase {
+    /// When the board took the sample, since it booted.
+    Board,
+    /// When the sample reached the host, since the session started.
+    Host,
+}
+
+impl FromStr for TimeBase {
+    type Err = CompError;
+
+    fn from_str(s: &str) -> Result<TimeBase> {
+        match s {
+            "board" => Ok(TimeBase::Board),
+            "host" => Ok(TimeBase::Host),
+            _ => Err(CompError::BadExport(format!("unknown time base {}, try board or host", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportOptions {
+    pub columns: Vec<Column>,
+    pub time_base: TimeBase,
+    /// Keep one in this many samples of each sensor from each board.
+    pub decimate: usize,
+    /// Skip samples from any other sensors.
+    pub sensors: Sensors,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions { columns: Column::DEFAULT.to_vec(), time_base: TimeBase::Board, decimate: 1, sensors: Sensors::ALL }
+    }
+}
+
+struct Row {
+    device: Uid,
+    // Index into `SENSORS`
+    sensor: usize,
+    time_us: u64,
+    count: u32,
+    xyz: [f32; 3],
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Field {
+    Text(String),
+    Int(u64),
+    Float(f32),
+}
+
+impl Field {
+    fn csv(&self) -> String {
+        match self {
+            Field::Text(s) => s.clone(),
+            Field::Int(n) => n.to_string(),
+            Field::Float(x) => x.to_string(),
+        }
+    }
+}
+
+/// Writes samples out in one of the export formats.
+pub struct Exporter<W: Write> {
+    format: ExportFormat,
+    options: ExportOptions,
+    out: W,
+    header_written: bool,
+    // Samples left to skip from each board's sensors, for decimating
+    skip: HashMap<(Uid, usize), usize>,
+    // Columnar exports are written whole by `finish`
+    rows: Vec<Row>,
+    exported: usize,
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(format: ExportFormat, options: ExportOptions, out: W) -> Exporter<W> {
+        Exporter { format, options, out, header_written: false, skip: HashMap::new(), rows: Vec::new(), exported: 0 }
+    }
+
+    /// Export `sample`, which reached the host `host_time_us` into the
+    /// session, unless the options leave it out. Returns whether it was
+    /// exported.
+    pub fn push(&mut self, host_time_us: u64, sample: &Sample) -> Result<bool> {
+        let (sensor, stamp, xyz) = match sample.msg {
+            Message::Accel(stamp, x, y, z) => (0, stamp, [x, y, z]),
+            Message::Mag(stamp, x, y, z) => (1, stamp, mag_gauss(x, y, z)),
+            Message::Gyro(stamp, x, y, z) => (2, stamp, [x, y, z]),
+            _ => return Ok(false),
+        };
+        if !self.options.sensors.contains(SENSORS[sensor].0) {
+            return Ok(false);
+        }
+        let skip = self.skip.entry((sample.device, sensor)).or_insert(0);
+        if *skip > 0 {
+            *skip -= 1;
+            return Ok(false);
+        }
+        *skip = self.options.decimate.max(1) - 1;
+        let time_us = match self.options.time_base {
+            TimeBase::Board => stamp.time_us,
+            TimeBase::Host => host_time_us,
+        };
+        let row = Row { device: sample.device, sensor, time_us, count: stamp.count, xyz };
+        match self.format {
+            ExportFormat::Csv => self.write_csv(&row)?,
+            ExportFormat::Ndjson => self.write_ndjson(&row)?,
+            ExportFormat::Columnar => self.rows.push(row),
+        }
+        self.exported += 1;
+        Ok(true)
+    }
+
+    /// Export a sample from a recording, timed by when it was recorded.
+    pub fn push_frame(&mut self, frame: &Frame) -> Result<bool> {
+        self.push(frame.time_us, &Sample { device: frame.device, msg: frame.env.msg.clone() })
+    }
+
+    /// How many samples have been exported so far.
+    pub fn exported(&self) -> usize {
+        self.exported
+    }
+
+    /// Flush what's been written so far. Columnar exports have nothing to
+    /// show until `finish`.
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Write out anything held back and hand back the writer.
+    pub fn finish(mut self) -> Result<W> {
+        if self.format == ExportFormat::Columnar {
+            self.write_columnar()?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn field(&self, row: &Row, column: Column) -> Field {
+        let (_, name, unit) = SENSORS[row.sensor];
+        match column {
+            Column::Device => Field::Text(row.device.to_string()),
+            Column::Sensor => Field::Text(name.to_string()),
+            Column::Time => Field::Int(row.time_us),
+            Column::Count => Field::Int(row.count as u64),
+            Column::X => Field::Float(row.xyz[0]),
+            Column::Y => Field::Float(row.xyz[1]),
+            Column::Z => Field::Float(row.xyz[2]),
+            Column::Unit => Field::Text(unit.to_string()),
+        }
+    }
+
+    fn write_csv(&mut self, row: &Row) -> Result<()> {
+        let time_base = self.options.time_base;
+        if !self.header_written {
+            let names: Vec<_> = self.options.columns.iter().map(|column| column.name(time_base)).collect();
+            writeln!(self.out, "{}", names.join(","))?;
+            self.header_written = true;
+        }
+        // None of the fields can hold a comma, so there's nothing to quote
+        let fields: Vec<_> = self.options.columns.iter().map(|column| self.field(row, *column).csv()).collect();
+        writeln!(self.out, "{}", fields.join(","))?;
+        Ok(())
+    }
+
+    fn write_ndjson(&mut self, row: &Row) -> Result<()> {
+        let time_base = self.options.time_base;
+        let mut members = Vec::new();
+        for column in &self.options.columns {
+            let name = serde_json::to_string(column.name(time_base))?;
+            members.push(format!("{}:{}", name, serde_json::to_string(&self.field(row, *column))?));
+        }
+        writeln!(self.out, "{{{}}}", members.join(","))?;
+        Ok(())
+    }
+
+    fn write_columnar(&mut self) -> Result<()> {
+        let mut devices: Vec<Uid> = Vec::new();
+        for row in &self.rows {
+            if !devices.contains(&row.device) {
+                devices.push(row.device);
+            }
+        }
+        let columns: Vec<_> = self
+            .options
+            .columns
+            .iter()
+            .filter_map(|column| column.dtype().map(|(dtype, size)| (*column, dtype, size)))
+            .collect();
+        let time_base = self.options.time_base;
+        let rows = self.rows.len();
+        let header = |start: usize| {
+            let mut offset = start;
+            let columns: Vec<_> = columns
+                .iter()
+                .map(|(column, dtype, size)| {
+                    let entry = json!({ "name": column.name(time_base), "dtype": dtype, "offset": offset });
+                    offset += align(rows * size);
+                    entry
+                })
+                .collect();
+            let sensors: Vec<_> =
+                SENSORS.iter().map(|(_, name, unit)| json!({ "name": name, "unit": unit })).collect();
+            let devices: Vec<_> = devices.iter().map(Uid::to_string).collect();
+            json!({ "rows": rows, "devices": devices, "sensors": sensors, "columns": columns }).to_string()
+        };
+        // The offsets in the header depend on how long it is, so grow it
+        // until it fits in front of the columns it describes. A longer
+        // header only ever pushes them further out.
+        let mut start = align(COLUMNAR_PREFIX_SIZE);
+        let mut text = header(start);
+        while align(COLUMNAR_PREFIX_SIZE + text.len()) != start {
+            start = align(COLUMNAR_PREFIX_SIZE + text.len());
+            text = header(start);
+        }
+        let text = format!("{:<1$}", text, start - COLUMNAR_PREFIX_SIZE);
+
+        self.out.write_all(&COLUMNAR_MAGIC)?;
+        self.out.write_all(&(text.len() as u32).to_le_bytes())?;
+        self.out.write_all(text.as_bytes())?;
+        for (column, _, size) in columns {
+            let mut bytes = Vec::with_capacity(align(rows * size));
+            for row in &self.rows {
+                match column {
+                    Column::Device => {
+                        let index = devices.iter().position(|uid| *uid == row.device).unwrap_or(0);
+                        bytes.extend(&(index as u16).to_le_bytes())
+                    }
+                    Column::Sensor => bytes.push(row.sensor as u8),
+                    Column::Time => bytes.extend(&row.time_us.to_le_bytes()),
+                    Column::Count => bytes.extend(&row.count.to_le_bytes()),
+                    Column::X => bytes.extend(&row.xyz[0].to_le_bytes()),
+                    Column::Y => bytes.extend(&row.xyz[1].to_le_bytes()),
+                    Column::Z => bytes.extend(&row.xyz[2].to_le_bytes()),
+                    Column::Unit => (),
+                }
+            }
+            bytes.resize(align(bytes.len()), 0);
+            self.out.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn align(size: usize) -> usize {
+    size.div_ceil(COLUMNAR_ALIGN) * COLUMNAR_ALIGN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sensors::Stamp;
+
+    fn samples() -> Vec<Sample> {
+        let a = Uid([1, 2, 3]);
+        let b = Uid([4, 5, 6]);
+        vec![
+            Sample { device: a, msg: Message::Accel(Stamp::new(1000, 1), 0., 0.5, 1.) },
+            Sample { device: b, msg: Message::Accel(Stamp::new(2000, 1), 1., 0., 0.) },
+            Sample { device: a, msg: Message::Accel(Stamp::new(3000, 2), 0., 0.25, 1.) },
+            Sample { device: a, msg: Message::Gyro(Stamp::new(3500, 1), 0., 0., 90.) },
+            Sample { device: a, msg: Message::Accel(Stamp::new(5000, 3), 0., 0.125, 1.) },
+        ]
+    }
+
+    fn export(format: ExportFormat, options: ExportOptions) -> Vec<u8> {
+        let mut exporter = Exporter::new(format, options, Vec::new());
+        for (i, sample) in samples().iter().enumerate() {
+            exporter.push(10 * i as u64, sample).unwrap();
+        }
+        exporter.finish().unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        let csv = String::from_utf8(export(ExportFormat::Csv, ExportOptions::default())).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("device,sensor,board_time_us,x,y,z,unit"));
+        assert_eq!(lines.next(), Some(format!("{},accel,1000,0,0.5,1,g", Uid([1, 2, 3])).as_str()));
+        assert_eq!(lines.count(), 4);
+    }
+
+    #[test]
+    fn ndjson_options() {
+        let options = ExportOptions {
+            columns: Column::parse_list("time,sensor,y").unwrap(),
+            time_base: TimeBase::Host,
+            decimate: 2,
+            sensors: Sensors::ACCEL,
+        };
+        let ndjson = String::from_utf8(export(ExportFormat::Ndjson, options)).unwrap();
+        // Every other accel sample from each board
+        assert_eq!(
+            ndjson,
+            "{\"host_time_us\":0,\"sensor\":\"accel\",\"y\":0.5}\n\
+             {\"host_time_us\":10,\"sensor\":\"accel\",\"y\":0.0}\n\
+             {\"host_time_us\":40,\"sensor\":\"accel\",\"y\":0.125}\n"
+        );
+        assert!(Column::parse_list("x,w").is_err());
+    }
+
+    #[test]
+    fn columnar() {
+        let bytes = export(ExportFormat::Columnar, ExportOptions::default());
+        assert_eq!(bytes[..8], COLUMNAR_MAGIC);
+        let len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+        let start = COLUMNAR_PREFIX_SIZE + len;
+        assert_eq!(start % COLUMNAR_ALIGN, 0);
+        let header: serde_json::Value = serde_json::from_slice(&bytes[COLUMNAR_PREFIX_SIZE..start]).unwrap();
+        assert_eq!(header["rows"], 5);
+        assert_eq!(header["devices"][1], Uid([4, 5, 6]).to_string());
+        assert_eq!(header["sensors"][2]["unit"], "dps");
+
+        let columns = header["columns"].as_array().unwrap();
+        let names: Vec<_> = columns.iter().map(|column| column["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["device", "sensor", "board_time_us", "x", "y", "z"]);
+        let offset = |i: usize| columns[i]["offset"].as_u64().unwrap() as usize;
+        assert_eq!(offset(0), start);
+        assert!(columns.iter().all(|column| column["offset"].as_u64().unwrap() % 8 == 0));
+        assert_eq!(bytes[offset(0)..offset(0) + 10], [0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes[offset(1)..offset(1) + 5], [0, 0, 0, 2, 0]);
+        let z = offset(5) + 3 * 4;
+        assert_eq!(f32::from_le_bytes([bytes[z], bytes[z + 1], bytes[z + 2], bytes[z + 3]]), 90.);
+        assert_eq!(bytes.len(), offset(5) + 24);
+    }
+}
@@ -0,0 +1,151 @@
+//! Streaming from several boards at once.
+//!
+//! Each board gets its own thread, so a board that is slow to answer or
+//! gets unplugged doesn't hold up the others. Samples from all of them come
+//! out of one channel, tagged with the UID of the board that took them.
+
+use crate::board::{Board, LinkState};
+use common::device::Uid;
+use common::{Message, Sensors};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Resubscribe if a stream goes quiet, e.g. after the board was replugged
+const STALE_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_PERIOD: Duration = Duration::from_secs(1);
+// How often to look for link events between samples
+const EVENT_POLL: Duration = Duration::from_millis(100);
+
+/// A sample pushed by one of the boards.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub device: Uid,
+    pub msg: Message,
+}
+
+/// Samples from every board of a `stream_all`, as they arrive.
+pub struct Fleet {
+    rx: Receiver<Sample>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Iterator for Fleet {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        self.rx.recv().ok()
+    }
+}
+
+// A board that never answers leaves its thread nothing to send, so it has
+// to be told
+impl Drop for Fleet {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Stream `sensors` from every board at `rate_hz`, or as close to it as
+/// each one allows. Boards without some of the sensors stream the rest.
+/// Each board is greeted and subscribed to again whenever its link comes
+/// back, until the returned `Fleet` is dropped.
+pub fn stream_all(boards: Vec<Board>, sensors: Sensors, rate_hz: u16) -> Fleet {
+    let (tx, rx) = channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    for board in boards {
+        let tx = tx.clone();
+        let stop = stop.clone();
+        thread::spawn(move || stream(board, sensors, rate_hz, tx, &stop));
+    }
+    Fleet { rx, stop }
+}
+
+fn stream(board: Board, sensors: Sensors, rate_hz: u16, tx: Sender<Sample>, stop: &AtomicBool) {
+    let events = board.link_events();
+    while !stop.load(Ordering::Relaxed) {
+        let info = match board.hello() {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("No answer to hello: {}", e);
+                thread::sleep(RETRY_PERIOD);
+                continue;
+            }
+        };
+        let samples = match board.subscribe(sensors & info.sensors, info.clamp_rate(rate_hz)) {
+            Ok(samples) => samples,
+            Err(e) => {
+                warn!("Failed to subscribe to {}: {}", info.uid, e);
+                thread::sleep(RETRY_PERIOD);
+                continue;
+            }
+        };
+        info!("Streaming from {} at {} Hz", info.uid, samples.rate_hz());
+        let mut last = Instant::now();
+        while !reconnected(&events) {
+            match samples.recv_timeout(EVENT_POLL) {
+                Ok(Some(msg)) => {
+                    last = Instant::now();
+                    if tx.send(Sample { device: info.uid, msg }).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) if last.elapsed() < STALE_TIMEOUT => (),
+                _ => break,
+            }
+        }
+    }
+}
+
+// The board behind the link may have been reset, or swapped for another
+fn reconnected(events: &Receiver<LinkState>) -> bool {
+    events.try_iter().any(|state| state == LinkState::Connected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::mock_board;
+    use std::collections::HashSet;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    #[test]
+    fn tags_samples_by_device() {
+        let boards = vec![mock_board(), mock_board()];
+        let uids: HashSet<Uid> = boards.iter().map(|board| board.hello().unwrap().uid).collect();
+        assert_eq!(uids.len(), 2);
+
+        let samples = stream_all(boards, Sensors::MAG, 50);
+        let mut seen = HashSet::new();
+        for sample in samples.take(100) {
+            assert!(matches!(sample.msg, Message::Mag(..)));
+            assert!(uids.contains(&sample.device));
+            seen.insert(sample.device);
+            if seen == uids {
+                break;
+            }
+        }
+        assert_eq!(seen, uids);
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        // A board that never answers, so `hello` keeps failing
+        let (to_board, requests) = channel();
+        let (_replies, from_board) = channel();
+        let samples = stream_all(vec![Board::spawn(to_board, from_board)], Sensors::MAG, 50);
+        assert!(matches!(requests.recv().unwrap().msg, Message::Hello));
+        drop(samples);
+        // The thread drops the board on its way out, which closes the link
+        let deadline = Instant::now() + 4 * RETRY_PERIOD;
+        while Instant::now() < deadline {
+            if let Err(RecvTimeoutError::Disconnected) = requests.recv_timeout(EVENT_POLL) {
+                return;
+            }
+        }
+        panic!("Still retrying after the stream was dropped");
+    }
+}
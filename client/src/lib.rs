@@ -0,0 +1,17 @@
+pub mod board;
+pub mod calibration;
+pub mod clock;
+pub mod config;
+pub mod daemon;
+mod error;
+pub mod export;
+pub mod fleet;
+pub mod link;
+pub mod orientation;
+pub mod record;
+pub mod symbols;
+pub mod tracker;
+pub mod transport;
+pub mod update;
+
+pub use error::{CompError, Result};
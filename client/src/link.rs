@@ -0,0 +1,159 @@
+use common::{
+    device::DeviceInfo,
+    message::{Envelope, Message},
+    link::{Link, LinkError, MAX_FRAME_SIZE},
+};
+use crate::tracker::{DEFAULT_RETRIES, DEFAULT_TIMEOUT};
+use crate::transport::Transport;
+use crate::{CompError, Result};
+use log::trace;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
+
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+fn link_read<T: Transport + ?Sized>(transport: &mut T, link: &mut Link) -> Result<Vec<Envelope>> {
+    let mut buf = [0u8; Message::MAX_SIZE];
+    let read = transport.read(&mut buf, READ_TIMEOUT)?;
+    decode_all(link, &buf[..read])
+}
+
+/// Every envelope completed by `bytes`. Partial frames are kept in `link`
+/// for the next call.
+pub(crate) fn decode_all(link: &mut Link, bytes: &[u8]) -> Result<Vec<Envelope>> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (size, rx) = match link.decode(&bytes[offset..]) {
+            Ok(res) => res,
+            Err(LinkError::Checksum { read }) => {
+                log::warn!("Dropped frame with bad checksum");
+                (read, None)
+            }
+            Err(e) => match e.read() {
+                // The bad frame is skipped: the rest may still hold messages
+                Some(read) => {
+                    log::warn!("Dropped bad frame: {:?}", e);
+                    (read, None)
+                }
+                None => Err(e)?,
+            },
+        };
+        if let Some(env) = rx {
+            messages.push(env);
+        }
+        offset += size;
+    }
+    Ok(messages)
+}
+
+fn link_write<T: Transport + ?Sized>(transport: &mut T, env: Envelope, link: &mut Link) -> Result<()> {
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    let size = link.encode(&env, &mut buf)?;
+    let write_size = transport.write(&buf[..size])?;
+    if size > write_size {
+        log::warn!("Partial write!");
+    }
+    Ok(())
+}
+
+/// Greet a board that was just opened, sending `Hello` again as a request
+/// would be until it answers. Anything else it sends first goes on to
+/// `from_board`.
+pub(crate) fn hello<T: Transport + ?Sized>(
+    transport: &mut T,
+    link: &mut Link,
+    from_board: &Sender<Envelope>,
+) -> Result<DeviceInfo> {
+    // Under the seq nothing is ever asked under, so a late answer to an
+    // earlier attempt can't be taken for the reply to a request
+    let hello = Envelope::new(Envelope::UNSOLICITED, Message::Hello);
+    for _ in 0..=DEFAULT_RETRIES {
+        link_write(transport, hello.clone(), link)?;
+        let deadline = Instant::now() + DEFAULT_TIMEOUT;
+        while Instant::now() < deadline {
+            for env in link_read(transport, link)? {
+                match env.msg {
+                    Message::HelloAck(info) if env.seq == hello.seq => return Ok(info),
+                    _ => from_board.send(env)?,
+                }
+            }
+        }
+    }
+    Err(CompError::Timeout)
+}
+
+/// Shuttle messages between the channels and the board until either side
+/// goes away. The transport's read timeout paces the loop.
+pub fn usb_link<T: Transport + ?Sized>(
+    to_board: &mut Receiver<Envelope>,
+    from_board: &Sender<Envelope>,
+    transport: &mut T,
+) -> Result<()> {
+    shuttle(to_board, from_board, transport, &mut Link::new())
+}
+
+/// `usb_link` through `link`, which may hold the start of a frame already.
+pub(crate) fn shuttle<T: Transport + ?Sized>(
+    to_board: &mut Receiver<Envelope>,
+    from_board: &Sender<Envelope>,
+    transport: &mut T,
+    link: &mut Link,
+) -> Result<()> {
+    trace!("Starting usb link loop");
+    loop {
+        for env in link_read(transport, link)? {
+            log::trace!("Received {:?}", env);
+            from_board.send(env)?;
+        }
+
+        loop {
+            match to_board.try_recv() {
+                Ok(env) => link_write(transport, env, link)?,
+                Err(TryRecvError::Empty) => break,
+                Err(e) => Err(e)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Loopback;
+    use mock::MockBoard;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn mock_board_round_trip() {
+        let (mut host, mut device) = Loopback::pair();
+        thread::spawn(move || {
+            let mut board = MockBoard::new();
+            board.sample();
+            let mut buf = [0u8; Message::MAX_SIZE];
+            while let Ok(read) = device.read(&mut buf, READ_TIMEOUT) {
+                let reply = board.exchange(&buf[..read]);
+                if !reply.is_empty() && device.write(&reply).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (to_board_tx, mut to_board_rx) = channel();
+        let (from_board_tx, from_board_rx) = channel();
+        thread::spawn(move || {
+            let _ = usb_link(&mut to_board_rx, &from_board_tx, &mut host);
+        });
+
+        to_board_tx.send(Envelope::new(1, Message::Hello)).unwrap();
+        to_board_tx.send(Envelope::new(2, Message::AccelReq)).unwrap();
+        let timeout = Duration::from_secs(1);
+        let reply = from_board_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(reply.seq, 1);
+        assert!(matches!(reply.msg, Message::HelloAck(_)));
+        let reply = from_board_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(reply.seq, 2);
+        assert!(matches!(reply.msg, Message::Accel(_, x, y, z) if (x, y, z) == (0., 0., 1.)));
+    }
+}
@@ -1,251 +1,232 @@
-use common::{
-    message::Message,
-    link::Link,
-    usb::{VENDOR_ID, PROD_ID},
-};
-use log::{trace, info};
-use rusb::{Device, DeviceHandle, UsbContext, Error as UsbError};
-use std::time::Duration;
-use std::thread::sleep;
-use std::sync::{Arc, mpsc::{channel, Sender, Receiver, TryRecvError}, Mutex};
-use bevy::{pbr::AmbientLight, prelude::*};
-
-mod error;
-
-pub use error::{CompError, Result};
+mod cli;
+
+use cli::{calibrate::Calibrate, commands, output::{Format, Output}, view};
+use client::export::{Column, ExportFormat, ExportOptions, Exporter, TimeBase};
+use client::symbols::{Symbolizer, DEFAULT_ADDR2LINE};
+use client::{record::Recorder, transport::Endpoint, CompError, Result};
+use common::Sensors;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(about = "Talk to usb-compass boards")]
+struct Cli {
+    /// Board to talk to: usb, usb:<serial>, tty:<path>, tcp:<host>:<port>,
+    /// replay:<file>[#<uid>][@<speed>], or all for every board plugged in
+    /// over USB
+    #[structopt(short, long, default_value = "usb", global = true)]
+    board: String,
+    /// Print records as text, json (one object per line) or csv
+    #[structopt(short, long, default_value = "text", global = true)]
+    format: Format,
+    #[structopt(subcommand)]
+    command: Command,
+}
 
-const WRITE_TIMEOUT: Duration = Duration::from_millis(10);
-const READ_TIMEOUT: Duration = Duration::from_millis(10);
-const WRITE_ADDR: u8 = 2;
-const READ_ADDR: u8 = 130;
-const DESIRED_CONFIG: u8 = 1;
-const SERIAL_DATA_INTERFACE: u8 = 1;
+#[derive(StructOpt)]
+enum Command {
+    /// List the boards plugged in over USB
+    List,
+    /// Say hello and show what each board is
+    Info,
+    /// Show how each board's last boot ended, if it crashed
+    Crash {
+        /// The firmware's ELF, to look up where a fault was taken
+        #[structopt(long)]
+        elf: Option<PathBuf>,
+        /// The addr2line to look it up with
+        #[structopt(long, default_value = DEFAULT_ADDR2LINE)]
+        addr2line: String,
+    },
+    /// Show how many messages each board has dropped for a slow host
+    Stats,
+    /// Print a few readings and exit
+    Read {
+        /// accel, mag, gyro or all, or several separated by commas
+        #[structopt(parse(try_from_str = parse_sensors))]
+        sensors: Sensors,
+        /// Readings of each sensor to print
+        #[structopt(short, long, default_value = "1")]
+        count: usize,
+        #[structopt(short, long, default_value = "20")]
+        rate: u16,
+    },
+    /// Print readings as they arrive until interrupted
+    Stream {
+        #[structopt(default_value = "all", parse(try_from_str = parse_sensors))]
+        sensors: Sensors,
+        #[structopt(short, long, default_value = "20")]
+        rate: u16,
+        /// Also record the session to this file, for replay:<file>
+        #[structopt(long)]
+        record: Option<PathBuf>,
+    },
+    /// Export readings for analysis, live or from a recording
+    Export {
+        #[structopt(default_value = "all", parse(try_from_str = parse_sensors))]
+        sensors: Sensors,
+        /// File to write, or - for stdout
+        #[structopt(short, long, default_value = "-")]
+        output: String,
+        /// csv, ndjson, or columnar for numpy to memory-map
+        #[structopt(long, default_value = "csv")]
+        to: ExportFormat,
+        /// Export this recording rather than streaming from the boards
+        #[structopt(long)]
+        from: Option<PathBuf>,
+        /// Columns to export, separated by commas, out of device, sensor,
+        /// time, count, x, y, z and unit
+        #[structopt(long, default_value = "device,sensor,time,x,y,z,unit")]
+        columns: String,
+        /// Time samples by the board's clock or by when they reached the
+        /// host: board or host
+        #[structopt(long, default_value = "board")]
+        time: TimeBase,
+        /// Keep one in this many samples of each sensor from each board
+        #[structopt(long, default_value = "1")]
+        decimate: usize,
+        /// Stop after exporting this many samples
+        #[structopt(long)]
+        samples: Option<usize>,
+        #[structopt(short, long, default_value = "20")]
+        rate: u16,
+    },
+    /// Calibrate the mag or accel sensor, following the prompts
+    Calibrate {
+        which: Calibrate,
+    },
+    /// Install new firmware through the board's bootloader
+    Flash {
+        /// The firmware as a raw binary, from `objcopy -O binary`
+        image: PathBuf,
+    },
+    /// Trace every message to and from one board
+    Monitor {
+        /// Also subscribe to these sensors
+        #[structopt(long, parse(try_from_str = parse_sensors))]
+        subscribe: Option<Sensors>,
+        #[structopt(short, long, default_value = "20")]
+        rate: u16,
+    },
+    /// Share one board with other local processes
+    Daemon {
+        /// Where to serve the board's own protocol, for -b tcp:<addr>
+        #[structopt(long, default_value = "127.0.0.1:7170")]
+        tcp: String,
+        /// Where to serve envelopes as JSON over WebSocket
+        #[structopt(long, default_value = "127.0.0.1:7171")]
+        websocket: String,
+    },
+    /// Show each board's attitude and heading in a window
+    View {
+        /// Local magnetic declination in degrees, positive east, to show
+        /// true rather than magnetic heading
+        #[structopt(short, long, default_value = "0", allow_hyphen_values = true)]
+        declination: f32,
+        /// Also record the session to this file, for replay:<file>
+        #[structopt(long)]
+        record: Option<PathBuf>,
+    },
+}
 
-fn print_device_info<T: UsbContext>(device: Device<T>) -> Result<()> {
-    let number_configs = device.device_descriptor()?.num_configurations();
-    let active_config_descriptor = device.active_config_descriptor()?;
-    let active_number = active_config_descriptor.number();
-    info!("Device has {} configurations:", number_configs);
-    for i in 0..number_configs {
-        let config = device.config_descriptor(i)?;
-        let config_number = config.number();
-        let active_flag = if active_number == config_number {
-            "*"
-        } else {
-            ""
-        };
-        trace!("\tConfig {}{}:", config_number, active_flag);
-        trace!("\tInterfaces {}:", config.num_interfaces());
-        for interface in config.interfaces() {
-            trace!("\t\tInterface no {}:", interface.number());
-            for desc in interface.descriptors() {
-                trace!("\t\t\tDescriptor no {}", desc.interface_number());
-                trace!("\t\t\tEndpoints {}:", desc.num_endpoints());
-                for endpoint in desc.endpoint_descriptors() {
-                    trace!("\t\t\t\t#{} @{} dir:{:?} type:{:?}",
-                        endpoint.number(),
-                        endpoint.address(),
-                        endpoint.direction(),
-                        endpoint.transfer_type());
-                }
-            }
-        }
-    }
-    Ok(())
+fn parse_sensors(s: &str) -> std::result::Result<Sensors, String> {
+    s.split(',').try_fold(Sensors::NONE, |sensors, name| {
+        Ok(sensors | match name {
+            "accel" => Sensors::ACCEL,
+            "mag" => Sensors::MAG,
+            "gyro" => Sensors::GYRO,
+            "all" => Sensors::ALL,
+            _ => return Err(format!("unknown sensor {}", name)),
+        })
+    })
 }
 
-fn usb_read<T: UsbContext>(handle: &mut DeviceHandle<T>, link: &mut Link) -> Result<Vec<Message>> {
-    let mut buf = [0u8; Message::MAX_SIZE];
-    let mut messages = Vec::new();
-    let read = match handle.read_bulk(READ_ADDR, &mut buf, READ_TIMEOUT) {
-        Ok(read) => read,
-        Err(UsbError::Timeout) => return Ok(messages),
-        Err(e) => Err(e)?,
+fn endpoints(board: &str) -> Result<Vec<Endpoint>> {
+    let endpoints = if board == "all" {
+        Endpoint::all_usb()?
+    } else {
+        vec![board.parse()?]
     };
-    let mut offset = 0;
-    loop {
-        let (size, rx) = link.decode(&buf[offset..read])?;
-        if size == 0 {
-            break;
-        }
-        if let Some(msg) = rx {
-            messages.push(msg);
-        }
-        offset += size;
+    if endpoints.is_empty() {
+        return Err(CompError::NotFound);
     }
-    Ok(messages)
+    Ok(endpoints)
 }
 
-fn usb_write<T: UsbContext>(handle: &mut DeviceHandle<T>, msg: Message, link: &mut Link) -> Result<()> {
-    let mut buf = [0u8; Message::MAX_SIZE];
-    let size = link.encode(&msg, &mut buf)?;
-    let write_size = handle.write_bulk(WRITE_ADDR, &buf[..size], WRITE_TIMEOUT)?;
-    if size > write_size {
-        log::warn!("Partial usb write!");
-    }
-    Ok(())
+fn recorder(path: Option<PathBuf>) -> Result<Option<Recorder>> {
+    path.map(|path| Recorder::create(&path)).transpose()
 }
 
-fn usb_link<T: UsbContext>(
-    to_board: &mut Receiver<Message>,
-    from_board: &Sender<Message>,
-    mut handle: DeviceHandle<T>,
-) -> Result<()> {
-    let mut link = Link::new();
-    trace!("Starting usb link loop");
-    loop {
-        for msg in usb_read(&mut handle, &mut link)? {
-            log::trace!("Received {:?}", msg);
-            from_board.send(msg)?;
+fn run(cli: Cli) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = Output::new(cli.format, stdout.lock());
+    match cli.command {
+        Command::List => commands::list(&mut out),
+        Command::Info => commands::info(&endpoints(&cli.board)?, &mut out),
+        Command::Crash { elf, addr2line } => {
+            let symbolizer = elf.map(|elf| Symbolizer::new(&addr2line, &elf));
+            commands::crash(&endpoints(&cli.board)?, symbolizer.as_ref(), &mut out)
         }
-
-        loop {
-            match to_board.try_recv() {
-                Ok(msg) => usb_write(&mut handle, msg, &mut link)?,
-                Err(TryRecvError::Empty) => break,
-                Err(e) => Err(e)?,
-            }
+        Command::Stats => commands::stats(&endpoints(&cli.board)?, &mut out),
+        Command::Read { sensors, count, rate } => commands::read(&endpoints(&cli.board)?, sensors, count, rate, &mut out),
+        Command::Stream { sensors, rate, record } => {
+            commands::stream(&endpoints(&cli.board)?, sensors, rate, recorder(record)?, &mut out)
         }
-        sleep(Duration::from_millis(50));
-    }
-}
-
-fn usb_configure<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
-    if DESIRED_CONFIG != handle.active_configuration()? {
-        handle.set_active_configuration(DESIRED_CONFIG)?;
-    }
-
-    print_device_info(handle.device()).unwrap();
-    if handle.kernel_driver_active(SERIAL_DATA_INTERFACE)? {
-        handle.detach_kernel_driver(SERIAL_DATA_INTERFACE)?;
-    }
-
-    handle.claim_interface(SERIAL_DATA_INTERFACE)?;
-    Ok(())
-}
-
-fn usb(mut to_board_rx: Receiver<Message>, from_board_tx: Sender<Message>) -> Result<()> {
-    let mut sleep_time = 1;
-    loop {
-        sleep(Duration::from_secs(sleep_time));
-        if let Some(mut handle) = rusb::open_device_with_vid_pid(VENDOR_ID, PROD_ID) {
-            if let Err(e) = usb_configure(&mut handle) {
-                error!("Failed to configure usb device! {}", e);
-                sleep_time = 10;
-                continue;
+        Command::Export { sensors, output, to, from, columns, time, decimate, samples, rate } => {
+            // Columnar exports are only written once the last sample is in
+            if to == ExportFormat::Columnar && from.is_none() && samples.is_none() {
+                return Err(CompError::BadExport("a live columnar export needs --samples".to_string()));
             }
-            usb_link(&mut to_board_rx, &from_board_tx, handle)?;
-        }
-    }
-}
-
-
-fn chatter(to_board_tx: Sender<Message>, from_board_rx: Receiver<Message>, accel: Arc<Mutex<(f32, f32, f32)>>) {
-    std::thread::spawn(move || {
-        trace!("Starting receiver loop");
-        loop {
-            match from_board_rx.recv() {
-                Ok(msg) => {
-                    trace!("Board said: {:?}", msg);
-                    if let Message::Accel(x, y, z) = msg {
-                        let mut data = accel.lock().unwrap();
-                        data.0 = x;
-                        data.1 = y;
-                        data.2 = z;
-                    }
-
-                }
-                Err(e) => error!("{:?}", e),
+            let options = ExportOptions { columns: Column::parse_list(&columns)?, time_base: time, decimate, sensors };
+            let out: Box<dyn Write> = if output == "-" {
+                Box::new(std::io::stdout())
+            } else {
+                Box::new(BufWriter::new(File::create(&output)?))
+            };
+            let exporter = Exporter::new(to, options, out);
+            match from {
+                Some(path) => commands::export_recording(&path, exporter, samples),
+                None => commands::export_live(&endpoints(&cli.board)?, sensors, rate, exporter, samples),
             }
         }
-    });
-    trace!("Starting chatter loop");
-    loop {
-        sleep(Duration::from_millis(500));
-        let msg = Message::MagReq;
-        to_board_tx.send(msg).unwrap();
-        let msg = Message::AccelReq;
-        to_board_tx.send(msg).unwrap();
+        Command::Calibrate { which } => commands::calibrate(&endpoints(&cli.board)?, which),
+        Command::Flash { image } => commands::flash(&endpoints(&cli.board)?, &image),
+        Command::Monitor { subscribe, rate } => {
+            // Interleaving several boards' traces would be unreadable
+            let endpoint = endpoints(&cli.board)?.remove(0);
+            commands::monitor(&endpoint, subscribe.map(|sensors| (sensors, rate)), &mut out)
+        }
+        Command::Daemon { tcp, websocket } => {
+            // Sharing several boards would take a daemon each
+            let endpoint = endpoints(&cli.board)?.remove(0);
+            commands::daemon(endpoint, &tcp, &websocket)
+        }
+        Command::View { declination, record } => {
+            view::run(endpoints(&cli.board)?, declination, recorder(record)?);
+            Ok(())
+        }
     }
 }
 
-
 fn main() {
     env_logger::init();
-    let (to_board_tx, to_board_rx) = channel();
-    let (from_board_tx, from_board_rx) = channel();
-    std::thread::spawn( move || {
-        usb(to_board_rx, from_board_tx).unwrap();
-    });
-    let accel = Arc::new(Mutex::new((0., 0., 0.)));
-    let accel_clone = accel.clone();
-    std::thread::spawn( move || {
-        chatter(to_board_tx, from_board_rx, accel_clone);
-    });
-    App::build()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(HelloPlugin)
-        .insert_resource(AccelData(accel))
-        .add_system(accel_system.system())
-        .run();
-}
-
-pub struct HelloPlugin;
-
-impl Plugin for HelloPlugin {
-    fn build(&self, app: &mut AppBuilder) {
-        app.insert_resource(AmbientLight {
-                color: Color::WHITE,
-                brightness: 1.0 / 5.0f32,
-            })
-            .add_startup_system(setup_scene.system())
-            .add_system(rotator_system.system());
-    }
-}
-
-fn setup_scene(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) {
-    // commands.spawn_bundle( PbrBundle {
-    //     mesh: meshes.add(Mesh::from(shape::Box::new(4.0, 0.5, 2.0))),
-    //     material: materials.add(Color::rgb(0.3, 0.5, 0.5).into()),
-    //     transform: Transform::from_xyz(0.0, 0.5, 0.0),
-    //     ..Default::default()
-    // }).insert(Rotates);
-    commands.spawn_bundle( PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Cube { size: 0.2 })),
-        material: materials.add(Color::rgb(0.3, 0.0, 0.0).into()),
-        transform: Transform::from_xyz(0.0, 0.5, 0.0),
-        ..Default::default()
-    }).insert(AccelVector);
-    commands.spawn_bundle( LightBundle {
-        transform: Transform::from_xyz(1.0, 1.0, 1.0),
-        ..Default::default()
-    });
-    commands.spawn_bundle( PerspectiveCameraBundle {
-        transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ..Default::default()
-    });
-
-}
-
-struct AccelData(Arc<Mutex<(f32, f32, f32)>>);
-
-struct AccelVector;
-
-fn accel_system(accel_data: Res<AccelData>, mut query: Query<&mut Transform, With<AccelVector>>) {
-    let (x, y, z) = accel_data.0.lock().unwrap().clone();
-    for mut transform in query.iter_mut() {
-        *transform = Transform::from_xyz(x, y, z);
+    if let Err(e) = run(Cli::from_args()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
 
-struct Rotates;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn rotator_system(time: Res<Time>, mut query: Query<&mut Transform, With<Rotates>>) {
-    for mut transform in query.iter_mut() {
-        *transform = Transform::from_rotation(Quat::from_rotation_y((4.0 * std::f32::consts::PI / 20.0) * time.delta_seconds(),)) * *transform;
+    #[test]
+    fn sensors() {
+        assert_eq!(parse_sensors("mag").unwrap(), Sensors::MAG);
+        assert_eq!(parse_sensors("accel,gyro").unwrap(), Sensors::ACCEL | Sensors::GYRO);
+        assert_eq!(parse_sensors("all").unwrap(), Sensors::ALL);
+        assert!(parse_sensors("baro").is_err());
     }
 }
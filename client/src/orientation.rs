@@ -0,0 +1,130 @@
+use common::{
+    fusion::{Madgwick, Quaternion},
+    sensors::mag_gauss,
+    Message, Sensors, Stamp,
+};
+
+// Longest step the filter is allowed to take, so a gap in the stream (e.g. a
+// replug) doesn't fling the estimate around.
+const MAX_DT: f32 = 0.5;
+
+#[derive(Default)]
+struct Group {
+    stamp: Option<Stamp>,
+    received: Sensors,
+    accel: [f32; 3],
+    mag: Option<[f32; 3]>,
+    gyro: [f32; 3],
+}
+
+/// Turns a stream of `Accel`, `Mag` and `Gyro` samples into orientation
+/// estimates.
+///
+/// Samples that share a `Stamp::count` were read in the same cycle and are
+/// fed to the filter together, as soon as every expected sensor has reported
+/// or the next cycle starts.
+pub struct Orientation {
+    filter: Madgwick,
+    expected: Sensors,
+    group: Group,
+    last_time_us: Option<u64>,
+}
+
+impl Orientation {
+    /// Track orientation from a stream carrying `sensors`.
+    pub fn new(sensors: Sensors, filter: Madgwick) -> Orientation {
+        Orientation {
+            filter,
+            expected: sensors,
+            group: Group::default(),
+            last_time_us: None,
+        }
+    }
+
+    pub fn quaternion(&self) -> Quaternion {
+        self.filter.quaternion()
+    }
+
+    /// Feed one message from the board. Returns the new estimate whenever
+    /// the filter was updated.
+    pub fn feed(&mut self, msg: &Message) -> Option<Quaternion> {
+        let (stamp, sensor, data) = match *msg {
+            Message::Accel(stamp, x, y, z) => (stamp, Sensors::ACCEL, [x, y, z]),
+            Message::Mag(stamp, x, y, z) => (stamp, Sensors::MAG, mag_gauss(x, y, z)),
+            Message::Gyro(stamp, x, y, z) => {
+                (stamp, Sensors::GYRO, [x.to_radians(), y.to_radians(), z.to_radians()])
+            }
+            _ => return None,
+        };
+
+        let mut updated = None;
+        if let Some(current) = self.group.stamp {
+            if current.count != stamp.count {
+                updated = self.flush();
+            }
+        }
+
+        let group = &mut self.group;
+        if group.stamp.is_none_or(|s| stamp.time_us < s.time_us) {
+            group.stamp = Some(stamp);
+        }
+        group.received = group.received | sensor;
+        match sensor {
+            Sensors::ACCEL => group.accel = data,
+            Sensors::MAG => group.mag = Some(data),
+            _ => group.gyro = data,
+        }
+
+        if group.received.contains(self.expected) {
+            updated = self.flush();
+        }
+        updated
+    }
+
+    fn flush(&mut self) -> Option<Quaternion> {
+        let group = std::mem::take(&mut self.group);
+        let time_us = group.stamp?.time_us;
+        let dt = match self.last_time_us {
+            Some(last) => (time_us.saturating_sub(last) as f32 / 1e6).min(MAX_DT),
+            None => 0.,
+        };
+        self.last_time_us = Some(time_us);
+        self.filter.update(group.gyro, group.accel, group.mag, dt);
+        Some(self.filter.quaternion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_by_count() {
+        let mut orientation = Orientation::new(Sensors::ALL, Madgwick::new(1.0));
+        let stamp = Stamp::new(0, 1);
+        assert!(orientation.feed(&Message::Accel(stamp, 0., 0., 1.)).is_none());
+        assert!(orientation.feed(&Message::Gyro(stamp, 0., 0., 0.)).is_none());
+        assert!(orientation.feed(&Message::Mag(stamp, 400, 0, -300)).is_some());
+
+        // A cycle with a missing sensor is flushed when the next one starts
+        let stamp = Stamp::new(10_000, 2);
+        assert!(orientation.feed(&Message::Accel(stamp, 0., 0., 1.)).is_none());
+        let stamp = Stamp::new(20_000, 3);
+        assert!(orientation.feed(&Message::Accel(stamp, 0., 0., 1.)).is_some());
+        assert!(orientation.feed(&Message::Time(0)).is_none());
+    }
+
+    #[test]
+    fn follows_yaw() {
+        // Board lies flat with its -y axis pointing north, i.e. yawed 90°
+        let mut orientation = Orientation::new(Sensors::ACCEL | Sensors::MAG, Madgwick::new(1.0));
+        for count in 0..1_000 {
+            let stamp = Stamp::new(count as u64 * 10_000, count);
+            orientation.feed(&Message::Accel(stamp, 0., 0., 1.));
+            orientation.feed(&Message::Mag(stamp, 0, -400, -300));
+        }
+        let (roll, pitch, yaw) = orientation.quaternion().euler();
+        assert!(roll.abs() < 0.02 && pitch.abs() < 0.02);
+        assert!((yaw.to_degrees() - 90.).abs() < 1., "yaw {}", yaw.to_degrees());
+    }
+}
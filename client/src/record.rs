@@ -0,0 +1,210 @@
+//! Recording sessions to a file, to be replayed later through
+//! `transport::ReplayTransport`.
+//!
+//! A recording starts with `MAGIC`, followed by one frame for every envelope
+//! the board sent. Each frame is little-endian: the payload length as a
+//! `u32`, the host time in microseconds since recording started as a `u64`,
+//! the sending board's UID as three `u32`s, then the envelope in the same
+//! CBOR encoding the link uses.
+
+use crate::link::decode_all;
+use crate::transport::Transport;
+use crate::{CompError, Result};
+use common::device::Uid;
+use common::link::Link;
+use common::{Envelope, Message};
+use log::warn;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAGIC: [u8; 8] = *b"COMPREC1";
+const FRAME_HEADER_SIZE: usize = 4 + 8 + 12;
+
+/// One envelope from a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// When it arrived, in microseconds since recording started.
+    pub time_us: u64,
+    pub device: Uid,
+    pub env: Envelope,
+}
+
+struct Writer {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+/// Appends frames to a recording. Clones share the file, so several boards
+/// can be recorded into one.
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Arc<Mutex<Writer>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder> {
+        Recorder::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(mut out: Box<dyn Write + Send>) -> Result<Recorder> {
+        out.write_all(&MAGIC)?;
+        let writer = Writer { out, start: Instant::now() };
+        Ok(Recorder { writer: Arc::new(Mutex::new(writer)) })
+    }
+
+    pub fn record(&self, device: Uid, env: &Envelope) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let time_us = writer.start.elapsed().as_micros() as u64;
+        write_frame(&mut writer.out, &Frame { time_us, device, env: env.clone() })?;
+        // Keep what's recorded so far if the client is killed
+        writer.out.flush()?;
+        Ok(())
+    }
+}
+
+pub fn write_frame<W: Write + ?Sized>(out: &mut W, frame: &Frame) -> Result<()> {
+    let mut payload = [0u8; Message::MAX_SIZE];
+    let size = frame.env.write_bytes(&mut payload).map_err(|e| CompError::Recording(e.to_string()))?;
+    out.write_all(&(size as u32).to_le_bytes())?;
+    out.write_all(&frame.time_us.to_le_bytes())?;
+    for word in &frame.device.0 {
+        out.write_all(&word.to_le_bytes())?;
+    }
+    out.write_all(&payload[..size])?;
+    Ok(())
+}
+
+/// Every frame in a recording. A frame cut short at the end, as when the
+/// recording client was killed mid-write, is dropped.
+pub fn read_frames<R: Read>(mut input: R) -> Result<Vec<Frame>> {
+    let mut magic = [0u8; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(CompError::Recording("not a recording".to_string()));
+    }
+    let mut frames = Vec::new();
+    loop {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        match input.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let word = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let size = word(0) as usize;
+        if size > Message::MAX_SIZE {
+            return Err(CompError::Recording(format!("{} byte frame", size)));
+        }
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&header[4..12]);
+        let time_us = u64::from_le_bytes(time);
+        let device = Uid([word(12), word(16), word(20)]);
+        let mut payload = vec![0u8; size];
+        match input.read_exact(&mut payload) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let env = Envelope::from_bytes(&mut payload).map_err(|e| CompError::Recording(e.to_string()))?;
+        frames.push(Frame { time_us, device, env });
+    }
+    Ok(frames)
+}
+
+pub fn load(path: &Path) -> Result<Vec<Frame>> {
+    read_frames(BufReader::new(File::open(path)?))
+}
+
+/// Records every envelope read through the transport it wraps, tagged with
+/// the UID from the board's `HelloAck`.
+pub struct RecordingTransport<T> {
+    inner: T,
+    recorder: Recorder,
+    link: Link,
+    device: Uid,
+    failed: bool,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T, recorder: Recorder) -> RecordingTransport<T> {
+        RecordingTransport { inner, recorder, link: Link::new(), device: Uid::default(), failed: false }
+    }
+
+    fn record(&mut self, bytes: &[u8]) -> Result<()> {
+        for env in decode_all(&mut self.link, bytes)? {
+            if let Message::HelloAck(info) = env.msg {
+                self.device = info.uid;
+            }
+            self.recorder.record(self.device, &env)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let read = self.inner.read(buf, timeout)?;
+        // A broken recording shouldn't take the live session down with it
+        if let Err(e) = self.record(&buf[..read]) {
+            if !self.failed {
+                warn!("Recording failed: {}", e);
+                self.failed = true;
+            }
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sensors::Stamp;
+
+    // A `Write` the test can look into after handing it to a `Recorder`
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let out = Shared::default();
+        let recorder = Recorder::new(Box::new(out.clone())).unwrap();
+        let device = Uid([1, 2, 3]);
+        let envs = vec![
+            Envelope::new(1, Message::Hello),
+            Envelope::new(2, Message::Mag(Stamp::new(10, 1), 1, -2, 3)),
+            Envelope::new(2, Message::Accel(Stamp::new(20, 2), 0., 0., 1.)),
+        ];
+        for env in &envs {
+            recorder.record(device, env).unwrap();
+        }
+
+        let bytes = out.0.lock().unwrap().clone();
+        assert_eq!(bytes[..MAGIC.len()], MAGIC);
+        let frames = read_frames(&bytes[..]).unwrap();
+        assert_eq!(frames.iter().map(|f| f.env.clone()).collect::<Vec<_>>(), envs);
+        assert!(frames.iter().all(|f| f.device == device));
+        assert!(frames.windows(2).all(|w| w[0].time_us <= w[1].time_us));
+
+        // Cut short mid-frame
+        let frames = read_frames(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(read_frames(&b"NOTAREC!"[..]).is_err());
+    }
+}
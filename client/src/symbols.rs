@@ -0,0 +1,124 @@
+//! Source locations for addresses in the board's firmware, looked up in its
+//! ELF by binutils' `addr2line`.
+
+use crate::{CompError, Result};
+use common::crash::ExceptionFrame;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The `addr2line` from the ARM toolchain the firmware is built with.
+pub const DEFAULT_ADDR2LINE: &str = "arm-none-eabi-addr2line";
+
+/// Where an address falls in the firmware's source.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub address: u32,
+    /// `None` if the ELF has nothing for the address.
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x} in {}", self.address, self.function.as_deref().unwrap_or("??"))?;
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " at {}:{}", file, line),
+            (Some(file), None) => write!(f, " at {}", file),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Looks addresses up in the board's ELF.
+pub struct Symbolizer {
+    addr2line: String,
+    elf: PathBuf,
+}
+
+impl Symbolizer {
+    /// Look addresses up in `elf` with the `addr2line` program `addr2line`.
+    pub fn new(addr2line: &str, elf: &Path) -> Symbolizer {
+        Symbolizer { addr2line: addr2line.to_string(), elf: elf.to_path_buf() }
+    }
+
+    pub fn locate(&self, addresses: &[u32]) -> Result<Vec<Location>> {
+        let output = Command::new(&self.addr2line)
+            .arg("-e")
+            .arg(&self.elf)
+            .args(["-f", "-C"])
+            .args(addresses.iter().map(|address| format!("{:#x}", address)))
+            .output()
+            .map_err(|e| CompError::Symbols(format!("couldn't run {}: {}", self.addr2line, e)))?;
+        if !output.status.success() {
+            return Err(CompError::Symbols(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        parse(addresses, &String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Where a fault was taken, and the call the faulting function was
+    /// called from.
+    pub fn locate_frame(&self, frame: &ExceptionFrame) -> Result<(Location, Location)> {
+        // LR is the Thumb return address: step back into the call itself
+        let call = (frame.lr & !1).saturating_sub(1);
+        let mut locations = self.locate(&[frame.pc, call])?.into_iter();
+        match (locations.next(), locations.next()) {
+            (Some(pc), Some(lr)) => Ok((pc, lr)),
+            _ => unreachable!("parse gives a location per address"),
+        }
+    }
+}
+
+// `addr2line -f` prints two lines per address, the function and then
+// `file:line`, with `??` for what it doesn't know
+fn parse(addresses: &[u32], output: &str) -> Result<Vec<Location>> {
+    let known = |s: &str| if s.starts_with("??") { None } else { Some(s.to_string()) };
+    let mut lines = output.lines();
+    addresses
+        .iter()
+        .map(|&address| {
+            let (function, place) = match (lines.next(), lines.next()) {
+                (Some(function), Some(place)) => (function, place),
+                _ => return Err(CompError::Symbols("addr2line stopped short".to_string())),
+            };
+            // Drop the likes of " (discriminator 2)"
+            let place = place.split(" (").next().unwrap_or(place);
+            let (file, line) = place.rsplit_once(':').unwrap_or((place, "?"));
+            let line = line.parse().ok().filter(|line| *line != 0);
+            Ok(Location { address, function: known(function), file: known(file), line })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addr2line() {
+        let output = concat!(
+            "board::read_gyro\n",
+            "/home/someone/usb-compass/board/src/main.rs:345 (discriminator 1)\n",
+            "??\n",
+            "??:0\n",
+        );
+        let locations = parse(&[0x0800_1234, 0x0800_0100], output).unwrap();
+        assert_eq!(locations[0].function.as_deref(), Some("board::read_gyro"));
+        assert_eq!(locations[0].line, Some(345));
+        assert_eq!(
+            locations[0].to_string(),
+            "0x08001234 in board::read_gyro at /home/someone/usb-compass/board/src/main.rs:345"
+        );
+        assert_eq!(locations[1], Location { address: 0x0800_0100, function: None, file: None, line: None });
+        assert_eq!(locations[1].to_string(), "0x08000100 in ??");
+        assert!(parse(&[1, 2], "main\nsrc/main.rs:1\n").is_err());
+    }
+
+    #[test]
+    fn missing_addr2line() {
+        let symbolizer = Symbolizer::new("no-such-addr2line", Path::new("board.elf"));
+        assert!(matches!(symbolizer.locate(&[0]), Err(CompError::Symbols(_))));
+    }
+}
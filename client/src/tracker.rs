@@ -0,0 +1,255 @@
+use common::{Envelope, Message};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(250);
+pub const DEFAULT_RETRIES: u8 = 2;
+
+/// What a message from the board turned out to be.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// The answer to an outstanding request.
+    Reply {
+        seq: u16,
+        request: Box<Message>,
+        reply: Message,
+        rtt: Duration,
+    },
+    /// A sample pushed for an open subscription.
+    Stream(Envelope),
+    /// The board sent this on its own.
+    Unsolicited(Message),
+    /// Carries a sequence number we are not waiting on, e.g. a late reply
+    /// to a request that was already given up on.
+    Unmatched(Envelope),
+}
+
+/// Outcome of checking outstanding requests against their deadlines.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expired {
+    /// Requests that timed out but have attempts left; send these again.
+    /// Only idempotent requests are sent again: the others just get as long
+    /// to answer as all the attempts would have taken.
+    pub resend: Vec<Envelope>,
+    /// Requests that ran out of retries.
+    pub lost: Vec<Envelope>,
+}
+
+struct Pending {
+    msg: Message,
+    first_sent: Instant,
+    last_sent: Instant,
+    attempts: u8,
+}
+
+/// Hands out sequence numbers for requests and pairs the board's replies
+/// with them, retrying requests that go unanswered.
+pub struct RequestTracker {
+    next_seq: u16,
+    timeout: Duration,
+    retries: u8,
+    pending: HashMap<u16, Pending>,
+    streams: HashSet<u16>,
+}
+
+impl RequestTracker {
+    pub fn new(timeout: Duration, retries: u8) -> RequestTracker {
+        RequestTracker {
+            next_seq: 1,
+            timeout,
+            retries,
+            pending: HashMap::new(),
+            streams: HashSet::new(),
+        }
+    }
+
+    /// Register `msg` as sent at `now` and return the envelope to send.
+    pub fn request(&mut self, msg: Message, now: Instant) -> Envelope {
+        let seq = self.allocate();
+        self.pending.insert(seq, Pending {
+            msg: msg.clone(),
+            first_sent: now,
+            last_sent: now,
+            attempts: 1,
+        });
+        Envelope::new(seq, msg)
+    }
+
+    /// Like `request`, but the sequence number stays open after the reply
+    /// so that samples the board pushes under it come back as
+    /// `Event::Stream` until `close` is called.
+    pub fn subscribe(&mut self, msg: Message, now: Instant) -> Envelope {
+        let env = self.request(msg, now);
+        self.streams.insert(env.seq);
+        env
+    }
+
+    /// Stop treating `seq` as an open subscription.
+    pub fn close(&mut self, seq: u16) {
+        self.streams.remove(&seq);
+        self.pending.remove(&seq);
+    }
+
+    /// Classify an envelope received from the board at `now`.
+    pub fn receive(&mut self, env: Envelope, now: Instant) -> Event {
+        if env.seq == Envelope::UNSOLICITED {
+            return Event::Unsolicited(env.msg);
+        }
+        if self.streams.contains(&env.seq) && !matches!(env.msg, Message::SubscribeAck(_)) {
+            return Event::Stream(env);
+        }
+        match self.pending.remove(&env.seq) {
+            Some(pending) => Event::Reply {
+                seq: env.seq,
+                request: Box::new(pending.msg),
+                reply: env.msg,
+                rtt: now.saturating_duration_since(pending.first_sent),
+            },
+            None => Event::Unmatched(env),
+        }
+    }
+
+    /// Check outstanding requests at `now`. Retries keep their original
+    /// sequence number so a slow reply to an earlier attempt still matches.
+    pub fn expire(&mut self, now: Instant) -> Expired {
+        let mut expired = Expired::default();
+        let timeout = self.timeout;
+        let retries = self.retries;
+        self.pending.retain(|seq, pending| {
+            if now.saturating_duration_since(pending.last_sent) < timeout {
+                true
+            } else if pending.attempts <= retries {
+                pending.attempts += 1;
+                pending.last_sent = now;
+                if pending.msg.is_idempotent() {
+                    expired.resend.push(Envelope::new(*seq, pending.msg.clone()));
+                }
+                true
+            } else {
+                expired.lost.push(Envelope::new(*seq, pending.msg.clone()));
+                false
+            }
+        });
+        expired.resend.sort_by_key(|env| env.seq);
+        expired.lost.sort_by_key(|env| env.seq);
+        expired
+    }
+
+    /// Number of requests still waiting on a reply.
+    pub fn outstanding(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn allocate(&mut self) -> u16 {
+        loop {
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            if seq != Envelope::UNSOLICITED && !self.pending.contains_key(&seq) && !self.streams.contains(&seq) {
+                return seq;
+            }
+        }
+    }
+}
+
+impl Default for RequestTracker {
+    fn default() -> RequestTracker {
+        RequestTracker::new(DEFAULT_TIMEOUT, DEFAULT_RETRIES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Sensors, Stamp};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn reply_matches_request() {
+        let mut tracker = RequestTracker::new(TIMEOUT, 0);
+        let start = Instant::now();
+        let accel = tracker.request(Message::AccelReq, start);
+        let mag = tracker.request(Message::MagReq, start);
+        assert_ne!(accel.seq, mag.seq);
+
+        let later = start + Duration::from_millis(5);
+        let event = tracker.receive(mag.reply(Message::Mag(Stamp::default(), 1, 2, 3)), later);
+        assert_eq!(event, Event::Reply {
+            seq: mag.seq,
+            request: Box::new(Message::MagReq),
+            reply: Message::Mag(Stamp::default(), 1, 2, 3),
+            rtt: Duration::from_millis(5),
+        });
+        assert_eq!(tracker.outstanding(), 1);
+    }
+
+    #[test]
+    fn retry_then_lose() {
+        let mut tracker = RequestTracker::new(TIMEOUT, 1);
+        let start = Instant::now();
+        let env = tracker.request(Message::AccelReq, start);
+
+        assert_eq!(tracker.expire(start + TIMEOUT / 2), Expired::default());
+        let expired = tracker.expire(start + TIMEOUT);
+        assert_eq!(expired.resend, vec![env.clone()]);
+        assert!(expired.lost.is_empty());
+
+        let expired = tracker.expire(start + TIMEOUT * 2);
+        assert!(expired.resend.is_empty());
+        assert_eq!(expired.lost, vec![env.clone()]);
+        assert_eq!(tracker.outstanding(), 0);
+
+        // The board finally answers, too late
+        let event = tracker.receive(env.reply(Message::Accel(Stamp::default(), 0., 0., 1.)), start + TIMEOUT * 3);
+        assert!(matches!(event, Event::Unmatched(_)));
+    }
+
+    #[test]
+    fn only_idempotent_requests_retry() {
+        let mut tracker = RequestTracker::new(TIMEOUT, 1);
+        let start = Instant::now();
+        let reboot = tracker.request(Message::Reboot, start);
+        let time = tracker.request(Message::TimeReq, start);
+
+        let expired = tracker.expire(start + TIMEOUT);
+        assert_eq!(expired.resend, vec![time]);
+        assert!(expired.lost.is_empty());
+
+        // Still given the time the retries would have had
+        let expired = tracker.expire(start + TIMEOUT * 2);
+        assert_eq!(expired.lost.len(), 2);
+        assert_eq!(expired.lost[0].seq, reboot.seq);
+    }
+
+    #[test]
+    fn stream() {
+        let mut tracker = RequestTracker::new(TIMEOUT, 0);
+        let now = Instant::now();
+        let sub = Message::Subscribe { sensors: Sensors::ACCEL, rate_hz: 10 };
+        let env = tracker.subscribe(sub.clone(), now);
+
+        let sample = env.reply(Message::Accel(Stamp::default(), 0., 0., 1.));
+        assert_eq!(tracker.receive(sample.clone(), now), Event::Stream(sample.clone()));
+        let event = tracker.receive(env.reply(Message::SubscribeAck(10)), now);
+        assert!(matches!(event, Event::Reply { request, .. } if *request == sub));
+        assert_eq!(tracker.receive(sample.clone(), now), Event::Stream(sample.clone()));
+
+        tracker.close(env.seq);
+        assert_eq!(tracker.receive(sample.clone(), now), Event::Unmatched(sample));
+    }
+
+    #[test]
+    fn unsolicited() {
+        let mut tracker = RequestTracker::default();
+        let event = tracker.receive(Envelope::from(Message::Hello), Instant::now());
+        assert_eq!(event, Event::Unsolicited(Message::Hello));
+    }
+
+    #[test]
+    fn sequence_skips_zero() {
+        let mut tracker = RequestTracker { next_seq: u16::MAX, ..RequestTracker::default() };
+        let now = Instant::now();
+        assert_eq!(tracker.request(Message::Nop, now).seq, u16::MAX);
+        assert_eq!(tracker.request(Message::Nop, now).seq, 1);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{CompError, Result};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+use super::Transport;
+
+/// One end of an in-memory byte pipe. Whatever is written to one end of a
+/// pair can be read from the other.
+pub struct Loopback {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Loopback {
+    /// Wrap an existing pair of channels, such as the ones returned by
+    /// `mock::spawn`.
+    pub fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Loopback {
+        Loopback { tx, rx, pending: Vec::new() }
+    }
+
+    pub fn pair() -> (Loopback, Loopback) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        (Loopback::new(a_tx, b_rx), Loopback::new(b_tx, a_rx))
+    }
+}
+
+impl Transport for Loopback {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(timeout) {
+                Ok(bytes) => self.pending = bytes,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => return Err(CompError::Disconnected),
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.tx.send(buf.to_vec()).map_err(|_| CompError::Disconnected)?;
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn round_trip() {
+        let (mut a, mut b) = Loopback::pair();
+        a.write(b"ping").unwrap();
+        let mut buf = [0u8; 2];
+        assert_eq!(b.read(&mut buf, TIMEOUT).unwrap(), 2);
+        assert_eq!(&buf, b"pi");
+        assert_eq!(b.read(&mut buf, TIMEOUT).unwrap(), 2);
+        assert_eq!(&buf, b"ng");
+        assert_eq!(b.read(&mut buf, TIMEOUT).unwrap(), 0);
+    }
+
+    #[test]
+    fn hang_up() {
+        let (mut a, b) = Loopback::pair();
+        drop(b);
+        let mut buf = [0u8; 4];
+        assert!(a.read(&mut buf, TIMEOUT).is_err());
+        assert!(a.write(b"ping").is_err());
+    }
+}
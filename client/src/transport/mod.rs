@@ -0,0 +1,188 @@
+use crate::{CompError, Result};
+use common::device::Uid;
+use log::warn;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+mod loopback;
+mod replay;
+mod serial;
+mod tcp;
+mod usb;
+
+pub use loopback::Loopback;
+pub use replay::ReplayTransport;
+pub use serial::SerialTransport;
+pub use tcp::TcpTransport;
+pub use usb::{list as list_usb, UsbHotplug, UsbTransport};
+
+// With hotplug notifications, still look now and then in case one was missed
+const HOTPLUG_BACKSTOP: Duration = Duration::from_secs(10);
+
+/// A byte pipe to a compass board.
+pub trait Transport: Send {
+    /// Read whatever is available into `buf`, waiting at most `timeout`.
+    /// Returns `Ok(0)` if nothing arrived in time.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+
+    /// Write `buf`, returning how many bytes were accepted.
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        (**self).read(buf, timeout)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+}
+
+/// Where to find a board.
+///
+/// Parsed from `usb`, `usb:<serial number>`, `tty:<path>`,
+/// `tcp:<host>:<port>` or `replay:<path>[#<uid>][@<speed>]`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// The board with this USB serial number, or the first one found.
+    Usb(Option<String>),
+    Serial(PathBuf),
+    Tcp(String),
+    /// A recording of the board with this UID, or of the first one in it,
+    /// played back `speed` times faster than it happened.
+    Replay { path: PathBuf, device: Option<Uid>, speed: f32 },
+}
+
+impl Endpoint {
+    /// One endpoint for each board plugged in over USB.
+    pub fn all_usb() -> Result<Vec<Endpoint>> {
+        Ok(list_usb()?.into_iter().map(|serial| Endpoint::Usb(Some(serial))).collect())
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Transport>> {
+        Ok(match self {
+            Endpoint::Usb(serial) => Box::new(UsbTransport::open(serial.as_deref())?),
+            Endpoint::Serial(path) => Box::new(SerialTransport::open(path)?),
+            Endpoint::Tcp(addr) => Box::new(TcpTransport::connect(addr)?),
+            Endpoint::Replay { path, device, speed } => Box::new(ReplayTransport::open(path, *device, *speed)?),
+        })
+    }
+}
+
+/// Paces a reconnect loop: wakes it as soon as a board is plugged in where
+/// libusb can tell, and polls otherwise.
+pub struct Arrivals {
+    hotplug: Option<UsbHotplug>,
+}
+
+impl Arrivals {
+    pub fn new(endpoint: &Endpoint) -> Arrivals {
+        let hotplug = match endpoint {
+            Endpoint::Usb(_) => UsbHotplug::register().unwrap_or_else(|e| {
+                warn!("No hotplug notifications, polling instead: {}", e);
+                None
+            }),
+            _ => None,
+        };
+        Arrivals { hotplug }
+    }
+
+    /// Wait until it's worth trying to open the board again: `poll`, or
+    /// until a board arrives if hotplug notifications are available.
+    pub fn wait(&self, poll: Duration) {
+        match &self.hotplug {
+            Some(hotplug) => hotplug.wait(poll.max(HOTPLUG_BACKSTOP)),
+            None => thread::sleep(poll),
+        }
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint::Usb(None)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Usb(None) => write!(f, "usb"),
+            Endpoint::Usb(Some(serial)) => write!(f, "usb:{}", serial),
+            Endpoint::Serial(path) => write!(f, "tty:{}", path.display()),
+            Endpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+            Endpoint::Replay { path, device, speed } => {
+                write!(f, "replay:{}", path.display())?;
+                if let Some(uid) = device {
+                    write!(f, "#{}", uid)?;
+                }
+                if *speed != 1. {
+                    write!(f, "@{}", speed)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = CompError;
+
+    fn from_str(s: &str) -> Result<Endpoint> {
+        if s == "usb" {
+            Ok(Endpoint::Usb(None))
+        } else if let Some(serial) = s.strip_prefix("usb:") {
+            Ok(Endpoint::Usb(Some(serial.to_string())))
+        } else if let Some(path) = s.strip_prefix("tty:") {
+            Ok(Endpoint::Serial(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            Ok(Endpoint::Tcp(addr.to_string()))
+        } else if let Some(rest) = s.strip_prefix("replay:") {
+            let bad = || CompError::BadEndpoint(s.to_string());
+            let (rest, speed) = match rest.rsplit_once('@') {
+                Some((rest, speed)) => (rest, speed.parse::<f32>().map_err(|_| bad())?),
+                None => (rest, 1.),
+            };
+            if !(speed.is_finite() && speed > 0.) {
+                return Err(bad());
+            }
+            let (path, device) = match rest.rsplit_once('#') {
+                Some((path, uid)) => (path, Some(uid.parse().map_err(|_| bad())?)),
+                None => (rest, None),
+            };
+            Ok(Endpoint::Replay { path: PathBuf::from(path), device, speed })
+        } else {
+            Err(CompError::BadEndpoint(s.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoint() {
+        assert_eq!("usb".parse::<Endpoint>().unwrap(), Endpoint::Usb(None));
+        assert_eq!("usb:0011AABB".parse::<Endpoint>().unwrap(), Endpoint::Usb(Some("0011AABB".into())));
+        assert_eq!("tty:/dev/ttyACM0".parse::<Endpoint>().unwrap(), Endpoint::Serial("/dev/ttyACM0".into()));
+        assert_eq!("tcp:localhost:7070".parse::<Endpoint>().unwrap(), Endpoint::Tcp("localhost:7070".into()));
+        assert_eq!(
+            "replay:run.rec#0000000100000002000000AB@4".parse::<Endpoint>().unwrap(),
+            Endpoint::Replay { path: "run.rec".into(), device: Some(Uid([0xAB, 2, 1])), speed: 4. }
+        );
+        assert_eq!(
+            "replay:run.rec".parse::<Endpoint>().unwrap(),
+            Endpoint::Replay { path: "run.rec".into(), device: None, speed: 1. }
+        );
+        assert!("replay:run.rec@0".parse::<Endpoint>().is_err());
+        assert!("replay:run.rec#beef".parse::<Endpoint>().is_err());
+        assert!("bluetooth".parse::<Endpoint>().is_err());
+        for s in &["usb", "usb:0011AABB", "tty:/dev/ttyACM0", "tcp:localhost:7070", "replay:run.rec", "replay:a.rec#0000000100000002000000AB@0.5"] {
+            assert_eq!(s.parse::<Endpoint>().unwrap().to_string(), *s);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::link::decode_all;
+use crate::record::{self, Frame};
+use crate::{CompError, Result};
+use common::device::{DeviceInfo, Uid};
+use common::link::{Link, MAX_FRAME_SIZE};
+use common::{Envelope, Message};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use super::Transport;
+
+/// Plays a recording back as if it were the board that was recorded.
+///
+/// It answers `Hello` with the recorded `HelloAck` and, once subscribed to,
+/// pushes the recorded samples at the pace they arrived, sped up by
+/// `speed`. Calibrations are acknowledged but change nothing, since the
+/// samples were taken with whatever the board had then. The link drops at
+/// the end of the recording, so a reconnecting `Board` starts it over.
+pub struct ReplayTransport {
+    info: DeviceInfo,
+    samples: Vec<Frame>,
+    speed: f64,
+    link: Link,
+    pending: VecDeque<u8>,
+    /// The seq of the subscription samples are pushed for.
+    stream: Option<u16>,
+    next: usize,
+    /// The host time playing (re)started at, and the recorded time it
+    /// started from.
+    clock: Option<(Instant, u64)>,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &Path, device: Option<Uid>, speed: f32) -> Result<ReplayTransport> {
+        ReplayTransport::new(record::load(path)?, device, speed)
+    }
+
+    /// Replay `device`'s part of `frames`, or the first board to say hello
+    /// if it's `None`.
+    pub fn new(frames: Vec<Frame>, device: Option<Uid>, speed: f32) -> Result<ReplayTransport> {
+        let info = frames
+            .iter()
+            .filter_map(|frame| match frame.env.msg {
+                Message::HelloAck(info) if device.is_none_or(|uid| uid == frame.device) => Some(info),
+                _ => None,
+            })
+            .next()
+            .ok_or_else(|| CompError::Recording("no HelloAck from the board to replay".to_string()))?;
+        let samples = frames
+            .into_iter()
+            .filter(|frame| frame.device == info.uid)
+            .filter(|frame| matches!(frame.env.msg, Message::Accel(..) | Message::Mag(..) | Message::Gyro(..)))
+            .collect();
+        Ok(ReplayTransport {
+            info,
+            samples,
+            speed: speed as f64,
+            link: Link::new(),
+            pending: VecDeque::new(),
+            stream: None,
+            next: 0,
+            clock: None,
+        })
+    }
+
+    fn send(&mut self, env: Envelope) -> Result<()> {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let size = self.link.encode(&env, &mut buf)?;
+        self.pending.extend(&buf[..size]);
+        Ok(())
+    }
+
+    // The board's clock as of the last sample sent
+    fn board_time_us(&self) -> u64 {
+        let last = self.next.checked_sub(1).and_then(|i| self.samples.get(i)).or_else(|| self.samples.first());
+        match last.map(|frame| &frame.env.msg) {
+            Some(Message::Accel(stamp, ..)) | Some(Message::Mag(stamp, ..)) | Some(Message::Gyro(stamp, ..)) => stamp.time_us,
+            _ => 0,
+        }
+    }
+
+    fn answer(&mut self, env: Envelope) -> Result<()> {
+        let reply = match env.msg {
+            Message::Hello => Message::HelloAck(self.info),
+            Message::Subscribe { rate_hz, .. } => {
+                self.stream = Some(env.seq);
+                self.clock = None;
+                Message::SubscribeAck(self.info.clamp_rate(rate_hz))
+            }
+            Message::Unsubscribe => {
+                self.stream = None;
+                return Ok(());
+            }
+            Message::TimeReq => Message::Time(self.board_time_us()),
+            Message::SetMagCalibration(_) | Message::SetAccelCalibration(_) => Message::Ack,
+            Message::Nop => return Ok(()),
+            other => Message::Unknown(other.tag()),
+        };
+        self.send(Envelope::new(env.seq, reply))
+    }
+
+    // Queue every sample that's due, waiting up to `timeout` for the next
+    fn play(&mut self, seq: u16, timeout: Duration) -> Result<()> {
+        let first = self.samples.get(self.next).ok_or(CompError::Disconnected)?.time_us;
+        let (start, start_us) = *self.clock.get_or_insert((Instant::now(), first));
+        let speed = self.speed;
+        let due = |time_us: u64| start + Duration::from_secs_f64((time_us - start_us) as f64 / 1e6 / speed);
+        let wait = due(first).saturating_duration_since(Instant::now());
+        sleep(wait.min(timeout));
+        while let Some(frame) = self.samples.get(self.next) {
+            if due(frame.time_us) > Instant::now() {
+                break;
+            }
+            let msg = frame.env.msg.clone();
+            self.next += 1;
+            self.send(Envelope::new(seq, msg))?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        if self.pending.is_empty() {
+            match self.stream {
+                Some(seq) => self.play(seq, timeout)?,
+                None => sleep(timeout),
+            }
+        }
+        let read = buf.len().min(self.pending.len());
+        for (b, p) in buf.iter_mut().zip(self.pending.drain(..read)) {
+            *b = p;
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for env in decode_all(&mut self.link, buf)? {
+            self.answer(env)?;
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::link::usb_link;
+    use common::device::FirmwareVersion;
+    use common::sensors::{Sensors, Stamp};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    fn recording(uid: Uid) -> Vec<Frame> {
+        let info = DeviceInfo::new(FirmwareVersion::default(), uid, Sensors::ALL);
+        let mut frames = vec![Frame { time_us: 0, device: uid, env: Envelope::new(1, Message::HelloAck(info)) }];
+        for i in 0..20u32 {
+            let stamp = Stamp::new(1_000_000 + 10_000 * i as u64, i);
+            frames.push(Frame {
+                time_us: 5_000 + 10_000 * i as u64,
+                device: uid,
+                env: Envelope::new(2, Message::Mag(stamp, i as i16, 0, 0)),
+            });
+        }
+        frames
+    }
+
+    fn board(mut transport: ReplayTransport) -> Board {
+        let (to_board_tx, mut to_board_rx) = channel();
+        let (from_board_tx, from_board_rx) = channel();
+        thread::spawn(move || {
+            let _ = usb_link(&mut to_board_rx, &from_board_tx, &mut transport);
+        });
+        Board::spawn(to_board_tx, from_board_rx)
+    }
+
+    #[test]
+    fn replays_like_the_board() {
+        let uid = Uid([7, 8, 9]);
+        let mut frames = recording(Uid([1, 1, 1]));
+        frames.extend(recording(uid));
+        let board = board(ReplayTransport::new(frames, Some(uid), 10.).unwrap());
+
+        assert_eq!(board.hello().unwrap().uid, uid);
+        let start = Instant::now();
+        let samples = board.subscribe(Sensors::MAG, 100).unwrap();
+        let xs: Vec<i16> = samples
+            .take(20)
+            .map(|msg| match msg {
+                Message::Mag(_, x, _, _) => x,
+                other => panic!("Replayed {:?}", other),
+            })
+            .collect();
+        assert_eq!(xs, (0..20).collect::<Vec<_>>());
+        // 190ms of samples at ten times the speed
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(19), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(190), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn needs_a_hello() {
+        let frames = recording(Uid([1, 1, 1]));
+        assert!(ReplayTransport::new(frames.clone(), Some(Uid([2, 2, 2])), 1.).is_err());
+        assert!(ReplayTransport::new(frames[1..].to_vec(), None, 1.).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::Result;
+use serialport::SerialPort;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use super::Transport;
+
+// CDC-ACM ignores the line rate but the tty layer still wants one
+const BAUD_RATE: u32 = 115_200;
+
+/// The board's CDC-ACM interface as seen through the kernel driver, e.g.
+/// `/dev/ttyACM0`. Needs no libusb permissions and leaves the driver bound.
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SerialTransport> {
+        let path = path.as_ref().to_string_lossy();
+        let port = serialport::new(path, BAUD_RATE).open()?;
+        Ok(SerialTransport { port })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.port.set_timeout(timeout)?;
+        match self.port.read(buf) {
+            Ok(read) => Ok(read),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.port.write(buf)?;
+        self.port.flush()?;
+        Ok(written)
+    }
+}
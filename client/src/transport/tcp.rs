@@ -0,0 +1,41 @@
+use crate::{CompError, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use super::Transport;
+
+/// The raw Link byte stream carried over a TCP connection.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpTransport> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl From<TcpStream> for TcpTransport {
+    fn from(stream: TcpStream) -> TcpTransport {
+        TcpTransport { stream }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        match self.stream.read(buf) {
+            Ok(0) => Err(CompError::Disconnected),
+            Ok(read) => Ok(read),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stream.write_all(buf)?;
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,202 @@
+use common::usb::{VENDOR_ID, PROD_ID};
+use crate::{CompError, Result};
+use log::{debug, trace, info, warn};
+use rusb::{Device, DeviceHandle, GlobalContext, Hotplug, HotplugBuilder, Registration, UsbContext, Error as UsbError};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+use super::Transport;
+
+const WRITE_TIMEOUT: Duration = Duration::from_millis(10);
+const WRITE_ADDR: u8 = 2;
+const READ_ADDR: u8 = 130;
+const DESIRED_CONFIG: u8 = 1;
+const SERIAL_DATA_INTERFACE: u8 = 1;
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_millis(100);
+// How long each turn of the hotplug event loop blocks in libusb
+const EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bulk transfers on the CDC data interface, claimed through libusb.
+pub struct UsbTransport<T: UsbContext = GlobalContext> {
+    handle: DeviceHandle<T>,
+}
+
+impl UsbTransport<GlobalContext> {
+    /// Open and claim the board with USB serial number `serial`, or the
+    /// first board matching our VID/PID if it's `None`.
+    pub fn open(serial: Option<&str>) -> Result<UsbTransport<GlobalContext>> {
+        let handle = match serial {
+            None => rusb::open_device_with_vid_pid(VENDOR_ID, PROD_ID).ok_or(CompError::NotFound)?,
+            Some(serial) => boards()?
+                .into_iter()
+                .find(|(s, _)| s == serial)
+                .map(|(_, handle)| handle)
+                .ok_or(CompError::NotFound)?,
+        };
+        UsbTransport::new(handle)
+    }
+}
+
+/// Serial numbers of every board plugged in. The firmware uses its chip's
+/// unique ID, so they tell boards apart.
+pub fn list() -> Result<Vec<String>> {
+    Ok(boards()?.into_iter().map(|(serial, _)| serial).collect())
+}
+
+// Open every device with our VID/PID and read its serial number. Boards
+// already claimed by another process, without permissions, or that don't
+// answer for their serial number, are skipped.
+fn boards() -> Result<Vec<(String, DeviceHandle<GlobalContext>)>> {
+    let mut boards = Vec::new();
+    for device in rusb::devices()?.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => continue,
+        };
+        if descriptor.vendor_id() != VENDOR_ID || descriptor.product_id() != PROD_ID {
+            continue;
+        }
+        let handle = match device.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                debug!("Skipping board at {}:{}: {}", device.bus_number(), device.address(), e);
+                continue;
+            }
+        };
+        let serial = handle.read_languages(DESCRIPTOR_TIMEOUT).and_then(|languages| match languages.first() {
+            Some(language) => handle.read_serial_number_string(*language, &descriptor, DESCRIPTOR_TIMEOUT),
+            None => Ok(String::new()),
+        });
+        match serial {
+            Ok(serial) => boards.push((serial, handle)),
+            Err(e) => warn!("Skipping board at {}:{}: no serial number: {}", device.bus_number(), device.address(), e),
+        }
+    }
+    Ok(boards)
+}
+
+/// libusb hotplug notifications for boards with our VID/PID.
+pub struct UsbHotplug {
+    arrivals: Receiver<()>,
+    _registration: Registration<GlobalContext>,
+}
+
+struct Notify(Sender<()>);
+
+impl Hotplug<GlobalContext> for Notify {
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        debug!("Board arrived at {}:{}", device.bus_number(), device.address());
+        let _ = self.0.send(());
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        debug!("Board left {}:{}", device.bus_number(), device.address());
+    }
+}
+
+impl UsbHotplug {
+    /// Start listening for boards being plugged in, or `None` where libusb
+    /// doesn't support hotplug on this platform.
+    pub fn register() -> Result<Option<UsbHotplug>> {
+        if !rusb::has_hotplug() {
+            return Ok(None);
+        }
+        let (tx, arrivals) = channel();
+        let mut builder = HotplugBuilder::new();
+        builder.vendor_id(VENDOR_ID).product_id(PROD_ID).enumerate(true);
+        let registration = builder.register(GlobalContext::default(), Box::new(Notify(tx)))?;
+        start_event_loop();
+        Ok(Some(UsbHotplug { arrivals, _registration: registration }))
+    }
+
+    /// Wait up to `timeout` for a board to be plugged in.
+    pub fn wait(&self, timeout: Duration) {
+        if self.arrivals.recv_timeout(timeout).is_ok() {
+            // One open attempt covers a burst of arrivals
+            self.arrivals.try_iter().for_each(drop);
+        }
+    }
+}
+
+// Hotplug callbacks only run from inside libusb's event handling, which
+// one thread keeps turning for every registration.
+fn start_event_loop() {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        thread::spawn(|| loop {
+            if let Err(e) = GlobalContext::default().handle_events(Some(EVENT_TIMEOUT)) {
+                debug!("USB event handling failed: {}", e);
+                thread::sleep(EVENT_TIMEOUT);
+            }
+        });
+    });
+}
+
+impl<T: UsbContext> UsbTransport<T> {
+    pub fn new(mut handle: DeviceHandle<T>) -> Result<UsbTransport<T>> {
+        usb_configure(&mut handle)?;
+        Ok(UsbTransport { handle })
+    }
+}
+
+impl<T: UsbContext> Transport for UsbTransport<T> {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        match self.handle.read_bulk(READ_ADDR, buf, timeout) {
+            Ok(read) => Ok(read),
+            Err(UsbError::Timeout) => Ok(0),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(self.handle.write_bulk(WRITE_ADDR, buf, WRITE_TIMEOUT)?)
+    }
+}
+
+fn print_device_info<T: UsbContext>(device: Device<T>) -> Result<()> {
+    let number_configs = device.device_descriptor()?.num_configurations();
+    let active_config_descriptor = device.active_config_descriptor()?;
+    let active_number = active_config_descriptor.number();
+    info!("Device has {} configurations:", number_configs);
+    for i in 0..number_configs {
+        let config = device.config_descriptor(i)?;
+        let config_number = config.number();
+        let active_flag = if active_number == config_number {
+            "*"
+        } else {
+            ""
+        };
+        trace!("\tConfig {}{}:", config_number, active_flag);
+        trace!("\tInterfaces {}:", config.num_interfaces());
+        for interface in config.interfaces() {
+            trace!("\t\tInterface no {}:", interface.number());
+            for desc in interface.descriptors() {
+                trace!("\t\t\tDescriptor no {}", desc.interface_number());
+                trace!("\t\t\tEndpoints {}:", desc.num_endpoints());
+                for endpoint in desc.endpoint_descriptors() {
+                    trace!("\t\t\t\t#{} @{} dir:{:?} type:{:?}",
+                        endpoint.number(),
+                        endpoint.address(),
+                        endpoint.direction(),
+                        endpoint.transfer_type());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn usb_configure<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    if DESIRED_CONFIG != handle.active_configuration()? {
+        handle.set_active_configuration(DESIRED_CONFIG)?;
+    }
+
+    print_device_info(handle.device())?;
+    if handle.kernel_driver_active(SERIAL_DATA_INTERFACE)? {
+        handle.detach_kernel_driver(SERIAL_DATA_INTERFACE)?;
+    }
+
+    handle.claim_interface(SERIAL_DATA_INTERFACE)?;
+    Ok(())
+}
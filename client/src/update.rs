@@ -0,0 +1,155 @@
+//! Updating the board's firmware through its bootloader. The new image is
+//! staged beside the running one and only installed once its CRC checks
+//! out, so an update that fails part way leaves the old firmware in place.
+//! Once installed, the new firmware is kept only if it starts and answers
+//! the host; otherwise the bootloader puts the old one back at the next
+//! reset.
+
+use common::update::{ImageChunk, ImageInfo, UpdateStatus, MAX_CHUNK_SIZE};
+use common::Message;
+use crate::board::Board;
+use crate::{CompError, Result};
+use log::debug;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest the board takes to reset and come back, swapping an image in
+// included
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// How far `flash` has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// Waiting for the board to reset into its bootloader.
+    Rebooting,
+    /// `sent` of the image's `size` bytes are staged.
+    Sent { sent: u32, size: u32 },
+    /// The whole image is staged, and matches its CRC.
+    Verified(ImageInfo),
+    /// Waiting for the bootloader to install the image and start it.
+    Installing,
+    /// The new firmware answered, and is rebooted so the bootloader keeps
+    /// it.
+    Confirming,
+}
+
+/// What the board is running, or how far an update has got if it's in its
+/// bootloader.
+pub fn status(board: &Board) -> Result<UpdateStatus> {
+    match board.request(Message::UpdateStatusReq)? {
+        Message::UpdateStatus(status) => Ok(status),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+/// Install `image` on the board, rebooting into the bootloader first if it
+/// isn't there already. If staging the image fails, the board is rebooted
+/// back into the firmware it had, as it is if the new firmware doesn't
+/// start.
+pub fn flash(board: &Board, image: &[u8], mut progress: impl FnMut(Progress)) -> Result<ImageInfo> {
+    let info = ImageInfo::of(image);
+    if let UpdateStatus::Application(_) = status(board)? {
+        progress(Progress::Rebooting);
+        acknowledged(board.request(Message::RebootToBootloader)?)?;
+        wait_for(board, |status| !matches!(status, UpdateStatus::Application(_)))?;
+    }
+    if let Err(e) = stage(board, image, info, &mut progress) {
+        let _ = board.request(Message::Reboot);
+        return Err(e);
+    }
+    progress(Progress::Verified(info));
+
+    progress(Progress::Installing);
+    reboot_into(board, info)?;
+    // Having answered the host, the new firmware is kept from the next reset
+    progress(Progress::Confirming);
+    board.request(Message::Hello)?;
+    reboot_into(board, info)
+}
+
+// Reboot and wait for the board to come back running `info`
+fn reboot_into(board: &Board, info: ImageInfo) -> Result<ImageInfo> {
+    acknowledged(board.request(Message::Reboot)?)?;
+    let status = wait_for(board, |status| {
+        matches!(status, UpdateStatus::Application(Some(_)) | UpdateStatus::InstallFailed(_))
+    })?;
+    match status {
+        UpdateStatus::Application(Some(installed)) if installed == info => Ok(info),
+        UpdateStatus::InstallFailed(e) => Err(CompError::Update(e)),
+        other => Err(CompError::Unexpected(Box::new(Message::UpdateStatus(other)))),
+    }
+}
+
+fn stage(board: &Board, image: &[u8], info: ImageInfo, progress: &mut impl FnMut(Progress)) -> Result<()> {
+    acknowledged(board.request(Message::UpdateBegin(info))?)?;
+    let mut sent = 0;
+    for chunk in image.chunks(MAX_CHUNK_SIZE) {
+        let data = ImageChunk::new(chunk).expect("chunks fit");
+        acknowledged(board.request(Message::UpdateChunk { offset: sent, data })?)?;
+        sent += chunk.len() as u32;
+        progress(Progress::Sent { sent, size: info.size });
+    }
+    acknowledged(board.request(Message::UpdateFinish)?)
+}
+
+// Poll the board's status until `done`, through the reset and the link
+// going away and coming back
+fn wait_for(board: &Board, done: impl Fn(&UpdateStatus) -> bool) -> Result<UpdateStatus> {
+    let deadline = Instant::now() + REBOOT_TIMEOUT;
+    loop {
+        let left = deadline.checked_duration_since(Instant::now()).ok_or(CompError::Timeout)?;
+        if board.wait_connected(left).is_ok() {
+            match status(board) {
+                Ok(status) if done(&status) => return Ok(status),
+                Ok(_) => (),
+                Err(e) => debug!("No status while rebooting: {}", e),
+            }
+        }
+        thread::sleep(POLL_PERIOD);
+    }
+}
+
+fn acknowledged(reply: Message) -> Result<()> {
+    match reply {
+        Message::Ack => Ok(()),
+        Message::UpdateFailed(e) => Err(CompError::Update(e)),
+        other => Err(CompError::Unexpected(Box::new(other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::mock_board_with;
+    use common::update::UpdateError;
+    use mock::MockBoard;
+
+    fn image() -> Vec<u8> {
+        (0..3000u32).map(|i| (i * 31 + i / 7) as u8).collect()
+    }
+
+    #[test]
+    fn flash_and_reboot() {
+        let board = mock_board_with(MockBoard::new());
+        assert_eq!(status(&board).unwrap(), UpdateStatus::Application(None));
+        let mut steps = Vec::new();
+        let info = flash(&board, &image(), |step| steps.push(step)).unwrap();
+        assert_eq!(info, ImageInfo::of(&image()));
+        assert_eq!(steps.first(), Some(&Progress::Rebooting));
+        assert!(steps.contains(&Progress::Sent { sent: 3000, size: 3000 }));
+        assert_eq!(steps.last(), Some(&Progress::Confirming));
+        assert_eq!(status(&board).unwrap(), UpdateStatus::Application(Some(info)));
+    }
+
+    #[test]
+    fn corrupt_image_rolls_back() {
+        let mut mock = MockBoard::new();
+        mock.corrupt_updates();
+        let board = mock_board_with(mock);
+        assert!(matches!(flash(&board, &image(), |_| ()), Err(CompError::Update(UpdateError::Crc))));
+        // Back in the old firmware, with nothing installed
+        let status = wait_for(&board, |status| matches!(status, UpdateStatus::Application(_))).unwrap();
+        assert_eq!(status, UpdateStatus::Application(None));
+    }
+}
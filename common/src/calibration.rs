@@ -0,0 +1,139 @@
+//! Sensor corrections computed on the host and applied on the board.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Hard- and soft-iron correction for the magnetometer:
+/// `corrected = matrix * (raw - offset)`.
+///
+/// `offset` is in raw counts. `matrix` turns the ellipsoid traced by the
+/// readings back into a sphere, and keeps them roughly in raw counts.
+///
+/// Goes on the wire, and in saved calibrations, as an array.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MagCalibration {
+    pub offset: [f32; 3],
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl MagCalibration {
+    /// Leaves readings untouched.
+    pub const IDENTITY: MagCalibration = MagCalibration {
+        offset: [0.; 3],
+        matrix: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+    };
+
+    pub fn apply(&self, raw: [f32; 3]) -> [f32; 3] {
+        let d = [raw[0] - self.offset[0], raw[1] - self.offset[1], raw[2] - self.offset[2]];
+        let m = &self.matrix;
+        [
+            m[0][0] * d[0] + m[0][1] * d[1] + m[0][2] * d[2],
+            m[1][0] * d[0] + m[1][1] * d[1] + m[1][2] * d[2],
+            m[2][0] * d[0] + m[2][1] * d[1] + m[2][2] * d[2],
+        ]
+    }
+
+    /// Correct a raw `Mag` reading, saturating at the limits of `i16`.
+    pub fn apply_raw(&self, x: i16, y: i16, z: i16) -> (i16, i16, i16) {
+        let [cx, cy, cz] = self.apply([x as f32, y as f32, z as f32]);
+        (round(cx), round(cy), round(cz))
+    }
+}
+
+impl Default for MagCalibration {
+    fn default() -> MagCalibration {
+        MagCalibration::IDENTITY
+    }
+}
+
+impl Serialize for MagCalibration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.offset, self.matrix).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MagCalibration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MagCalibration, D::Error> {
+        let (offset, matrix) = Deserialize::deserialize(deserializer)?;
+        Ok(MagCalibration { offset, matrix })
+    }
+}
+
+/// Per-axis accelerometer correction: `corrected = (raw - offset) * scale`,
+/// in g. Goes on the wire, and in saved calibrations, as an array.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccelCalibration {
+    pub offset: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl AccelCalibration {
+    /// Leaves readings untouched.
+    pub const IDENTITY: AccelCalibration = AccelCalibration {
+        offset: [0.; 3],
+        scale: [1.; 3],
+    };
+
+    pub fn apply(&self, raw: [f32; 3]) -> [f32; 3] {
+        [
+            (raw[0] - self.offset[0]) * self.scale[0],
+            (raw[1] - self.offset[1]) * self.scale[1],
+            (raw[2] - self.offset[2]) * self.scale[2],
+        ]
+    }
+}
+
+impl Default for AccelCalibration {
+    fn default() -> AccelCalibration {
+        AccelCalibration::IDENTITY
+    }
+}
+
+impl Serialize for AccelCalibration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.offset, self.scale).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccelCalibration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<AccelCalibration, D::Error> {
+        let (offset, scale) = Deserialize::deserialize(deserializer)?;
+        Ok(AccelCalibration { offset, scale })
+    }
+}
+
+// `as` saturates, but truncates towards zero
+fn round(v: f32) -> i16 {
+    if v < 0. {
+        (v - 0.5) as i16
+    } else {
+        (v + 0.5) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity() {
+        assert_eq!(MagCalibration::default().apply_raw(-300, 0, 421), (-300, 0, 421));
+    }
+
+    #[test]
+    fn correction() {
+        let cal = MagCalibration {
+            offset: [100., -50., 0.],
+            matrix: [[2., 0., 0.], [0., 1., 0.5], [0., 0., 1.]],
+        };
+        assert_eq!(cal.apply([101., -50., 2.]), [2., 1., 2.]);
+        assert_eq!(cal.apply_raw(101, -51, -3), (2, -3, -3));
+        assert_eq!(cal.apply_raw(i16::MAX, 0, 0).0, i16::MAX);
+    }
+
+    #[test]
+    fn accel() {
+        let cal = AccelCalibration { offset: [0.5, 0., -0.25], scale: [2., 1., 0.5] };
+        assert_eq!(cal.apply([1., 1., 1.75]), [1., 1., 1.]);
+        assert_eq!(AccelCalibration::default().apply([0.1, 0.2, 0.3]), [0.1, 0.2, 0.3]);
+    }
+}
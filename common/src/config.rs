@@ -0,0 +1,745 @@
+//! Key/value configuration store kept in two pages of flash.
+//!
+//! Committed values are appended to the active page as records, so a page
+//! only needs erasing once it fills up. The newest record for each key is
+//! then copied to the other page, which takes over. A page's header is
+//! written after its records, so an interrupted compaction leaves the old
+//! page in charge; if both headers are valid the higher generation wins.
+//!
+//! Page header: magic `u32`, generation `u32`.
+//! Record: key `u16`, length `u16`, data padded to an even length, then a
+//! CRC-16 of everything before it. A length with `TOMBSTONE` set marks the
+//! key as erased. All fields are little-endian.
+
+use crate::crc::{crc16, CRC_SIZE};
+use core::fmt;
+use serde::{
+    de::{self, DeserializeOwned, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_cbor::ser::SliceWrite;
+
+/// Longest value a key can hold.
+pub const MAX_VALUE_SIZE: usize = 64;
+/// How many keys can be changed between commits.
+pub const MAX_PENDING: usize = 8;
+
+const MAGIC: u32 = 0x3147_4643; // "CFG1"
+const HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 4;
+const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + MAX_VALUE_SIZE + CRC_SIZE;
+const ERASED_KEY: u16 = 0xFFFF;
+const TOMBSTONE: u16 = 0x8000;
+
+/// Keys the firmware knows about.
+pub mod keys {
+    /// `calibration::MagCalibration`
+    pub const MAG_CALIBRATION: u16 = 1;
+    /// `calibration::AccelCalibration`
+    pub const ACCEL_CALIBRATION: u16 = 2;
+    /// `u16` sample rate used while nobody is subscribed
+    pub const IDLE_RATE_HZ: u16 = 3;
+    /// Free-form device name, UTF-8
+    pub const DEVICE_NAME: u16 = 4;
+}
+
+/// Two pages of NOR flash: erasing sets every byte to `0xFF`, and writes
+/// can only program erased half-words.
+pub trait Flash {
+    type Error: fmt::Debug;
+
+    /// Bytes in one erasable page.
+    const PAGE_SIZE: usize;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Program `data` at `offset`. Both are half-word aligned.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erase page 0 or 1.
+    fn erase_page(&mut self, page: usize) -> Result<(), Self::Error>;
+}
+
+/// Goes on the wire as its tag, see `ConfigError::tag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The flash reported an error.
+    Flash,
+    /// The live values don't fit in a page.
+    Full,
+    /// The value is longer than `MAX_VALUE_SIZE` or can't be encoded.
+    TooLarge,
+    /// More than `MAX_PENDING` keys changed since the last commit.
+    TooManyPending,
+    /// The key is reserved.
+    BadKey,
+    /// A tag from a newer peer.
+    Unknown(u8),
+}
+
+impl ConfigError {
+    /// This error's wire tag. Tags are never reused or renumbered.
+    pub fn tag(self) -> u8 {
+        use ConfigError::*;
+        match self {
+            Flash => 0,
+            Full => 1,
+            TooLarge => 2,
+            TooManyPending => 3,
+            BadKey => 4,
+            Unknown(tag) => tag,
+        }
+    }
+
+    fn from_tag(tag: u8) -> ConfigError {
+        use ConfigError::*;
+        match tag {
+            0 => Flash,
+            1 => Full,
+            2 => TooLarge,
+            3 => TooManyPending,
+            4 => BadKey,
+            _ => Unknown(tag),
+        }
+    }
+}
+
+impl Serialize for ConfigError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.tag())
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConfigError, D::Error> {
+        Ok(ConfigError::from_tag(u8::deserialize(deserializer)?))
+    }
+}
+
+/// A value of up to `MAX_VALUE_SIZE` bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ConfigData {
+    len: u8,
+    buf: [u8; MAX_VALUE_SIZE],
+}
+
+impl ConfigData {
+    pub fn new(data: &[u8]) -> Option<ConfigData> {
+        if data.len() > MAX_VALUE_SIZE {
+            return None;
+        }
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+        Some(ConfigData { len: data.len() as u8, buf })
+    }
+
+    /// Encode `value` as CBOR.
+    pub fn from_value<T: Serialize>(value: &T) -> Option<ConfigData> {
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        let mut ser = serde_cbor::Serializer::new(SliceWrite::new(&mut buf[..]));
+        value.serialize(&mut ser).ok()?;
+        let len = ser.into_inner().bytes_written();
+        ConfigData::new(&buf[..len])
+    }
+
+    /// Decode a value stored with `from_value`.
+    pub fn to_value<T: DeserializeOwned>(&self) -> Option<T> {
+        let mut scratch = [0u8; MAX_VALUE_SIZE];
+        serde_cbor::de::from_slice_with_scratch(self.as_bytes(), &mut scratch).ok()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for ConfigData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConfigData({:?})", self.as_bytes())
+    }
+}
+
+impl Serialize for ConfigData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConfigData, D::Error> {
+        struct DataVisitor;
+
+        impl<'de> Visitor<'de> for DataVisitor {
+            type Value = ConfigData;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "at most {} bytes", MAX_VALUE_SIZE)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ConfigData, E> {
+                ConfigData::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+
+            // Formats without a byte string type, such as JSON, send a list
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ConfigData, A::Error> {
+                let mut buf = [0u8; MAX_VALUE_SIZE];
+                let mut len = 0;
+                while let Some(b) = seq.next_element()? {
+                    if len == MAX_VALUE_SIZE {
+                        return Err(de::Error::invalid_length(len + 1, &self));
+                    }
+                    buf[len] = b;
+                    len += 1;
+                }
+                Ok(ConfigData { len: len as u8, buf })
+            }
+        }
+
+        deserializer.deserialize_bytes(DataVisitor)
+    }
+}
+
+struct Record {
+    key: u16,
+    // None for a tombstone
+    data: Option<ConfigData>,
+    size: usize,
+}
+
+fn record_size(data_len: usize) -> usize {
+    RECORD_HEADER_SIZE + data_len.div_ceil(2) * 2 + CRC_SIZE
+}
+
+pub struct ConfigStore<F: Flash> {
+    flash: F,
+    active: usize,
+    generation: u32,
+    // Where the next record goes in the active page
+    write_offset: usize,
+    // The active page holds a damaged record, e.g. from a power cut during
+    // a commit. It can't be appended to, so the next commit compacts.
+    damaged: bool,
+    pending: [Option<(u16, Option<ConfigData>)>; MAX_PENDING],
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Open the store in `flash`, formatting it if neither page holds one.
+    pub fn new(flash: F) -> Result<ConfigStore<F>, ConfigError> {
+        let mut store = ConfigStore {
+            flash,
+            active: 0,
+            generation: 0,
+            write_offset: HEADER_SIZE,
+            damaged: false,
+            pending: [None; MAX_PENDING],
+        };
+        let headers = [store.read_header(0)?, store.read_header(1)?];
+        match headers {
+            [Some(a), Some(b)] if b > a => store.mount(1, b)?,
+            [Some(a), _] => store.mount(0, a)?,
+            [None, Some(b)] => store.mount(1, b)?,
+            [None, None] => {
+                store.flash.erase_page(0).map_err(|_| ConfigError::Flash)?;
+                store.write_header(0, 1)?;
+                store.mount(0, 1)?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Give the flash back.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Current value of `key`, including changes not yet committed.
+    pub fn get(&mut self, key: u16) -> Result<Option<ConfigData>, ConfigError> {
+        if let Some((_, value)) = self.pending.iter().flatten().find(|(k, _)| *k == key) {
+            return Ok(*value);
+        }
+        let mut offset = HEADER_SIZE;
+        let mut value = None;
+        while let Some(record) = self.read_record(self.active, offset)? {
+            if record.key == key {
+                value = record.data;
+            }
+            offset += record.size;
+        }
+        Ok(value)
+    }
+
+    /// Stage a new value for `key`.
+    pub fn set(&mut self, key: u16, data: ConfigData) -> Result<(), ConfigError> {
+        self.stage(key, Some(data))
+    }
+
+    /// Stage removing `key`.
+    pub fn erase(&mut self, key: u16) -> Result<(), ConfigError> {
+        self.stage(key, None)
+    }
+
+    /// Number of keys changed since the last commit.
+    pub fn pending(&self) -> usize {
+        self.pending.iter().flatten().count()
+    }
+
+    /// Forget changes that haven't been committed.
+    pub fn discard(&mut self) {
+        self.pending = [None; MAX_PENDING];
+    }
+
+    /// Write staged changes to flash.
+    pub fn commit(&mut self) -> Result<(), ConfigError> {
+        if self.pending() == 0 {
+            return Ok(());
+        }
+        let needed: usize = self.pending.iter().flatten()
+            .map(|(_, data)| record_size(data.map_or(0, |d| d.len as usize)))
+            .sum();
+        if self.damaged || self.write_offset + needed > F::PAGE_SIZE {
+            return self.compact();
+        }
+        let pending = self.pending;
+        for (key, data) in pending.iter().flatten() {
+            match self.write_record(self.active, self.write_offset, *key, data.as_ref()) {
+                Ok(size) => self.write_offset += size,
+                Err(e) => {
+                    self.damaged = true;
+                    return Err(e);
+                }
+            }
+        }
+        self.discard();
+        Ok(())
+    }
+
+    fn stage(&mut self, key: u16, data: Option<ConfigData>) -> Result<(), ConfigError> {
+        if key == ERASED_KEY {
+            return Err(ConfigError::BadKey);
+        }
+        let slot = match self.pending.iter().position(|p| matches!(p, Some((k, _)) if *k == key)) {
+            Some(i) => i,
+            None => self.pending.iter().position(Option::is_none).ok_or(ConfigError::TooManyPending)?,
+        };
+        self.pending[slot] = Some((key, data));
+        Ok(())
+    }
+
+    fn mount(&mut self, page: usize, generation: u32) -> Result<(), ConfigError> {
+        self.active = page;
+        self.generation = generation;
+        self.damaged = false;
+        let mut offset = HEADER_SIZE;
+        while let Some(record) = self.read_record(page, offset)? {
+            offset += record.size;
+        }
+        self.write_offset = offset;
+        if offset + RECORD_HEADER_SIZE <= F::PAGE_SIZE {
+            let mut header = [0u8; RECORD_HEADER_SIZE];
+            self.read(page, offset, &mut header)?;
+            // Anything but erased flash after the last good record
+            self.damaged = header.iter().any(|b| *b != 0xFF);
+        }
+        Ok(())
+    }
+
+    // Copy the newest record of every key, plus the staged changes, into the
+    // other page and switch to it.
+    fn compact(&mut self) -> Result<(), ConfigError> {
+        let from = self.active;
+        let to = 1 - from;
+        self.flash.erase_page(to).map_err(|_| ConfigError::Flash)?;
+
+        let mut write_offset = HEADER_SIZE;
+        let mut offset = HEADER_SIZE;
+        while let Some(record) = self.read_record(from, offset)? {
+            offset += record.size;
+            let superseded = self.pending.iter().flatten().any(|(k, _)| *k == record.key)
+                || self.rewritten_after(from, offset, record.key)?;
+            if let (Some(data), false) = (record.data, superseded) {
+                write_offset += self.append(to, write_offset, record.key, &data)?;
+            }
+        }
+        let pending = self.pending;
+        for (key, data) in pending.iter().flatten() {
+            if let Some(data) = data {
+                write_offset += self.append(to, write_offset, *key, data)?;
+            }
+        }
+
+        self.write_header(to, self.generation.wrapping_add(1))?;
+        self.active = to;
+        self.generation = self.generation.wrapping_add(1);
+        self.write_offset = write_offset;
+        self.damaged = false;
+        self.discard();
+        // Harmless if this fails: the new page's higher generation wins
+        let _ = self.flash.erase_page(from);
+        Ok(())
+    }
+
+    fn append(&mut self, page: usize, offset: usize, key: u16, data: &ConfigData) -> Result<usize, ConfigError> {
+        if offset + record_size(data.len as usize) > F::PAGE_SIZE {
+            return Err(ConfigError::Full);
+        }
+        self.write_record(page, offset, key, Some(data))
+    }
+
+    fn rewritten_after(&mut self, page: usize, mut offset: usize, key: u16) -> Result<bool, ConfigError> {
+        while let Some(record) = self.read_record(page, offset)? {
+            if record.key == key {
+                return Ok(true);
+            }
+            offset += record.size;
+        }
+        Ok(false)
+    }
+
+    fn read(&mut self, page: usize, offset: usize, buf: &mut [u8]) -> Result<(), ConfigError> {
+        self.flash.read(page * F::PAGE_SIZE + offset, buf).map_err(|_| ConfigError::Flash)
+    }
+
+    fn read_header(&mut self, page: usize) -> Result<Option<u32>, ConfigError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.read(page, 0, &mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok(if magic == MAGIC { Some(generation) } else { None })
+    }
+
+    fn write_header(&mut self, page: usize, generation: u32) -> Result<(), ConfigError> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        self.flash.write(page * F::PAGE_SIZE, &header).map_err(|_| ConfigError::Flash)
+    }
+
+    // The record at `offset`, or None at the end of the log or at the first
+    // damaged record.
+    fn read_record(&mut self, page: usize, offset: usize) -> Result<Option<Record>, ConfigError> {
+        if offset + RECORD_HEADER_SIZE > F::PAGE_SIZE {
+            return Ok(None);
+        }
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        self.read(page, offset, &mut buf[..RECORD_HEADER_SIZE])?;
+        let key = u16::from_le_bytes([buf[0], buf[1]]);
+        let len = u16::from_le_bytes([buf[2], buf[3]]);
+        let data_len = (len & !TOMBSTONE) as usize;
+        if key == ERASED_KEY || data_len > MAX_VALUE_SIZE {
+            return Ok(None);
+        }
+        let size = record_size(data_len);
+        if offset + size > F::PAGE_SIZE {
+            return Ok(None);
+        }
+        self.read(page, offset + RECORD_HEADER_SIZE, &mut buf[RECORD_HEADER_SIZE..size])?;
+        let crc = u16::from_le_bytes([buf[size - 2], buf[size - 1]]);
+        if crc16(&buf[..size - CRC_SIZE]) != crc {
+            return Ok(None);
+        }
+        let data = if len & TOMBSTONE != 0 {
+            None
+        } else {
+            ConfigData::new(&buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + data_len])
+        };
+        Ok(Some(Record { key, data, size }))
+    }
+
+    fn write_record(&mut self, page: usize, offset: usize, key: u16, data: Option<&ConfigData>) -> Result<usize, ConfigError> {
+        let (len, bytes) = match data {
+            Some(data) => (data.len as u16, data.as_bytes()),
+            None => (TOMBSTONE, &[][..]),
+        };
+        let size = record_size(bytes.len());
+        if offset + size > F::PAGE_SIZE {
+            return Err(ConfigError::Full);
+        }
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        buf[..2].copy_from_slice(&key.to_le_bytes());
+        buf[2..4].copy_from_slice(&len.to_le_bytes());
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + bytes.len()].copy_from_slice(bytes);
+        let crc = crc16(&buf[..size - CRC_SIZE]);
+        buf[size - CRC_SIZE..size].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(page * F::PAGE_SIZE + offset, &buf[..size]).map_err(|_| ConfigError::Flash)?;
+        Ok(size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamFlashError {
+    OutOfRange,
+    Unaligned,
+    /// Tried to program a half-word that wasn't erased.
+    NotErased,
+    /// The simulated power cut set up by `RamFlash::cut_power_after` hit.
+    PowerCut,
+}
+
+/// Flash simulated in RAM, for running `ConfigStore` on the host.
+pub struct RamFlash<const PAGE: usize> {
+    pages: [[u8; PAGE]; 2],
+    /// How many times each page was erased.
+    pub erases: [u32; 2],
+    power: Option<usize>,
+}
+
+impl<const PAGE: usize> RamFlash<PAGE> {
+    /// Fresh from the factory: fully erased.
+    pub fn new() -> RamFlash<PAGE> {
+        RamFlash { pages: [[0xFF; PAGE]; 2], erases: [0; 2], power: None }
+    }
+
+    /// Let `half_words` more half-words be programmed, then fail every
+    /// write and erase, as if the power went out.
+    pub fn cut_power_after(&mut self, half_words: usize) {
+        self.power = Some(half_words);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power = None;
+    }
+}
+
+impl<const PAGE: usize> Default for RamFlash<PAGE> {
+    fn default() -> RamFlash<PAGE> {
+        RamFlash::new()
+    }
+}
+
+impl<const PAGE: usize> Flash for RamFlash<PAGE> {
+    type Error = RamFlashError;
+
+    const PAGE_SIZE: usize = PAGE;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), RamFlashError> {
+        if offset + buf.len() > 2 * PAGE {
+            return Err(RamFlashError::OutOfRange);
+        }
+        for (i, b) in buf.iter_mut().enumerate() {
+            let at = offset + i;
+            *b = self.pages[at / PAGE][at % PAGE];
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), RamFlashError> {
+        if !offset.is_multiple_of(2) || !data.len().is_multiple_of(2) {
+            return Err(RamFlashError::Unaligned);
+        }
+        if offset + data.len() > 2 * PAGE {
+            return Err(RamFlashError::OutOfRange);
+        }
+        for (i, half_word) in data.chunks(2).enumerate() {
+            if let Some(left) = self.power.as_mut() {
+                if *left == 0 {
+                    return Err(RamFlashError::PowerCut);
+                }
+                *left -= 1;
+            }
+            let at = offset + 2 * i;
+            let page = &mut self.pages[at / PAGE];
+            let at = at % PAGE;
+            if page[at] != 0xFF || page[at + 1] != 0xFF {
+                return Err(RamFlashError::NotErased);
+            }
+            page[at..at + 2].copy_from_slice(half_word);
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), RamFlashError> {
+        if page > 1 {
+            return Err(RamFlashError::OutOfRange);
+        }
+        if self.power == Some(0) {
+            return Err(RamFlashError::PowerCut);
+        }
+        self.pages[page] = [0xFF; PAGE];
+        self.erases[page] += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::MagCalibration;
+
+    type SmallFlash = RamFlash<256>;
+
+    fn data(bytes: &[u8]) -> ConfigData {
+        ConfigData::new(bytes).unwrap()
+    }
+
+    fn reopen(store: ConfigStore<SmallFlash>) -> ConfigStore<SmallFlash> {
+        ConfigStore::new(store.release()).unwrap()
+    }
+
+    #[test]
+    fn set_commit_get() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        assert_eq!(store.get(1).unwrap(), None);
+        store.set(1, data(b"one")).unwrap();
+        store.set(2, data(b"two")).unwrap();
+        // Staged values are visible straight away
+        assert_eq!(store.get(1).unwrap(), Some(data(b"one")));
+        assert_eq!(store.pending(), 2);
+        store.commit().unwrap();
+        assert_eq!(store.pending(), 0);
+
+        let mut store = reopen(store);
+        assert_eq!(store.get(1).unwrap(), Some(data(b"one")));
+        assert_eq!(store.get(2).unwrap(), Some(data(b"two")));
+        assert_eq!(store.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn uncommitted_changes_are_lost() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        store.set(1, data(b"kept")).unwrap();
+        store.commit().unwrap();
+        store.set(1, data(b"lost")).unwrap();
+        store.erase(1).unwrap();
+        assert_eq!(store.get(1).unwrap(), None);
+        let mut store = reopen(store);
+        assert_eq!(store.get(1).unwrap(), Some(data(b"kept")));
+    }
+
+    #[test]
+    fn erase() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        store.set(7, data(b"gone soon")).unwrap();
+        store.commit().unwrap();
+        store.erase(7).unwrap();
+        store.commit().unwrap();
+        let mut store = reopen(store);
+        assert_eq!(store.get(7).unwrap(), None);
+    }
+
+    #[test]
+    fn compaction_levels_wear() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        store.set(1, data(b"constant")).unwrap();
+        for i in 0..200u16 {
+            store.set(2, data(&i.to_le_bytes())).unwrap();
+            store.commit().unwrap();
+        }
+        let mut store = reopen(store);
+        assert_eq!(store.get(1).unwrap(), Some(data(b"constant")));
+        assert_eq!(store.get(2).unwrap(), Some(data(&199u16.to_le_bytes())));
+        let flash = store.release();
+        // Both pages take their turn, and each was erased far less often
+        // than there were commits.
+        assert!(flash.erases[0] > 5 && flash.erases[1] > 5, "{:?}", flash.erases);
+        assert!(flash.erases[0] + flash.erases[1] < 40, "{:?}", flash.erases);
+    }
+
+    #[test]
+    fn full() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        for key in 0..3 {
+            store.set(key, data(&[key as u8; MAX_VALUE_SIZE])).unwrap();
+        }
+        store.commit().unwrap();
+        store.set(3, data(&[3; MAX_VALUE_SIZE])).unwrap();
+        assert_eq!(store.commit(), Err(ConfigError::Full));
+        // Nothing committed is lost, and freeing space lets it through
+        store.erase(0).unwrap();
+        store.commit().unwrap();
+        let mut store = reopen(store);
+        assert_eq!(store.get(0).unwrap(), None);
+        assert_eq!(store.get(2).unwrap(), Some(data(&[2; MAX_VALUE_SIZE])));
+        assert_eq!(store.get(3).unwrap(), Some(data(&[3; MAX_VALUE_SIZE])));
+    }
+
+    #[test]
+    fn limits() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        assert_eq!(store.set(ERASED_KEY, data(b"x")), Err(ConfigError::BadKey));
+        for key in 0..MAX_PENDING as u16 {
+            store.set(key, data(b"x")).unwrap();
+        }
+        // Changing an already staged key doesn't need another slot
+        store.set(0, data(b"y")).unwrap();
+        assert_eq!(store.set(100, data(b"x")), Err(ConfigError::TooManyPending));
+        assert!(ConfigData::new(&[0; MAX_VALUE_SIZE + 1]).is_none());
+    }
+
+    #[test]
+    fn power_cut_during_commit() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        store.set(1, data(b"before")).unwrap();
+        store.commit().unwrap();
+        for cut in 0..6 {
+            let mut flash = store.release();
+            flash.cut_power_after(cut);
+            let mut store_cut = ConfigStore::new(flash).unwrap();
+            store_cut.set(1, data(b"after")).unwrap();
+            assert!(store_cut.commit().is_err());
+            let mut flash = store_cut.release();
+            flash.restore_power();
+
+            // Back on: the old value is intact and the store still works
+            let mut restarted = ConfigStore::new(flash).unwrap();
+            assert_eq!(restarted.get(1).unwrap(), Some(data(b"before")), "cut after {}", cut);
+            restarted.set(2, data(b"later")).unwrap();
+            restarted.commit().unwrap();
+            let mut restarted = reopen(restarted);
+            assert_eq!(restarted.get(1).unwrap(), Some(data(b"before")));
+            restarted.erase(2).unwrap();
+            restarted.commit().unwrap();
+            store = restarted;
+        }
+    }
+
+    #[test]
+    fn power_cut_during_compaction() {
+        let mut store = ConfigStore::new(SmallFlash::new()).unwrap();
+        store.set(1, data(&[1; MAX_VALUE_SIZE])).unwrap();
+        store.set(2, data(&[2; MAX_VALUE_SIZE])).unwrap();
+        store.commit().unwrap();
+        store.set(2, data(&[3; MAX_VALUE_SIZE])).unwrap();
+        store.commit().unwrap();
+        // The next commit doesn't fit and has to compact; stop it halfway
+        let mut flash = store.release();
+        flash.cut_power_after(40);
+        let mut store = ConfigStore::new(flash).unwrap();
+        store.set(3, data(&[4; MAX_VALUE_SIZE])).unwrap();
+        assert!(store.commit().is_err());
+        let mut flash = store.release();
+        flash.restore_power();
+
+        let mut store = ConfigStore::new(flash).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(data(&[1; MAX_VALUE_SIZE])));
+        assert_eq!(store.get(2).unwrap(), Some(data(&[3; MAX_VALUE_SIZE])));
+        assert_eq!(store.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn values() {
+        let cal = MagCalibration { offset: [1., 2., 3.], ..MagCalibration::IDENTITY };
+        let stored = ConfigData::from_value(&cal).unwrap();
+        assert_eq!(stored.to_value::<MagCalibration>(), Some(cal));
+        assert_eq!(ConfigData::from_value(&50u16).unwrap().to_value::<u16>(), Some(50));
+        assert_eq!(stored.to_value::<u16>(), None);
+    }
+
+    #[test]
+    fn error_tags() {
+        // The exact bytes of each error; see `Message`'s golden tests
+        let golden = [
+            (ConfigError::Flash, [0x00]),
+            (ConfigError::Full, [0x01]),
+            (ConfigError::TooLarge, [0x02]),
+            (ConfigError::TooManyPending, [0x03]),
+            (ConfigError::BadKey, [0x04]),
+        ];
+        for (error, bytes) in golden.iter() {
+            let stored = ConfigData::from_value(error).unwrap();
+            assert_eq!(stored.as_bytes(), bytes, "{:?}", error);
+            assert_eq!(stored.to_value::<ConfigError>(), Some(*error));
+        }
+        // An error from a newer peer
+        assert_eq!(data(&[0x18, 0xc8]).to_value::<ConfigError>(), Some(ConfigError::Unknown(200)));
+    }
+}
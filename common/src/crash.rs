@@ -0,0 +1,174 @@
+//! How the board's last boot ended, when it ended in a crash.
+//!
+//! The firmware keeps a `CrashReport` in RAM that survives the reset after
+//! a panic or a HardFault, and sends it to the host on the next boot.
+
+use core::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::message::{BoundedText, LogText};
+
+/// Longest source path a `CrashReport` carries. Longer paths keep their
+/// end, which names the file.
+pub const MAX_CRASH_FILE_SIZE: usize = 48;
+
+/// The source file a panic was raised in.
+pub type CrashFile = BoundedText<MAX_CRASH_FILE_SIZE>;
+
+/// Goes on the wire as its tag, see `CrashCause::tag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashCause {
+    Panic,
+    HardFault,
+    /// A tag from a newer peer.
+    Unknown(u8),
+}
+
+impl CrashCause {
+    /// This cause's wire tag. Tags are never reused or renumbered.
+    pub fn tag(self) -> u8 {
+        match self {
+            CrashCause::Panic => 0,
+            CrashCause::HardFault => 1,
+            CrashCause::Unknown(tag) => tag,
+        }
+    }
+
+    fn from_tag(tag: u8) -> CrashCause {
+        match tag {
+            0 => CrashCause::Panic,
+            1 => CrashCause::HardFault,
+            _ => CrashCause::Unknown(tag),
+        }
+    }
+}
+
+impl Serialize for CrashCause {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.tag())
+    }
+}
+
+impl<'de> Deserialize<'de> for CrashCause {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CrashCause, D::Error> {
+        Ok(CrashCause::from_tag(u8::deserialize(deserializer)?))
+    }
+}
+
+/// The registers the core stacked on taking a fault that point to where it
+/// was.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExceptionFrame {
+    pub pc: u32,
+    pub lr: u32,
+    pub xpsr: u32,
+}
+
+impl Serialize for ExceptionFrame {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.pc, self.lr, self.xpsr).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExceptionFrame {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ExceptionFrame, D::Error> {
+        let (pc, lr, xpsr) = Deserialize::deserialize(deserializer)?;
+        Ok(ExceptionFrame { pc, lr, xpsr })
+    }
+}
+
+/// Goes on the wire as an array rather than a map, to fit a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrashReport {
+    pub cause: CrashCause,
+    /// What the panic said. Empty for a fault.
+    pub message: LogText,
+    /// Where the panic was raised. Empty and 0 for a fault.
+    pub file: CrashFile,
+    pub line: u32,
+    /// Where the fault was taken. `None` for a panic.
+    pub frame: Option<ExceptionFrame>,
+}
+
+impl CrashReport {
+    pub fn panic(message: LogText, file: &str, line: u32) -> CrashReport {
+        let file = &file.as_bytes()[file.len().saturating_sub(MAX_CRASH_FILE_SIZE)..];
+        CrashReport { cause: CrashCause::Panic, message, file: CrashFile::new(file), line, frame: None }
+    }
+
+    pub fn hard_fault(frame: ExceptionFrame) -> CrashReport {
+        CrashReport {
+            cause: CrashCause::HardFault,
+            message: LogText::EMPTY,
+            file: CrashFile::EMPTY,
+            line: 0,
+            frame: Some(frame),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn text(text: &[u8]) -> &str {
+            core::str::from_utf8(text).unwrap_or("(not UTF-8)")
+        }
+        match self.cause {
+            CrashCause::Panic => write!(f, "panic at {}:{}", text(self.file.as_bytes()), self.line)?,
+            CrashCause::HardFault => write!(f, "HardFault")?,
+            CrashCause::Unknown(tag) => write!(f, "crash of unknown cause {}", tag)?,
+        }
+        if let Some(frame) = self.frame {
+            write!(f, " with PC {:#010x}, LR {:#010x}, xPSR {:#010x}", frame.pc, frame.lr, frame.xpsr)?;
+        }
+        if !self.message.as_bytes().is_empty() {
+            write!(f, ": {}", text(self.message.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for CrashReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.cause, &self.message, &self.file, self.line, self.frame).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CrashReport {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CrashReport, D::Error> {
+        let (cause, message, file, line, frame) = Deserialize::deserialize(deserializer)?;
+        Ok(CrashReport { cause, message, file, line, frame })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_paths_keep_their_end() {
+        let path = "/home/someone/.cargo/registry/src/github.com-1ecc6299db9ec823/l3gd20-0.3.0/src/lib.rs";
+        let report = CrashReport::panic(LogText::new(b"oops"), path, 7);
+        assert_eq!(report.file.as_bytes(), &path.as_bytes()[path.len() - MAX_CRASH_FILE_SIZE..]);
+        assert!(report.to_string().ends_with("l3gd20-0.3.0/src/lib.rs:7: oops"));
+    }
+
+    #[test]
+    fn display() {
+        let report = CrashReport::hard_fault(ExceptionFrame { pc: 0x0800_1234, lr: 0x0800_0101, xpsr: 0x6100_0000 });
+        assert_eq!(report.to_string(), "HardFault with PC 0x08001234, LR 0x08000101, xPSR 0x61000000");
+    }
+
+    #[test]
+    fn cause_tags() {
+        // The exact bytes of each cause; see `Message`'s golden tests
+        let golden = [
+            (CrashCause::Panic, vec![0x00]),
+            (CrashCause::HardFault, vec![0x01]),
+            (CrashCause::Unknown(200), vec![0x18, 0xc8]),
+        ];
+        for (cause, bytes) in golden.iter() {
+            assert_eq!(&serde_cbor::to_vec(cause).unwrap(), bytes, "{:?}", cause);
+            assert_eq!(serde_cbor::from_slice::<CrashCause>(bytes).unwrap(), *cause);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! What a board tells the host about itself in `HelloAck`.
+
+use crate::message::Message;
+use crate::sensors::{Sensors, MAX_RATE_HZ, MIN_RATE_HZ};
+use core::fmt;
+use core::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub type ProtocolVersion = u16;
+
+/// Bumped whenever `Message` changes in a way older builds can't follow,
+/// see `message::Message`. Version 2 moved to numbered tags.
+pub const PROTOCOL_VERSION: ProtocolVersion = 2;
+/// Oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = 2;
+
+/// Whether this build can talk to a peer speaking protocol `version`.
+pub fn protocol_supported(version: ProtocolVersion) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Goes on the wire as an array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl FirmwareVersion {
+    /// Parse a `major.minor.patch` version such as `CARGO_PKG_VERSION`,
+    /// ignoring any pre-release or build suffix.
+    pub fn parse(version: &str) -> Option<FirmwareVersion> {
+        let version = version.split(['-', '+']).next()?;
+        let mut parts = version.split('.').map(|p| p.parse().ok());
+        let v = FirmwareVersion {
+            major: parts.next()??,
+            minor: parts.next()??,
+            patch: parts.next()??,
+        };
+        match parts.next() {
+            None => Some(v),
+            Some(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Serialize for FirmwareVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.major, self.minor, self.patch).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FirmwareVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FirmwareVersion, D::Error> {
+        let (major, minor, patch) = Deserialize::deserialize(deserializer)?;
+        Ok(FirmwareVersion { major, minor, patch })
+    }
+}
+
+/// The STM32's factory-programmed 96-bit unique device ID. The board also
+/// uses it, in hex, as its USB serial number.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Uid(pub [u32; 3]);
+
+impl Uid {
+    /// Length of the hex form.
+    pub const HEX_LEN: usize = 24;
+
+    /// Write the ID as upper-case hex, most significant word first.
+    pub fn to_hex<'a>(&self, buf: &'a mut [u8; Uid::HEX_LEN]) -> &'a str {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        for (i, word) in self.0.iter().rev().enumerate() {
+            for nibble in 0..8 {
+                buf[8 * i + nibble] = DIGITS[(word >> (28 - 4 * nibble) & 0xF) as usize];
+            }
+        }
+        // Only ASCII digits were written
+        core::str::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = [0u8; Uid::HEX_LEN];
+        f.write_str(self.to_hex(&mut buf))
+    }
+}
+
+impl FromStr for Uid {
+    type Err = ();
+
+    /// Parse the hex form, e.g. a USB serial number.
+    fn from_str(s: &str) -> Result<Uid, ()> {
+        if s.len() != Uid::HEX_LEN || !s.is_ascii() {
+            return Err(());
+        }
+        let word = |i: usize| u32::from_str_radix(&s[8 * i..8 * i + 8], 16).map_err(|_| ());
+        Ok(Uid([word(2)?, word(1)?, word(0)?]))
+    }
+}
+
+/// Goes on the wire as an array, in field order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub protocol_version: ProtocolVersion,
+    pub firmware_version: FirmwareVersion,
+    pub uid: Uid,
+    /// Sensors that answered at boot.
+    pub sensors: Sensors,
+    /// Largest encoded `Envelope` the board sends or accepts, in bytes.
+    pub max_message_size: u16,
+    pub min_rate_hz: u16,
+    pub max_rate_hz: u16,
+}
+
+impl DeviceInfo {
+    /// Describe a board built from this version of `common`.
+    pub fn new(firmware_version: FirmwareVersion, uid: Uid, sensors: Sensors) -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version,
+            uid,
+            sensors,
+            max_message_size: Message::MAX_SIZE as u16,
+            min_rate_hz: MIN_RATE_HZ,
+            max_rate_hz: MAX_RATE_HZ,
+        }
+    }
+
+    /// Clamp a requested sample rate to what this board supports.
+    pub fn clamp_rate(&self, rate_hz: u16) -> u16 {
+        rate_hz.max(self.min_rate_hz).min(self.max_rate_hz)
+    }
+}
+
+impl Serialize for DeviceInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (
+            self.protocol_version,
+            self.firmware_version,
+            self.uid,
+            self.sensors,
+            self.max_message_size,
+            self.min_rate_hz,
+            self.max_rate_hz,
+        )
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceInfo, D::Error> {
+        let (protocol_version, firmware_version, uid, sensors, max_message_size, min_rate_hz, max_rate_hz) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(DeviceInfo { protocol_version, firmware_version, uid, sensors, max_message_size, min_rate_hz, max_rate_hz })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version() {
+        let v = FirmwareVersion { major: 1, minor: 22, patch: 3 };
+        assert_eq!(FirmwareVersion::parse("1.22.3"), Some(v));
+        assert_eq!(FirmwareVersion::parse("1.22.3-rc.1"), Some(v));
+        assert_eq!(FirmwareVersion::parse("1.22.3+dirty"), Some(v));
+        assert_eq!(FirmwareVersion::parse("1.22"), None);
+        assert_eq!(FirmwareVersion::parse("1.22.3.4"), None);
+        assert_eq!(FirmwareVersion::parse("one.2.3"), None);
+    }
+
+    #[test]
+    fn uid_display() {
+        let uid = Uid([0x0011_2233, 0x4455_6677, 0x8899_AABB]);
+        assert_eq!(format!("{}", uid), "8899AABB4455667700112233");
+        assert_eq!("8899AABB4455667700112233".parse(), Ok(uid));
+        assert_eq!("8899aabb4455667700112233".parse(), Ok(uid));
+        assert_eq!("8899AABB44556677001122".parse::<Uid>(), Err(()));
+        assert_eq!("8899AABB44556677001122XY".parse::<Uid>(), Err(()));
+    }
+
+    #[test]
+    fn supported() {
+        assert!(protocol_supported(PROTOCOL_VERSION));
+        assert!(!protocol_supported(PROTOCOL_VERSION + 1));
+        assert!(!protocol_supported(MIN_PROTOCOL_VERSION - 1));
+    }
+}
@@ -0,0 +1,404 @@
+//! Answering the host. The firmware, the bootloader and the mock board all
+//! run these dispatches over their own `Hardware`, so they can't answer a
+//! request differently.
+
+use crate::{
+    calibration::{AccelCalibration, MagCalibration},
+    config::{keys, ConfigError, ConfigStore, Flash},
+    crash::CrashReport,
+    device::DeviceInfo,
+    heading::heading,
+    message_queue::QueueStats,
+    sensors::{clamp_rate, mag_gauss},
+    update::{ImageInfo, Slots, UpdateError, Updater},
+    Envelope, Message, Sensors, Stamp,
+};
+use log::warn;
+use serde::de::DeserializeOwned;
+
+/// Sensor sample rate when nobody is subscribed, unless the config store
+/// says otherwise.
+pub const IDLE_RATE_HZ: u16 = 1;
+
+/// Where to go after a reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Bootloader,
+    Application,
+}
+
+/// What the application and the bootloader both need from the board.
+pub trait Hardware {
+    /// Queue `env` for the host. Returns whether there was room for it,
+    /// which anything but a sensor sample gets by dropping a queued sample
+    /// (see `message_queue::OverflowPolicy`).
+    fn push(&mut self, env: Envelope) -> bool;
+    /// Describe the board, with no sensors: the application fills in the
+    /// ones that answered.
+    fn device_info(&self) -> DeviceInfo;
+    fn stats(&self) -> QueueStats;
+    /// Reset into `target` once every queued reply is sent.
+    fn reset(&mut self, target: Target);
+}
+
+/// What the application needs from the board on top of `Hardware`.
+pub trait AppHardware: Hardware {
+    type Flash: Flash;
+
+    /// Microseconds since the board booted.
+    fn now_us(&self) -> u64;
+    /// Sample the sensors at `rate_hz`, starting a full period from now.
+    fn set_sample_rate(&mut self, rate_hz: u16);
+    /// The config store, unless it couldn't be opened at boot.
+    fn config(&mut self) -> Option<&mut ConfigStore<Self::Flash>>;
+    /// The image the bootloader last installed, if it installed one.
+    fn installed(&mut self) -> Option<ImageInfo>;
+    /// Tell the bootloader this image boots well enough to keep, if it's on
+    /// trial after an update.
+    fn confirm_boot(&mut self);
+}
+
+/// What the bootloader needs from the board on top of `Hardware`.
+pub trait BootHardware: Hardware {
+    type Slots: Slots;
+
+    fn updater(&mut self) -> &mut Updater<Self::Slots>;
+}
+
+/// A sensor's latest calibrated reading, and when it was taken.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reading<T> {
+    pub stamp: Stamp,
+    pub value: [T; 3],
+}
+
+pub struct Subscription {
+    pub seq: u16,
+    pub sensors: Sensors,
+}
+
+/// Everything the application keeps between requests, apart from the
+/// hardware it runs on.
+pub struct AppState {
+    pub accel: Reading<f32>,
+    pub mag: Reading<i16>,
+    pub gyro: Reading<f32>,
+    pub accel_calibration: AccelCalibration,
+    pub mag_calibration: MagCalibration,
+    pub subscription: Option<Subscription>,
+    /// The host has said `Hello` since it last went away, so there's
+    /// someone to send log records to.
+    pub host_connected: bool,
+    /// How the last boot ended, and whether a host has been told yet.
+    pub crash: Option<CrashReport>,
+    pub crash_reported: bool,
+    pub idle_rate_hz: u16,
+    /// Sensors that answered at boot.
+    pub sensors: Sensors,
+}
+
+impl AppState {
+    pub fn new(sensors: Sensors, crash: Option<CrashReport>) -> AppState {
+        AppState {
+            accel: Reading::default(),
+            mag: Reading::default(),
+            gyro: Reading::default(),
+            accel_calibration: AccelCalibration::IDENTITY,
+            mag_calibration: MagCalibration::IDENTITY,
+            subscription: None,
+            host_connected: false,
+            crash,
+            crash_reported: false,
+            idle_rate_hz: IDLE_RATE_HZ,
+            sensors,
+        }
+    }
+
+    /// Load the stored settings, at boot and after every commit. Anything
+    /// not stored goes back to its default.
+    pub fn apply_config<F: Flash>(&mut self, mut config: Option<&mut ConfigStore<F>>) {
+        self.mag_calibration = stored(&mut config, keys::MAG_CALIBRATION).unwrap_or_default();
+        self.accel_calibration = stored(&mut config, keys::ACCEL_CALIBRATION).unwrap_or_default();
+        self.idle_rate_hz = stored(&mut config, keys::IDLE_RATE_HZ).map_or(IDLE_RATE_HZ, clamp_rate);
+    }
+
+    /// Push the latest readings to the subscriber, if there is one.
+    pub fn push_samples<H: Hardware>(&self, hw: &mut H) {
+        if let Some(sub) = &self.subscription {
+            if sub.sensors.contains(Sensors::ACCEL) {
+                hw.push(Envelope::new(sub.seq, self.accel_message()));
+            }
+            if sub.sensors.contains(Sensors::MAG) {
+                hw.push(Envelope::new(sub.seq, self.mag_message()));
+            }
+            if sub.sensors.contains(Sensors::GYRO) {
+                hw.push(Envelope::new(sub.seq, self.gyro_message()));
+            }
+        }
+    }
+
+    pub fn device_info<H: Hardware>(&self, hw: &H) -> DeviceInfo {
+        DeviceInfo { sensors: self.sensors, ..hw.device_info() }
+    }
+
+    fn accel_message(&self) -> Message {
+        let [x, y, z] = self.accel.value;
+        Message::Accel(self.accel.stamp, x, y, z)
+    }
+
+    fn mag_message(&self) -> Message {
+        let [x, y, z] = self.mag.value;
+        Message::Mag(self.mag.stamp, x, y, z)
+    }
+
+    fn gyro_message(&self) -> Message {
+        let [x, y, z] = self.gyro.value;
+        Message::Gyro(self.gyro.stamp, x, y, z)
+    }
+
+    fn heading_message(&self) -> Message {
+        let [x, y, z] = self.mag.value;
+        let heading = heading(self.accel.value, mag_gauss(x, y, z));
+        Message::Heading(heading.unwrap_or(f32::NAN))
+    }
+
+    /// Answer a request from the host to the application.
+    pub fn process_message<H: AppHardware>(&mut self, env: Envelope, hw: &mut H) {
+        use Message::*;
+        match env.msg {
+            Nop => (),
+            Hello => {
+                self.host_connected = true;
+                hw.confirm_boot();
+                answer(hw, env.reply(HelloAck(self.device_info(hw))));
+                if let (Some(report), false) = (self.crash, self.crash_reported) {
+                    // Try again after the next `Hello` if there's no room
+                    self.crash_reported = hw.push(CrashReport(Some(report)).into());
+                }
+            }
+            HelloAck(_) => (),
+            AccelReq => {
+                answer(hw, env.reply(self.accel_message()));
+            }
+            MagReq => {
+                answer(hw, env.reply(self.mag_message()));
+            }
+            GyroReq => {
+                answer(hw, env.reply(self.gyro_message()));
+            }
+            HeadingReq => {
+                answer(hw, env.reply(self.heading_message()));
+            }
+            Subscribe { sensors, rate_hz } => {
+                let rate_hz = clamp_rate(rate_hz);
+                hw.set_sample_rate(rate_hz);
+                // Sensors that didn't answer at boot are quietly left out
+                self.subscription = Some(Subscription { seq: env.seq, sensors: sensors & self.sensors });
+                answer(hw, env.reply(SubscribeAck(rate_hz)));
+            }
+            Unsubscribe => {
+                self.subscription = None;
+                hw.set_sample_rate(self.idle_rate_hz);
+            }
+            TimeReq => {
+                answer(hw, env.reply(Time(hw.now_us())));
+            }
+            SetMagCalibration(cal) => {
+                self.mag_calibration = cal;
+                answer(hw, env.reply(Ack));
+            }
+            SetAccelCalibration(cal) => {
+                self.accel_calibration = cal;
+                answer(hw, env.reply(Ack));
+            }
+            ConfigGet(key) => {
+                let reply = match hw.config().ok_or(ConfigError::Flash).and_then(|c| c.get(key)) {
+                    Ok(data) => Config(key, data),
+                    Err(e) => ConfigFailed(e),
+                };
+                answer(hw, env.reply(reply));
+            }
+            ConfigSet(key, data) => {
+                let result = hw.config().ok_or(ConfigError::Flash).and_then(|c| c.set(key, data));
+                answer(hw, env.reply(Message::config_reply(result)));
+            }
+            ConfigErase(key) => {
+                let result = hw.config().ok_or(ConfigError::Flash).and_then(|c| c.erase(key));
+                answer(hw, env.reply(Message::config_reply(result)));
+            }
+            ConfigCommit => {
+                let result = hw.config().ok_or(ConfigError::Flash).and_then(|c| c.commit());
+                if result.is_ok() {
+                    self.apply_config(hw.config());
+                    if self.subscription.is_none() {
+                        hw.set_sample_rate(self.idle_rate_hz);
+                    }
+                }
+                answer(hw, env.reply(Message::config_reply(result)));
+            }
+            CrashReportReq => {
+                answer(hw, env.reply(CrashReport(self.crash)));
+            }
+            RebootToBootloader => {
+                answer(hw, env.reply(Ack));
+                hw.reset(Target::Bootloader);
+            }
+            Reboot => {
+                answer(hw, env.reply(Ack));
+                hw.reset(Target::Application);
+            }
+            UpdateStatusReq => {
+                let installed = hw.installed();
+                answer(hw, env.reply(UpdateStatus(crate::update::UpdateStatus::Application(installed))));
+            }
+            UpdateBegin(_) | UpdateChunk { .. } | UpdateFinish => {
+                answer(hw, env.reply(UpdateFailed(UpdateError::NotInBootloader)));
+            }
+            StatsReq => {
+                answer(hw, env.reply(Stats(hw.stats())));
+            }
+            Unknown(tag) => {
+                answer(hw, env.reply(Unknown(tag)));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn stored<F: Flash, T: DeserializeOwned>(config: &mut Option<&mut ConfigStore<F>>, key: u16) -> Option<T> {
+    config.as_mut()?.get(key).ok().flatten()?.to_value()
+}
+
+/// Answer a request from the host to the bootloader.
+pub fn process_boot_message<H: BootHardware>(env: Envelope, hw: &mut H) {
+    use Message::*;
+    let reply = match env.msg {
+        Hello => HelloAck(hw.device_info()),
+        RebootToBootloader => Ack,
+        Reboot => {
+            hw.reset(Target::Application);
+            Ack
+        }
+        UpdateStatusReq => UpdateStatus(hw.updater().status()),
+        UpdateBegin(image) => Message::update_reply(hw.updater().begin(image)),
+        UpdateChunk { offset, data } => Message::update_reply(hw.updater().chunk(offset, data.as_bytes())),
+        UpdateFinish => Message::update_reply(hw.updater().finish()),
+        StatsReq => Stats(hw.stats()),
+        Unknown(tag) => Unknown(tag),
+        // Sensors and config are the application's business
+        _ => return,
+    };
+    answer(hw, Envelope::new(env.seq, reply));
+}
+
+// Queue a reply. As it pushes out a sample if it has to, this only fails
+// with the queue full of other replies and logs
+fn answer<H: Hardware>(hw: &mut H, reply: Envelope) {
+    let seq = reply.seq;
+    if !hw.push(reply) {
+        warn!("No room to answer request {}", seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RamFlash;
+    use crate::device::{FirmwareVersion, Uid};
+    use crate::message::LogText;
+    use crate::MessageQueue;
+
+    struct TestHardware {
+        queue: MessageQueue,
+        config: ConfigStore<RamFlash<256>>,
+    }
+
+    impl TestHardware {
+        fn new() -> TestHardware {
+            TestHardware { queue: MessageQueue::new(), config: ConfigStore::new(RamFlash::new()).unwrap() }
+        }
+
+        fn replies(&mut self) -> Vec<Message> {
+            core::iter::from_fn(|| self.queue.dequeue()).map(|env| env.msg).collect()
+        }
+    }
+
+    impl Hardware for TestHardware {
+        fn push(&mut self, env: Envelope) -> bool {
+            self.queue.enqueue(&env).is_ok()
+        }
+
+        fn device_info(&self) -> DeviceInfo {
+            DeviceInfo::new(FirmwareVersion::default(), Uid([1, 2, 3]), Sensors::NONE)
+        }
+
+        fn stats(&self) -> QueueStats {
+            self.queue.stats()
+        }
+
+        fn reset(&mut self, _target: Target) {}
+    }
+
+    impl AppHardware for TestHardware {
+        type Flash = RamFlash<256>;
+
+        fn now_us(&self) -> u64 {
+            0
+        }
+
+        fn set_sample_rate(&mut self, _rate_hz: u16) {}
+
+        fn config(&mut self) -> Option<&mut ConfigStore<RamFlash<256>>> {
+            Some(&mut self.config)
+        }
+
+        fn installed(&mut self) -> Option<ImageInfo> {
+            None
+        }
+
+        fn confirm_boot(&mut self) {}
+    }
+
+    #[test]
+    fn subscribe_leaves_out_missing_sensors() {
+        let mut hw = TestHardware::new();
+        let mut app = AppState::new(Sensors::ACCEL | Sensors::MAG, None);
+        app.process_message(Envelope::new(3, Message::Subscribe { sensors: Sensors::ALL, rate_hz: 10 }), &mut hw);
+        assert_eq!(hw.replies(), vec![Message::SubscribeAck(10)]);
+        app.push_samples(&mut hw);
+        let replies = hw.replies();
+        assert_eq!(replies.len(), 2);
+        assert!(!replies.iter().any(|msg| matches!(msg, Message::Gyro(..))));
+    }
+
+    #[test]
+    fn crash_report_waits_for_room() {
+        let mut hw = TestHardware::new();
+        let report = CrashReport::panic(LogText::new(b"oops"), "src/main.rs", 7);
+        let mut app = AppState::new(Sensors::ALL, Some(report));
+        // Only room for the `HelloAck`
+        while hw.queue.available_empty() > 1 {
+            hw.push(Message::Nop.into());
+        }
+        app.process_message(Message::Hello.into(), &mut hw);
+        assert!(!hw.replies().contains(&Message::CrashReport(Some(report))));
+        app.process_message(Message::Hello.into(), &mut hw);
+        assert_eq!(hw.replies().last(), Some(&Message::CrashReport(Some(report))));
+    }
+
+    #[test]
+    fn replies_get_past_samples() {
+        let mut hw = TestHardware::new();
+        let mut app = AppState::new(Sensors::ALL, None);
+        app.process_message(Envelope::new(3, Message::Subscribe { sensors: Sensors::ALL, rate_hz: 10 }), &mut hw);
+        hw.replies();
+        while hw.queue.available_empty() > 0 {
+            app.push_samples(&mut hw);
+        }
+        let dropped = hw.stats().dropped;
+        app.process_message(Envelope::new(4, Message::TimeReq), &mut hw);
+        // In place of a sample
+        assert_eq!(hw.stats().dropped, dropped + 1);
+        assert_eq!(hw.replies().last(), Some(&Message::Time(0)));
+    }
+}
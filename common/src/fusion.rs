@@ -0,0 +1,307 @@
+//! Orientation estimation from accelerometer, magnetometer and gyroscope
+//! readings.
+//!
+//! The earth frame has x pointing at magnetic north and z pointing up. An
+//! orientation quaternion `q` rotates vectors from the sensor frame into the
+//! earth frame: `v_earth = q.rotate(v_sensor)`.
+
+use libm::{acosf, asinf, atan2f, cosf, sinf, sqrtf};
+use serde::{Deserialize, Serialize};
+
+/// Default filter gain, in rad/s. Higher values trust the accelerometer and
+/// magnetometer more and converge faster, at the cost of more noise.
+pub const DEFAULT_BETA: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion::new(1., 0., 0., 0.);
+
+    pub const fn new(w: f32, x: f32, y: f32, z: f32) -> Quaternion {
+        Quaternion { w, x, y, z }
+    }
+
+    /// Rotation of `angle` radians about `axis`, which need not be normalised.
+    pub fn from_axis_angle(axis: [f32; 3], angle: f32) -> Quaternion {
+        let [x, y, z] = normalize(axis);
+        let s = sinf(angle / 2.);
+        Quaternion::new(cosf(angle / 2.), x * s, y * s, z * s)
+    }
+
+    pub fn norm(&self) -> f32 {
+        sqrtf(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z)
+    }
+
+    pub fn normalize(&self) -> Quaternion {
+        let n = self.norm();
+        if n == 0. {
+            return Quaternion::IDENTITY;
+        }
+        Quaternion::new(self.w / n, self.x / n, self.y / n, self.z / n)
+    }
+
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    /// Rotate `v` by this (unit) quaternion.
+    pub fn rotate(&self, v: [f32; 3]) -> [f32; 3] {
+        let p = *self * Quaternion::new(0., v[0], v[1], v[2]) * self.conjugate();
+        [p.x, p.y, p.z]
+    }
+
+    /// Smallest angle, in radians, that rotates `self` onto `other`.
+    pub fn angle_to(&self, other: &Quaternion) -> f32 {
+        let dot = self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z;
+        2. * acosf(dot.abs().min(1.))
+    }
+
+    /// Roll, pitch and yaw in radians, applied in that order about the
+    /// sensor's x, y and z axes.
+    pub fn euler(&self) -> (f32, f32, f32) {
+        let Quaternion { w, x, y, z } = *self;
+        let roll = atan2f(2. * (w * x + y * z), 1. - 2. * (x * x + y * y));
+        let pitch = asinf((2. * (w * y - z * x)).clamp(-1., 1.));
+        let yaw = atan2f(2. * (w * z + x * y), 1. - 2. * (y * y + z * z));
+        (roll, pitch, yaw)
+    }
+}
+
+impl Default for Quaternion {
+    fn default() -> Quaternion {
+        Quaternion::IDENTITY
+    }
+}
+
+impl core::ops::Mul for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, o: Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+            self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+            self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+            self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+        )
+    }
+}
+
+/// Madgwick's gradient descent orientation filter.
+///
+/// Each update integrates the gyro rate, then steps the estimate towards the
+/// orientation that best explains the measured gravity and magnetic field.
+/// Without a gyro, pass zero rates and the estimate will follow the
+/// accelerometer and magnetometer at up to `beta` rad/s.
+pub struct Madgwick {
+    q: Quaternion,
+    beta: f32,
+}
+
+impl Madgwick {
+    pub fn new(beta: f32) -> Madgwick {
+        Madgwick { q: Quaternion::IDENTITY, beta }
+    }
+
+    pub fn quaternion(&self) -> Quaternion {
+        self.q
+    }
+
+    pub fn set_quaternion(&mut self, q: Quaternion) {
+        self.q = q.normalize();
+    }
+
+    /// Advance the filter by `dt` seconds. `gyro` is in rad/s; `accel` and
+    /// `mag` may be in any unit since only their directions are used. An
+    /// all-zero `accel` skips the correction step entirely, and without `mag`
+    /// the yaw is left to the gyro alone.
+    pub fn update(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: Option<[f32; 3]>, dt: f32) {
+        let q = self.q;
+        let mut q_dot = q * Quaternion::new(0., gyro[0], gyro[1], gyro[2]);
+        q_dot = Quaternion::new(q_dot.w / 2., q_dot.x / 2., q_dot.y / 2., q_dot.z / 2.);
+
+        if accel != [0., 0., 0.] {
+            let mut grad = gravity_gradient(&q, normalize(accel));
+            if let Some(mag) = mag.filter(|m| *m != [0., 0., 0.]) {
+                let g = field_gradient(&q, normalize(mag));
+                for (total, gi) in grad.iter_mut().zip(g.iter()) {
+                    *total += gi;
+                }
+            }
+            let n = sqrtf(grad.iter().map(|g| g * g).sum());
+            if n > 0. {
+                q_dot.w -= self.beta * grad[0] / n;
+                q_dot.x -= self.beta * grad[1] / n;
+                q_dot.y -= self.beta * grad[2] / n;
+                q_dot.z -= self.beta * grad[3] / n;
+            }
+        }
+
+        self.q = Quaternion::new(
+            q.w + q_dot.w * dt,
+            q.x + q_dot.x * dt,
+            q.y + q_dot.y * dt,
+            q.z + q_dot.z * dt,
+        ).normalize();
+    }
+}
+
+impl Default for Madgwick {
+    fn default() -> Madgwick {
+        Madgwick::new(DEFAULT_BETA)
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let n = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if n == 0. {
+        return v;
+    }
+    [v[0] / n, v[1] / n, v[2] / n]
+}
+
+// J^T f for the objective f, with J its Jacobian with respect to (w, x, y, z)
+fn jacobian_step(j: [[f32; 4]; 3], f: [f32; 3]) -> [f32; 4] {
+    let mut grad = [0.; 4];
+    for (row, fi) in j.iter().zip(f.iter()) {
+        for (g, jij) in grad.iter_mut().zip(row.iter()) {
+            *g += jij * fi;
+        }
+    }
+    grad
+}
+
+// Gradient of the error between gravity, rotated into the sensor frame, and
+// the normalised accelerometer reading `a`.
+fn gravity_gradient(q: &Quaternion, a: [f32; 3]) -> [f32; 4] {
+    let Quaternion { w, x, y, z } = *q;
+    let f = [
+        2. * (x * z - w * y) - a[0],
+        2. * (w * x + y * z) - a[1],
+        2. * (0.5 - x * x - y * y) - a[2],
+    ];
+    let j = [
+        [-2. * y, 2. * z, -2. * w, 2. * x],
+        [2. * x, 2. * w, 2. * z, 2. * y],
+        [0., -4. * x, -4. * y, 0.],
+    ];
+    jacobian_step(j, f)
+}
+
+// Same for the magnetic field. The reference field is the current reading
+// rotated into the earth frame with its horizontal part swung onto north, so
+// the magnetometer only ever corrects yaw and the dip angle needn't be known.
+fn field_gradient(q: &Quaternion, m: [f32; 3]) -> [f32; 4] {
+    let h = q.rotate(m);
+    let bx = sqrtf(h[0] * h[0] + h[1] * h[1]);
+    let bz = h[2];
+    let Quaternion { w, x, y, z } = *q;
+    let f = [
+        2. * bx * (0.5 - y * y - z * z) + 2. * bz * (x * z - w * y) - m[0],
+        2. * bx * (x * y - w * z) + 2. * bz * (w * x + y * z) - m[1],
+        2. * bx * (w * y + x * z) + 2. * bz * (0.5 - x * x - y * y) - m[2],
+    ];
+    let j = [
+        [-2. * bz * y, 2. * bz * z, -4. * bx * y - 2. * bz * w, -4. * bx * z + 2. * bz * x],
+        [-2. * bx * z + 2. * bz * x, 2. * bx * y + 2. * bz * w, 2. * bx * x + 2. * bz * z, -2. * bx * w + 2. * bz * y],
+        [2. * bx * y, 2. * bx * z - 4. * bz * x, 2. * bx * w - 4. * bz * y, 2. * bx * x],
+    ];
+    jacobian_step(j, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    const DT: f32 = 0.01;
+    const GRAVITY: [f32; 3] = [0., 0., 1.];
+    // Pointing north and steeply down, as in the northern hemisphere
+    const FIELD: [f32; 3] = [0.2, 0., -0.45];
+
+    // What a perfect sensor at orientation `q` would read
+    fn readings(q: &Quaternion) -> ([f32; 3], [f32; 3]) {
+        let inv = q.conjugate();
+        (inv.rotate(GRAVITY), inv.rotate(FIELD))
+    }
+
+    fn degrees(rad: f32) -> f32 {
+        rad * 180. / PI
+    }
+
+    #[test]
+    fn quaternion_algebra() {
+        let q = Quaternion::from_axis_angle([0., 0., 1.], PI / 2.);
+        let v = q.rotate([1., 0., 0.]);
+        assert!((v[0]).abs() < 1e-6 && (v[1] - 1.).abs() < 1e-6 && v[2].abs() < 1e-6);
+        assert!((q * q.conjugate()).angle_to(&Quaternion::IDENTITY) < 1e-3);
+        let (roll, pitch, yaw) = q.euler();
+        assert!(roll.abs() < 1e-6 && pitch.abs() < 1e-6);
+        assert!((degrees(yaw) - 90.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn converges_from_accel_and_mag() {
+        let truth = Quaternion::from_axis_angle([1., 2., 3.], 1.2);
+        let (accel, mag) = readings(&truth);
+        let mut filter = Madgwick::new(0.5);
+        for _ in 0..2_000 {
+            filter.update([0.; 3], accel, Some(mag), DT);
+        }
+        let error = degrees(filter.quaternion().angle_to(&truth));
+        assert!(error < 1., "error {}°", error);
+    }
+
+    #[test]
+    fn accel_alone_fixes_tilt() {
+        let truth = Quaternion::from_axis_angle([1., -1., 0.], 0.5);
+        let (accel, _) = readings(&truth);
+        let mut filter = Madgwick::new(0.5);
+        for _ in 0..2_000 {
+            filter.update([0.; 3], accel, None, DT);
+        }
+        // Yaw is unobservable, but the estimated gravity must line up
+        let up = filter.quaternion().rotate(accel);
+        let error = degrees(acosf(up[2].min(1.)));
+        assert!(error < 1., "error {}°", error);
+    }
+
+    #[test]
+    fn gyro_alone_integrates() {
+        let mut filter = Madgwick::new(0.1);
+        // A quarter turn about x in one second
+        let rate = PI / 2.;
+        for _ in 0..100 {
+            filter.update([rate, 0., 0.], [0.; 3], None, DT);
+        }
+        let expected = Quaternion::from_axis_angle([1., 0., 0.], PI / 2.);
+        let error = degrees(filter.quaternion().angle_to(&expected));
+        assert!(error < 0.5, "error {}°", error);
+    }
+
+    #[test]
+    fn tracks_rotation() {
+        // Tilted board spinning about the vertical at 45°/s for 8 s
+        let tilt = Quaternion::from_axis_angle([0., 1., 0.], 0.3);
+        let rate = PI / 4.;
+        let mut filter = Madgwick::default();
+        filter.set_quaternion(tilt);
+        let mut truth = tilt;
+        for i in 1..=800 {
+            let spin = Quaternion::from_axis_angle([0., 0., 1.], rate * i as f32 * DT);
+            truth = spin * tilt;
+            // The spin axis expressed in the sensor frame
+            let axis = truth.conjugate().rotate([0., 0., 1.]);
+            let gyro = [axis[0] * rate, axis[1] * rate, axis[2] * rate];
+            let (accel, mag) = readings(&truth);
+            filter.update(gyro, accel, Some(mag), DT);
+        }
+        let error = degrees(filter.quaternion().angle_to(&truth));
+        assert!(error < 2., "error {}°", error);
+    }
+}
@@ -0,0 +1,116 @@
+//! Compass heading from accelerometer and magnetometer readings.
+//!
+//! Headings are in degrees, clockwise from north, in `[0, 360)`, and give
+//! the direction the sensor's +x axis points once projected onto the
+//! horizontal plane.
+
+use libm::{atan2f, fmodf, sqrtf};
+
+/// Tilt-compensated magnetic heading.
+///
+/// `accel` is the accelerometer reading with the board at rest, i.e. pointing
+/// up. `mag` may be in any unit, but its axes must be scaled alike. Returns
+/// `None` when there is no usable reference: no gravity, no field, or a
+/// field that points straight up or down.
+pub fn heading(accel: [f32; 3], mag: [f32; 3]) -> Option<f32> {
+    let up = normalize(accel)?;
+    let east = normalize(cross(mag, up))?;
+    let north = cross(up, east);
+    // Components of the sensor's x axis along east and north
+    Some(wrap(atan2f(east[0], north[0]).to_degrees()))
+}
+
+/// Correct a magnetic heading to a true heading. `declination` is in degrees,
+/// positive where magnetic north lies east of true north.
+pub fn true_heading(magnetic: f32, declination: f32) -> f32 {
+    wrap(magnetic + declination)
+}
+
+/// Bring any angle in degrees into `[0, 360)`.
+pub fn wrap(degrees: f32) -> f32 {
+    let wrapped = fmodf(degrees, 360.);
+    if wrapped < 0. {
+        // Tiny negative inputs can round up to exactly 360
+        fmodf(wrapped + 360., 360.)
+    } else {
+        wrapped
+    }
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let n = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if n > f32::EPSILON {
+        Some([v[0] / n, v[1] / n, v[2] / n])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fusion::Quaternion;
+
+    const GRAVITY: [f32; 3] = [0., 0., 1.];
+    // North and dipping steeply down, in a north/west/up frame
+    const FIELD: [f32; 3] = [0.2, 0., -0.45];
+
+    fn readings(q: &Quaternion) -> ([f32; 3], [f32; 3]) {
+        let inv = q.conjugate();
+        (inv.rotate(GRAVITY), inv.rotate(FIELD))
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        let diff = wrap(a - b);
+        !(0.1..=359.9).contains(&diff)
+    }
+
+    #[test]
+    fn flat() {
+        for &expected in &[0f32, 45., 90., 180., 270., 315.] {
+            // Turning clockwise seen from above is a negative yaw about up
+            let q = Quaternion::from_axis_angle([0., 0., 1.], -expected.to_radians());
+            let (accel, mag) = readings(&q);
+            let h = heading(accel, mag).unwrap();
+            assert!(close(h, expected), "expected {} got {}", expected, h);
+        }
+    }
+
+    #[test]
+    fn tilted() {
+        let yaw = Quaternion::from_axis_angle([0., 0., 1.], -(120f32).to_radians());
+        for &axis in &[[1., 0., 0.], [0., 1., 0.], [1., 1., 0.], [0., 1., 1.]] {
+            for &tilt in &[-40f32, -10., 25., 60.] {
+                let q = yaw * Quaternion::from_axis_angle(axis, tilt.to_radians());
+                let (accel, mag) = readings(&q);
+                // Where the x axis ends up, seen from above
+                let forward = q.rotate([1., 0., 0.]);
+                let expected = wrap(atan2f(-forward[1], forward[0]).to_degrees());
+                let h = heading(accel, mag).unwrap();
+                assert!(close(h, expected), "tilt {} about {:?}: {} vs {}", tilt, axis, h, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate() {
+        assert!(heading([0., 0., 0.], FIELD).is_none());
+        assert!(heading(GRAVITY, [0., 0., -1.]).is_none());
+    }
+
+    #[test]
+    fn declination() {
+        assert!(close(true_heading(350., 15.), 5.));
+        assert!(close(true_heading(10., -15.), 355.));
+        assert_eq!(wrap(-1e-6), 0.);
+        assert_eq!(wrap(720.), 0.);
+    }
+}
@@ -6,10 +6,22 @@ pub mod usb {
     pub const PROD_ID: u16 = 0x0001;
 }
 
+pub mod calibration;
+pub mod config;
+pub mod crash;
+pub mod crc;
+pub mod device;
+pub mod dispatch;
+pub mod fusion;
+pub mod heading;
 pub mod link;
 pub mod message;
 pub mod message_queue;
+pub mod sensors;
+pub mod update;
 
+pub use device::{ProtocolVersion, PROTOCOL_VERSION};
 pub use link::Link;
-pub use message::Message;
+pub use message::{Envelope, Message};
 pub use message_queue::MessageQueue;
+pub use sensors::{Sensors, Stamp};
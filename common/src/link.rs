@@ -1,12 +1,36 @@
 use serde_cbor::error::Error as CborError;
 use serial_line_ip::{Encoder, Decoder, Error as SlipError};
-use crate::message::Message;
+use crate::crc::{crc16, CRC_SIZE};
+use crate::message::{Envelope, Message};
 use static_assertions::const_assert;
 
 #[derive(Debug)]
 pub enum LinkError {
     Cbor(CborError),
     Slip(SlipError),
+    /// A complete frame was received but its CRC trailer did not match.
+    /// `read` is the number of input bytes consumed up to and including the
+    /// bad frame, so decoding can resume right after it.
+    Checksum { read: usize },
+    /// A frame held a bad SLIP escape sequence. It was dropped, and `read`
+    /// input bytes consumed up to and including its END; if that's still to
+    /// come, later calls skip the rest.
+    Framing { error: SlipError, read: usize },
+    /// A frame ran past the longest a message can be. It was dropped as for
+    /// `Framing`.
+    Overflow { read: usize },
+}
+
+impl LinkError {
+    /// How many input bytes a failed `Link::decode` consumed, so decoding
+    /// can carry on after them. `None` for errors that don't come from
+    /// decoding.
+    pub fn read(&self) -> Option<usize> {
+        match *self {
+            LinkError::Checksum { read } | LinkError::Framing { read, .. } | LinkError::Overflow { read } => Some(read),
+            LinkError::Cbor(_) | LinkError::Slip(_) => None,
+        }
+    }
 }
 
 impl From<CborError> for LinkError {
@@ -23,70 +47,152 @@ impl From<SlipError> for LinkError {
 
 type Result<T> = core::result::Result<T, LinkError>;
 
-const MAX_PACKET_SIZE: usize = Message::MAX_SIZE;
+const MAX_PACKET_SIZE: usize = Message::MAX_SIZE + CRC_SIZE;
 const_assert!(MAX_PACKET_SIZE < u16::MAX as usize);
+/// Most bytes `Link::encode` writes for one envelope: every byte of the
+/// packet escaped, between two ENDs.
+pub const MAX_FRAME_SIZE: usize = 2 * MAX_PACKET_SIZE + 2;
+// The SLIP byte that starts and ends a frame
+const END: u8 = 0xC0;
 
 pub struct Link {
     decoder: Decoder,
     scratch_offset: usize,
-    scratch: [u8; MAX_PACKET_SIZE],
+    // One byte over the largest frame, so a full scratch means the frame is
+    // too long rather than that its END is still to come
+    scratch: [u8; MAX_PACKET_SIZE + 1],
+    // Dropping the rest of a bad frame, up to and including its END
+    skipping: bool,
 }
 
 impl Link {
     pub fn new() -> Link {
         Link {
-            decoder: Decoder::new(),
+            decoder: decoder(),
             scratch_offset: 0,
-            scratch: [0u8; MAX_PACKET_SIZE],
+            scratch: [0u8; MAX_PACKET_SIZE + 1],
+            skipping: false,
         }
     }
 
-    pub fn encode(&mut self, msg: &Message, output: &mut [u8]) -> Result<usize> {
+    pub fn encode(&mut self, env: &Envelope, output: &mut [u8]) -> Result<usize> {
         let mut encoder = Encoder::new();
         let mut buf = [0u8; MAX_PACKET_SIZE];
-        let size = msg.write_bytes(&mut buf)?;
-        let mut totals = encoder.encode(&buf[..size], output)?;
+        let size = env.write_bytes(&mut buf[..Message::MAX_SIZE])?;
+        let crc = crc16(&buf[..size]);
+        buf[size..size + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+        let mut totals = encoder.encode(&buf[..size + CRC_SIZE], output)?;
         totals += encoder.finish(&mut output[totals.written..])?;
         Ok(totals.written)
     }
 
-    pub fn decode(&mut self, buf: &[u8]) -> Result<(usize, Option<Message>)> {
-        let (read, packet, present) = self.decoder.decode(buf, &mut self.scratch[self.scratch_offset..])?;
-        self.scratch_offset += packet.len();
-        let mut res = None;
-        if present {
-            if packet.len() != 0 {
-                match Message::from_bytes(&mut self.scratch[..self.scratch_offset]) {
-                    Ok(msg) => {
-                        res = Some(msg);
-                    }
-                    Err(_e) => {
-                        //Bad packet, report no packet with data read
-                        //println!("Bad packet: {}", _e);
-                    }
+    /// Decode the first frame in `buf`, returning how many bytes it read
+    /// and the envelope, if that completed one. Any error drops the frame it
+    /// came from and says how many bytes that took; decoding picks up again
+    /// at the next frame.
+    pub fn decode(&mut self, buf: &[u8]) -> Result<(usize, Option<Envelope>)> {
+        let mut read = 0;
+        if self.skipping {
+            match buf.iter().position(|&b| b == END) {
+                Some(end) => {
+                    self.skipping = false;
+                    read = end + 1;
                 }
-                self.scratch_offset = 0;
+                None => return Ok((buf.len(), None)),
             }
         }
+        while read < buf.len() {
+            let (size, packet, present) = match self.decoder.decode(&buf[read..], &mut self.scratch[self.scratch_offset..]) {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    let read = self.resync(buf, read);
+                    return Err(LinkError::Framing { error, read });
+                }
+            };
+            read += size;
+            self.scratch_offset += packet.len();
+            if !present {
+                if self.scratch_offset == self.scratch.len() {
+                    let read = self.resync(buf, read);
+                    return Err(LinkError::Overflow { read });
+                }
+                break;
+            }
+            let frame_len = self.scratch_offset;
+            self.scratch_offset = 0;
+            // The END opening a frame closes an empty one
+            if frame_len == 0 {
+                continue;
+            }
+            if frame_len < CRC_SIZE {
+                return Err(LinkError::Checksum { read });
+            }
+            let (payload, trailer) = self.scratch[..frame_len].split_at_mut(frame_len - CRC_SIZE);
+            let received = u16::from_be_bytes([trailer[0], trailer[1]]);
+            if crc16(payload) != received {
+                return Err(LinkError::Checksum { read });
+            }
+            //Checksum passed but we don't understand the payload,
+            //report no packet with data read
+            return Ok((read, Envelope::from_bytes(payload).ok()));
+        }
 
-        Ok((read, res))
+        Ok((read, None))
     }
+
+    // Drop the frame being decoded and skip `buf` from `from` past the END
+    // that closes it. Returns how many bytes of `buf` that leaves consumed.
+    fn resync(&mut self, buf: &[u8], from: usize) -> usize {
+        self.decoder = decoder();
+        self.scratch_offset = 0;
+        match buf[from..].iter().position(|&b| b == END) {
+            Some(end) => from + end + 1,
+            None => {
+                self.skipping = true;
+                buf.len()
+            }
+        }
+    }
+}
+
+// A SLIP decoder that takes every END as the close of a frame, as if it
+// had already seen one open. Bytes before the first END then make a frame
+// of their own, one that fails its checksum, rather than a header error
+// the decoder never gets past.
+fn decoder() -> Decoder {
+    let mut decoder = Decoder::new();
+    // Only an END to take as the header, so it can't fail
+    let _ = decoder.decode(&[END], &mut []);
+    decoder
 }
 
 #[cfg(test)]
 mod test {
-    use crate::Message;
-    use super::{Link, MAX_PACKET_SIZE};
+    use crate::calibration::{AccelCalibration, MagCalibration};
+    use crate::config::{ConfigData, ConfigError, MAX_VALUE_SIZE};
+    use crate::crash::{CrashReport, ExceptionFrame};
+    use crate::device::{DeviceInfo, FirmwareVersion, Uid};
+    use crate::message::{Envelope, LogLevel, LogTarget, LogText, Message, MAX_LOG_SIZE, MAX_LOG_TARGET_SIZE};
+    use crate::message_queue::QueueStats;
+    use crate::sensors::{Sensors, Stamp};
+    use crate::update::{ImageChunk, ImageInfo, UpdateError, UpdateStatus, MAX_CHUNK_SIZE};
+    use crate::crc::{crc16, CRC_SIZE};
+    use super::{Link, LinkError, END, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
+    use proptest::prelude::*;
+
+    // SLIP frame carrying `Envelope { seq: 0, msg: Message::Hello }`
+    const HELLO_FRAME: [u8; 8] = [192, 130, 0, 129, 1, 140, 24, 192];
 
     fn echo_test(msg: Message) {
         let mut buf = [0u8; MAX_PACKET_SIZE];
         let mut link = Link::new();
-        let size = link.encode(&msg, &mut buf).unwrap();
+        let env = Envelope::new(7, msg);
+        let size = link.encode(&env, &mut buf).unwrap();
         let (_size, rx) = link.decode(&buf[..size]).unwrap();
-        assert_eq!(msg, rx.unwrap());
+        assert_eq!(env, rx.unwrap());
     }
 
-    fn multi_message_encode(msgs: &Vec<Message>, link: &mut Link) -> (usize, Vec<u8>) {
+    fn multi_message_encode(msgs: &Vec<Envelope>, link: &mut Link) -> (usize, Vec<u8>) {
         let mut buf = vec![0u8; msgs.len() * MAX_PACKET_SIZE];
         println!("Buffer size: {}", buf.len());
         let mut offset = 0;
@@ -98,7 +204,7 @@ mod test {
         (offset, buf)
     }
 
-    fn multi_message_decode(msgs: &Vec<Message>, link: &mut Link, buf: &[u8]) {
+    fn multi_message_decode(msgs: &Vec<Envelope>, link: &mut Link, buf: &[u8]) {
         let mut msgindex = 0;
         let mut offset = 0;
         loop {
@@ -115,6 +221,37 @@ mod test {
         }
     }
 
+    fn encode(env: &Envelope) -> Vec<u8> {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let size = Link::new().encode(env, &mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    // Every envelope decoded from `input`, read `chunk` bytes at a time,
+    // and how many errors were skipped on the way
+    fn decode_stream(link: &mut Link, input: &[u8], chunk: usize) -> (Vec<Envelope>, usize) {
+        let mut received = Vec::new();
+        let mut errors = 0;
+        for bytes in input.chunks(chunk) {
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let read = match link.decode(&bytes[offset..]) {
+                    Ok((read, rx)) => {
+                        received.extend(rx);
+                        read
+                    }
+                    Err(e) => {
+                        errors += 1;
+                        e.read().unwrap()
+                    }
+                };
+                assert!(read > 0, "no progress at {} of {:?}", offset, bytes);
+                offset += read;
+            }
+        }
+        (received, errors)
+    }
+
     #[test]
     fn say_hello() {
         echo_test(Message::Hello);
@@ -127,7 +264,7 @@ mod test {
 
     #[test]
     fn multiple_message() {
-        let msgs = vec![Message::Hello, Message::Hello];
+        let msgs = vec![Envelope::new(1, Message::Hello), Envelope::new(2, Message::Hello)];
         let mut link = Link::new();
         let (length, buf) = multi_message_encode(&msgs, &mut link);
         multi_message_decode(&msgs, &mut link, &buf[..length]);
@@ -135,25 +272,35 @@ mod test {
 
     #[test]
     fn bad_message() {
-        let mut offset = 0;
-        let input = vec![
-            192, 111, 73, 111, 118, 118, 111, 192,
-            192, 101, 72, 101, 108, 108, 111, 192];
+        // Hello with a valid CRC trailer, preceded by a copy with a flipped bit
+        let mut input = HELLO_FRAME.to_vec();
+        input[4] ^= 0x04;
+        input.extend_from_slice(&HELLO_FRAME);
         let mut link = Link::new();
-        let (sz, msg) = link.decode(&input).unwrap();
-        offset += sz;
-        assert!(msg.is_none());
-        let (sz, msg) = link.decode(&input[offset..]).unwrap();
-        offset += sz;
-        assert!(msg.is_none());
-        let (_, msg) = link.decode(&input[offset..]).unwrap();
-        assert_eq!(Message::Hello, msg.unwrap());
+        let mut offset = 0;
+        let mut checksum_errors = 0;
+        let mut received = Vec::new();
+        while offset < input.len() {
+            match link.decode(&input[offset..]) {
+                Ok((sz, msg)) => {
+                    received.extend(msg);
+                    offset += sz;
+                }
+                Err(LinkError::Checksum { read }) => {
+                    checksum_errors += 1;
+                    offset += read;
+                }
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+        }
+        assert_eq!(checksum_errors, 1);
+        assert_eq!(received, vec![Envelope::from(Message::Hello)]);
     }
 
     #[test]
     fn partial_decodes() {
 
-        let input = vec![192, 101, 72, 101, 108, 108, 111, 192];
+        let input = HELLO_FRAME;
         let mut link = Link::new();
         let (sz, msg) = link.decode(&input[..2]).unwrap();
         assert!(msg.is_none());
@@ -163,4 +310,204 @@ mod test {
         assert_eq!(sz, 6);
     }
 
+    #[test]
+    fn short_frame() {
+        let input = [192, 101, 192];
+        let mut link = Link::new();
+        let mut offset = 0;
+        loop {
+            match link.decode(&input[offset..]) {
+                Ok((sz, msg)) => {
+                    assert!(msg.is_none());
+                    assert!(sz != 0);
+                    offset += sz;
+                }
+                Err(LinkError::Checksum { .. }) => break,
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_escape() {
+        // A bad escape sequence in the first frame
+        let mut input = HELLO_FRAME.to_vec();
+        input[2] = 0xDB;
+        input.extend_from_slice(&HELLO_FRAME);
+        let mut link = Link::new();
+        let sz = match link.decode(&input) {
+            Err(LinkError::Framing { read, .. }) => read,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(sz, HELLO_FRAME.len());
+        assert_eq!(link.decode(&input[sz..]).unwrap(), (HELLO_FRAME.len(), Some(Envelope::from(Message::Hello))));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut input = vec![END];
+        input.resize(3 * MAX_PACKET_SIZE, 1);
+        input.extend_from_slice(&HELLO_FRAME);
+        let mut link = Link::new();
+        let (received, errors) = decode_stream(&mut link, &input, input.len());
+        assert_eq!((received, errors), (vec![Envelope::from(Message::Hello)], 1));
+        // And again, with the frame's END in a later read
+        let (received, errors) = decode_stream(&mut link, &input, 64);
+        assert_eq!((received, errors), (vec![Envelope::from(Message::Hello)], 1));
+    }
+
+    #[test]
+    fn escaped_frame_fits() {
+        // Nearly every byte needs escaping
+        let target = LogTarget::new(&[END; MAX_LOG_TARGET_SIZE]);
+        let log = Message::Log { level: LogLevel::Trace, target, text: LogText::new(&[END; MAX_LOG_SIZE]) };
+        let env = Envelope::new(0xC0C0, log);
+        let frame = encode(&env);
+        assert!(frame.len() > MAX_PACKET_SIZE);
+        assert_eq!(decode_stream(&mut Link::new(), &frame, frame.len()), (vec![env], 0));
+    }
+
+    #[test]
+    fn longest_frame() {
+        // As long as a frame can be, with a good CRC though not a message
+        let mut frame = vec![1u8; MAX_PACKET_SIZE - CRC_SIZE];
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        let mut input = vec![END];
+        input.extend(frame);
+        input.push(END);
+        input.extend_from_slice(&HELLO_FRAME);
+        let (received, errors) = decode_stream(&mut Link::new(), &input, 1);
+        assert_eq!((received, errors), (vec![Envelope::from(Message::Hello)], 0));
+    }
+
+    fn sample_stamp() -> impl Strategy<Value = Stamp> {
+        (any::<u64>(), any::<u32>()).prop_map(|(t, c)| Stamp::new(t, c))
+    }
+
+    fn sample_config_data() -> impl Strategy<Value = ConfigData> {
+        proptest::collection::vec(any::<u8>(), 0..=MAX_VALUE_SIZE).prop_map(|v| ConfigData::new(&v).unwrap())
+    }
+
+    fn sample_message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            Just(Message::Nop),
+            Just(Message::Hello),
+            (any::<[u16; 3]>(), any::<[u32; 3]>(), prop::sample::select(vec![Sensors::ALL, Sensors::ACCEL | Sensors::MAG]))
+                .prop_map(|(v, uid, sensors)| {
+                    let version = FirmwareVersion { major: v[0], minor: v[1], patch: v[2] };
+                    Message::HelloAck(DeviceInfo::new(version, Uid(uid), sensors))
+                }),
+            Just(Message::AccelReq),
+            Just(Message::MagReq),
+            (sample_stamp(), any::<f32>(), any::<f32>(), any::<f32>())
+                .prop_filter("NaN never compares equal", |(_, x, y, z)| !(x.is_nan() || y.is_nan() || z.is_nan()))
+                .prop_map(|(t, x, y, z)| Message::Accel(t, x, y, z)),
+            (sample_stamp(), any::<i16>(), any::<i16>(), any::<i16>()).prop_map(|(t, x, y, z)| Message::Mag(t, x, y, z)),
+            any::<u64>().prop_map(Message::Time),
+            Just(Message::GyroReq),
+            Just(Message::HeadingReq),
+            (0f32..360.).prop_map(Message::Heading),
+            (any::<[f32; 3]>(), any::<[[f32; 3]; 3]>())
+                .prop_filter("NaN never compares equal", |(o, m)| !o.iter().chain(m.iter().flatten()).any(|v| v.is_nan()))
+                .prop_map(|(offset, matrix)| Message::SetMagCalibration(MagCalibration { offset, matrix })),
+            Just(Message::Ack),
+            (any::<[f32; 3]>(), any::<[f32; 3]>())
+                .prop_filter("NaN never compares equal", |(o, s)| !o.iter().chain(s.iter()).any(|v| v.is_nan()))
+                .prop_map(|(offset, scale)| Message::SetAccelCalibration(AccelCalibration { offset, scale })),
+            (sample_stamp(), any::<f32>(), any::<f32>(), any::<f32>())
+                .prop_filter("NaN never compares equal", |(_, x, y, z)| !(x.is_nan() || y.is_nan() || z.is_nan()))
+                .prop_map(|(t, x, y, z)| Message::Gyro(t, x, y, z)),
+            // Text past its limit too, to cover truncation, which is only
+            // flagged on the wire for the text
+            (
+                proptest::sample::select(vec![LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]),
+                "[a-z:]{0,32}",
+                proptest::collection::vec(any::<u8>(), 0..2 * MAX_LOG_SIZE),
+            )
+                .prop_map(|(level, target, text)| Message::log_at(level, &target, text)),
+            any::<u16>().prop_map(Message::ConfigGet),
+            (any::<u16>(), proptest::option::of(sample_config_data())).prop_map(|(k, v)| Message::Config(k, v)),
+            (any::<u16>(), sample_config_data()).prop_map(|(k, v)| Message::ConfigSet(k, v)),
+            any::<u16>().prop_map(Message::ConfigErase),
+            Just(Message::ConfigCommit),
+            Just(Message::ConfigFailed(ConfigError::Full)),
+            Just(Message::CrashReportReq),
+            (
+                proptest::collection::vec(any::<u8>(), 0..=MAX_LOG_SIZE),
+                "[a-z/.]{0,80}",
+                any::<u32>(),
+                proptest::option::of(any::<[u32; 3]>()),
+            )
+                .prop_map(|(text, file, line, frame)| {
+                    let mut report = CrashReport::panic(LogText::new(&text), &file, line);
+                    report.frame = frame.map(|[pc, lr, xpsr]| ExceptionFrame { pc, lr, xpsr });
+                    Message::CrashReport(Some(report))
+                }),
+            Just(Message::RebootToBootloader),
+            Just(Message::Reboot),
+            Just(Message::UpdateStatusReq),
+            (any::<u32>(), any::<u32>()).prop_map(|(size, received)| Message::UpdateStatus(UpdateStatus::Receiving { size, received })),
+            (any::<u32>(), any::<u32>()).prop_map(|(size, crc)| Message::UpdateBegin(ImageInfo { size, crc })),
+            (any::<u32>(), proptest::collection::vec(any::<u8>(), 0..=MAX_CHUNK_SIZE))
+                .prop_map(|(offset, data)| Message::UpdateChunk { offset, data: ImageChunk::new(&data).unwrap() }),
+            Just(Message::UpdateFinish),
+            Just(Message::UpdateFailed(UpdateError::OutOfOrder)),
+            Just(Message::StatsReq),
+            (any::<u32>(), any::<u32>(), any::<u16>()).prop_map(|(enqueued, dropped, high_watermark)| {
+                Message::Stats(QueueStats { enqueued, dropped, high_watermark })
+            }),
+            // Well clear of any tag in use
+            (1000u16..).prop_map(Message::Unknown),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn corrupted_frames_never_decode_wrong(
+            seq in any::<u16>(),
+            msg in sample_message(),
+            flips in proptest::collection::vec((any::<prop::sample::Index>(), 0u8..8), 1..4),
+            chunk in 1..64usize,
+        ) {
+            let env = Envelope::new(seq, msg);
+            let frame = encode(&env);
+            let mut input = frame.clone();
+            for (index, bit) in flips {
+                let i = index.index(frame.len());
+                input[i] ^= 1 << bit;
+            }
+            // The same frame, intact, must still come through after it
+            input.extend_from_slice(&frame);
+
+            let (received, _) = decode_stream(&mut Link::new(), &input, chunk);
+            prop_assert!(received.iter().all(|rx| rx == &env));
+            prop_assert_eq!(received.last(), Some(&env));
+        }
+
+        #[test]
+        fn frames_decode_after_garbage(
+            garbage in proptest::collection::vec(prop_oneof![8 => any::<u8>(), 1 => Just(END), 1 => Just(0xDB)], 0..3 * MAX_PACKET_SIZE),
+            msg in sample_message(),
+            chunk in 1..64usize,
+        ) {
+            let env = Envelope::new(3, msg);
+            let mut input = garbage;
+            input.extend(encode(&env));
+            let (received, _) = decode_stream(&mut Link::new(), &input, chunk);
+            prop_assert_eq!(received.last(), Some(&env));
+        }
+
+        #[test]
+        fn concatenated_frames_decode(
+            msgs in proptest::collection::vec(sample_message(), 1..5),
+            chunk in 1..64usize,
+        ) {
+            let envs: Vec<Envelope> = msgs.into_iter().enumerate().map(|(i, msg)| Envelope::new(i as u16, msg)).collect();
+            let input: Vec<u8> = envs.iter().flat_map(encode).collect();
+            let (received, errors) = decode_stream(&mut Link::new(), &input, chunk);
+            prop_assert_eq!(errors, 0);
+            prop_assert_eq!(received, envs);
+        }
+    }
+
 }
@@ -1,5 +1,8 @@
-use serde::{Deserialize, Serialize};
-use serde_big_array::big_array;
+use core::fmt;
+use serde::{
+    de::{self, IgnoredAny, SeqAccess, Visitor},
+    ser, Deserialize, Deserializer, Serialize,
+};
 use serde_cbor::{
     ser::SliceWrite,
     Serializer,
@@ -7,50 +10,497 @@ use serde_cbor::{
     de::from_mut_slice,
 };
 
-big_array! { BigArray; }
-
+use crate::calibration::{AccelCalibration, MagCalibration};
+use crate::config::{ConfigData, ConfigError};
+use crate::crash::CrashReport;
+use crate::device::DeviceInfo;
+use crate::message_queue::QueueStats;
+use crate::sensors::{Sensors, Stamp};
+use crate::update::{ImageChunk, ImageInfo, UpdateError, UpdateStatus};
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+/// On the wire each message is a CBOR array: its tag from `tags`, then its
+/// fields in order. Tags are never reused or renumbered. A peer that
+/// doesn't know a tag decodes it as `Unknown`, and ignores fields beyond
+/// the ones it knows, so a variant may gain fields at the end without a
+/// new tag. Anything else that changes the bytes of an existing variant
+/// needs a new `device::PROTOCOL_VERSION`; the golden tests below pin them.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum Message {
+    #[default]
     Nop,
+    /// Opens a session. Answered with `HelloAck`.
     Hello,
-    HelloAck,
-    Log(InternalBuffer),
+    /// Describes the board, so the host can check it speaks a compatible
+    /// protocol and knows which sensors and rates to ask for.
+    HelloAck(DeviceInfo),
+    /// A log record from the board, sent unsolicited. `target` names the
+    /// part of the firmware it came from, as a module path.
+    Log { level: LogLevel, target: LogTarget, text: LogText },
     AccelReq,
-    Accel(f32, f32, f32),
+    Accel(Stamp, f32, f32, f32),
     MagReq,
-    Mag(i16, i16, i16),
+    Mag(Stamp, i16, i16, i16),
+    /// Ask the board to push `Accel`/`Mag`/`Gyro` samples at `rate_hz`, tagged with
+    /// this request's sequence number. Replaces any previous subscription.
+    Subscribe { sensors: Sensors, rate_hz: u16 },
+    /// Confirms a subscription with the rate actually in use.
+    SubscribeAck(u16),
+    Unsubscribe,
+    /// Ask for the board's clock, used to estimate its offset from the host.
+    TimeReq,
+    /// Microseconds since the board booted.
+    Time(u64),
+    GyroReq,
+    /// Angular rate about each axis in degrees per second.
+    Gyro(Stamp, f32, f32, f32),
+    HeadingReq,
+    /// Tilt-compensated magnetic heading in degrees clockwise from north,
+    /// computed on the board from its latest readings. NaN if the readings
+    /// don't give a heading.
+    Heading(f32),
+    /// Correct every `Mag` reading from now on. Answered with `Ack`.
+    SetMagCalibration(MagCalibration),
+    /// Generic reply to requests that have nothing else to report.
+    Ack,
+    /// Correct every `Accel` reading from now on. Answered with `Ack`.
+    SetAccelCalibration(AccelCalibration),
+    /// Read a setting from the board's config store, answered with `Config`.
+    /// See `config::keys`.
+    ConfigGet(u16),
+    /// A setting's value, or `None` if it isn't set.
+    Config(u16, Option<ConfigData>),
+    /// Stage a new value for a setting. Nothing is written to flash or
+    /// applied until `ConfigCommit`. Answered with `Ack` or `ConfigFailed`.
+    ConfigSet(u16, ConfigData),
+    /// Stage removing a setting. Answered with `Ack` or `ConfigFailed`.
+    ConfigErase(u16),
+    /// Write staged settings to flash and apply them. Answered with `Ack` or
+    /// `ConfigFailed`.
+    ConfigCommit,
+    ConfigFailed(ConfigError),
+    /// Ask how the board's last boot ended, answered with `CrashReport`.
+    CrashReportReq,
+    /// Why the board reset, or `None` if it didn't crash. Also sent
+    /// unsolicited after the first `Hello` following a crash.
+    CrashReport(Option<CrashReport>),
+    /// Reset into the bootloader, to update the firmware. Answered with
+    /// `Ack` before the board resets. The bootloader answers it too, and
+    /// stays put.
+    RebootToBootloader,
+    /// Reset. Leaving the bootloader, this installs the image `UpdateFinish`
+    /// verified, if there is one; an update that never got that far is
+    /// dropped, and the old firmware starts. Answered with `Ack` first.
+    Reboot,
+    /// Ask where the board is with an update, answered with `UpdateStatus`.
+    UpdateStatusReq,
+    UpdateStatus(UpdateStatus),
+    /// Start staging a new firmware image in the bootloader. Answered with
+    /// `Ack` or `UpdateFailed`, as are `UpdateChunk` and `UpdateFinish`.
+    UpdateBegin(ImageInfo),
+    /// The image's bytes at `offset`, sent in order. See
+    /// `update::Updater::chunk`.
+    UpdateChunk { offset: u32, data: ImageChunk },
+    /// Check the staged image against its CRC, ready for `Reboot`.
+    UpdateFinish,
+    UpdateFailed(UpdateError),
+    /// Ask how the board's outgoing queue is coping, answered with `Stats`.
+    StatsReq,
+    /// Counters of the board's outgoing queue since it booted. Rising
+    /// `dropped` means the host isn't reading fast enough.
+    Stats(QueueStats),
+    /// A message with a tag this build doesn't know, from a newer peer.
+    /// The board echoes unknown requests back, so the host can tell
+    /// "not supported" apart from a lost message.
+    Unknown(u16),
+}
+
+/// Wire tag of each `Message` variant.
+pub mod tags {
+    pub const NOP: u16 = 0;
+    pub const HELLO: u16 = 1;
+    pub const HELLO_ACK: u16 = 2;
+    // 3 was `Log` as a fixed 128-byte array, before `LOG`
+    pub const ACCEL_REQ: u16 = 4;
+    pub const ACCEL: u16 = 5;
+    pub const MAG_REQ: u16 = 6;
+    pub const MAG: u16 = 7;
+    pub const SUBSCRIBE: u16 = 8;
+    pub const SUBSCRIBE_ACK: u16 = 9;
+    pub const UNSUBSCRIBE: u16 = 10;
+    pub const TIME_REQ: u16 = 11;
+    pub const TIME: u16 = 12;
+    pub const GYRO_REQ: u16 = 13;
+    pub const GYRO: u16 = 14;
+    pub const HEADING_REQ: u16 = 15;
+    pub const HEADING: u16 = 16;
+    pub const SET_MAG_CALIBRATION: u16 = 17;
+    pub const ACK: u16 = 18;
+    pub const SET_ACCEL_CALIBRATION: u16 = 19;
+    pub const CONFIG_GET: u16 = 20;
+    pub const CONFIG: u16 = 21;
+    pub const CONFIG_SET: u16 = 22;
+    pub const CONFIG_ERASE: u16 = 23;
+    pub const CONFIG_COMMIT: u16 = 24;
+    pub const CONFIG_FAILED: u16 = 25;
+    pub const LOG: u16 = 26;
+    pub const CRASH_REPORT_REQ: u16 = 27;
+    pub const CRASH_REPORT: u16 = 28;
+    pub const REBOOT_TO_BOOTLOADER: u16 = 29;
+    pub const REBOOT: u16 = 30;
+    pub const UPDATE_STATUS_REQ: u16 = 31;
+    pub const UPDATE_STATUS: u16 = 32;
+    pub const UPDATE_BEGIN: u16 = 33;
+    pub const UPDATE_CHUNK: u16 = 34;
+    pub const UPDATE_FINISH: u16 = 35;
+    pub const UPDATE_FAILED: u16 = 36;
+    pub const STATS_REQ: u16 = 37;
+    pub const STATS: u16 = 38;
+}
+
+impl Serialize for Message {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use Message::*;
+        let tag = self.tag();
+        match self {
+            Nop | Hello | AccelReq | MagReq | Unsubscribe | TimeReq | GyroReq | HeadingReq | Ack
+            | ConfigCommit | CrashReportReq | RebootToBootloader | Reboot | UpdateStatusReq | UpdateFinish
+            | StatsReq | Unknown(_) => (tag,).serialize(serializer),
+            HelloAck(info) => (tag, info).serialize(serializer),
+            Log { level, target, text } => (tag, text, text.truncated, level, target).serialize(serializer),
+            Accel(stamp, x, y, z) => (tag, stamp, x, y, z).serialize(serializer),
+            Mag(stamp, x, y, z) => (tag, stamp, x, y, z).serialize(serializer),
+            Subscribe { sensors, rate_hz } => (tag, sensors, rate_hz).serialize(serializer),
+            SubscribeAck(rate_hz) => (tag, rate_hz).serialize(serializer),
+            Time(us) => (tag, us).serialize(serializer),
+            Gyro(stamp, x, y, z) => (tag, stamp, x, y, z).serialize(serializer),
+            Heading(heading) => (tag, heading).serialize(serializer),
+            SetMagCalibration(cal) => (tag, cal).serialize(serializer),
+            SetAccelCalibration(cal) => (tag, cal).serialize(serializer),
+            ConfigGet(key) | ConfigErase(key) => (tag, key).serialize(serializer),
+            Config(key, data) => (tag, key, data).serialize(serializer),
+            ConfigSet(key, data) => (tag, key, data).serialize(serializer),
+            ConfigFailed(e) => (tag, e).serialize(serializer),
+            CrashReport(report) => (tag, report).serialize(serializer),
+            UpdateStatus(status) => (tag, status).serialize(serializer),
+            UpdateBegin(image) => (tag, image).serialize(serializer),
+            UpdateChunk { offset, data } => (tag, offset, data).serialize(serializer),
+            UpdateFailed(e) => (tag, e).serialize(serializer),
+            Stats(stats) => (tag, stats).serialize(serializer),
+        }
+    }
 }
 
-impl Default for Message {
-    fn default() -> Message {
-        Message::Nop
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Message, D::Error> {
+        deserializer.deserialize_seq(MessageVisitor)
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-pub struct InternalBuffer {
-    #[serde(with = "BigArray")]
-    b: [u8; 128],
+struct MessageVisitor;
+
+impl<'de> Visitor<'de> for MessageVisitor {
+    type Value = Message;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a tagged message")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Message, A::Error> {
+        use Message::*;
+        let mut fields = Fields::new(seq);
+        let tag = fields.next()?;
+        let msg = match tag {
+            tags::NOP => Nop,
+            tags::HELLO => Hello,
+            tags::HELLO_ACK => HelloAck(fields.next()?),
+            tags::ACCEL_REQ => AccelReq,
+            tags::ACCEL => Accel(fields.next()?, fields.next()?, fields.next()?, fields.next()?),
+            tags::MAG_REQ => MagReq,
+            tags::MAG => Mag(fields.next()?, fields.next()?, fields.next()?, fields.next()?),
+            tags::SUBSCRIBE => Subscribe { sensors: fields.next()?, rate_hz: fields.next()? },
+            tags::SUBSCRIBE_ACK => SubscribeAck(fields.next()?),
+            tags::UNSUBSCRIBE => Unsubscribe,
+            tags::TIME_REQ => TimeReq,
+            tags::TIME => Time(fields.next()?),
+            tags::GYRO_REQ => GyroReq,
+            tags::GYRO => Gyro(fields.next()?, fields.next()?, fields.next()?, fields.next()?),
+            tags::HEADING_REQ => HeadingReq,
+            tags::HEADING => Heading(fields.next()?),
+            tags::SET_MAG_CALIBRATION => SetMagCalibration(fields.next()?),
+            tags::ACK => Ack,
+            tags::SET_ACCEL_CALIBRATION => SetAccelCalibration(fields.next()?),
+            tags::CONFIG_GET => ConfigGet(fields.next()?),
+            tags::CONFIG => Config(fields.next()?, fields.next()?),
+            tags::CONFIG_SET => ConfigSet(fields.next()?, fields.next()?),
+            tags::CONFIG_ERASE => ConfigErase(fields.next()?),
+            tags::CONFIG_COMMIT => ConfigCommit,
+            tags::CONFIG_FAILED => ConfigFailed(fields.next()?),
+            tags::CRASH_REPORT_REQ => CrashReportReq,
+            tags::CRASH_REPORT => CrashReport(fields.next()?),
+            tags::REBOOT_TO_BOOTLOADER => RebootToBootloader,
+            tags::REBOOT => Reboot,
+            tags::UPDATE_STATUS_REQ => UpdateStatusReq,
+            tags::UPDATE_STATUS => UpdateStatus(fields.next()?),
+            tags::UPDATE_BEGIN => UpdateBegin(fields.next()?),
+            tags::UPDATE_CHUNK => UpdateChunk { offset: fields.next()?, data: fields.next()? },
+            tags::UPDATE_FINISH => UpdateFinish,
+            tags::UPDATE_FAILED => UpdateFailed(fields.next()?),
+            tags::STATS_REQ => StatsReq,
+            tags::STATS => Stats(fields.next()?),
+            tags::LOG => {
+                let mut text: LogText = fields.next()?;
+                text.truncated |= fields.next::<bool>()?;
+                Log { level: fields.next()?, target: fields.next()?, text }
+            }
+            _ => Unknown(tag),
+        };
+        fields.finish()?;
+        Ok(msg)
+    }
+}
+
+/// The fields of a tagged array, such as a `Message`, read in order.
+pub(crate) struct Fields<A> {
+    seq: A,
+    read: usize,
+}
+
+impl<'de, A: SeqAccess<'de>> Fields<A> {
+    pub(crate) fn new(seq: A) -> Fields<A> {
+        Fields { seq, read: 0 }
+    }
+
+    pub(crate) fn next<T: Deserialize<'de>>(&mut self) -> Result<T, A::Error> {
+        let field = self.seq.next_element()?
+            .ok_or_else(|| de::Error::invalid_length(self.read, &"all of the message's fields"))?;
+        self.read += 1;
+        Ok(field)
+    }
+
+    /// Skip the fields added by a newer peer, or everything after an
+    /// unknown tag.
+    pub(crate) fn finish(mut self) -> Result<(), A::Error> {
+        while self.seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+}
+
+/// A `Message` tagged with a sequence number. Requests carry a fresh number
+/// and the board echoes it back in the matching reply, so the host can pair
+/// them up. Unsolicited messages use `Envelope::UNSOLICITED`. Goes on the
+/// wire as an array of the two.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Envelope {
+    pub seq: u16,
+    pub msg: Message,
+}
+
+impl Envelope {
+    pub const UNSOLICITED: u16 = 0;
+
+    pub fn new(seq: u16, msg: Message) -> Envelope {
+        Envelope { seq, msg }
+    }
+
+    /// Wrap `msg` as the answer to this envelope.
+    pub fn reply(&self, msg: Message) -> Envelope {
+        Envelope::new(self.seq, msg)
+    }
+
+    pub fn write_bytes(&self, buf: &mut [u8]) -> Result<usize, CborError> {
+        let mut ser = Serializer::new(SliceWrite::new(&mut buf[..]));
+        self.serialize(&mut ser)?;
+        Ok(ser.into_inner().bytes_written())
+    }
+
+    pub fn from_bytes(buf: &mut [u8]) -> Result<Envelope, CborError> {
+        from_mut_slice(buf)
+    }
+}
+
+impl From<Message> for Envelope {
+    fn from(msg: Message) -> Envelope {
+        Envelope::new(Envelope::UNSOLICITED, msg)
+    }
+}
+
+impl Serialize for Envelope {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.seq, &self.msg).serialize(serializer)
+    }
 }
 
-impl<T> From<T> for InternalBuffer
-where
-    T: AsRef<[u8]>,
-{
-    fn from(t: T) -> InternalBuffer {
-        let tref = t.as_ref();
-        if tref.len() > 128 {
-            panic!("Buffer conversion too large!");
+impl<'de> Deserialize<'de> for Envelope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Envelope, D::Error> {
+        let (seq, msg) = Deserialize::deserialize(deserializer)?;
+        Ok(Envelope { seq, msg })
+    }
+}
+
+/// Longest text a `Log` message carries. Longer text is cut short.
+pub const MAX_LOG_SIZE: usize = 128;
+/// Longest target a `Log` message carries.
+pub const MAX_LOG_TARGET_SIZE: usize = 32;
+
+/// The text of a `Log` message.
+pub type LogText = BoundedText<MAX_LOG_SIZE>;
+/// The target of a `Log` message, such as `board::flash`.
+pub type LogTarget = BoundedText<MAX_LOG_TARGET_SIZE>;
+
+/// Up to `N` bytes of text, at most 255, of which only those in use go on
+/// the wire, as a CBOR byte string.
+#[derive(Clone, Copy, PartialEq)]
+pub struct BoundedText<const N: usize> {
+    len: u8,
+    buf: [u8; N],
+    truncated: bool,
+}
+
+impl<const N: usize> BoundedText<N> {
+    pub const EMPTY: BoundedText<N> = BoundedText { len: 0, buf: [0; N], truncated: false };
+
+    /// `text`, cut short to `N` bytes if it's longer.
+    pub fn new(text: &[u8]) -> BoundedText<N> {
+        let len = text.len().min(N);
+        let mut buf = [0u8; N];
+        buf[..len].copy_from_slice(&text[..len]);
+        BoundedText { len: len as u8, buf, truncated: len < text.len() }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+
+    /// Whether the text was cut short to fit.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The text as UTF-8, with U+FFFD in place of anything that isn't.
+    #[cfg(feature = "std")]
+    pub fn to_string_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+}
+
+/// Appends as much as fits, so records can be formatted straight into one.
+impl<const N: usize> fmt::Write for BoundedText<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len as usize;
+        let take = s.len().min(N - len);
+        self.buf[len..len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take as u8;
+        self.truncated |= take < s.len();
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Debug for BoundedText<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match core::str::from_utf8(self.as_bytes()) {
+            Ok(text) => write!(f, "{:?}", text)?,
+            Err(_) => write!(f, "{:?}", self.as_bytes())?,
+        }
+        if self.truncated {
+            write!(f, " (truncated)")?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Serialize for BoundedText<N> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for BoundedText<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BoundedText<N>, D::Error> {
+        struct TextVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for TextVisitor<N> {
+            type Value = BoundedText<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "bytes of text")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<BoundedText<N>, E> {
+                Ok(BoundedText::new(v))
+            }
+
+            // Formats without a byte string type, such as JSON, send a list
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<BoundedText<N>, A::Error> {
+                let mut text = BoundedText::EMPTY;
+                while let Some(b) = seq.next_element()? {
+                    if (text.len as usize) < N {
+                        text.buf[text.len as usize] = b;
+                        text.len += 1;
+                    } else {
+                        text.truncated = true;
+                    }
+                }
+                Ok(text)
+            }
+        }
+
+        deserializer.deserialize_bytes(TextVisitor::<N>)
+    }
+}
+
+/// How serious a `Log` record is, numbered as in the `log` crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> LogLevel {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
         }
-        let mut b = [0u8; 128];
-        b[..tref.len()].copy_from_slice(tref);
-        InternalBuffer {
-            b,
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> log::Level {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
         }
     }
 }
 
+impl Serialize for LogLevel {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LogLevel, D::Error> {
+        // Levels past `Trace` from a newer peer are taken as the most verbose
+        Ok(match u8::deserialize(deserializer)? {
+            0 | 1 => LogLevel::Error,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Info,
+            4 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        })
+    }
+}
+
 impl Message {
     pub const MAX_SIZE: usize = 256;
 
@@ -64,8 +514,101 @@ impl Message {
         from_mut_slice(buf)
     }
 
+    /// This message's wire tag, see `tags`.
+    pub fn tag(&self) -> u16 {
+        use Message::*;
+        match self {
+            Nop => tags::NOP,
+            Hello => tags::HELLO,
+            HelloAck(_) => tags::HELLO_ACK,
+            Log { .. } => tags::LOG,
+            AccelReq => tags::ACCEL_REQ,
+            Accel(..) => tags::ACCEL,
+            MagReq => tags::MAG_REQ,
+            Mag(..) => tags::MAG,
+            Subscribe { .. } => tags::SUBSCRIBE,
+            SubscribeAck(_) => tags::SUBSCRIBE_ACK,
+            Unsubscribe => tags::UNSUBSCRIBE,
+            TimeReq => tags::TIME_REQ,
+            Time(_) => tags::TIME,
+            GyroReq => tags::GYRO_REQ,
+            Gyro(..) => tags::GYRO,
+            HeadingReq => tags::HEADING_REQ,
+            Heading(_) => tags::HEADING,
+            SetMagCalibration(_) => tags::SET_MAG_CALIBRATION,
+            Ack => tags::ACK,
+            SetAccelCalibration(_) => tags::SET_ACCEL_CALIBRATION,
+            ConfigGet(_) => tags::CONFIG_GET,
+            Config(..) => tags::CONFIG,
+            ConfigSet(..) => tags::CONFIG_SET,
+            ConfigErase(_) => tags::CONFIG_ERASE,
+            ConfigCommit => tags::CONFIG_COMMIT,
+            ConfigFailed(_) => tags::CONFIG_FAILED,
+            CrashReportReq => tags::CRASH_REPORT_REQ,
+            CrashReport(_) => tags::CRASH_REPORT,
+            RebootToBootloader => tags::REBOOT_TO_BOOTLOADER,
+            Reboot => tags::REBOOT,
+            UpdateStatusReq => tags::UPDATE_STATUS_REQ,
+            UpdateStatus(_) => tags::UPDATE_STATUS,
+            UpdateBegin(_) => tags::UPDATE_BEGIN,
+            UpdateChunk { .. } => tags::UPDATE_CHUNK,
+            UpdateFinish => tags::UPDATE_FINISH,
+            UpdateFailed(_) => tags::UPDATE_FAILED,
+            StatsReq => tags::STATS_REQ,
+            Stats(_) => tags::STATS,
+            Unknown(tag) => *tag,
+        }
+    }
+
+    /// Whether this is a sensor sample, as pushed to a subscription.
+    pub fn is_sample(&self) -> bool {
+        matches!(self, Message::Accel(..) | Message::Mag(..) | Message::Gyro(..))
+    }
+
+    /// Whether the board can get this request twice without harm, because
+    /// it only reads, so it's safe to send again when the reply is late.
+    pub fn is_idempotent(&self) -> bool {
+        use Message::*;
+        matches!(
+            self,
+            Nop | Hello
+                | AccelReq
+                | MagReq
+                | GyroReq
+                | HeadingReq
+                | TimeReq
+                | ConfigGet(_)
+                | CrashReportReq
+                | UpdateStatusReq
+                | StatsReq
+        )
+    }
+
+    /// An `Info` level `Log` of `t` with no target, cut short to
+    /// `MAX_LOG_SIZE` bytes if it's longer.
     pub fn log<T: AsRef<[u8]>>(t: T) -> Self {
-        Message::Log(InternalBuffer::from(t))
+        Message::log_at(LogLevel::Info, "", t)
+    }
+
+    /// A `Log` of `t` from `target`, each cut short if it's too long.
+    pub fn log_at<T: AsRef<[u8]>>(level: LogLevel, target: &str, t: T) -> Self {
+        Message::Log { level, target: LogTarget::new(target.as_bytes()), text: LogText::new(t.as_ref()) }
+    }
+
+    /// The reply to `ConfigSet`, `ConfigErase` or `ConfigCommit`.
+    pub fn config_reply(result: Result<(), ConfigError>) -> Self {
+        match result {
+            Ok(()) => Message::Ack,
+            Err(e) => Message::ConfigFailed(e),
+        }
+    }
+
+    /// The reply to `UpdateBegin`, `UpdateChunk` or `UpdateFinish`.
+    pub fn update_reply(result: Result<(), UpdateError>) -> Self {
+        match result {
+            Ok(()) => Message::Ack,
+            Err(e) => Message::UpdateFailed(e),
+        }
     }
 
 }
@@ -73,7 +616,15 @@ impl Message {
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, InternalBuffer};
+    use super::{tags, Envelope, LogLevel, LogTarget, LogText, Message, MAX_LOG_SIZE, MAX_LOG_TARGET_SIZE};
+    use core::fmt::Write;
+    use crate::calibration::{AccelCalibration, MagCalibration};
+    use crate::config::{ConfigData, ConfigError, MAX_VALUE_SIZE};
+    use crate::crash::{CrashReport, ExceptionFrame, MAX_CRASH_FILE_SIZE};
+    use crate::device::{DeviceInfo, FirmwareVersion, Uid};
+    use crate::message_queue::QueueStats;
+    use crate::sensors::{Sensors, Stamp};
+    use crate::update::{ImageChunk, ImageInfo, UpdateError, UpdateStatus, MAX_CHUNK_SIZE};
     use serde::Serialize;
     use serde_cbor::Serializer;
     use serde_cbor::ser::SliceWrite;
@@ -94,7 +645,28 @@ mod tests {
         assert!(std::mem::size_of::<Message>() < Message::MAX_SIZE);
         assert!(get_size(&Message::Nop, &mut buf) < Message::MAX_SIZE);
         assert!(get_size(&Message::Hello, &mut buf) < Message::MAX_SIZE);
-        assert!(get_size(&Message::Log(InternalBuffer{b: [0u8; 128]}), &mut buf) < Message::MAX_SIZE);
+        let target = core::str::from_utf8(&[b'x'; MAX_LOG_TARGET_SIZE]).unwrap();
+        let log = Message::log_at(LogLevel::Trace, target, [0xFFu8; MAX_LOG_SIZE]);
+        assert!(get_size(&log, &mut buf) < Message::MAX_SIZE);
+        let env = Envelope::new(u16::MAX, log);
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+
+        // The largest crash report carries everything a panic and a fault do
+        let file = core::str::from_utf8(&[b'x'; MAX_CRASH_FILE_SIZE]).unwrap();
+        let mut report = CrashReport::panic(LogText::new(&[0xFFu8; MAX_LOG_SIZE]), file, u32::MAX);
+        report.frame = Some(ExceptionFrame { pc: u32::MAX, lr: u32::MAX, xpsr: u32::MAX });
+        let env = Envelope::new(u16::MAX, Message::CrashReport(Some(report)));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+
+        let data = ImageChunk::new(&[0xFF; MAX_CHUNK_SIZE]).unwrap();
+        let env = Envelope::new(u16::MAX, Message::UpdateChunk { offset: u32::MAX, data });
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+        let status = UpdateStatus::Receiving { size: u32::MAX, received: u32::MAX };
+        let env = Envelope::new(u16::MAX, Message::UpdateStatus(status));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+        let stats = QueueStats { enqueued: u32::MAX, dropped: u32::MAX, high_watermark: u16::MAX };
+        let env = Envelope::new(u16::MAX, Message::Stats(stats));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
     }
 
     #[test]
@@ -105,5 +677,237 @@ mod tests {
         let rx_msg = Message::from_bytes(&mut buf[..size]).unwrap();
         assert_eq!(msg, rx_msg);
     }
-}
 
+    #[test]
+    fn subscribe_size() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let msg = Message::Subscribe { sensors: Sensors::ALL, rate_hz: u16::MAX };
+        assert!(get_size(&msg, &mut buf) < Message::MAX_SIZE);
+        let msg = Message::Accel(Stamp::new(u64::MAX, u32::MAX), f32::MAX, f32::MAX, f32::MAX);
+        assert!(get_size(&msg, &mut buf) < Message::MAX_SIZE);
+        let msg = Message::Gyro(Stamp::new(u64::MAX, u32::MAX), f32::MAX, f32::MAX, f32::MAX);
+        assert!(get_size(&msg, &mut buf) < Message::MAX_SIZE);
+    }
+
+    #[test]
+    fn calibration_size() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let cal = MagCalibration { offset: [f32::MAX; 3], matrix: [[f32::MIN; 3]; 3] };
+        let env = Envelope::new(u16::MAX, Message::SetMagCalibration(cal));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+        let cal = AccelCalibration { offset: [f32::MAX; 3], scale: [f32::MIN; 3] };
+        let env = Envelope::new(u16::MAX, Message::SetAccelCalibration(cal));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+    }
+
+    #[test]
+    fn hello_ack_size() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let version = FirmwareVersion { major: u16::MAX, minor: u16::MAX, patch: u16::MAX };
+        let mut info = DeviceInfo::new(version, Uid([u32::MAX; 3]), Sensors::ALL);
+        info.protocol_version = u16::MAX;
+        let env = Envelope::new(u16::MAX, Message::HelloAck(info));
+        let size = env.write_bytes(&mut buf).unwrap();
+        assert!(size < Message::MAX_SIZE);
+        assert_eq!(env, Envelope::from_bytes(&mut buf[..size]).unwrap());
+    }
+
+    #[test]
+    fn config_size() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let data = ConfigData::new(&[0xFF; MAX_VALUE_SIZE]).unwrap();
+        let env = Envelope::new(u16::MAX, Message::ConfigSet(u16::MAX, data));
+        assert!(env.write_bytes(&mut buf).unwrap() < Message::MAX_SIZE);
+        let env = Envelope::new(u16::MAX, Message::Config(u16::MAX, Some(data)));
+        let size = env.write_bytes(&mut buf).unwrap();
+        assert!(size < Message::MAX_SIZE);
+        assert_eq!(env, Envelope::from_bytes(&mut buf[..size]).unwrap());
+    }
+
+    #[test]
+    fn envelope_reply() {
+        let req = Envelope::new(42, Message::AccelReq);
+        let reply = req.reply(Message::Accel(Stamp::new(1_000, 1), 0., 0., 1.));
+        assert_eq!(reply.seq, 42);
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let size = reply.write_bytes(&mut buf).unwrap();
+        assert_eq!(reply, Envelope::from_bytes(&mut buf[..size]).unwrap());
+    }
+
+    fn device_info() -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: 2,
+            firmware_version: FirmwareVersion { major: 1, minor: 2, patch: 3 },
+            uid: Uid([1, 2, 3]),
+            sensors: Sensors::ALL,
+            max_message_size: 256,
+            min_rate_hz: 1,
+            max_rate_hz: 100,
+        }
+    }
+
+    fn mag_calibration() -> MagCalibration {
+        MagCalibration {
+            offset: [100., -50., 0.],
+            matrix: [[2., 0., 0.], [0., 1., 0.5], [0., 0., 1.]],
+        }
+    }
+
+    fn accel_calibration() -> AccelCalibration {
+        AccelCalibration { offset: [0.5, 0., -0.25], scale: [2., 1., 0.5] }
+    }
+
+    fn image() -> ImageInfo {
+        ImageInfo { size: 1000, crc: 0xCBF4_3926 }
+    }
+
+    fn data(bytes: &[u8]) -> ConfigData {
+        ConfigData::new(bytes).unwrap()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // The exact bytes of every variant. If one of these fails, the change
+    // breaks every board and client built before it: give the variant a
+    // new tag, or bump `PROTOCOL_VERSION`, rather than editing the bytes.
+    fn golden() -> Vec<(Message, &'static str)> {
+        vec![
+            (Message::Nop, "8100"),
+            (Message::Hello, "8101"),
+            (Message::HelloAck(device_info()), "82028702830102038301020307190100011864"),
+            (Message::AccelReq, "8104"),
+            (Message::Accel(Stamp::new(1000, 7), 0.5, -0.25, 1.), "8505821903e807f93800f9b400f93c00"),
+            (Message::MagReq, "8106"),
+            (Message::Mag(Stamp::new(2000, 8), -300, 0, 421), "8507821907d00839012b001901a5"),
+            (Message::Subscribe { sensors: Sensors::ALL, rate_hz: 50 }, "8308071832"),
+            (Message::SubscribeAck(50), "82091832"),
+            (Message::Unsubscribe, "810a"),
+            (Message::TimeReq, "810b"),
+            (Message::Time(1_234_567_890_123), "820c1b0000011f71fb04cb"),
+            (Message::GyroReq, "810d"),
+            (Message::Gyro(Stamp::new(3000, 9), 0.1, 0., -2.), "850e82190bb809fa3dcccccdf90000f9c000"),
+            (Message::HeadingReq, "810f"),
+            (Message::Heading(90.), "8210f955a0"),
+            (Message::SetMagCalibration(mag_calibration()), concat!(
+                "82118283f95640f9d240f900008383f94000f90000f9000083f90000f93c00f9380083f90000f900",
+                "00f93c00",
+            )),
+            (Message::Ack, "8112"),
+            (Message::SetAccelCalibration(accel_calibration()), "82138283f93800f90000f9b40083f94000f93c00f93800"),
+            (Message::ConfigGet(3), "821403"),
+            (Message::Config(4, Some(data(b"compass"))), "83150447636f6d70617373"),
+            (Message::Config(5, None), "831505f6"),
+            (Message::ConfigSet(3, data(&[20])), "8316034114"),
+            (Message::ConfigErase(1), "821701"),
+            (Message::ConfigCommit, "811818"),
+            (Message::ConfigFailed(ConfigError::Full), "82181901"),
+            (Message::CrashReportReq, "81181b"),
+            (Message::CrashReport(None), "82181cf6"),
+            (
+                Message::CrashReport(Some(CrashReport::panic(LogText::new(b"oops"), "src/main.rs", 7))),
+                "82181c8500446f6f70734b7372632f6d61696e2e727307f6",
+            ),
+            (
+                Message::CrashReport(Some(CrashReport::hard_fault(ExceptionFrame { pc: 0x0800_1234, lr: 0x0800_0101, xpsr: 0x6100_0000 }))),
+                "82181c8501404000831a080012341a080001011a61000000",
+            ),
+            (Message::RebootToBootloader, "81181d"),
+            (Message::Reboot, "81181e"),
+            (Message::UpdateStatusReq, "81181f"),
+            (Message::UpdateStatus(UpdateStatus::Application(None)), "8218208200f6"),
+            (Message::UpdateStatus(UpdateStatus::Application(Some(image()))), "8218208200821903e81acbf43926"),
+            (Message::UpdateStatus(UpdateStatus::Idle), "8218208101"),
+            (Message::UpdateStatus(UpdateStatus::Receiving { size: 1000, received: 128 }), "82182083021903e81880"),
+            (Message::UpdateStatus(UpdateStatus::Staged(image())), "8218208203821903e81acbf43926"),
+            (Message::UpdateStatus(UpdateStatus::InstallFailed(UpdateError::Crc)), "821820820405"),
+            (Message::UpdateBegin(image()), "821821821903e81acbf43926"),
+            (Message::UpdateChunk { offset: 128, data: ImageChunk::new(&[1, 2, 3]).unwrap() }, "831822188043010203"),
+            (Message::UpdateFinish, "811823"),
+            (Message::UpdateFailed(UpdateError::Crc), "82182405"),
+            (Message::StatsReq, "811825"),
+            (Message::Stats(QueueStats { enqueued: 1000, dropped: 3, high_watermark: 10 }), "821826831903e8030a"),
+            (Message::log(b"hi"), "85181a426869f40340"),
+            (Message::log_at(LogLevel::Warn, "board::flash", b"hi"), "85181a426869f4024c626f6172643a3a666c617368"),
+            (Message::Unknown(1000), "811903e8"),
+        ]
+    }
+
+    #[test]
+    fn golden_encoding() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        for (msg, expected) in golden() {
+            let size = msg.write_bytes(&mut buf).unwrap();
+            assert_eq!(hex(&buf[..size]), expected, "{:?}", msg);
+            assert_eq!(Message::from_bytes(&mut unhex(expected)).unwrap(), msg);
+        }
+        let env = Envelope::new(42, Message::Ack);
+        let size = env.write_bytes(&mut buf).unwrap();
+        assert_eq!(hex(&buf[..size]), "82182a8112");
+    }
+
+    #[test]
+    fn every_tag_is_pinned() {
+        let golden = golden();
+        // Bar 3, which is retired
+        for tag in (tags::NOP..=tags::STATS).filter(|tag| *tag != 3) {
+            assert!(golden.iter().any(|(msg, _)| msg.tag() == tag), "no golden bytes for tag {}", tag);
+        }
+    }
+
+    #[test]
+    fn log_text() {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        let long = [b'x'; MAX_LOG_SIZE + 10];
+        let msg = Message::log(&long[..]);
+        assert!(matches!(msg, Message::Log { text, .. } if text.is_truncated() && text.as_bytes()[..] == long[..MAX_LOG_SIZE]));
+        let size = msg.write_bytes(&mut buf).unwrap();
+        assert_eq!(Message::from_bytes(&mut buf[..size]).unwrap(), msg);
+        assert!(matches!(Message::log(b"short"), Message::Log { text, .. } if !text.is_truncated()));
+
+        let text = LogText::new(b"caf\xc3\xa9 \xff!");
+        assert_eq!(text.to_string_lossy(), "caf\u{e9} \u{fffd}!");
+
+        let mut text = LogTarget::EMPTY;
+        write!(text, "board::{}", "x".repeat(MAX_LOG_TARGET_SIZE)).unwrap();
+        assert_eq!(text.as_bytes().len(), MAX_LOG_TARGET_SIZE);
+        assert!(text.is_truncated());
+    }
+
+    #[test]
+    fn newer_log_levels() {
+        // Levels past `Trace`, from a newer peer
+        let mut bytes = unhex("85181a426869f40740");
+        assert_eq!(Message::from_bytes(&mut bytes).unwrap(), Message::log_at(LogLevel::Trace, "", b"hi"));
+    }
+
+    #[test]
+    fn unknown_tag() {
+        // [1000, 1, "two"]: a message from the future, with fields
+        let mut bytes = unhex("831903e8016374776f");
+        assert_eq!(Message::from_bytes(&mut bytes).unwrap(), Message::Unknown(1000));
+        // Still delivered in an envelope, so the reply can be matched up
+        let mut bytes = unhex("8207831903e80102");
+        assert_eq!(Envelope::from_bytes(&mut bytes).unwrap(), Envelope::new(7, Message::Unknown(1000)));
+    }
+
+    #[test]
+    fn extra_fields_are_ignored() {
+        // Time(5) from a peer that added two fields to it
+        let mut bytes = unhex("840c0506f6");
+        assert_eq!(Message::from_bytes(&mut bytes).unwrap(), Message::Time(5));
+    }
+
+    #[test]
+    fn missing_fields_fail() {
+        // Time without its field
+        assert!(Message::from_bytes(&mut unhex("810c")).is_err());
+        // Log without its level and target
+        assert!(Message::from_bytes(&mut unhex("83181a426869f4")).is_err());
+    }
+}
@@ -1,39 +1,111 @@
-use crate::Message;
+//! The board's outgoing messages, waiting for the USB endpoint.
+//!
+//! When the host can't keep up the queue fills, and what happens next is
+//! up to its `OverflowPolicy`. Everything dropped is counted in its
+//! `QueueStats`, which the board reports in `Message::Stats`.
+
+use crate::Envelope;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug)]
 pub enum Error {
     Full,
 }
 
+/// What `MessageQueue::enqueue` does when the queue is full. Whatever the
+/// policy, replies and other messages that aren't sensor samples never
+/// lose out to samples: they take the place of the oldest queued sample
+/// first, and a sample only ever takes the place of another sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the message being enqueued.
+    DropNewest,
+    /// Drop the oldest message to make room.
+    DropOldest,
+    /// Put a sensor sample in place of the latest queued sample of the same
+    /// sensor and subscription, so the host gets the freshest reading.
+    /// Failing that, drop the message being enqueued, as with `DropNewest`.
+    Coalesce,
+}
+
+/// Counters kept by a `MessageQueue` since it was created. Goes on the wire
+/// as an array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages taken into the queue, coalesced samples included.
+    pub enqueued: u32,
+    /// Messages lost to a full queue: rejected, evicted or coalesced away.
+    pub dropped: u32,
+    /// Most messages the queue has held at once.
+    pub high_watermark: u16,
+}
+
+impl Serialize for QueueStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.enqueued, self.dropped, self.high_watermark).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueueStats {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QueueStats, D::Error> {
+        let (enqueued, dropped, high_watermark) = Deserialize::deserialize(deserializer)?;
+        Ok(QueueStats { enqueued, dropped, high_watermark })
+    }
+}
+
 pub struct MessageQueue {
     count: usize,
     read: usize,
     capacity: usize,
-    buf: [Message; 10],
+    buf: [Envelope; 10],
+    policy: OverflowPolicy,
+    stats: QueueStats,
 }
 
 impl MessageQueue {
     pub fn new() -> MessageQueue {
-        let buf = arr_macro::arr![Message::default(); 10];
+        MessageQueue::with_policy(OverflowPolicy::DropNewest)
+    }
+
+    pub fn with_policy(policy: OverflowPolicy) -> MessageQueue {
+        let buf = arr_macro::arr![Envelope::default(); 10];
         MessageQueue {
             count: 0,
             read: 0,
             capacity: buf.len(),
             buf,
+            policy,
+            stats: QueueStats::default(),
         }
     }
 
-    pub fn enqueue(&mut self, msg: &Message) -> Result<(), Error> {
+    /// Queue `msg`, or fail with `Full` if the queue is full and the policy
+    /// drops it. The policy may make room by dropping something else.
+    pub fn enqueue(&mut self, msg: &Envelope) -> Result<(), Error> {
         if self.count == self.capacity {
-            Err(Error::Full)
-        } else {
-            self.buf[self.next(self.read, self.count)] = msg.clone();
-            self.count += 1;
-            Ok(())
+            let sample = msg.msg.is_sample();
+            let evict = match self.policy {
+                OverflowPolicy::Coalesce if sample => return self.coalesce(msg),
+                OverflowPolicy::DropNewest if sample => None,
+                OverflowPolicy::DropOldest if !sample => self.oldest_sample().or(Some(0)),
+                _ => self.oldest_sample(),
+            };
+            match evict {
+                Some(i) => {
+                    self.remove(i);
+                    self.stats.dropped = self.stats.dropped.wrapping_add(1);
+                }
+                None => return self.reject(),
+            }
         }
+        self.buf[self.next(self.read, self.count)] = msg.clone();
+        self.count += 1;
+        self.stats.enqueued = self.stats.enqueued.wrapping_add(1);
+        self.stats.high_watermark = self.stats.high_watermark.max(self.count as u16);
+        Ok(())
     }
 
-    pub fn dequeue(&mut self) -> Option<Message> {
+    pub fn dequeue(&mut self) -> Option<Envelope> {
         if self.count == 0 {
             None
         } else {
@@ -44,6 +116,45 @@ impl MessageQueue {
         }
     }
 
+    fn reject(&mut self) -> Result<(), Error> {
+        self.stats.dropped = self.stats.dropped.wrapping_add(1);
+        Err(Error::Full)
+    }
+
+    // Put the sample `msg` in place of the latest queued one like it
+    fn coalesce(&mut self, msg: &Envelope) -> Result<(), Error> {
+        let slot = match self.latest_like(msg) {
+            Some(slot) => slot,
+            None => return self.reject(),
+        };
+        self.buf[slot] = msg.clone();
+        self.stats.enqueued = self.stats.enqueued.wrapping_add(1);
+        self.stats.dropped = self.stats.dropped.wrapping_add(1);
+        Ok(())
+    }
+
+    // Where the latest queued sample of the same sensor and subscription as
+    // `msg` is
+    fn latest_like(&self, msg: &Envelope) -> Option<usize> {
+        (0..self.count)
+            .rev()
+            .map(|i| self.next(self.read, i))
+            .find(|&slot| self.buf[slot].seq == msg.seq && self.buf[slot].msg.tag() == msg.msg.tag())
+    }
+
+    // How many messages are queued ahead of the oldest sample, if there is one
+    fn oldest_sample(&self) -> Option<usize> {
+        (0..self.count).find(|&i| self.buf[self.next(self.read, i)].msg.is_sample())
+    }
+
+    // Drop the `i`th queued message, closing the gap
+    fn remove(&mut self, i: usize) {
+        for j in i..self.count - 1 {
+            self.buf[self.next(self.read, j)] = self.buf[self.next(self.read, j + 1)].clone();
+        }
+        self.count -= 1;
+    }
+
     fn next(&self, x: usize, a: usize) -> usize {
         (x + a) % self.capacity
     }
@@ -59,15 +170,20 @@ impl MessageQueue {
     pub fn available_empty(&self) -> usize {
         self.capacity - self.count
     }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{Message, Stamp};
     #[test]
     fn capacity() {
         let mut mm = MessageQueue::new();
-        let msg = Message::Nop;
+        let msg = Envelope::from(Message::Nop);
         for _ in 0..mm.capacity() {
             mm.enqueue(&msg).unwrap();
         }
@@ -83,7 +199,7 @@ mod tests {
     #[test]
     fn wrap() {
         let mut mm = MessageQueue::new();
-        let msg = Message::Hello;
+        let msg = Envelope::from(Message::Hello);
 
         for _ in 0..mm.capacity() {
             mm.enqueue(&msg).unwrap();
@@ -96,14 +212,93 @@ mod tests {
     #[test]
     fn contents() {
         let mut mm = MessageQueue::new();
-        let msg = Message::Hello;
+        let msg = Envelope::from(Message::Hello);
         mm.enqueue(&msg).unwrap();
         let rmsg = mm.dequeue().unwrap();
-        match rmsg {
+        match rmsg.msg {
             Message::Hello => {},
             _ => {
                 assert!(false);
             }
         }
     }
+
+    #[test]
+    fn drop_newest() {
+        let mut mm = MessageQueue::new();
+        for seq in 0..mm.capacity() as u16 + 2 {
+            let _ = mm.enqueue(&Envelope::new(seq, Message::Ack));
+        }
+        assert_eq!(mm.dequeue().unwrap().seq, 0);
+        assert_eq!(mm.stats(), QueueStats { enqueued: 10, dropped: 2, high_watermark: 10 });
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut mm = MessageQueue::with_policy(OverflowPolicy::DropOldest);
+        for seq in 0..mm.capacity() as u16 + 2 {
+            mm.enqueue(&Envelope::new(seq, Message::Ack)).unwrap();
+        }
+        assert_eq!(mm.count(), mm.capacity());
+        assert_eq!(mm.dequeue().unwrap().seq, 2);
+        assert_eq!(mm.stats(), QueueStats { enqueued: 12, dropped: 2, high_watermark: 10 });
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut mm = MessageQueue::with_policy(OverflowPolicy::Coalesce);
+        let mag = |count| Envelope::new(1, Message::Mag(Stamp::new(0, count), 0, 0, 0));
+        let accel = |count| Envelope::new(1, Message::Accel(Stamp::new(0, count), 0., 0., 0.));
+        for count in 0..mm.capacity() as u32 / 2 {
+            mm.enqueue(&accel(count)).unwrap();
+            mm.enqueue(&mag(count)).unwrap();
+        }
+        // The newest accel sample takes the place of the last one queued
+        mm.enqueue(&accel(100)).unwrap();
+        // Another subscription's sample has nothing to replace
+        assert!(mm.enqueue(&Envelope::new(2, Message::Mag(Stamp::new(0, 100), 0, 0, 0))).is_err());
+        // A reply pushes out the oldest sample
+        mm.enqueue(&Envelope::new(3, Message::Ack)).unwrap();
+        let queued: Vec<_> = core::iter::from_fn(|| mm.dequeue())
+            .map(|env| match env.msg {
+                Message::Accel(stamp, ..) => ("accel", stamp.count),
+                Message::Mag(stamp, ..) => ("mag", stamp.count),
+                _ => ("ack", 0),
+            })
+            .collect();
+        assert_eq!(queued[0], ("mag", 0));
+        assert_eq!(queued[7], ("accel", 100));
+        assert_eq!(queued[9], ("ack", 0));
+        assert_eq!(mm.stats(), QueueStats { enqueued: 12, dropped: 3, high_watermark: 10 });
+
+        let mut mm = MessageQueue::with_policy(OverflowPolicy::Coalesce);
+        for _ in 0..mm.capacity() {
+            mm.enqueue(&Envelope::new(1, Message::Ack)).unwrap();
+        }
+        assert!(mm.enqueue(&Envelope::new(1, Message::Ack)).is_err());
+        assert!(mm.enqueue(&accel(0)).is_err());
+    }
+
+    #[test]
+    fn replies_beat_samples() {
+        let accel = |seq| Envelope::new(seq, Message::Accel(Stamp::default(), 0., 0., 0.));
+        for &policy in &[OverflowPolicy::DropNewest, OverflowPolicy::DropOldest, OverflowPolicy::Coalesce] {
+            let mut mm = MessageQueue::with_policy(policy);
+            mm.enqueue(&Envelope::new(1, Message::Ack)).unwrap();
+            for _ in 1..mm.capacity() {
+                mm.enqueue(&accel(2)).unwrap();
+            }
+            mm.enqueue(&Envelope::new(3, Message::Ack)).unwrap();
+            let queued: Vec<_> = core::iter::from_fn(|| mm.dequeue()).collect();
+            assert_eq!(queued.iter().filter(|env| env.msg == Message::Ack).count(), 2, "{:?}", policy);
+
+            // Nor does a sample push out a reply
+            let mut mm = MessageQueue::with_policy(policy);
+            for seq in 0..mm.capacity() as u16 {
+                mm.enqueue(&Envelope::new(seq, Message::Ack)).unwrap();
+            }
+            assert!(mm.enqueue(&accel(2)).is_err(), "{:?}", policy);
+            assert_eq!(mm.dequeue().unwrap().seq, 0);
+        }
+    }
 }
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Slowest rate a subscription can ask for.
+pub const MIN_RATE_HZ: u16 = 1;
+/// Fastest rate the board can sample and push at.
+pub const MAX_RATE_HZ: u16 = 100;
+
+/// Clamp a requested sample rate to what the board supports.
+pub fn clamp_rate(rate_hz: u16) -> u16 {
+    rate_hz.clamp(MIN_RATE_HZ, MAX_RATE_HZ)
+}
+
+// LSM303DLHC magnetometer gain at its default ±1.3 gauss range. The z axis
+// is less sensitive than the other two.
+const MAG_XY_LSB_PER_GAUSS: f32 = 1100.;
+const MAG_Z_LSB_PER_GAUSS: f32 = 980.;
+
+/// Convert raw `Mag` counts to gauss, so that all three axes share a scale.
+pub fn mag_gauss(x: i16, y: i16, z: i16) -> [f32; 3] {
+    [
+        x as f32 / MAG_XY_LSB_PER_GAUSS,
+        y as f32 / MAG_XY_LSB_PER_GAUSS,
+        z as f32 / MAG_Z_LSB_PER_GAUSS,
+    ]
+}
+
+/// When a sample was taken: microseconds since the board booted, on the
+/// board's monotonic clock, plus a counter that increments once per sensor
+/// read cycle. Samples read in the same cycle share a count. Goes on the
+/// wire as an array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stamp {
+    pub time_us: u64,
+    pub count: u32,
+}
+
+impl Stamp {
+    pub const fn new(time_us: u64, count: u32) -> Stamp {
+        Stamp { time_us, count }
+    }
+}
+
+impl Serialize for Stamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.time_us, self.count).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Stamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Stamp, D::Error> {
+        let (time_us, count) = Deserialize::deserialize(deserializer)?;
+        Ok(Stamp { time_us, count })
+    }
+}
+
+/// A set of sensors, stored as a bitmask.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Sensors(u8);
+
+impl Sensors {
+    pub const NONE: Sensors = Sensors(0);
+    pub const ACCEL: Sensors = Sensors(1 << 0);
+    pub const MAG: Sensors = Sensors(1 << 1);
+    pub const GYRO: Sensors = Sensors(1 << 2);
+    pub const ALL: Sensors = Sensors(Sensors::ACCEL.0 | Sensors::MAG.0 | Sensors::GYRO.0);
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Sensors) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(&self, other: Sensors) -> Sensors {
+        Sensors(self.0 | other.0)
+    }
+
+    pub const fn intersection(&self, other: Sensors) -> Sensors {
+        Sensors(self.0 & other.0)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for Sensors {
+    type Output = Sensors;
+
+    fn bitor(self, other: Sensors) -> Sensors {
+        self.union(other)
+    }
+}
+
+impl core::ops::BitAnd for Sensors {
+    type Output = Sensors;
+
+    fn bitand(self, other: Sensors) -> Sensors {
+        self.intersection(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets() {
+        let both = Sensors::ACCEL | Sensors::MAG;
+        assert_eq!(both | Sensors::GYRO, Sensors::ALL);
+        assert!(both.contains(Sensors::ACCEL));
+        assert!(!both.contains(Sensors::GYRO));
+        assert!(!Sensors::MAG.contains(Sensors::ACCEL));
+        assert!(Sensors::NONE.is_empty());
+        assert_eq!(Sensors::ALL & both, both);
+        assert!((Sensors::GYRO & both).is_empty());
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(clamp_rate(0), MIN_RATE_HZ);
+        assert_eq!(clamp_rate(10), 10);
+        assert_eq!(clamp_rate(u16::MAX), MAX_RATE_HZ);
+    }
+}
@@ -0,0 +1,1006 @@
+//! Firmware updates, staged in flash by the bootloader.
+//!
+//! The host sends the new image in chunks after `UpdateBegin`, and they are
+//! written to the staging slot, never over the running application. Only
+//! once `finish` finds the CRC-32 of everything staged matches the one the
+//! host gave does a header go after the image, marking it for `install`.
+//! Anything short of that leaves the application slot as it was, so a
+//! failed update rolls back to the old firmware just by rebooting.
+//!
+//! `install` swaps a staged image with the application a page at a time,
+//! through a scratch page, so the old firmware ends up in the staging slot
+//! rather than lost. Each step of the swap is journalled after the header,
+//! so a swap cut short by a power cut carries on at the next boot. The new
+//! image is then on trial: unless it confirms a good boot before the next
+//! reset (see `BOOT_CONFIRM`), `install` swaps the old firmware back.
+//!
+//! Header, in the last page of the staging slot: magic `u32`, image size
+//! `u32`, CRC-32 `u32`, then a `u16` each programmed to 0 once the image is
+//! installed, once it confirmed a good boot, and once it was swapped back
+//! out. All fields are little-endian. The journal starts at
+//! `JOURNAL_OFFSET`, a `u16` programmed to 0 per step of each page's swap,
+//! for the swap in and then the swap back.
+
+use crate::crc::Crc32;
+use crate::message::Fields;
+use core::fmt;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Most image bytes an `UpdateChunk` carries.
+pub const MAX_CHUNK_SIZE: usize = 128;
+/// Bytes of the header at the start of the staging slot's last page.
+pub const HEADER_SIZE: usize = 18;
+
+/// Left by the application in the boot request word (`_boot_request` in
+/// `memory.x`) before a reset, to keep the bootloader in charge.
+pub const BOOT_REQUEST: u32 = 0x544F_4F42; // "BOOT"
+/// Left by the application in the boot confirm word (`_boot_confirm` in
+/// `memory.x`) once the host has talked to it, to keep an image on trial.
+pub const BOOT_CONFIRM: u32 = 0x444F_4F47; // "GOOD"
+
+const MAGIC: u32 = 0x3147_4D49; // "IMG1"
+const INSTALLED_OFFSET: usize = 12;
+const CONFIRMED_OFFSET: usize = 14;
+const REVERTED_OFFSET: usize = 16;
+const JOURNAL_OFFSET: usize = 32;
+// Application page to scratch, staging page to application, scratch to
+// staging
+const SWAP_STEPS: usize = 3;
+const UNSET: u16 = 0xFFFF;
+// Bytes read or copied at a time, to keep the bootloader's stack small
+const COPY_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// Where the application runs from.
+    Application,
+    /// Where an update is staged: an image's worth of pages, then one for
+    /// the header.
+    Staging,
+    /// One page, to swap the other two through.
+    Scratch,
+}
+
+/// The flash the bootloader manages, as NOR flash: erasing sets every byte
+/// to `0xFF`, and writes can only program erased half-words.
+pub trait Slots {
+    type Error: fmt::Debug;
+
+    /// Bytes in one erasable page.
+    const PAGE_SIZE: usize;
+    /// Largest image, in bytes: the size of the application slot, and a
+    /// whole number of pages.
+    const IMAGE_SIZE: usize;
+
+    fn read(&mut self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Program `data` at `offset`. Both are half-word aligned.
+    fn write(&mut self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase_page(&mut self, slot: Slot, page: usize) -> Result<(), Self::Error>;
+}
+
+/// Goes on the wire as its tag, see `UpdateError::tag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    /// Updates go through the bootloader, see `Message::RebootToBootloader`.
+    NotInBootloader,
+    /// The image is empty or bigger than the application slot.
+    BadSize,
+    /// A chunk or `UpdateFinish` came without an `UpdateBegin`.
+    NotStarted,
+    /// A chunk doesn't carry on from the last one, or runs past the image.
+    OutOfOrder,
+    /// `UpdateFinish` came before the whole image.
+    Incomplete,
+    /// The image's CRC doesn't match the one given to `UpdateBegin`.
+    Crc,
+    /// The flash reported an error.
+    Flash,
+    /// The image didn't confirm a good boot, so the old firmware was put
+    /// back.
+    RolledBack,
+    /// A tag from a newer peer.
+    Unknown(u8),
+}
+
+impl UpdateError {
+    /// This error's wire tag. Tags are never reused or renumbered.
+    pub fn tag(self) -> u8 {
+        use UpdateError::*;
+        match self {
+            NotInBootloader => 0,
+            BadSize => 1,
+            NotStarted => 2,
+            OutOfOrder => 3,
+            Incomplete => 4,
+            Crc => 5,
+            Flash => 6,
+            RolledBack => 7,
+            Unknown(tag) => tag,
+        }
+    }
+
+    fn from_tag(tag: u8) -> UpdateError {
+        use UpdateError::*;
+        match tag {
+            0 => NotInBootloader,
+            1 => BadSize,
+            2 => NotStarted,
+            3 => OutOfOrder,
+            4 => Incomplete,
+            5 => Crc,
+            6 => Flash,
+            7 => RolledBack,
+            _ => Unknown(tag),
+        }
+    }
+}
+
+impl Serialize for UpdateError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.tag())
+    }
+}
+
+impl<'de> Deserialize<'de> for UpdateError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<UpdateError, D::Error> {
+        Ok(UpdateError::from_tag(u8::deserialize(deserializer)?))
+    }
+}
+
+/// The size and CRC-32 of a firmware image. Goes on the wire as an array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub size: u32,
+    pub crc: u32,
+}
+
+impl ImageInfo {
+    pub fn of(image: &[u8]) -> ImageInfo {
+        let mut crc = Crc32::new();
+        crc.update(image);
+        ImageInfo { size: image.len() as u32, crc: crc.finish() }
+    }
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes, CRC {:#010x}", self.size, self.crc)
+    }
+}
+
+impl Serialize for ImageInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.size, self.crc).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ImageInfo, D::Error> {
+        let (size, crc) = Deserialize::deserialize(deserializer)?;
+        Ok(ImageInfo { size, crc })
+    }
+}
+
+/// Where a board is with updating its firmware. Goes on the wire as an
+/// array of its tag then its fields, like a `Message`, see
+/// `UpdateStatus::tag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The application is running, not the bootloader. Holds the image the
+    /// bootloader last installed, unless the firmware went on some other
+    /// way, such as through a debugger.
+    Application(Option<ImageInfo>),
+    /// In the bootloader, waiting for `UpdateBegin`.
+    Idle,
+    Receiving { size: u32, received: u32 },
+    /// `UpdateFinish` verified the staged image, for `Reboot` to install.
+    Staged(ImageInfo),
+    /// The bootloader couldn't install the staged image at boot, so it
+    /// stayed in charge.
+    InstallFailed(UpdateError),
+    /// A tag from a newer peer.
+    Unknown(u8),
+}
+
+impl UpdateStatus {
+    /// This status's wire tag. Tags are never reused or renumbered.
+    pub fn tag(&self) -> u8 {
+        use UpdateStatus::*;
+        match self {
+            Application(_) => 0,
+            Idle => 1,
+            Receiving { .. } => 2,
+            Staged(_) => 3,
+            InstallFailed(_) => 4,
+            Unknown(tag) => *tag,
+        }
+    }
+}
+
+impl Serialize for UpdateStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use UpdateStatus::*;
+        let tag = self.tag();
+        match self {
+            Idle | Unknown(_) => (tag,).serialize(serializer),
+            Application(image) => (tag, image).serialize(serializer),
+            Receiving { size, received } => (tag, size, received).serialize(serializer),
+            Staged(image) => (tag, image).serialize(serializer),
+            InstallFailed(e) => (tag, e).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UpdateStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<UpdateStatus, D::Error> {
+        struct StatusVisitor;
+
+        impl<'de> Visitor<'de> for StatusVisitor {
+            type Value = UpdateStatus;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a tagged update status")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<UpdateStatus, A::Error> {
+                use UpdateStatus::*;
+                let mut fields = Fields::new(seq);
+                let tag = fields.next()?;
+                let status = match tag {
+                    0 => Application(fields.next()?),
+                    1 => Idle,
+                    2 => Receiving { size: fields.next()?, received: fields.next()? },
+                    3 => Staged(fields.next()?),
+                    4 => InstallFailed(fields.next()?),
+                    _ => Unknown(tag),
+                };
+                fields.finish()?;
+                Ok(status)
+            }
+        }
+
+        deserializer.deserialize_seq(StatusVisitor)
+    }
+}
+
+/// Up to `MAX_CHUNK_SIZE` bytes of an image.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ImageChunk {
+    len: u8,
+    buf: [u8; MAX_CHUNK_SIZE],
+}
+
+impl ImageChunk {
+    pub fn new(data: &[u8]) -> Option<ImageChunk> {
+        if data.len() > MAX_CHUNK_SIZE {
+            return None;
+        }
+        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+        Some(ImageChunk { len: data.len() as u8, buf })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for ImageChunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ImageChunk({} bytes)", self.len)
+    }
+}
+
+impl Serialize for ImageChunk {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageChunk {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ImageChunk, D::Error> {
+        struct ChunkVisitor;
+
+        impl<'de> Visitor<'de> for ChunkVisitor {
+            type Value = ImageChunk;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "at most {} bytes", MAX_CHUNK_SIZE)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ImageChunk, E> {
+                ImageChunk::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(ChunkVisitor)
+    }
+}
+
+/// The header marking a verified image in the staging slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub image: ImageInfo,
+    /// Swapped into the application slot.
+    pub installed: bool,
+    /// Confirmed a good boot, so it stays.
+    pub confirmed: bool,
+    /// Swapped back out for the old firmware.
+    pub reverted: bool,
+}
+
+impl Header {
+    /// Read a header from the start of the staging slot's last page, if
+    /// there is one.
+    pub fn parse(bytes: &[u8; HEADER_SIZE]) -> Option<Header> {
+        let word = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        if word(0) != MAGIC {
+            return None;
+        }
+        let set = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) != UNSET;
+        Some(Header {
+            image: ImageInfo { size: word(4), crc: word(8) },
+            installed: set(INSTALLED_OFFSET),
+            confirmed: set(CONFIRMED_OFFSET),
+            reverted: set(REVERTED_OFFSET),
+        })
+    }
+
+    /// The image in the application slot, if the bootloader put it there.
+    pub fn application(&self) -> Option<ImageInfo> {
+        if self.installed && !self.reverted {
+            Some(self.image)
+        } else {
+            None
+        }
+    }
+
+    // As written by `finish`, with the flags left erased
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0xFF; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.image.size.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.image.crc.to_le_bytes());
+        bytes
+    }
+}
+
+// Which way `swap` is going
+#[derive(Clone, Copy)]
+enum Swap {
+    Install = 0,
+    Revert = 1,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Idle,
+    Receiving { image: ImageInfo, received: u32 },
+    Staged(ImageInfo),
+    InstallFailed(UpdateError),
+}
+
+/// Stages updates sent by the host, and installs them.
+pub struct Updater<S: Slots> {
+    slots: S,
+    state: State,
+}
+
+impl<S: Slots> Updater<S> {
+    pub fn new(slots: S) -> Updater<S> {
+        debug_assert!(JOURNAL_OFFSET + 2 * 2 * SWAP_STEPS * S::IMAGE_SIZE / S::PAGE_SIZE <= S::PAGE_SIZE);
+        Updater { slots, state: State::Idle }
+    }
+
+    /// Give the flash back.
+    pub fn release(self) -> S {
+        self.slots
+    }
+
+    pub fn status(&self) -> UpdateStatus {
+        match self.state {
+            State::Idle => UpdateStatus::Idle,
+            State::Receiving { image, received } => UpdateStatus::Receiving { size: image.size, received },
+            State::Staged(image) => UpdateStatus::Staged(image),
+            State::InstallFailed(e) => UpdateStatus::InstallFailed(e),
+        }
+    }
+
+    /// Start staging `image`, dropping whatever was staged before.
+    pub fn begin(&mut self, image: ImageInfo) -> Result<(), UpdateError> {
+        if image.size == 0 || image.size as usize > S::IMAGE_SIZE {
+            return Err(UpdateError::BadSize);
+        }
+        self.state = State::Idle;
+        self.slots.erase_page(Slot::Staging, Self::header_page()).map_err(flash_error)?;
+        self.state = State::Receiving { image, received: 0 };
+        Ok(())
+    }
+
+    /// Stage `data` at `offset` in the image. Chunks come in order, each of
+    /// an even length but the last. One that was staged already, sent
+    /// again because its reply went missing, is accepted as it is.
+    pub fn chunk(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateError> {
+        let (image, received) = match self.state {
+            State::Receiving { image, received } => (image, received as usize),
+            _ => return Err(UpdateError::NotStarted),
+        };
+        let (offset, end) = (offset as usize, offset as usize + data.len());
+        if end > image.size as usize {
+            return Err(UpdateError::OutOfOrder);
+        }
+        if end <= received {
+            return match self.staged_as(offset, data)? {
+                true => Ok(()),
+                false => Err(UpdateError::OutOfOrder),
+            };
+        }
+        if offset != received || (!data.len().is_multiple_of(2) && end != image.size as usize) {
+            return Err(UpdateError::OutOfOrder);
+        }
+        // Pages are erased as the image reaches them
+        let mut page = offset.div_ceil(S::PAGE_SIZE);
+        while page * S::PAGE_SIZE < end {
+            self.slots.erase_page(Slot::Staging, page).map_err(flash_error)?;
+            page += 1;
+        }
+        let even = data.len() & !1;
+        self.slots.write(Slot::Staging, offset, &data[..even]).map_err(flash_error)?;
+        if even < data.len() {
+            self.slots.write(Slot::Staging, offset + even, &[data[even], 0xFF]).map_err(flash_error)?;
+        }
+        self.state = State::Receiving { image, received: end as u32 };
+        Ok(())
+    }
+
+    /// Check the whole image is staged with the right CRC, and mark it for
+    /// `install`. A bad CRC drops the image.
+    pub fn finish(&mut self) -> Result<(), UpdateError> {
+        let image = match self.state {
+            State::Idle | State::InstallFailed(_) => return Err(UpdateError::NotStarted),
+            State::Receiving { image, received } if received == image.size => image,
+            State::Receiving { .. } => return Err(UpdateError::Incomplete),
+            State::Staged(_) => return Ok(()),
+        };
+        if self.crc(Slot::Staging, image.size)? != image.crc {
+            self.state = State::Idle;
+            return Err(UpdateError::Crc);
+        }
+        // The magic goes last, so a header cut short by a power cut isn't one
+        let header = Header { image, installed: false, confirmed: false, reverted: false }.to_bytes();
+        let offset = Self::header_page() * S::PAGE_SIZE;
+        self.slots.write(Slot::Staging, offset + 4, &header[4..INSTALLED_OFFSET]).map_err(flash_error)?;
+        self.slots.write(Slot::Staging, offset, &header[..4]).map_err(flash_error)?;
+        self.state = State::Staged(image);
+        Ok(())
+    }
+
+    /// The header in the staging slot, if there is one.
+    pub fn header(&mut self) -> Result<Option<Header>, UpdateError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        self.slots.read(Slot::Staging, Self::header_page() * S::PAGE_SIZE, &mut bytes).map_err(flash_error)?;
+        Ok(Header::parse(&bytes).filter(|header| header.image.size as usize <= S::IMAGE_SIZE))
+    }
+
+    /// At boot, swap a staged image into the application slot, and return
+    /// it if it was. One installed at the last boot is kept if it
+    /// `confirmed` a good boot since, or swapped back out for the old
+    /// firmware, failing with `RolledBack`. A staged image that no longer
+    /// matches its CRC is dropped, leaving the application alone.
+    pub fn install(&mut self, confirmed: bool) -> Result<Option<ImageInfo>, UpdateError> {
+        let result = self.settle(confirmed);
+        if let Err(e) = result {
+            self.state = State::InstallFailed(e);
+        }
+        result
+    }
+
+    /// Whether the application slot holds the image the header says was
+    /// installed there. One put there some other way, such as by a
+    /// debugger over an image that was never installed, can't be checked.
+    pub fn application_intact(&mut self) -> Result<bool, UpdateError> {
+        match self.header()?.and_then(|header| header.application()) {
+            Some(image) => Ok(self.crc(Slot::Application, image.size)? == image.crc),
+            None => Ok(true),
+        }
+    }
+
+    fn settle(&mut self, confirmed: bool) -> Result<Option<ImageInfo>, UpdateError> {
+        let header = match self.header()? {
+            Some(header) if !header.confirmed && !header.reverted => header,
+            _ => return Ok(None),
+        };
+        let image = header.image;
+        if header.installed {
+            // A swap back that was cut short is finished regardless
+            if confirmed && !self.swapped(Swap::Revert)? {
+                self.mark(CONFIRMED_OFFSET)?;
+                return Ok(None);
+            }
+            self.revert(image)?;
+            return Err(UpdateError::RolledBack);
+        }
+        if !self.swapped(Swap::Install)? && self.crc(Slot::Staging, image.size)? != image.crc {
+            self.slots.erase_page(Slot::Staging, Self::header_page()).map_err(flash_error)?;
+            return Err(UpdateError::Crc);
+        }
+        self.swap(Swap::Install, image)?;
+        if self.crc(Slot::Application, image.size)? != image.crc {
+            self.revert(image)?;
+            return Err(UpdateError::Crc);
+        }
+        self.mark(INSTALLED_OFFSET)?;
+        Ok(Some(image))
+    }
+
+    fn revert(&mut self, image: ImageInfo) -> Result<(), UpdateError> {
+        self.swap(Swap::Revert, image)?;
+        self.mark(REVERTED_OFFSET)
+    }
+
+    // Swap as many pages of the application and staging slots as `image`
+    // covers, skipping the steps the journal has done already
+    fn swap(&mut self, swap: Swap, image: ImageInfo) -> Result<(), UpdateError> {
+        for page in 0..(image.size as usize).div_ceil(S::PAGE_SIZE) {
+            let steps = [
+                (Slot::Application, page, Slot::Scratch, 0),
+                (Slot::Staging, page, Slot::Application, page),
+                (Slot::Scratch, 0, Slot::Staging, page),
+            ];
+            for (step, &(from, from_page, to, to_page)) in steps.iter().enumerate() {
+                let offset = Self::journal(swap, page, step);
+                if !self.marked(offset)? {
+                    self.copy_page(from, from_page, to, to_page)?;
+                    self.mark(offset)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether `swap` has got as far as its first step
+    fn swapped(&mut self, swap: Swap) -> Result<bool, UpdateError> {
+        self.marked(Self::journal(swap, 0, 0))
+    }
+
+    fn copy_page(&mut self, from: Slot, from_page: usize, to: Slot, to_page: usize) -> Result<(), UpdateError> {
+        self.slots.erase_page(to, to_page).map_err(flash_error)?;
+        let mut buf = [0u8; COPY_SIZE];
+        let mut offset = 0;
+        while offset < S::PAGE_SIZE {
+            let len = (S::PAGE_SIZE - offset).min(COPY_SIZE);
+            self.slots.read(from, from_page * S::PAGE_SIZE + offset, &mut buf[..len]).map_err(flash_error)?;
+            self.slots.write(to, to_page * S::PAGE_SIZE + offset, &buf[..len]).map_err(flash_error)?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    // Where in the header page the journal records `step` of swapping `page`
+    fn journal(swap: Swap, page: usize, step: usize) -> usize {
+        let pages = S::IMAGE_SIZE / S::PAGE_SIZE;
+        JOURNAL_OFFSET + 2 * ((swap as usize * pages + page) * SWAP_STEPS + step)
+    }
+
+    // Program the flag or journal entry at `offset` in the header page
+    fn mark(&mut self, offset: usize) -> Result<(), UpdateError> {
+        let offset = Self::header_page() * S::PAGE_SIZE + offset;
+        self.slots.write(Slot::Staging, offset, &0u16.to_le_bytes()).map_err(flash_error)
+    }
+
+    fn marked(&mut self, offset: usize) -> Result<bool, UpdateError> {
+        let mut bytes = [0u8; 2];
+        let offset = Self::header_page() * S::PAGE_SIZE + offset;
+        self.slots.read(Slot::Staging, offset, &mut bytes).map_err(flash_error)?;
+        Ok(u16::from_le_bytes(bytes) != UNSET)
+    }
+
+    fn header_page() -> usize {
+        S::IMAGE_SIZE / S::PAGE_SIZE
+    }
+
+    fn crc(&mut self, slot: Slot, size: u32) -> Result<u32, UpdateError> {
+        let mut crc = Crc32::new();
+        let mut buf = [0u8; COPY_SIZE];
+        let mut offset = 0;
+        while offset < size as usize {
+            let len = (size as usize - offset).min(COPY_SIZE);
+            self.slots.read(slot, offset, &mut buf[..len]).map_err(flash_error)?;
+            crc.update(&buf[..len]);
+            offset += len;
+        }
+        Ok(crc.finish())
+    }
+
+    // Whether `data` is what's staged at `offset`
+    fn staged_as(&mut self, offset: usize, data: &[u8]) -> Result<bool, UpdateError> {
+        let mut buf = [0u8; COPY_SIZE];
+        for (i, piece) in data.chunks(COPY_SIZE).enumerate() {
+            let staged = &mut buf[..piece.len()];
+            self.slots.read(Slot::Staging, offset + i * COPY_SIZE, staged).map_err(flash_error)?;
+            if staged != piece {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S: Slots + Default> Default for Updater<S> {
+    fn default() -> Updater<S> {
+        Updater::new(S::default())
+    }
+}
+
+fn flash_error<E: fmt::Debug>(_: E) -> UpdateError {
+    UpdateError::Flash
+}
+
+#[cfg(feature = "std")]
+pub use ram::RamSlots;
+
+#[cfg(feature = "std")]
+mod ram {
+    use super::{Slot, Slots};
+    use crate::config::RamFlashError;
+
+    /// Slots simulated in RAM, for running `Updater` on the host. `IMAGE`
+    /// is a whole number of `PAGE`s.
+    pub struct RamSlots<const PAGE: usize, const IMAGE: usize> {
+        application: Vec<u8>,
+        staging: Vec<u8>,
+        scratch: Vec<u8>,
+        power: Option<usize>,
+    }
+
+    impl<const PAGE: usize, const IMAGE: usize> RamSlots<PAGE, IMAGE> {
+        /// Fresh from the factory: fully erased.
+        pub fn new() -> RamSlots<PAGE, IMAGE> {
+            debug_assert_eq!(IMAGE % PAGE, 0);
+            RamSlots {
+                application: vec![0xFF; IMAGE],
+                staging: vec![0xFF; IMAGE + PAGE],
+                scratch: vec![0xFF; PAGE],
+                power: None,
+            }
+        }
+
+        /// The application slot, as a debugger would read it.
+        pub fn application(&self) -> &[u8] {
+            &self.application
+        }
+
+        /// Program the application slot as a debugger would, such as with
+        /// the firmware the board left the factory with.
+        pub fn flash_application(&mut self, image: &[u8]) {
+            self.application.fill(0xFF);
+            self.application[..image.len()].copy_from_slice(image);
+        }
+
+        /// Let `half_words` more half-words be programmed, then fail every
+        /// write and erase, as if the power went out.
+        pub fn cut_power_after(&mut self, half_words: usize) {
+            self.power = Some(half_words);
+        }
+
+        pub fn restore_power(&mut self) {
+            self.power = None;
+        }
+
+        fn slot(&mut self, slot: Slot) -> &mut Vec<u8> {
+            match slot {
+                Slot::Application => &mut self.application,
+                Slot::Staging => &mut self.staging,
+                Slot::Scratch => &mut self.scratch,
+            }
+        }
+    }
+
+    impl<const PAGE: usize, const IMAGE: usize> Default for RamSlots<PAGE, IMAGE> {
+        fn default() -> RamSlots<PAGE, IMAGE> {
+            RamSlots::new()
+        }
+    }
+
+    impl<const PAGE: usize, const IMAGE: usize> Slots for RamSlots<PAGE, IMAGE> {
+        type Error = RamFlashError;
+
+        const PAGE_SIZE: usize = PAGE;
+        const IMAGE_SIZE: usize = IMAGE;
+
+        fn read(&mut self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), RamFlashError> {
+            let slot = self.slot(slot);
+            if offset + buf.len() > slot.len() {
+                return Err(RamFlashError::OutOfRange);
+            }
+            buf.copy_from_slice(&slot[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), RamFlashError> {
+            if !offset.is_multiple_of(2) || !data.len().is_multiple_of(2) {
+                return Err(RamFlashError::Unaligned);
+            }
+            if offset + data.len() > self.slot(slot).len() {
+                return Err(RamFlashError::OutOfRange);
+            }
+            for (i, half_word) in data.chunks(2).enumerate() {
+                if let Some(left) = self.power.as_mut() {
+                    if *left == 0 {
+                        return Err(RamFlashError::PowerCut);
+                    }
+                    *left -= 1;
+                }
+                let at = offset + 2 * i;
+                let slot = self.slot(slot);
+                if slot[at] != 0xFF || slot[at + 1] != 0xFF {
+                    return Err(RamFlashError::NotErased);
+                }
+                slot[at..at + 2].copy_from_slice(half_word);
+            }
+            Ok(())
+        }
+
+        fn erase_page(&mut self, slot: Slot, page: usize) -> Result<(), RamFlashError> {
+            if self.power == Some(0) {
+                return Err(RamFlashError::PowerCut);
+            }
+            let slot = self.slot(slot);
+            if (page + 1) * PAGE > slot.len() {
+                return Err(RamFlashError::OutOfRange);
+            }
+            slot[page * PAGE..(page + 1) * PAGE].fill(0xFF);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type SmallSlots = RamSlots<128, 512>;
+
+    fn image(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    // What the board left the factory with
+    fn factory(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 5 + 1) as u8).collect()
+    }
+
+    fn stage(updater: &mut Updater<SmallSlots>, image: &[u8]) -> Result<(), UpdateError> {
+        updater.begin(ImageInfo::of(image))?;
+        for (i, chunk) in image.chunks(24).enumerate() {
+            updater.chunk((i * 24) as u32, chunk)?;
+        }
+        updater.finish()
+    }
+
+    fn header(image: &[u8], installed: bool, confirmed: bool, reverted: bool) -> Option<Header> {
+        Some(Header { image: ImageInfo::of(image), installed, confirmed, reverted })
+    }
+
+    // Boot with the power going out after `half_words` are programmed, and
+    // come back to the next boot
+    fn power_cut(updater: Updater<SmallSlots>, half_words: usize, confirmed: bool) -> Updater<SmallSlots> {
+        let mut slots = updater.release();
+        slots.cut_power_after(half_words);
+        let mut updater = Updater::new(slots);
+        let _ = updater.install(confirmed);
+        let mut slots = updater.release();
+        slots.restore_power();
+        Updater::new(slots)
+    }
+
+    #[test]
+    fn stage_and_install() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let new = image(201);
+        stage(&mut updater, &new).unwrap();
+        assert_eq!(updater.status(), UpdateStatus::Staged(ImageInfo::of(&new)));
+        assert_eq!(updater.header().unwrap(), header(&new, false, false, false));
+
+        assert_eq!(updater.install(false), Ok(Some(ImageInfo::of(&new))));
+        assert_eq!(updater.header().unwrap(), header(&new, true, false, false));
+        assert_eq!(updater.application_intact(), Ok(true));
+        // Kept once it confirms, and only installed once
+        assert_eq!(updater.install(true), Ok(None));
+        assert_eq!(updater.header().unwrap(), header(&new, true, true, false));
+        assert_eq!(updater.install(false), Ok(None));
+        let slots = updater.release();
+        assert_eq!(&slots.application()[..new.len()], &new[..]);
+    }
+
+    #[test]
+    fn unconfirmed_image_rolls_back() {
+        let mut slots = SmallSlots::new();
+        let old = factory(300);
+        slots.flash_application(&old);
+        let mut updater = Updater::new(slots);
+        let new = image(180);
+        stage(&mut updater, &new).unwrap();
+        assert_eq!(updater.install(false), Ok(Some(ImageInfo::of(&new))));
+
+        // It never got as far as confirming
+        assert_eq!(updater.install(false), Err(UpdateError::RolledBack));
+        assert_eq!(updater.status(), UpdateStatus::InstallFailed(UpdateError::RolledBack));
+        assert_eq!(updater.header().unwrap(), header(&new, true, false, true));
+        assert_eq!(updater.header().unwrap().unwrap().application(), None);
+        assert_eq!(updater.install(true), Ok(None));
+        assert_eq!(updater.application_intact(), Ok(true));
+        assert_eq!(&updater.release().application()[..old.len()], &old[..]);
+    }
+
+    #[test]
+    fn resent_chunks_are_accepted() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let new = image(100);
+        updater.begin(ImageInfo::of(&new)).unwrap();
+        updater.chunk(0, &new[..50]).unwrap();
+        updater.chunk(0, &new[..50]).unwrap();
+        assert_eq!(updater.status(), UpdateStatus::Receiving { size: 100, received: 50 });
+        assert_eq!(updater.chunk(0, &[0; 50]), Err(UpdateError::OutOfOrder));
+        assert_eq!(updater.chunk(60, &new[60..]), Err(UpdateError::OutOfOrder));
+        assert_eq!(updater.finish(), Err(UpdateError::Incomplete));
+        // Only the last chunk may be odd
+        assert_eq!(updater.chunk(50, &new[50..75]), Err(UpdateError::OutOfOrder));
+        updater.chunk(50, &new[50..]).unwrap();
+        updater.finish().unwrap();
+        updater.finish().unwrap();
+    }
+
+    #[test]
+    fn bad_crc_rolls_back() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let old = image(100);
+        stage(&mut updater, &old).unwrap();
+        updater.install(false).unwrap();
+        updater.install(true).unwrap();
+
+        let new = image(150);
+        updater.begin(ImageInfo { crc: 0, ..ImageInfo::of(&new) }).unwrap();
+        updater.chunk(0, &new).unwrap();
+        assert_eq!(updater.finish(), Err(UpdateError::Crc));
+        assert_eq!(updater.status(), UpdateStatus::Idle);
+        // Nothing to install: the old firmware stays
+        assert_eq!(updater.header().unwrap(), None);
+        assert_eq!(updater.install(false), Ok(None));
+        assert_eq!(&updater.release().application()[..old.len()], &old[..]);
+    }
+
+    #[test]
+    fn sizes() {
+        let mut updater = Updater::new(SmallSlots::new());
+        assert_eq!(updater.begin(ImageInfo { size: 0, crc: 0 }), Err(UpdateError::BadSize));
+        assert_eq!(updater.begin(ImageInfo { size: 513, crc: 0 }), Err(UpdateError::BadSize));
+        assert_eq!(updater.chunk(0, &[1, 2]), Err(UpdateError::NotStarted));
+        assert_eq!(updater.finish(), Err(UpdateError::NotStarted));
+        stage(&mut updater, &image(512)).unwrap();
+    }
+
+    #[test]
+    fn interrupted_install_is_redone() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let new = image(180);
+        stage(&mut updater, &new).unwrap();
+        let mut slots = updater.release();
+        slots.cut_power_after(40);
+        let mut updater = Updater::new(slots);
+        assert_eq!(updater.install(false), Err(UpdateError::Flash));
+        assert_eq!(updater.status(), UpdateStatus::InstallFailed(UpdateError::Flash));
+
+        // The next boot
+        let mut slots = updater.release();
+        slots.restore_power();
+        let mut updater = Updater::new(slots);
+        assert_eq!(updater.install(false), Ok(Some(ImageInfo::of(&new))));
+        assert_eq!(&updater.release().application()[..new.len()], &new[..]);
+    }
+
+    #[test]
+    fn swaps_survive_power_cuts() {
+        let (old, new) = (factory(300), image(180));
+        // Two pages, each taking three page copies and their journal entries
+        for half_words in (0..400).step_by(7) {
+            let mut slots = SmallSlots::new();
+            slots.flash_application(&old);
+            let mut updater = Updater::new(slots);
+            stage(&mut updater, &new).unwrap();
+            // Confirmed at the boot after, whether or not it got to start
+            let mut updater = power_cut(updater, half_words, false);
+            updater.install(true).unwrap();
+            assert_eq!(updater.header().unwrap().unwrap().application(), Some(ImageInfo::of(&new)));
+            assert_eq!(updater.application_intact(), Ok(true), "cut after {}", half_words);
+
+            let mut updater = Updater::new(SmallSlots::new());
+            updater.slots.flash_application(&old);
+            stage(&mut updater, &new).unwrap();
+            updater.install(false).unwrap();
+            let mut updater = power_cut(updater, half_words, false);
+            assert!(matches!(updater.install(false), Ok(None) | Err(UpdateError::RolledBack)));
+            assert_eq!(&updater.release().application()[..old.len()], &old[..], "cut after {}", half_words);
+        }
+    }
+
+    #[test]
+    fn corrupt_application_is_caught() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let new = image(150);
+        stage(&mut updater, &new).unwrap();
+        updater.install(false).unwrap();
+        updater.slots.flash_application(&factory(150));
+        assert_eq!(updater.application_intact(), Ok(false));
+    }
+
+    #[test]
+    fn corrupt_staging_is_dropped() {
+        let mut updater = Updater::new(SmallSlots::new());
+        let old = image(100);
+        stage(&mut updater, &old).unwrap();
+        updater.install(false).unwrap();
+        updater.install(true).unwrap();
+        stage(&mut updater, &image(120)).unwrap();
+        // The staged image goes bad before the next boot
+        let mut slots = updater.release();
+        slots.erase_page(Slot::Staging, 0).unwrap();
+
+        let mut updater = Updater::new(slots);
+        assert_eq!(updater.install(false), Err(UpdateError::Crc));
+        assert_eq!(updater.header().unwrap(), None);
+        assert_eq!(&updater.release().application()[..old.len()], &old[..]);
+    }
+
+    #[test]
+    fn error_tags() {
+        // The exact bytes of each error; see `Message`'s golden tests
+        let golden = [
+            (UpdateError::NotInBootloader, "00"),
+            (UpdateError::BadSize, "01"),
+            (UpdateError::NotStarted, "02"),
+            (UpdateError::OutOfOrder, "03"),
+            (UpdateError::Incomplete, "04"),
+            (UpdateError::Crc, "05"),
+            (UpdateError::Flash, "06"),
+            (UpdateError::RolledBack, "07"),
+            (UpdateError::Unknown(200), "18c8"),
+        ];
+        for (error, expected) in golden.iter() {
+            let bytes = serde_cbor::to_vec(error).unwrap();
+            assert_eq!(hex(&bytes), *expected, "{:?}", error);
+            assert_eq!(serde_cbor::from_slice::<UpdateError>(&bytes).unwrap(), *error);
+        }
+    }
+
+    #[test]
+    fn status_tags() {
+        let image = ImageInfo { size: 1000, crc: 0xCBF4_3926 };
+        let golden = [
+            (UpdateStatus::Application(None), "8200f6"),
+            (UpdateStatus::Application(Some(image)), "8200821903e81acbf43926"),
+            (UpdateStatus::Idle, "8101"),
+            (UpdateStatus::Receiving { size: 1000, received: 128 }, "83021903e81880"),
+            (UpdateStatus::Staged(image), "8203821903e81acbf43926"),
+            (UpdateStatus::InstallFailed(UpdateError::Crc), "820405"),
+            (UpdateStatus::Unknown(200), "8118c8"),
+        ];
+        for (status, expected) in golden.iter() {
+            let bytes = serde_cbor::to_vec(status).unwrap();
+            assert_eq!(hex(&bytes), *expected, "{:?}", status);
+            assert_eq!(serde_cbor::from_slice::<UpdateStatus>(&bytes).unwrap(), *status);
+        }
+        // A status from a newer peer, with fields, and one that gained a field
+        assert_eq!(serde_cbor::from_slice::<UpdateStatus>(&[0x83, 0x18, 0xc8, 0x01, 0x02]).unwrap(), UpdateStatus::Unknown(200));
+        assert_eq!(serde_cbor::from_slice::<UpdateStatus>(&[0x82, 0x01, 0x05]).unwrap(), UpdateStatus::Idle);
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
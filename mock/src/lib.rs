@@ -0,0 +1,678 @@
+//! A host-side stand-in for the STM32F3 Discovery firmware.
+//!
+//! `MockBoard` answers requests through the same `common::dispatch` as the
+//! firmware and the bootloader, with synthetic sensor data, and speaks the
+//! `common::Link` framing so it can be plugged in anywhere the real board's
+//! byte stream is expected.
+
+use common::{
+    config::{ConfigStore, RamFlash},
+    crash::CrashReport,
+    device::{DeviceInfo, FirmwareVersion, Uid},
+    dispatch::{self, AppHardware, AppState, BootHardware, Hardware, Reading, Target},
+    link::{Link, LinkError, MAX_FRAME_SIZE},
+    message_queue::{OverflowPolicy, QueueStats},
+    update::{ImageChunk, ImageInfo, RamSlots, Updater},
+    Envelope, Message, MessageQueue, Sensors, Stamp,
+};
+use log::{trace, warn};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far the synthetic board turns between samples, in degrees.
+pub const YAW_STEP_DEG: f32 = 10.0;
+
+pub use common::dispatch::IDLE_RATE_HZ;
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+/// First word of every mock board's unique ID ("MOCK"). The rest is the
+/// process ID and a counter, so mocks in separate processes or threads can
+/// be told apart like real boards.
+pub const MOCK_UID_TAG: u32 = 0x4B43_4F4D;
+
+static NEXT_UID: AtomicU32 = AtomicU32::new(0);
+
+fn next_uid() -> Uid {
+    Uid([MOCK_UID_TAG, std::process::id(), NEXT_UID.fetch_add(1, Ordering::Relaxed)])
+}
+
+/// Page size of the STM32F303's flash, which the mock's config store and
+/// firmware slots mimic.
+pub const CONFIG_PAGE_SIZE: usize = 2048;
+/// Largest firmware image, as big as the board's application slot (see
+/// `board/memory.x`).
+pub const IMAGE_SIZE: usize = 100 * 1024;
+
+/// The mock's stand-in for the flash pages holding its config store.
+pub type MockFlash = RamFlash<CONFIG_PAGE_SIZE>;
+/// The mock's stand-in for the bootloader's application and staging slots.
+pub type MockSlots = RamSlots<CONFIG_PAGE_SIZE, IMAGE_SIZE>;
+
+/// The mock's side of `Hardware`, as the firmware's and the bootloader's.
+struct MockHardware {
+    uid: Uid,
+    config: ConfigStore<MockFlash>,
+    queue: MessageQueue,
+    rate_hz: u16,
+    boot: Instant,
+    updater: Updater<MockSlots>,
+    // Reset once the request being answered is done
+    reset: Option<Target>,
+    // The firmware's word to the bootloader that it booted well
+    confirmed: bool,
+}
+
+impl Hardware for MockHardware {
+    fn push(&mut self, env: Envelope) -> bool {
+        if self.queue.enqueue(&env).is_err() {
+            warn!("Queue full, dropping {:?}", env);
+            return false;
+        }
+        true
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let version = FirmwareVersion::parse(env!("CARGO_PKG_VERSION")).unwrap_or_default();
+        DeviceInfo::new(version, self.uid, Sensors::NONE)
+    }
+
+    fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    fn reset(&mut self, target: Target) {
+        self.reset = Some(target);
+    }
+}
+
+impl AppHardware for MockHardware {
+    type Flash = MockFlash;
+
+    fn now_us(&self) -> u64 {
+        self.boot.elapsed().as_micros() as u64
+    }
+
+    fn set_sample_rate(&mut self, rate_hz: u16) {
+        self.rate_hz = rate_hz;
+    }
+
+    fn config(&mut self) -> Option<&mut ConfigStore<MockFlash>> {
+        Some(&mut self.config)
+    }
+
+    fn installed(&mut self) -> Option<ImageInfo> {
+        self.updater.header().ok().flatten().and_then(|header| header.application())
+    }
+
+    fn confirm_boot(&mut self) {
+        self.confirmed = true;
+    }
+}
+
+impl BootHardware for MockHardware {
+    type Slots = MockSlots;
+
+    fn updater(&mut self) -> &mut Updater<MockSlots> {
+        &mut self.updater
+    }
+}
+
+pub struct MockBoard {
+    app: AppState,
+    hw: MockHardware,
+    link: Link,
+    ticks: u32,
+    // Running the bootloader rather than the application
+    bootloader: bool,
+    corrupt_updates: bool,
+}
+
+impl MockBoard {
+    pub fn new() -> MockBoard {
+        MockBoard::with_flash(MockFlash::new())
+    }
+
+    /// Boot with the config store left in `flash` by an earlier board.
+    pub fn with_flash(flash: MockFlash) -> MockBoard {
+        let mut board = MockBoard {
+            app: AppState::new(Sensors::ALL, None),
+            hw: MockHardware {
+                uid: next_uid(),
+                config: ConfigStore::new(flash).expect("RAM flash can't fail"),
+                queue: MessageQueue::with_policy(OverflowPolicy::Coalesce),
+                rate_hz: IDLE_RATE_HZ,
+                boot: Instant::now(),
+                updater: Updater::new(MockSlots::new()),
+                reset: None,
+                confirmed: false,
+            },
+            link: Link::new(),
+            ticks: 0,
+            bootloader: false,
+            corrupt_updates: false,
+        };
+        board.app.apply_config(board.hw.config());
+        board.hw.rate_hz = board.app.idle_rate_hz;
+        board
+    }
+
+    /// Pretend the last boot ended in `report`, to be reported after the
+    /// next `Hello` and to `CrashReportReq`.
+    pub fn crashed(&mut self, report: CrashReport) {
+        self.app.crash = Some(report);
+        self.app.crash_reported = false;
+    }
+
+    /// Flip a bit at the start of every firmware image sent from now on, so
+    /// it no longer matches its CRC.
+    pub fn corrupt_updates(&mut self) {
+        self.corrupt_updates = true;
+    }
+
+    /// The image the bootloader last installed, if it installed one.
+    pub fn installed(&mut self) -> Option<ImageInfo> {
+        self.hw.installed()
+    }
+
+    /// Whether the bootloader is running rather than the application.
+    pub fn in_bootloader(&self) -> bool {
+        self.bootloader
+    }
+
+    /// Reset, into the bootloader if `bootloader`. As on the board, the
+    /// bootloader first installs any staged image, or rolls back one that
+    /// didn't confirm, and stays in charge if either happens.
+    fn reboot(&mut self, bootloader: bool) {
+        let slots = std::mem::take(&mut self.hw.updater).release();
+        self.hw.updater = Updater::new(slots);
+        self.bootloader = bootloader;
+        let confirmed = std::mem::take(&mut self.hw.confirmed);
+        if let Err(e) = self.hw.updater.install(confirmed) {
+            warn!("Mock bootloader failed to install the staged image: {:?}", e);
+            self.bootloader = true;
+        }
+        self.app.subscription = None;
+        self.hw.rate_hz = self.app.idle_rate_hz;
+        self.hw.boot = Instant::now();
+    }
+
+    /// Power off, keeping only what's in flash.
+    pub fn into_flash(self) -> MockFlash {
+        self.hw.config.release()
+    }
+
+    /// Microseconds since the mock board was created.
+    pub fn now_us(&self) -> u64 {
+        self.hw.now_us()
+    }
+
+    /// Time between sensor readings, the mock equivalent of the TIM7 period.
+    pub fn sample_period(&self) -> Duration {
+        Duration::from_secs(1) / self.hw.rate_hz as u32
+    }
+
+    /// Take a new set of sensor readings, as the firmware does on every TIM7
+    /// update. The board lies flat and slowly turns about its vertical axis.
+    pub fn sample(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+        let yaw = (self.ticks as f32 * YAW_STEP_DEG).to_radians();
+        let stamp = Stamp::new(self.now_us(), self.ticks);
+        let accel = self.app.accel_calibration.apply([0., 0., 1.]);
+        let (x, y, z) = self.app.mag_calibration.apply_raw(
+            (400. * yaw.cos()) as i16,
+            (-400. * yaw.sin()) as i16,
+            -300,
+        );
+        let gyro = [0., 0., YAW_STEP_DEG * self.hw.rate_hz as f32];
+        self.app.accel = Reading { stamp, value: accel };
+        self.app.mag = Reading { stamp, value: [x, y, z] };
+        self.app.gyro = Reading { stamp, value: gyro };
+        self.app.push_samples(&mut self.hw);
+    }
+
+    pub fn uid(&self) -> Uid {
+        self.hw.uid
+    }
+
+    /// What the mock reports in `HelloAck`.
+    pub fn device_info(&self) -> DeviceInfo {
+        self.app.device_info(&self.hw)
+    }
+
+    /// Feed bytes received from the host into the board.
+    pub fn receive(&mut self, buf: &[u8]) {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let read = match self.link.decode(&buf[offset..]) {
+                Ok((read, Some(env))) => {
+                    self.process_message(env);
+                    read
+                }
+                Ok((read, None)) => read,
+                Err(LinkError::Checksum { read }) => {
+                    warn!("Bad checksum!");
+                    read
+                }
+                Err(e) => {
+                    warn!("Error decoding! {:?}", e);
+                    match e.read() {
+                        Some(read) => read,
+                        None => break,
+                    }
+                }
+            };
+            offset += read;
+        }
+    }
+
+    /// Encode every queued reply and append it to `out`.
+    pub fn transmit(&mut self, out: &mut Vec<u8>) {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        while let Some(env) = self.hw.queue.dequeue() {
+            match self.link.encode(&env, &mut buf) {
+                Ok(size) => out.extend_from_slice(&buf[..size]),
+                Err(e) => warn!("Failed to encode! {:?}", e),
+            }
+        }
+    }
+
+    /// Convenience wrapper: receive `input` and return whatever the board
+    /// wants to send back.
+    pub fn exchange(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.receive(input);
+        self.transmit(&mut out);
+        out
+    }
+
+    /// The firmware's dispatch, or the bootloader's when that's running.
+    fn process_message(&mut self, mut env: Envelope) {
+        trace!("Mock board received {:?}", env);
+        if self.bootloader {
+            if let Message::UpdateChunk { offset: 0, data } = &mut env.msg {
+                if self.corrupt_updates && !data.as_bytes().is_empty() {
+                    let mut bytes = data.as_bytes().to_vec();
+                    bytes[0] ^= 1;
+                    *data = ImageChunk::new(&bytes).unwrap();
+                }
+            }
+            dispatch::process_boot_message(env, &mut self.hw);
+        } else {
+            self.app.process_message(env, &mut self.hw);
+        }
+        if let Some(target) = self.hw.reset.take() {
+            self.reboot(target == Target::Bootloader);
+        }
+    }
+}
+
+impl Default for MockBoard {
+    fn default() -> MockBoard {
+        MockBoard::new()
+    }
+}
+
+/// Run `board` until `to_board` hangs up, sampling at the board's current
+/// rate and sending encoded replies through `from_board`.
+fn run(mut board: MockBoard, to_board: Receiver<Vec<u8>>, mut from_board: impl FnMut(Vec<u8>) -> bool) {
+    let mut next_sample = Instant::now() + board.sample_period();
+    loop {
+        match to_board.recv_timeout(POLL_PERIOD) {
+            Ok(bytes) => board.receive(&bytes),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = Instant::now();
+        // A new, faster rate takes effect straight away, as the firmware
+        // restarts TIM7 on `Subscribe`
+        next_sample = next_sample.min(now + board.sample_period());
+        if now >= next_sample {
+            board.sample();
+            next_sample = now + board.sample_period();
+        }
+        let mut out = Vec::new();
+        board.transmit(&mut out);
+        if !out.is_empty() && !from_board(out) {
+            return;
+        }
+    }
+}
+
+/// Start a mock board on its own thread, connected through an in-process
+/// pipe. Bytes sent on the returned `Sender` reach the board; its replies
+/// arrive on the `Receiver`. Dropping the `Sender` stops the board.
+pub fn spawn() -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    spawn_board(MockBoard::new())
+}
+
+/// `spawn` for a board set up by the caller.
+pub fn spawn_board(board: MockBoard) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    let (to_board_tx, to_board_rx) = channel();
+    let (from_board_tx, from_board_rx) = channel::<Vec<u8>>();
+    thread::spawn(move || {
+        run(board, to_board_rx, |bytes| from_board_tx.send(bytes).is_ok());
+    });
+    (to_board_tx, from_board_rx)
+}
+
+/// Serve a mock board over a byte stream, such as a PTY master or a socket.
+/// Returns once the reader reaches end of file or the writer fails.
+pub fn serve<R, W>(mut reader: R, mut writer: W)
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (to_board_tx, to_board_rx) = channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; Message::MAX_SIZE];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => {
+                    if to_board_tx.send(buf[..count].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Mock board read failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    run(MockBoard::new(), to_board_rx, |bytes| {
+        writer.write_all(&bytes).and_then(|_| writer.flush()).is_ok()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::calibration::MagCalibration;
+    use common::config::{keys, ConfigData, ConfigError};
+    use common::message::LogText;
+    use common::update::{UpdateError, UpdateStatus, MAX_CHUNK_SIZE};
+
+    fn encode(host: &mut Link, msg: Message) -> Vec<u8> {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let size = host.encode(&Envelope::new(1, msg), &mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    fn decode_envelopes(host: &mut Link, bytes: &[u8]) -> Vec<Envelope> {
+        let mut envelopes = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (read, rx) = host.decode(&bytes[offset..]).unwrap();
+            envelopes.extend(rx);
+            offset += read;
+        }
+        envelopes
+    }
+
+    fn decode_all(host: &mut Link, bytes: &[u8]) -> Vec<Message> {
+        decode_envelopes(host, bytes)
+            .into_iter()
+            .map(|env| {
+                assert_eq!(env.seq, 1);
+                env.msg
+            })
+            .collect()
+    }
+
+    fn request(board: &mut MockBoard, host: &mut Link, msg: Message) -> Vec<Message> {
+        let bytes = encode(host, msg);
+        let reply = board.exchange(&bytes);
+        decode_all(host, &reply)
+    }
+
+    #[test]
+    fn hello() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let info = board.device_info();
+        assert_eq!(request(&mut board, &mut host, Message::Hello), vec![Message::HelloAck(info)]);
+        assert_eq!(info.protocol_version, common::device::PROTOCOL_VERSION);
+        assert_eq!(info.uid, board.uid());
+        assert_eq!(info.uid.0[0], MOCK_UID_TAG);
+        assert_ne!(MockBoard::new().uid(), board.uid());
+        assert_eq!(info.firmware_version, FirmwareVersion::parse(env!("CARGO_PKG_VERSION")).unwrap());
+    }
+
+    #[test]
+    fn crash_report() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        assert_eq!(request(&mut board, &mut host, Message::CrashReportReq), vec![Message::CrashReport(None)]);
+
+        let report = CrashReport::panic(LogText::new(b"oops"), "src/main.rs", 7);
+        board.crashed(report);
+        // Told once, unsolicited, straight after the first `Hello`
+        let crash = Envelope::from(Message::CrashReport(Some(report)));
+        for told in [true, false] {
+            let reply = board.exchange(&encode(&mut host, Message::Hello));
+            let envelopes = decode_envelopes(&mut host, &reply);
+            assert!(matches!(envelopes[0], Envelope { seq: 1, msg: Message::HelloAck(_) }));
+            assert_eq!(envelopes.get(1), Some(&crash).filter(|_| told));
+        }
+        assert_eq!(request(&mut board, &mut host, Message::CrashReportReq), vec![Message::CrashReport(Some(report))]);
+    }
+
+    #[test]
+    fn sensors() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        board.sample();
+        match request(&mut board, &mut host, Message::AccelReq).as_slice() {
+            [Message::Accel(stamp, x, y, z)] => {
+                assert_eq!(stamp.count, 1);
+                assert_eq!((*x, *y, *z), (0., 0., 1.));
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+        match request(&mut board, &mut host, Message::MagReq).as_slice() {
+            [Message::Mag(stamp, _, _, z)] => {
+                assert_eq!(stamp.count, 1);
+                assert_eq!(*z, -300);
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+        match request(&mut board, &mut host, Message::GyroReq).as_slice() {
+            [Message::Gyro(stamp, x, y, z)] => {
+                assert_eq!(stamp.count, 1);
+                assert_eq!((*x, *y), (0., 0.));
+                assert_eq!(*z, YAW_STEP_DEG * IDLE_RATE_HZ as f32);
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+        // Turning anticlockwise from north, seen from above
+        match request(&mut board, &mut host, Message::HeadingReq).as_slice() {
+            [Message::Heading(h)] => assert!((h - (360. - YAW_STEP_DEG)).abs() < 0.5, "heading {}", h),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mag_calibration() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let cal = MagCalibration { offset: [0., 0., -100.], ..MagCalibration::IDENTITY };
+        assert_eq!(request(&mut board, &mut host, Message::SetMagCalibration(cal)), vec![Message::Ack]);
+        board.sample();
+        match request(&mut board, &mut host, Message::MagReq).as_slice() {
+            [Message::Mag(_, _, _, z)] => assert_eq!(*z, -200),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn config_survives_reboot() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let cal = MagCalibration { offset: [0., 0., -100.], ..MagCalibration::IDENTITY };
+        let data = ConfigData::from_value(&cal).unwrap();
+        let set = Message::ConfigSet(keys::MAG_CALIBRATION, data);
+        assert_eq!(request(&mut board, &mut host, set), vec![Message::Ack]);
+        let rate = Message::ConfigSet(keys::IDLE_RATE_HZ, ConfigData::from_value(&20u16).unwrap());
+        assert_eq!(request(&mut board, &mut host, rate), vec![Message::Ack]);
+        assert_eq!(request(&mut board, &mut host, Message::ConfigCommit), vec![Message::Ack]);
+        let staged = Message::ConfigSet(keys::DEVICE_NAME, ConfigData::new(b"lost").unwrap());
+        assert_eq!(request(&mut board, &mut host, staged), vec![Message::Ack]);
+
+        let mut board = MockBoard::with_flash(board.into_flash());
+        let mut host = Link::new();
+        assert_eq!(board.sample_period(), Duration::from_millis(50));
+        assert_eq!(
+            request(&mut board, &mut host, Message::ConfigGet(keys::MAG_CALIBRATION)),
+            vec![Message::Config(keys::MAG_CALIBRATION, Some(data))]
+        );
+        assert_eq!(
+            request(&mut board, &mut host, Message::ConfigGet(keys::DEVICE_NAME)),
+            vec![Message::Config(keys::DEVICE_NAME, None)]
+        );
+        board.sample();
+        match request(&mut board, &mut host, Message::MagReq).as_slice() {
+            [Message::Mag(_, _, _, z)] => assert_eq!(*z, -200),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+
+        let erase = Message::ConfigErase(keys::MAG_CALIBRATION);
+        assert_eq!(request(&mut board, &mut host, erase), vec![Message::Ack]);
+        assert_eq!(request(&mut board, &mut host, Message::ConfigCommit), vec![Message::Ack]);
+        let bad = Message::ConfigSet(0xFFFF, data);
+        assert_eq!(request(&mut board, &mut host, bad), vec![Message::ConfigFailed(ConfigError::BadKey)]);
+    }
+
+    #[test]
+    fn time() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let before = board.now_us();
+        match request(&mut board, &mut host, Message::TimeReq).as_slice() {
+            [Message::Time(t)] => assert!(*t >= before),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscription() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let sub = Message::Subscribe { sensors: Sensors::ACCEL, rate_hz: 500 };
+        assert_eq!(request(&mut board, &mut host, sub), vec![Message::SubscribeAck(100)]);
+        assert_eq!(board.sample_period(), Duration::from_millis(10));
+        board.sample();
+        let pushed = board.exchange(&[]);
+        assert!(matches!(decode_all(&mut host, &pushed).as_slice(), [Message::Accel(..)]));
+
+        assert!(request(&mut board, &mut host, Message::Unsubscribe).is_empty());
+        board.sample();
+        assert!(board.exchange(&[]).is_empty());
+    }
+
+    #[test]
+    fn stats() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let sub = Message::Subscribe { sensors: Sensors::ALL, rate_hz: 100 };
+        assert_eq!(request(&mut board, &mut host, sub), vec![Message::SubscribeAck(100)]);
+        // Ten rounds of three samples, with the host not reading
+        for _ in 0..10 {
+            board.sample();
+        }
+        // Counted before the reply itself, which pushes out a sample
+        let replies = request(&mut board, &mut host, Message::StatsReq);
+        assert_eq!(replies.iter().filter(|msg| msg.is_sample()).count(), 9);
+        assert_eq!(
+            replies.last(),
+            Some(&Message::Stats(QueueStats { enqueued: 31, dropped: 20, high_watermark: 10 }))
+        );
+    }
+
+    fn update(board: &mut MockBoard, host: &mut Link, image: &[u8]) -> Vec<Message> {
+        let mut replies = request(board, host, Message::UpdateBegin(ImageInfo::of(image)));
+        for (i, chunk) in image.chunks(MAX_CHUNK_SIZE).enumerate() {
+            let offset = (i * MAX_CHUNK_SIZE) as u32;
+            let data = ImageChunk::new(chunk).unwrap();
+            replies.extend(request(board, host, Message::UpdateChunk { offset, data }));
+        }
+        replies.extend(request(board, host, Message::UpdateFinish));
+        replies
+    }
+
+    #[test]
+    fn firmware_update() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        let image: Vec<u8> = (0..1001u32).map(|i| (i * 7) as u8).collect();
+        let info = ImageInfo::of(&image);
+        let status = |board: &mut MockBoard, host: &mut Link| request(board, host, Message::UpdateStatusReq);
+        assert_eq!(status(&mut board, &mut host), vec![Message::UpdateStatus(UpdateStatus::Application(None))]);
+        let begin = Message::UpdateBegin(info);
+        let not_here = Message::UpdateFailed(UpdateError::NotInBootloader);
+        assert_eq!(request(&mut board, &mut host, begin), vec![not_here]);
+
+        assert_eq!(request(&mut board, &mut host, Message::RebootToBootloader), vec![Message::Ack]);
+        assert!(board.in_bootloader());
+        match request(&mut board, &mut host, Message::Hello).as_slice() {
+            [Message::HelloAck(info)] => assert!(info.sensors.is_empty()),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+        assert!(request(&mut board, &mut host, Message::MagReq).is_empty());
+        assert!(update(&mut board, &mut host, &image).iter().all(|reply| *reply == Message::Ack));
+        assert_eq!(status(&mut board, &mut host), vec![Message::UpdateStatus(UpdateStatus::Staged(info))]);
+
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(!board.in_bootloader());
+        assert_eq!(status(&mut board, &mut host), vec![Message::UpdateStatus(UpdateStatus::Application(Some(info)))]);
+
+        // The host talking to the new firmware keeps it
+        assert_eq!(request(&mut board, &mut host, Message::Hello).len(), 1);
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(!board.in_bootloader());
+        assert_eq!(board.installed(), Some(info));
+    }
+
+    #[test]
+    fn unconfirmed_update_rolls_back() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        assert_eq!(request(&mut board, &mut host, Message::RebootToBootloader), vec![Message::Ack]);
+        assert!(update(&mut board, &mut host, &[1, 2, 3, 4]).iter().all(|reply| *reply == Message::Ack));
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(!board.in_bootloader());
+
+        // Reset before the host ever got through
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(board.in_bootloader());
+        let rolled_back = UpdateStatus::InstallFailed(UpdateError::RolledBack);
+        assert_eq!(request(&mut board, &mut host, Message::UpdateStatusReq), vec![Message::UpdateStatus(rolled_back)]);
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(!board.in_bootloader());
+        assert_eq!(board.installed(), None);
+    }
+
+    #[test]
+    fn corrupt_update_rolls_back() {
+        let mut board = MockBoard::new();
+        let mut host = Link::new();
+        board.corrupt_updates();
+        assert_eq!(request(&mut board, &mut host, Message::RebootToBootloader), vec![Message::Ack]);
+        let replies = update(&mut board, &mut host, &[1, 2, 3]);
+        assert_eq!(replies.last(), Some(&Message::UpdateFailed(UpdateError::Crc)));
+        assert_eq!(request(&mut board, &mut host, Message::Reboot), vec![Message::Ack]);
+        assert!(!board.in_bootloader());
+        assert_eq!(board.installed(), None);
+    }
+
+    #[test]
+    fn pipe() {
+        let (to_board, from_board) = spawn();
+        let mut host = Link::new();
+        to_board.send(encode(&mut host, Message::Hello)).unwrap();
+        let reply = from_board.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(decode_all(&mut host, &reply).as_slice(), [Message::HelloAck(_)]));
+    }
+}
@@ -0,0 +1,62 @@
+use log::info;
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::dup;
+use std::fs::{File, OpenOptions};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+/// Serve a fresh mock board to every client that connects to `addr`.
+fn tcp(addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Mock board listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        info!("Connection from {}", stream.peer_addr()?);
+        let reader = stream.try_clone()?;
+        mock::serve(reader, stream);
+    }
+    Ok(())
+}
+
+/// Open a pseudo-terminal and serve a mock board on it. The client can then
+/// connect to the printed slave path as if it were `/dev/ttyACM0`.
+fn pty() -> nix::Result<()> {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let slave_path = ptsname_r(&master)?;
+
+    // Keep a handle on the slave open so the line discipline stays in raw
+    // mode between client connections and the master never sees EOF.
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&slave_path)
+        .expect("Failed to open pty slave");
+    let mut termios = tcgetattr(slave.as_raw_fd())?;
+    cfmakeraw(&mut termios);
+    tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+    let reader = unsafe { File::from_raw_fd(dup(master.as_raw_fd())?) };
+    let writer = unsafe { File::from_raw_fd(dup(master.as_raw_fd())?) };
+    info!("Mock board listening on {}", slave_path);
+    println!("{}", slave_path);
+    mock::serve(reader, writer);
+    drop(slave);
+    Ok(())
+}
+
+/// `mock` serves on a PTY, `mock --tcp <addr>` on a TCP socket.
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("--tcp") => {
+            let addr = args.get(2).map(String::as_str).unwrap_or("127.0.0.1:7070");
+            tcp(addr).unwrap();
+        }
+        _ => pty().unwrap(),
+    }
+}